@@ -0,0 +1,90 @@
+//! Measures encoding and decoding of messages.
+//!
+//!     `cargo bench --bench codec --features testing`
+//!
+//! A plain timing loop, so that the bench builds without extra dependencies.
+//! The allocations are counted by the allocator the unit tests count with.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use hyperswarm_dht::allocs::{self, Counting};
+use hyperswarm_dht::rpc::message::{Message, Type};
+
+const ITERATIONS: u32 = 100_000;
+
+/// Number of responses of a large lookup.
+const RESPONSES: u32 = 10_000;
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn announce() -> Message {
+    Message {
+        version: Some(1),
+        r#type: Type::Update.id(),
+        rid: 300,
+        to: Some(vec![127, 0, 0, 1, 0x30, 0x39]),
+        id: Some(vec![1; 32]),
+        target: Some(vec![2; 32].into()),
+        roundtrip_token: Some(vec![3; 32].into()),
+        command: Some("announce".to_string()),
+        value: Some(vec![4; 256].into()),
+        ..Default::default()
+    }
+}
+
+/// A response with the 20 closer nodes of a full bucket.
+fn find_node_response() -> Message {
+    Message {
+        version: Some(1),
+        r#type: Type::Response.id(),
+        rid: 300,
+        to: Some(vec![127, 0, 0, 1, 0x30, 0x39]),
+        id: Some(vec![1; 32]),
+        closer_nodes: Some(vec![5; 20 * 38].into()),
+        roundtrip_token: Some(vec![3; 32].into()),
+        ..Default::default()
+    }
+}
+
+fn bench(name: &str, iterations: u32, mut f: impl FnMut()) {
+    let start = Instant::now();
+    let ((), allocations) = allocs::count(|| {
+        for _ in 0..iterations {
+            f();
+        }
+    });
+    let per_iter: Duration = start.elapsed() / iterations;
+    let allocations = allocations as f64;
+    println!(
+        "{:<32} {:>8?}/iter {:>6.2} allocations/iter",
+        name,
+        per_iter,
+        allocations / iterations as f64
+    );
+}
+
+fn main() {
+    let msg = announce();
+    let buf = Bytes::from(msg.encode_to_vec(false));
+
+    bench("encode", ITERATIONS, || {
+        black_box(black_box(&msg).encode_to_vec(false));
+    });
+    bench("decode copying", ITERATIONS, || {
+        black_box(<Message as prost::Message>::decode(black_box(&buf[..])).unwrap());
+    });
+    bench("decode sharing", ITERATIONS, || {
+        black_box(Message::decode_bytes(black_box(buf.clone())).unwrap());
+    });
+
+    // the responses of a large lookup, before and after sharing the packet
+    let resp = Bytes::from(find_node_response().encode_to_vec(false));
+    bench("10k responses copying", RESPONSES, || {
+        black_box(<Message as prost::Message>::decode(black_box(&resp[..])).unwrap());
+    });
+    bench("10k responses sharing", RESPONSES, || {
+        black_box(Message::decode_bytes(black_box(resp.clone())).unwrap());
+    });
+}
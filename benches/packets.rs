@@ -0,0 +1,109 @@
+//! Measures how many packets per second a node handles.
+//!
+//!     `cargo bench --bench packets --features testing`
+//!
+//! Each packet is a ping that the node decodes, dispatches and answers, sent
+//! over the in-memory network of the `testing` module so that the numbers
+//! don't depend on the sockets of the machine. The decoding of an announce is
+//! also measured on its own, copying the fields out of the packet as before
+//! and sharing the packet as the node does now.
+use std::time::Instant;
+
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use futures::{SinkExt, StreamExt};
+use hyperswarm_dht::rpc::message::{Command, Message, Type};
+use hyperswarm_dht::rpc::{DhtConfig, RpcDht};
+use hyperswarm_dht::testing::{spawn, Network, Simulation};
+
+/// Number of pings that wait for their response at once.
+const BATCH: u64 = 64;
+
+fn ping() -> Vec<u8> {
+    Message {
+        version: Some(1),
+        r#type: Type::Query.id(),
+        rid: 300,
+        // an ephemeral client, which the node doesn't add to its routing table
+        id: None,
+        command: Some(Command::Ping.to_string()),
+        ..Default::default()
+    }
+    .encode_to_vec(false)
+}
+
+fn announce() -> Vec<u8> {
+    Message {
+        version: Some(1),
+        r#type: Type::Update.id(),
+        rid: 300,
+        to: Some(vec![127, 0, 0, 1, 0x30, 0x39]),
+        id: Some(vec![1; 32]),
+        target: Some(vec![2; 32].into()),
+        roundtrip_token: Some(vec![3; 32].into()),
+        command: Some("announce".to_string()),
+        value: Some(vec![4; 256].into()),
+        ..Default::default()
+    }
+    .encode_to_vec(false)
+}
+
+fn decode(c: &mut Criterion) {
+    let packet = Bytes::from(announce());
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("copying", |b| {
+        b.iter(|| <Message as prost::Message>::decode(black_box(&packet[..])).unwrap())
+    });
+    group.bench_function("sharing", |b| {
+        b.iter(|| Message::decode_bytes(black_box(packet.clone())).unwrap())
+    });
+    group.finish();
+}
+
+fn respond(c: &mut Criterion) {
+    let mut sim = Simulation::new();
+    let network = Network::new(1);
+    let (addr, mut client) = sim.run(async {
+        let mut node = RpcDht::with_config(
+            DhtConfig::default()
+                .set_transport(network.bind())
+                .empty_bootstrap_nodes()
+                // all pings come from the same client
+                .disable_rate_limit(),
+        )
+        .await
+        .unwrap();
+        let addr = node.local_addr().unwrap();
+        spawn(async move { while node.next().await.is_some() {} });
+        (addr, network.bind())
+    });
+    let packet = ping();
+
+    let mut group = c.benchmark_group("node");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("ping", |b| {
+        b.iter_custom(|iters| {
+            sim.run(async {
+                let start = Instant::now();
+                let (mut sent, mut answered) = (0, 0);
+                while answered < iters {
+                    while sent < iters && sent - answered < BATCH {
+                        client.feed((packet.clone(), addr)).await.unwrap();
+                        sent += 1;
+                    }
+                    client.flush().await.unwrap();
+                    let (resp, _) = client.next().await.unwrap().unwrap();
+                    if resp.is_response() {
+                        answered += 1;
+                    }
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode, respond);
+criterion_main!(benches);
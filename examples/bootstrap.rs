@@ -0,0 +1,33 @@
+//! Bootstraps a node and prints the size of its routing table
+//!
+//! By default the public hyperswarm bootstrap nodes are used, by running
+//!     `cargo run --example bootstrap -- <addr>...`
+//! the node bootstraps off the given nodes instead.
+use futures::StreamExt;
+use hyperswarm_dht::{DhtConfig, HyperDht, HyperDhtEvent};
+
+#[async_std::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    pretty_env_logger::init();
+
+    let bootstrap = std::env::args().skip(1).collect::<Vec<_>>();
+    let mut config = DhtConfig::default().ephemeral();
+    if !bootstrap.is_empty() {
+        config = config.set_bootstrap_nodes(&bootstrap);
+    }
+    let mut node = HyperDht::with_config(config).await?;
+    println!("listening on {}", node.local_addr()?);
+
+    while let Some(event) = node.next().await {
+        if let HyperDhtEvent::Bootstrapped { stats } = event {
+            println!("bootstrapped {:?}", stats);
+            break;
+        }
+    }
+
+    println!("{} nodes in the routing table", node.nodes().count());
+    for bucket in node.bucket_info().iter().filter(|b| b.nodes > 0) {
+        println!("  bucket {}: {} nodes", bucket.index, bucket.nodes);
+    }
+    Ok(())
+}
@@ -0,0 +1,35 @@
+//! Looks up the nodes closest to an id through a handle of a spawned node
+//!
+//!     `cargo run --example find-node -- <id> [<addr>...]`
+//!
+//! The id is given as 64 hex characters. By default the public hyperswarm
+//! bootstrap nodes are used, the given addresses instead if there are any.
+use futures::StreamExt;
+use hyperswarm_dht::{DhtConfig, HyperDht, HyperDhtEvent, IdBytes};
+
+#[async_std::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    pretty_env_logger::init();
+
+    let mut args = std::env::args().skip(1);
+    let id = args.next().ok_or("missing id")?.parse::<IdBytes>()?;
+    let bootstrap = args.collect::<Vec<_>>();
+    let mut config = DhtConfig::default().ephemeral();
+    if !bootstrap.is_empty() {
+        config = config.set_bootstrap_nodes(&bootstrap);
+    }
+    let mut node = HyperDht::with_config(config).await?;
+    while let Some(event) = node.next().await {
+        if let HyperDhtEvent::Bootstrapped { .. } = event {
+            break;
+        }
+    }
+
+    let (handle, _task) = node.spawn();
+    let (closest, stats) = futures::join!(handle.find_node(id), handle.stats());
+    for node in closest? {
+        println!("{} at {}", node.id, node.addr);
+    }
+    println!("{} queries finished", stats?.finished_queries);
+    Ok(())
+}
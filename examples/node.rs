@@ -0,0 +1,61 @@
+//! Runs a node and optionally joins a topic
+//!
+//!     `cargo run --example node -- [--bootstrap <addr>]... [--announce <topic>]`
+//!
+//! Without `--bootstrap` the public hyperswarm bootstrap nodes are used. The
+//! topic is given as 64 hex characters, it is announced with the port of the
+//! node and looked up periodically, every discovered peer is printed.
+use futures::StreamExt;
+use hyperswarm_dht::{DhtConfig, HyperDht, HyperDhtEvent, IdBytes, JoinOpts, QueryOpts};
+
+#[async_std::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    pretty_env_logger::init();
+
+    let mut bootstrap = Vec::new();
+    let mut topic = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--bootstrap", Some(addr)) => bootstrap.push(addr),
+            ("--announce", Some(hex)) => topic = Some(hex.parse::<IdBytes>()?),
+            _ => return Err(format!("unexpected argument: {}", arg).into()),
+        }
+    }
+
+    let mut config = DhtConfig::default();
+    if !bootstrap.is_empty() {
+        config = config.set_bootstrap_nodes(&bootstrap);
+    }
+    let mut node = HyperDht::with_config(config).await?;
+    let addr = node.local_addr()?;
+    println!("listening on {}", addr);
+
+    let _handle = topic.map(|topic| {
+        let opts = QueryOpts::new(topic).port(addr.port() as u32);
+        node.join(opts, JoinOpts::default())
+    });
+
+    while let Some(event) = node.next().await {
+        match event {
+            HyperDhtEvent::Bootstrapped { stats } => {
+                println!(
+                    "bootstrapped with {} nodes in {:?}",
+                    node.nodes().count(),
+                    stats.duration()
+                );
+            }
+            HyperDhtEvent::BootstrapFailed { .. } => {
+                println!("no bootstrap node responded, retrying later");
+            }
+            HyperDhtEvent::AnnounceResult { peers, .. } => {
+                println!("announced to {} nodes", peers.len());
+            }
+            HyperDhtEvent::PeerDiscovered { peer, local, .. } => {
+                println!("discovered peer {} (local: {})", peer, local);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
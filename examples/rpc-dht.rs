@@ -11,7 +11,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut b = RpcDht::with_config(
             DhtConfig::default()
                 .set_bootstrap_nodes(&[bootstrap])
-                .register_commands(&["values"])
+                .register_commands(["values"])
                 .bind("127.0.0.1:3402")
                 .await
                 .expect("Failed to create dht with socket"),
@@ -30,10 +30,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     RpcDhtEvent::RoutingUpdated { peer, old_peer: _ } => {
                         println!("b routing updated {:?}", peer)
                     }
-                    RpcDhtEvent::QueryResult { id: _, cmd, stats } => {
+                    RpcDhtEvent::QueryResult { cmd, stats, .. } => {
                         println!("b query result {} {:?}", cmd, stats)
                     }
+                    RpcDhtEvent::QueryCancelled { id, .. } => {
+                        println!("b query cancelled {:?}", id)
+                    }
+                    RpcDhtEvent::NodeRemoved { peer } => println!("b node removed {:?}", peer),
+                    RpcDhtEvent::NodeAddressChanged { id, addr, .. } => {
+                        println!("b node {:?} moved to {}", id, addr)
+                    }
+                    RpcDhtEvent::NodeIdChanged { addr, id, .. } => {
+                        println!("b node at {} is now {:?}", addr, id)
+                    }
                     RpcDhtEvent::Bootstrapped { .. } => {}
+                    RpcDhtEvent::BootstrapFailed { .. } => println!("b bootstrap failed"),
+                    RpcDhtEvent::ExternalAddrConfirmed { addr, .. } => {
+                        println!("b external addr {:?}", addr)
+                    }
+                    RpcDhtEvent::NetworkSuspect { timeouts, .. } => {
+                        println!("b network suspect after {} timeouts", timeouts)
+                    }
+                    RpcDhtEvent::NatStatus { status } => println!("b nat status {:?}", status),
+                    RpcDhtEvent::RefreshCompleted {
+                        bucket_index,
+                        new_nodes,
+                    } => {
+                        println!("b refreshed bucket {}: {} new", bucket_index, new_nodes)
+                    }
+                    RpcDhtEvent::Rebound { addr } => println!("b rebound to {}", addr),
+                    RpcDhtEvent::SocketError { err } => println!("b socket failed: {}", err),
                 }
             }
         }
@@ -55,7 +81,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         RpcDhtEvent::ResponseResult(_) => println!("response result"),
                         RpcDhtEvent::RoutingUpdated { .. } => println!("routing updated"),
                         RpcDhtEvent::QueryResult { .. } => println!("query result"),
+                        RpcDhtEvent::QueryCancelled { .. } => println!("query cancelled"),
+                        RpcDhtEvent::NodeRemoved { .. } => println!("node removed"),
+                        RpcDhtEvent::NodeAddressChanged { .. } => println!("node address changed"),
+                        RpcDhtEvent::NodeIdChanged { .. } => println!("node id changed"),
                         RpcDhtEvent::Bootstrapped { .. } => {}
+                        RpcDhtEvent::BootstrapFailed { .. } => println!("bootstrap failed"),
+                        RpcDhtEvent::ExternalAddrConfirmed { .. } => {
+                            println!("external addr confirmed")
+                        }
+                        RpcDhtEvent::NetworkSuspect { .. } => println!("network suspect"),
+                        RpcDhtEvent::NatStatus { .. } => println!("nat status changed"),
+                        RpcDhtEvent::RefreshCompleted { .. } => println!("bucket refreshed"),
+                        RpcDhtEvent::Rebound { .. } => println!("socket rebound"),
+                        RpcDhtEvent::SocketError { .. } => println!("socket failed"),
                     }
                 }
             }
@@ -0,0 +1,97 @@
+#!/usr/bin/env node
+// Captures messages of the dht-rpc version in package-lock.json into
+// ../tests/fixtures/, as one hex encoded packet per file.
+//
+//     npm ci && node capture-fixtures.js
+//
+// A node `a` talks to a node `b` through a proxy that records every packet
+// it forwards. `b` knows a third node, so that its `find_node` responses
+// carry closer nodes.
+const dgram = require('dgram')
+const fs = require('fs')
+const path = require('path')
+const crypto = require('crypto')
+const dht = require('dht-rpc')
+const { Message } = require('dht-rpc/lib/messages')
+
+const QUERY = 1
+const RESPONSE = 2
+const UPDATE = 3
+
+const dir = path.join(__dirname, '..', 'tests', 'fixtures')
+fs.mkdirSync(dir, { recursive: true })
+
+const fixtures = {}
+const queries = new Map()
+
+// Keeps the first packet of each kind.
+function record (buf) {
+  const msg = Message.decode(buf)
+  let name = null
+  if (msg.type === QUERY) {
+    queries.set(msg.rid, msg.command)
+    if (msg.command === 'ping') name = 'ping_query'
+  } else if (msg.type === UPDATE) {
+    if (msg.command === 'values' && msg.roundtripToken) name = 'announce_update'
+  } else if (msg.type === RESPONSE) {
+    const command = queries.get(msg.rid)
+    if (msg.error) name = 'error_response'
+    else if (command === 'ping') name = 'ping_response'
+    else if (command === '_find_node' && msg.closerNodes) name = 'find_node_response'
+  }
+  if (name && !fixtures[name]) {
+    fixtures[name] = buf.toString('hex')
+    fs.writeFileSync(path.join(dir, name + '.hex'), fixtures[name] + '\n')
+  }
+}
+
+function node (bootstrap) {
+  const n = dht({ bootstrap, ephemeral: false })
+  n.command('values', {
+    query (query, cb) { cb(null, null) },
+    update (query, cb) { cb(null, null) }
+  })
+  n.command('fail', {
+    query (query, cb) { cb(new Error('fail')) }
+  })
+  return n
+}
+
+const b = node([])
+b.listen(0, function () {
+  const bAddr = `127.0.0.1:${b.address().port}`
+  const c = node([bAddr])
+  c.once('ready', function () {
+    const proxy = dgram.createSocket('udp4')
+    let aAddr = null
+    proxy.on('message', function (buf, from) {
+      record(buf)
+      if (from.port === b.address().port) {
+        proxy.send(buf, aAddr.port, aAddr.address)
+      } else {
+        aAddr = from
+        proxy.send(buf, b.address().port, '127.0.0.1')
+      }
+    })
+    proxy.bind(0, function () {
+      const via = { host: '127.0.0.1', port: proxy.address().port }
+      const a = dht({ bootstrap: [`${via.host}:${via.port}`], ephemeral: true })
+      a.once('ready', function () {
+        a.ping(via, function (err) {
+          if (err) throw err
+          const target = crypto.randomBytes(32)
+          a.update('values', target, Buffer.from('value'), function (err) {
+            if (err) throw err
+            a.query('fail', target, function () {
+              console.log('captured', Object.keys(fixtures).sort().join(', '))
+              a.destroy()
+              b.destroy()
+              c.destroy()
+              proxy.close()
+            })
+          })
+        })
+      })
+    })
+  })
+})
@@ -0,0 +1,53 @@
+//! Counts the allocations of the current thread, so that tests and benches
+//! can check how much a code path allocates while other threads run in
+//! parallel.
+//!
+//! The unit tests install [`Counting`] as their global allocator, a bench
+//! installs it with
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: hyperswarm_dht::allocs::Counting = hyperswarm_dht::allocs::Counting;
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The system allocator, counting allocations and reallocations.
+#[derive(Debug)]
+pub struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // the counter is gone while the thread shuts down
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Runs `f` and returns its result along with the number of allocations it
+/// made on this thread.
+///
+/// Only counts if [`Counting`] is the global allocator.
+pub fn count<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let res = f();
+    (res, ALLOCATIONS.with(Cell::get) - before)
+}
@@ -1,6 +1,5 @@
-use blake2::crypto_mac::generic_array::{typenum::U64, GenericArray};
+use blake2::digest::Output;
 use blake2::{Blake2b, VarBlake2b};
-use ed25519_dalek::ed25519::signature::Signature as Sig;
 use ed25519_dalek::SignatureError;
 pub use ed25519_dalek::{ExpandedSecretKey, Keypair, PublicKey, SecretKey, Signature, Verifier};
 
@@ -65,7 +64,7 @@ pub fn verify(public: &PublicKey, msg: &[u8], sig: &Signature) -> Result<(), Sig
 
 /// Create a 64B `blake2b` hash of `val`.
 #[inline]
-pub fn hash(val: &[u8]) -> GenericArray<u8, U64> {
+pub fn hash(val: &[u8]) -> Output<Blake2b> {
     use blake2::Digest;
     let mut hasher = Blake2b::new();
     hasher.update(val);
@@ -87,7 +86,7 @@ pub fn hash_id(val: &[u8]) -> IdBytes {
 pub fn keypair() -> Keypair {
     use rand::rngs::{OsRng, StdRng};
     use rand::SeedableRng;
-    Keypair::generate(&mut StdRng::from_rng(OsRng::default()).unwrap())
+    Keypair::generate(&mut StdRng::from_rng(OsRng).unwrap())
 }
 
 #[inline]
@@ -0,0 +1,299 @@
+//! A handle to a [`HyperDht`] that is driven by its own task, see
+//! [`HyperDht::spawn`].
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use async_std::task::JoinHandle;
+use fnv::FnvHashMap;
+use futures::channel::{mpsc, oneshot};
+use futures::task::{waker, ArcWake, AtomicWaker, Context, Poll};
+use futures::StreamExt;
+
+use crate::rpc::message;
+use crate::rpc::query::QueryId;
+use crate::{
+    CommandResult, DhtStats, HyperDht, HyperDhtEvent, IdBytes, Lookup, PeerId, Peers, QueryOpts,
+};
+
+#[derive(Debug)]
+enum Command {
+    Query {
+        cmd: message::Command,
+        target: IdBytes,
+        value: Option<Vec<u8>>,
+        tx: oneshot::Sender<CommandResult>,
+    },
+    Lookup(QueryOpts, oneshot::Sender<Lookup>),
+    Announce(QueryOpts, oneshot::Sender<Vec<Peers>>),
+    UnAnnounce(QueryOpts, oneshot::Sender<Vec<Peers>>),
+    Stats(oneshot::Sender<DhtStats>),
+    Ping(SocketAddr, oneshot::Sender<io::Result<()>>),
+}
+
+/// The caller waiting for the result of a query.
+#[derive(Debug)]
+enum Pending {
+    Query(oneshot::Sender<CommandResult>),
+    Lookup(oneshot::Sender<Lookup>),
+    Announce(oneshot::Sender<Vec<Peers>>),
+}
+
+impl Pending {
+    /// Whether the caller stopped waiting for the result, registers the
+    /// waker of `cx` to be woken once it does otherwise.
+    fn poll_canceled(&mut self, cx: &mut Context<'_>) -> bool {
+        match self {
+            Pending::Query(tx) => tx.poll_canceled(cx).is_ready(),
+            Pending::Lookup(tx) => tx.poll_canceled(cx).is_ready(),
+            Pending::Announce(tx) => tx.poll_canceled(cx).is_ready(),
+        }
+    }
+}
+
+/// The queries whose caller stopped waiting for the result.
+#[derive(Debug, Default)]
+struct Cancelled {
+    ids: Mutex<Vec<QueryId>>,
+    /// The task driving the DHT.
+    task: AtomicWaker,
+}
+
+impl Cancelled {
+    fn push(&self, id: QueryId) {
+        self.ids.lock().expect("cancelled lock poisoned").push(id);
+    }
+
+    fn take(&self) -> Vec<QueryId> {
+        std::mem::take(&mut *self.ids.lock().expect("cancelled lock poisoned"))
+    }
+}
+
+/// Woken when the receiver of the query's result is dropped.
+struct Cancellation {
+    id: QueryId,
+    cancelled: Arc<Cancelled>,
+}
+
+impl ArcWake for Cancellation {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.cancelled.push(arc_self.id);
+        arc_self.cancelled.task.wake();
+    }
+}
+
+/// The callers waiting for the results of the commands of the handles.
+#[derive(Debug, Default)]
+struct Waiting {
+    queries: FnvHashMap<QueryId, Pending>,
+    pings: FnvHashMap<SocketAddr, Vec<oneshot::Sender<io::Result<()>>>>,
+    cancelled: Arc<Cancelled>,
+}
+
+impl Waiting {
+    /// Waits for the result of the query, until the caller stops waiting.
+    fn insert(&mut self, id: QueryId, mut pending: Pending) {
+        let cancellation = waker(Arc::new(Cancellation {
+            id,
+            cancelled: self.cancelled.clone(),
+        }));
+        if pending.poll_canceled(&mut Context::from_waker(&cancellation)) {
+            self.cancelled.push(id);
+        }
+        self.queries.insert(id, pending);
+    }
+
+    fn remove(&mut self, id: &QueryId) -> Option<Pending> {
+        self.queries.remove(id)
+    }
+}
+
+/// A cloneable handle to a [`HyperDht`] running in its own task.
+///
+/// Every method sends a command to the task and waits for its result. The task
+/// stops once all handles are dropped. If a future of a method is dropped
+/// before it completed, its query is cancelled.
+#[derive(Debug, Clone)]
+pub struct DhtHandle {
+    tx: mpsc::UnboundedSender<Command>,
+}
+
+impl DhtHandle {
+    /// Finds the closest nodes to `id`, closest first, see
+    /// [`HyperDht::find_node`].
+    pub async fn find_node(&self, id: impl Into<IdBytes>) -> io::Result<Vec<PeerId>> {
+        let result = self
+            .query(message::Command::FindNode, id.into(), None)
+            .await?;
+        Ok(result.closest)
+    }
+
+    /// Runs a query with the command `cmd`, see [`HyperDht::query`].
+    pub async fn query(
+        &self,
+        cmd: impl Into<message::Command>,
+        target: impl Into<IdBytes>,
+        value: Option<Vec<u8>>,
+    ) -> io::Result<CommandResult> {
+        let (cmd, target) = (cmd.into(), target.into());
+        self.send(|tx| Command::Query {
+            cmd,
+            target,
+            value,
+            tx,
+        })
+        .await
+    }
+
+    /// Looks up the topic, see [`HyperDht::lookup`].
+    pub async fn lookup(&self, opts: impl Into<QueryOpts>) -> io::Result<Lookup> {
+        self.send(|tx| Command::Lookup(opts.into(), tx)).await
+    }
+
+    /// Announces the topic and returns the peers of the topic that were
+    /// found on the way, see [`HyperDht::announce`].
+    pub async fn announce(&self, opts: impl Into<QueryOpts>) -> io::Result<Vec<Peers>> {
+        self.send(|tx| Command::Announce(opts.into(), tx)).await
+    }
+
+    /// Unannounces the topic, see [`HyperDht::unannounce`].
+    pub async fn unannounce(&self, opts: impl Into<QueryOpts>) -> io::Result<Vec<Peers>> {
+        self.send(|tx| Command::UnAnnounce(opts.into(), tx)).await
+    }
+
+    /// The current stats of the DHT, see [`HyperDht::stats`].
+    pub async fn stats(&self) -> io::Result<DhtStats> {
+        self.send(Command::Stats).await
+    }
+
+    /// Pings the node at `addr`, see [`HyperDht::ping`].
+    ///
+    /// Fails with [`io::ErrorKind::TimedOut`] if the node didn't respond.
+    pub async fn ping(&self, addr: SocketAddr) -> io::Result<()> {
+        self.send(|tx| Command::Ping(addr, tx)).await?
+    }
+
+    async fn send<T>(&self, cmd: impl FnOnce(oneshot::Sender<T>) -> Command) -> io::Result<T> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.unbounded_send(cmd(tx)).map_err(|_| stopped())?;
+        // dropped if the query was cancelled
+        rx.await.map_err(|_| stopped())
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("the dht stopped before the command finished")
+}
+
+impl HyperDht {
+    /// Drives the DHT in a new task and returns a handle to control it.
+    ///
+    /// Events that are not the result of a command of the handle are dropped,
+    /// the [`futures::Stream`] of the DHT is for manual control over it.
+    pub fn spawn(mut self) -> (DhtHandle, JoinHandle<()>) {
+        let (tx, mut rx) = mpsc::unbounded();
+        let task = async_std::task::spawn(async move {
+            let mut pending = Waiting::default();
+            futures::future::poll_fn(|cx| loop {
+                pending.cancelled.task.register(cx.waker());
+                self.cancel_dropped(&mut pending);
+                match rx.poll_next_unpin(cx) {
+                    Poll::Ready(Some(cmd)) => {
+                        self.on_handle_command(cmd, &mut pending);
+                        continue;
+                    }
+                    Poll::Ready(None) => return Poll::Ready(()),
+                    Poll::Pending => {}
+                }
+                match self.poll_next_unpin(cx) {
+                    Poll::Ready(Some(event)) => Self::on_handle_event(event, &mut pending),
+                    Poll::Ready(None) => return Poll::Ready(()),
+                    Poll::Pending => return Poll::Pending,
+                }
+            })
+            .await
+        });
+        (DhtHandle { tx }, task)
+    }
+
+    fn on_handle_command(&mut self, cmd: Command, pending: &mut Waiting) {
+        match cmd {
+            Command::Query {
+                cmd,
+                target,
+                value,
+                tx,
+            } => {
+                pending.insert(self.query(cmd, target, value), Pending::Query(tx));
+            }
+            Command::Lookup(opts, tx) => {
+                pending.insert(self.lookup(opts), Pending::Lookup(tx));
+            }
+            Command::Announce(opts, tx) => {
+                pending.insert(self.announce(opts), Pending::Announce(tx));
+            }
+            Command::UnAnnounce(opts, tx) => {
+                pending.insert(self.unannounce(opts), Pending::Announce(tx));
+            }
+            Command::Stats(tx) => {
+                let _ = tx.send(self.stats());
+            }
+            Command::Ping(addr, tx) => {
+                self.ping(addr);
+                pending.pings.entry(addr).or_default().push(tx);
+            }
+        }
+    }
+
+    /// Cancels the queries nobody waits for anymore.
+    fn cancel_dropped(&mut self, pending: &mut Waiting) {
+        for id in pending.cancelled.take() {
+            if pending.remove(&id).is_some() {
+                self.cancel_query(&id);
+            }
+        }
+    }
+
+    fn on_handle_event(event: HyperDhtEvent, pending: &mut Waiting) {
+        match event {
+            HyperDhtEvent::CommandResult(result) => {
+                if let Some(Pending::Query(tx)) = pending.remove(&result.query_id) {
+                    let _ = tx.send(result);
+                }
+            }
+            HyperDhtEvent::LookupResult { lookup, query_id } => {
+                if let Some(Pending::Lookup(tx)) = pending.remove(&query_id) {
+                    let _ = tx.send(lookup);
+                }
+            }
+            HyperDhtEvent::AnnounceResult {
+                peers, query_id, ..
+            }
+            | HyperDhtEvent::UnAnnounceResult {
+                peers, query_id, ..
+            } => {
+                if let Some(Pending::Announce(tx)) = pending.remove(&query_id) {
+                    let _ = tx.send(peers);
+                }
+            }
+            HyperDhtEvent::QueryCancelled { query_id, .. } => {
+                pending.remove(&query_id);
+            }
+            HyperDhtEvent::Pong { peer } => {
+                for tx in pending.pings.remove(&peer).into_iter().flatten() {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+            HyperDhtEvent::PingTimeout { peer } => {
+                for tx in pending.pings.remove(&peer).into_iter().flatten() {
+                    let _ = tx.send(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("{} didn't respond to the ping", peer),
+                    )));
+                }
+            }
+            _ => {}
+        }
+    }
+}
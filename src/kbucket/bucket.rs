@@ -70,7 +70,7 @@ impl<TKey, TVal> PendingNode<TKey, TVal> {
     }
 
     pub fn is_ready(&self) -> bool {
-        Instant::now() >= self.replace
+        time::now() >= self.replace
     }
 
     pub fn set_ready_at(&mut self, t: Instant) {
@@ -128,6 +128,9 @@ pub struct KBucket<TKey, TVal> {
     /// if the least-recently connected node is not updated as being connected
     /// in the meantime.
     pending_timeout: Duration,
+
+    /// The maximum number of nodes in the bucket, at most `K_VALUE`.
+    capacity: usize,
 }
 
 /// The result of inserting an entry into a bucket.
@@ -171,14 +174,26 @@ where
 {
     /// Creates a new `KBucket` with the given timeout for pending entries.
     pub fn new(pending_timeout: Duration) -> Self {
+        Self::with_capacity(pending_timeout, K_VALUE)
+    }
+
+    /// Creates a new `KBucket` that holds up to `capacity` nodes, but no more
+    /// than `K_VALUE`.
+    pub fn with_capacity(pending_timeout: Duration, capacity: NonZeroUsize) -> Self {
         KBucket {
             nodes: ArrayVec::new(),
             first_connected_pos: None,
             pending: None,
             pending_timeout,
+            capacity: capacity.min(K_VALUE).get(),
         }
     }
 
+    /// Whether the bucket holds as many nodes as it may.
+    fn is_full(&self) -> bool {
+        self.nodes.len() >= self.capacity
+    }
+
     /// Returns a reference to the pending node of the bucket, if there is any.
     pub fn pending(&self) -> Option<&PendingNode<TKey, TVal>> {
         self.pending.as_ref()
@@ -219,14 +234,15 @@ where
     /// bucket remained unchanged.
     pub fn apply_pending(&mut self) -> Option<AppliedPending<TKey, TVal>> {
         if let Some(pending) = self.pending.take() {
-            if pending.replace <= Instant::now() {
-                if self.nodes.is_full() {
+            if pending.replace <= time::now() {
+                if self.is_full() {
                     if self.status(Position(0)) == NodeStatus::Connected {
                         // The bucket is full with connected nodes. Drop the pending node.
                         return None;
                     }
-                    debug_assert!(self.first_connected_pos.map_or(true, |p| p > 0)); // (*)
-                                                                                     // The pending node will be inserted.
+                    debug_assert!(self.first_connected_pos.is_none_or(|p| p > 0)); // (*)
+
+                    // The pending node will be inserted.
                     let inserted = pending.node.clone();
                     // A connected pending node goes at the end of the list for
                     // the connected peers, removing the least-recently connected.
@@ -328,14 +344,14 @@ where
     pub fn insert(&mut self, node: Node<TKey, TVal>, status: NodeStatus) -> InsertResult<TKey> {
         match status {
             NodeStatus::Connected => {
-                if self.nodes.is_full() {
+                if self.is_full() {
                     if self.first_connected_pos == Some(0) || self.pending.is_some() {
                         return InsertResult::Full;
                     } else {
                         self.pending = Some(PendingNode {
                             node,
                             status: NodeStatus::Connected,
-                            replace: Instant::now() + self.pending_timeout,
+                            replace: time::now() + self.pending_timeout,
                         });
                         return InsertResult::Pending {
                             disconnected: self.nodes[0].key.clone(),
@@ -348,7 +364,7 @@ where
                 InsertResult::Inserted
             }
             NodeStatus::Disconnected => {
-                if self.nodes.is_full() {
+                if self.is_full() {
                     return InsertResult::Full;
                 }
                 if let Some(ref mut p) = self.first_connected_pos {
@@ -371,9 +387,7 @@ where
             // Adjust `first_connected_pos` accordingly.
             match status {
                 NodeStatus::Connected => {
-                    if self.first_connected_pos.map_or(false, |p| p == pos.0)
-                        && pos.0 == self.nodes.len()
-                    {
+                    if self.first_connected_pos == Some(pos.0) && pos.0 == self.nodes.len() {
                         // It was the last connected node.
                         self.first_connected_pos = None
                     }
@@ -392,7 +406,7 @@ where
 
     /// Returns the status of the node at the given position.
     pub fn status(&self, pos: Position) -> NodeStatus {
-        if self.first_connected_pos.map_or(false, |i| pos.0 >= i) {
+        if self.first_connected_pos.is_some_and(|i| pos.0 >= i) {
             NodeStatus::Connected
         } else {
             NodeStatus::Disconnected
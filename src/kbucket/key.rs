@@ -25,10 +25,9 @@
 // DEALINGS IN THE SOFTWARE.
 
 use std::borrow::Borrow;
+use std::convert::TryFrom;
 use std::hash::{Hash, Hasher};
 
-use sha2::digest::generic_array::typenum::U32;
-use sha2::digest::generic_array::GenericArray;
 use sha2::{Digest, Sha256};
 use uint::construct_uint;
 
@@ -45,7 +44,7 @@ construct_uint! {
 /// the records stored in the DHT.
 ///
 /// `Key`s have an XOR metric as defined in the Kademlia paper, i.e. the bitwise
-/// XOR of the hash digests, interpreted as an integer. See [`Key::distance`].
+/// XOR of the key bytes, interpreted as an integer. See [`Key::distance`].
 #[derive(Clone, Debug)]
 pub struct Key<T> {
     preimage: T,
@@ -53,8 +52,7 @@ pub struct Key<T> {
 }
 
 impl<T> Key<T> {
-    /// Constructs a new `Key` by running the given value through a random
-    /// oracle.
+    /// Constructs a new `Key` for the given value, see [`KeyBytes::new`].
     ///
     /// The preimage of type `T` is preserved. See [`Key::preimage`] and
     /// [`Key::into_preimage`].
@@ -92,11 +90,21 @@ impl<T> Key<T> {
     pub fn for_distance(&self, d: Distance) -> KeyBytes {
         self.bytes.for_distance(d)
     }
+
+    /// Constructs a `Key` at the given position in the keyspace instead of
+    /// the hash of the preimage, to place keys at exact distances in tests.
+    #[cfg(test)]
+    pub(crate) fn with_bytes(preimage: T, bytes: [u8; 32]) -> Key<T> {
+        Key {
+            preimage,
+            bytes: KeyBytes(bytes),
+        }
+    }
 }
 
-impl<T> Into<KeyBytes> for Key<T> {
-    fn into(self) -> KeyBytes {
-        self.bytes
+impl<T> From<Key<T>> for KeyBytes {
+    fn from(key: Key<T>) -> KeyBytes {
+        key.bytes
     }
 }
 
@@ -128,16 +136,22 @@ impl<T> Hash for Key<T> {
 
 /// The raw bytes of a key in the DHT keyspace.
 #[derive(PartialEq, Eq, Clone, Debug)]
-pub struct KeyBytes(GenericArray<u8, U32>);
+pub struct KeyBytes([u8; 32]);
 
 impl KeyBytes {
-    /// Creates a new key in the DHT keyspace by running the given
-    /// value through a random oracle.
+    /// Creates a new key in the DHT keyspace.
+    ///
+    /// Ids of 32 bytes are keys as they are, like the node ids and targets of
+    /// dht-rpc. Any other value is run through a random oracle.
     pub fn new<T>(value: T) -> Self
     where
         T: Borrow<[u8]>,
     {
-        KeyBytes(Sha256::digest(value.borrow()))
+        let value = value.borrow();
+        match <[u8; 32]>::try_from(value) {
+            Ok(id) => KeyBytes(id),
+            Err(_) => KeyBytes(Sha256::digest(value).into()),
+        }
     }
 
     /// Computes the distance of the keys according to the XOR metric.
@@ -145,8 +159,8 @@ impl KeyBytes {
     where
         U: AsRef<KeyBytes>,
     {
-        let a = U256::from(self.0.as_slice());
-        let b = U256::from(other.as_ref().0.as_slice());
+        let a = U256::from(&self.0[..]);
+        let b = U256::from(&other.as_ref().0[..]);
         Distance(a ^ b)
     }
 
@@ -156,8 +170,8 @@ impl KeyBytes {
     ///
     /// `self xor other = distance <==> other = self xor distance`
     pub fn for_distance(&self, d: Distance) -> KeyBytes {
-        let key_int = U256::from(self.0.as_slice()) ^ d.0;
-        KeyBytes(GenericArray::from(<[u8; 32]>::from(key_int)))
+        let key_int = U256::from(&self.0[..]) ^ d.0;
+        KeyBytes(key_int.into())
     }
 }
 
@@ -170,3 +184,13 @@ impl AsRef<KeyBytes> for KeyBytes {
 /// A distance between two keys in the DHT keyspace.
 #[derive(Copy, Clone, PartialEq, Eq, Default, PartialOrd, Ord, Debug)]
 pub struct Distance(pub(super) U256);
+
+impl Distance {
+    /// Returns the integer part of the base 2 logarithm of the distance, i.e.
+    /// the index of the bucket a key at this distance falls into.
+    ///
+    /// Returns `None` if the distance is zero.
+    pub fn ilog2(&self) -> Option<u32> {
+        (256 - self.0.leading_zeros()).checked_sub(1)
+    }
+}
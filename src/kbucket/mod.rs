@@ -75,6 +75,8 @@ use std::time::{Duration, Instant};
 use arrayvec::{self, ArrayVec};
 
 use bucket::KBucket;
+
+use crate::time;
 pub use entry::*;
 
 mod bucket;
@@ -87,16 +89,16 @@ mod key;
 ///
 ///   1) The (fixed) maximum number of nodes in a bucket.
 ///   2) The (default) replication factor, which in turn determines:
-///       a) The number of closer peers returned in response to a request.
-///       b) The number of closest peers to a key to search for in an iterative
-/// query.
+///      a) The number of closer peers returned in response to a request.
+///      b) The number of closest peers to a key to search for in an iterative
+///      query.
 ///
 /// The choice of (1) is fixed to this constant. The replication factor is
 /// configurable but should generally be no greater than `K_VALUE`. All nodes in
 /// a Kademlia DHT should agree on the choices made for (1) and (2).
 ///
 /// The current value is `20`.
-pub const K_VALUE: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(20) };
+pub const K_VALUE: NonZeroUsize = NonZeroUsize::new(20).unwrap();
 
 /// The `α` parameter of the Kademlia specification.
 ///
@@ -106,7 +108,7 @@ pub const K_VALUE: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(20) };
 /// locating the closest peers to a key.
 ///
 /// The current value is `3`.
-pub const ALPHA_VALUE: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(3) };
+pub const ALPHA_VALUE: NonZeroUsize = NonZeroUsize::new(3).unwrap();
 
 /// Maximum number of k-buckets.
 const NUM_BUCKETS: usize = 256;
@@ -121,6 +123,9 @@ pub struct KBucketsTable<TKey, TVal> {
     /// The list of evicted entries that have been replaced with pending
     /// entries since the last call to [`KBucketsTable::take_applied_pending`].
     applied_pending: VecDeque<AppliedPending<TKey, TVal>>,
+    /// When we last heard from a node of every bucket, or the bucket was
+    /// refreshed.
+    activity: Vec<Instant>,
 }
 
 /// A (type-safe) index into a `KBucketsTable`, i.e. a non-negative integer in
@@ -174,12 +179,23 @@ where
     /// a [`PendingEntry`] after which it becomes eligible for insertion into
     /// a full bucket, replacing the least-recently (dis)connected node.
     pub fn new(local_key: TKey, pending_timeout: Duration) -> Self {
+        Self::with_bucket_size(local_key, pending_timeout, K_VALUE)
+    }
+
+    /// Creates a new, empty routing table with buckets of up to `bucket_size`
+    /// nodes, but no more than `K_VALUE`.
+    pub fn with_bucket_size(
+        local_key: TKey,
+        pending_timeout: Duration,
+        bucket_size: NonZeroUsize,
+    ) -> Self {
         KBucketsTable {
             local_key,
             buckets: (0..NUM_BUCKETS)
-                .map(|_| KBucket::new(pending_timeout))
+                .map(|_| KBucket::with_capacity(pending_timeout, bucket_size))
                 .collect(),
             applied_pending: VecDeque::new(),
+            activity: vec![time::now(); NUM_BUCKETS],
         }
     }
 
@@ -188,6 +204,31 @@ where
         &self.local_key
     }
 
+    /// Records activity in the bucket the given key falls into, e.g. because
+    /// its node just contacted us.
+    pub fn touch<T: AsRef<KeyBytes>>(&mut self, key: &T) {
+        if let Some(i) = BucketIndex::new(&self.local_key.as_ref().distance(key)) {
+            self.activity[i.get()] = time::now();
+        }
+    }
+
+    /// Records activity in the bucket at `index`, e.g. because it is being
+    /// refreshed.
+    pub fn touch_bucket(&mut self, index: usize) {
+        if let Some(at) = self.activity.get_mut(index) {
+            *at = time::now();
+        }
+    }
+
+    /// Returns the indices of the buckets without any activity for `idle`.
+    pub fn idle_buckets(&self, now: Instant, idle: Duration) -> impl Iterator<Item = usize> + '_ {
+        self.activity
+            .iter()
+            .enumerate()
+            .filter(move |(_, at)| now.saturating_duration_since(**at) >= idle)
+            .map(|(i, _)| i)
+    }
+
     /// Returns an `Entry` for the given key, representing the state of the
     /// entry in the routing table.
     pub fn entry<'a>(&'a mut self, key: &'a TKey) -> Entry<'a, TKey, TVal> {
@@ -203,8 +244,23 @@ where
         }
     }
 
+    /// Returns the least-recently connected node of the bucket the given key
+    /// falls into, i.e. the first candidate for eviction if that bucket is
+    /// full.
+    pub fn least_recently_connected(&mut self, key: &TKey) -> Option<EntryView<TKey, TVal>> {
+        let index = BucketIndex::new(&self.local_key.as_ref().distance(key))?;
+        let bucket = &mut self.buckets[index.get()];
+        if let Some(applied) = bucket.apply_pending() {
+            self.applied_pending.push_back(applied)
+        }
+        bucket.iter().next().map(|(node, status)| EntryView {
+            node: node.clone(),
+            status,
+        })
+    }
+
     /// Returns an iterator over all the entries in the routing table.
-    pub fn iter(&mut self) -> impl Iterator<Item = EntryRefView<'_, TKey, TVal>> {
+    pub fn iter(&mut self) -> impl Iterator<Item = EntryRefView<'_, TKey, TVal>> + '_ {
         let applied_pending = &mut self.applied_pending;
         self.buckets.iter_mut().flat_map(move |table| {
             if let Some(applied) = table.apply_pending() {
@@ -221,11 +277,67 @@ where
         })
     }
 
+    /// Returns an iterator over all the entries in the routing table, like
+    /// [`KBucketsTable::iter`], but without applying pending entries.
+    ///
+    /// Pending entries whose timeout already expired are not part of the
+    /// iterator until the table is accessed mutably.
+    pub fn iter_ref(&self) -> impl Iterator<Item = EntryRefView<'_, TKey, TVal>> + '_ {
+        self.buckets.iter().flat_map(|bucket| {
+            bucket.iter().map(|(n, status)| EntryRefView {
+                node: NodeRefView {
+                    key: &n.key,
+                    value: &n.value,
+                },
+                status,
+            })
+        })
+    }
+
+    /// Returns the number of entries and whether a node is pending, for every
+    /// bucket, without applying pending entries.
+    ///
+    /// The buckets are ordered like in [`KBucketsTable::buckets`].
+    pub fn bucket_sizes(&self) -> impl Iterator<Item = (usize, bool)> + '_ {
+        self.buckets
+            .iter()
+            .map(|b| (b.num_entries(), b.pending().is_some_and(|n| !n.is_ready())))
+    }
+
+    /// Returns the `num` entries closest to the `target` key, ordered by
+    /// increasing distance, like [`KBucketsTable::closest`] but without
+    /// applying pending entries.
+    pub fn closest_ref<T>(&self, target: &T, num: usize) -> Vec<EntryRefView<'_, TKey, TVal>>
+    where
+        T: AsRef<KeyBytes>,
+    {
+        let distance = self.local_key.as_ref().distance(target);
+        let mut closest = Vec::with_capacity(num);
+        for i in ClosestBucketsIter::new(distance) {
+            if closest.len() >= num {
+                break;
+            }
+            let mut entries = self.buckets[i.get()]
+                .iter()
+                .map(|(n, status)| EntryRefView {
+                    node: NodeRefView {
+                        key: &n.key,
+                        value: &n.value,
+                    },
+                    status,
+                })
+                .collect::<Vec<_>>();
+            entries.sort_by_key(|e| target.as_ref().distance(e.node.key));
+            closest.extend(entries.into_iter().take(num - closest.len()));
+        }
+        closest
+    }
+
     /// Returns a by-reference iterator over all buckets.
     ///
     /// The buckets are ordered by proximity to the `local_key`, i.e. the first
     /// bucket is the closest bucket (containing at most one key).
-    pub fn buckets<'a>(&'a mut self) -> impl Iterator<Item = KBucketRef<'a, TKey, TVal>> + '_ {
+    pub fn buckets(&mut self) -> impl Iterator<Item = KBucketRef<'_, TKey, TVal>> + '_ {
         let applied_pending = &mut self.applied_pending;
         self.buckets.iter_mut().enumerate().map(move |(i, b)| {
             if let Some(applied) = b.apply_pending() {
@@ -258,7 +370,7 @@ where
 
     /// Returns an iterator over the keys closest to `target`, ordered by
     /// increasing distance.
-    pub fn closest_keys<'a, T>(&'a mut self, target: &'a T) -> impl Iterator<Item = TKey> + '_
+    pub fn closest_keys<'a, T>(&'a mut self, target: &'a T) -> impl Iterator<Item = TKey> + 'a
     where
         T: Clone + AsRef<KeyBytes>,
     {
@@ -279,7 +391,7 @@ where
     pub fn closest<'a, T>(
         &'a mut self,
         target: &'a T,
-    ) -> impl Iterator<Item = EntryView<TKey, TVal>> + '_
+    ) -> impl Iterator<Item = EntryView<TKey, TVal>> + 'a
     where
         T: Clone + AsRef<KeyBytes>,
         TVal: Clone,
@@ -499,12 +611,12 @@ where
 
     /// Returns true if the bucket has a pending node.
     pub fn has_pending(&self) -> bool {
-        self.bucket.pending().map_or(false, |n| !n.is_ready())
+        self.bucket.pending().is_some_and(|n| !n.is_ready())
     }
 
     /// Tests whether the given distance falls into this bucket.
     pub fn contains(&self, d: &Distance) -> bool {
-        BucketIndex::new(d).map_or(false, |i| i == self.index)
+        BucketIndex::new(d) == Some(self.index)
     }
 
     /// Generates a random distance that falls into this bucket.
@@ -1,31 +1,44 @@
 //! Rust Implementation of the hyperswarm DHT
 #![warn(unused, rust_2018_idioms)]
 
-use core::cmp;
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::io;
-use std::net::{IpAddr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::num::NonZeroUsize;
 use std::pin::Pin;
 use std::time::Duration;
 
 use ed25519_dalek::{Keypair, PublicKey, Signature};
 use either::Either;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::task::{Context, Poll};
-use futures::Stream;
+use futures::{Future, Stream, StreamExt};
 use prost::Message as ProstMessage;
-use sha2::digest::generic_array::{typenum::U32, GenericArray};
+use sha2::{digest::Output, Sha256};
 use smallvec::alloc::collections::VecDeque;
+use wasm_timer::Instant;
 
 use crate::dht_proto::{encode_input, Mutable, PeersInput, PeersOutput};
-use crate::lru::{CacheKey, PeerCache};
-use crate::peers::{decode_local_peers, decode_peers, PeersEncoding};
-use crate::rpc::message::{Message, Type};
-use crate::rpc::query::{CommandQuery, CommandQueryResponse, QueryId, QueryStats};
-pub use crate::rpc::{DhtConfig, IdBytes, Peer, PeerId};
-use crate::rpc::{RequestOk, Response, ResponseOk, RpcDht, RpcDhtEvent};
+pub use crate::handle::DhtHandle;
+use crate::lru::{CacheKey, PeerCache, KEY_CAPACITY};
+pub use crate::peers::AddrFamily;
+use crate::peers::{
+    decode_local_peers, decode_peers, decode_peers6, encode_peers6, ipv4_subnet, PeersEncoding,
+};
+use crate::rpc::message::{Command, Message, Type};
+use crate::rpc::query::{
+    CommandQuery, CommandQueryResponse, QueryId, QueryLimits, QueryStats, QueryType, ResponseSender,
+};
+use crate::rpc::udp::Transport;
+pub use crate::rpc::{
+    BucketInfo, DhtConfig, DhtStats, IdBytes, NatStatus, NodeInfo, NodesSnapshot, Peer, PeerId,
+};
+use crate::rpc::{RequestOk, Response, ResponseError, ResponseOk, RpcDht, RpcDhtEvent};
 use crate::store::{StorageEntry, StorageKey, Store, PUT_VALUE_MAX_SIZE};
+use crate::time::Delay;
+pub use crate::topics::{JoinOpts, TopicHandle};
+use crate::topics::{TopicAction, Topics};
 
 mod dht_proto {
     use prost::Message;
@@ -41,12 +54,23 @@ mod dht_proto {
     }
 }
 
+#[macro_use]
+mod trace;
+mod time;
+
+#[cfg(any(test, feature = "testing"))]
+#[doc(hidden)]
+pub mod allocs;
 pub mod crypto;
+mod handle;
 pub mod kbucket;
 pub mod lru;
 pub mod peers;
 pub mod rpc;
 pub mod store;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod topics;
 
 #[allow(dead_code)]
 const EPH_AFTER: u64 = 1000 * 60 * 20;
@@ -74,34 +98,54 @@ pub struct HyperDht {
     inner: RpcDht,
     /// Map to track the queries currently in progress
     queries: FnvHashMap<QueryId, QueryStreamType>,
-    /// If `true`, the node will become non-ephemeral after the node has shown
-    /// to be long-lived
-    adaptive: bool,
+    /// The queries of [`HyperDht::query`] in progress.
+    commands: FnvHashSet<QueryId>,
     /// Cache for known peers
     peers: PeerCache,
+    /// Length of the public subnet prefix local addresses are revealed in.
+    local_subnet_prefix: u8,
     /// Storage for the mutable/immutable values
     store: Store,
     /// Queued events to return when being polled.
     queued_events: VecDeque<HyperDhtEvent>,
+    /// Holepunches in progress by the address of the peer, with the
+    /// referrers left to try, the current one first.
+    holepunches: FnvHashMap<SocketAddr, VecDeque<SocketAddr>>,
+    /// The addresses pinged with [`HyperDht::ping`] that didn't respond yet.
+    pings: FnvHashSet<SocketAddr>,
+    /// The topics this node announced and didn't unannounce yet.
+    announced: Vec<QueryOpts>,
+    /// The topics joined with [`HyperDht::join`].
+    topics: Topics,
+    /// Elapses once a started shutdown stops waiting for the running queries.
+    shutdown: Option<Delay>,
+    /// Whether the shutdown completed and the stream ended.
+    shut_down: bool,
 }
 
 impl HyperDht {
     /// Create a new DHT based on the configuration
     pub async fn with_config(mut config: DhtConfig) -> io::Result<Self> {
-        config = config.register_commands(&[MUTABLE_STORE_CMD, IMMUTABLE_STORE_CMD, PEERS_CMD]);
+        config = config.register_commands([MUTABLE_STORE_CMD, IMMUTABLE_STORE_CMD, PEERS_CMD]);
 
         if config.bootstrap_nodes().is_none() {
             config = config.set_bootstrap_nodes(&DEFAULT_BOOTSTRAP[..]);
         }
 
         Ok(Self {
-            adaptive: config.adaptive,
             queries: Default::default(),
+            commands: Default::default(),
+            peers: PeerCache::new(65536, config.peers_max_age).with_key_capacity(KEY_CAPACITY),
+            local_subnet_prefix: config.local_subnet_prefix,
+            store: Store::new(5000, config.peers_max_age),
+            topics: Topics::new(config.peers_max_age),
             inner: RpcDht::with_config(config).await?,
-            // peer cache with 25 min timeout
-            peers: PeerCache::new(65536, Duration::from_secs(60 * 25)),
-            store: Store::new(5000),
             queued_events: Default::default(),
+            holepunches: Default::default(),
+            pings: Default::default(),
+            announced: Vec::new(),
+            shutdown: None,
+            shut_down: false,
         })
     }
 
@@ -111,13 +155,192 @@ impl HyperDht {
         self.inner.local_addr()
     }
 
+    /// Closes the socket and binds a new UDP socket, e.g. after
+    /// [`HyperDhtEvent::NetworkSuspect`].
+    ///
+    /// All joined topics are announced and looked up again. See
+    /// [`RpcDht::rebind`].
+    pub fn rebind(&mut self, addr: Option<SocketAddr>) -> std::io::Result<()> {
+        self.inner.rebind(addr)?;
+        self.topics.refresh();
+        Ok(())
+    }
+
+    /// Like [`HyperDht::rebind`], but sends and receives over the `transport`
+    /// from now on.
+    pub fn rebind_transport(&mut self, transport: impl Transport + 'static) {
+        self.inner.rebind_transport(transport);
+        self.topics.refresh();
+    }
+
+    /// Holepunches to the `peer` via one of the `referrers`, the nodes that
+    /// returned the peer for a lookup (see [`Lookup::referrers`]).
+    ///
+    /// The referrers are tried in order, until one was able to relay the
+    /// holepunch to the peer. Once the peer responded,
+    /// [`HyperDhtEvent::HolepunchReady`] is emitted and the peer can be
+    /// dialed, if none of the referrers succeeds
+    /// [`HyperDhtEvent::HolepunchFailed`] is emitted instead.
+    ///
+    /// Returns `false` if no referrer was provided.
+    pub fn connect(
+        &mut self,
+        peer: SocketAddr,
+        referrers: impl IntoIterator<Item = SocketAddr>,
+    ) -> bool {
+        let referrers = referrers.into_iter().collect::<VecDeque<_>>();
+        if let Some(referrer) = referrers.front() {
+            self.inner.holepunch(Peer::new(peer, Some(*referrer)));
+            self.holepunches.insert(peer, referrers);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Pings the node at `addr`.
+    ///
+    /// Emits [`HyperDhtEvent::Pong`] once it responds, or
+    /// [`HyperDhtEvent::PingTimeout`] if it doesn't.
+    pub fn ping(&mut self, addr: SocketAddr) {
+        if self.pings.insert(addr) {
+            self.inner.ping_addr(addr);
+        }
+    }
+
+    /// The target of a holepunch responded.
+    fn on_holepunched(&mut self, peer: Peer) {
+        if let Some(referrers) = self.holepunches.remove(&peer.addr) {
+            if let Some(referrer) = referrers.front() {
+                self.queued_events.push_back(HyperDhtEvent::HolepunchReady {
+                    peer: peer.addr,
+                    referrer: *referrer,
+                });
+            }
+        }
+    }
+
+    /// The target of a holepunch didn't respond, retry with the next referrer.
+    fn on_holepunch_timeout(&mut self, peer: Peer) {
+        if let Some(referrers) = self.holepunches.get_mut(&peer.addr) {
+            referrers.pop_front();
+            if let Some(referrer) = referrers.front() {
+                self.inner.holepunch(Peer::new(peer.addr, Some(*referrer)));
+            } else {
+                self.holepunches.remove(&peer.addr);
+                self.queued_events
+                    .push_back(HyperDhtEvent::HolepunchFailed { peer: peer.addr });
+            }
+        }
+    }
+
+    /// Returns the id used to identify this node.
+    #[inline]
+    pub fn local_id(&self) -> &IdBytes {
+        self.inner.local_id()
+    }
+
+    /// Returns the number of nodes in the routing table.
+    ///
+    /// See [`RpcDht::num_nodes`].
+    #[inline]
+    pub fn num_nodes(&self) -> usize {
+        self.inner.num_nodes()
+    }
+
+    /// Returns the address remote peers see this node at.
+    ///
+    /// See [`RpcDht::external_addr`].
+    #[inline]
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        self.inner.external_addr()
+    }
+
+    /// Returns what is known about the NAT in front of this node.
+    ///
+    /// See [`RpcDht::nat_status`].
+    #[inline]
+    pub fn nat_status(&self) -> NatStatus {
+        self.inner.nat_status()
+    }
+
+    /// Returns the smoothed round trip time of the node with the given id.
+    ///
+    /// See [`RpcDht::peer_rtt`].
+    #[inline]
+    pub fn peer_rtt(&self, id: &IdBytes) -> Option<Duration> {
+        self.inner.peer_rtt(id)
+    }
+
+    /// Returns the ids and addresses of all nodes in the routing table.
+    ///
+    /// See [`RpcDht::snapshot_nodes`].
+    #[inline]
+    pub fn snapshot_nodes(&mut self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.inner.snapshot_nodes()
+    }
+
+    /// Returns an iterator over all nodes in the routing table.
+    ///
+    /// See [`RpcDht::nodes`].
+    #[inline]
+    pub fn nodes(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        self.inner.nodes()
+    }
+
+    /// Returns up to `num` nodes of the routing table closest to `target`.
+    ///
+    /// See [`RpcDht::closest`].
+    #[inline]
+    pub fn closest(&self, target: &[u8; 32], num: usize) -> Vec<NodeInfo> {
+        self.inner.closest(target, num)
+    }
+
+    /// Returns the occupancy of every bucket of the routing table.
+    ///
+    /// See [`RpcDht::bucket_info`].
+    #[inline]
+    pub fn bucket_info(&self) -> Vec<BucketInfo> {
+        self.inner.bucket_info()
+    }
+
+    /// Returns statistics about this node, including the number of stored
+    /// announcements and values.
+    ///
+    /// See [`RpcDht::stats`].
+    pub fn stats(&self) -> DhtStats {
+        let mut stats = self.inner.stats();
+        stats.announcements = self.peers.len();
+        stats.stored_values = self.store.len();
+        stats
+    }
+
+    /// Stops the query with the given id, e.g. a lookup that already found a
+    /// usable peer.
+    ///
+    /// Instead of its result [`HyperDhtEvent::QueryCancelled`] is emitted.
+    /// See [`RpcDht::cancel_query`].
+    pub fn cancel_query(&mut self, id: &QueryId) -> bool {
+        self.queries.remove(id);
+        self.commands.remove(id);
+        self.inner.cancel_query(id)
+    }
+
+    /// Turns an ephemeral node into a persistent one.
+    ///
+    /// See [`RpcDht::persistent`].
+    #[inline]
+    pub fn persistent(&mut self) {
+        self.inner.persistent()
+    }
+
     #[allow(dead_code)]
     fn tally(&mut self, _only_ip: bool) {
         unimplemented!()
     }
 
     /// Handle an incoming requests for the registered commands and reply.
-    fn on_command(&mut self, q: CommandQuery) {
+    fn on_command(&mut self, q: CommandQuery, reply: ResponseSender) {
         match q.command.as_str() {
             MUTABLE_STORE_CMD => {
                 let resp = self.store.on_command_mut(q);
@@ -136,6 +359,7 @@ impl HyperDht {
                         command,
                         msg: Box::new(resp.msg),
                         peer: resp.peer,
+                        reply,
                     })
             }
         }
@@ -300,87 +524,72 @@ impl HyperDht {
                     .and_then(|port| u16::try_from(port).ok())
                     .unwrap_or_else(|| query.peer.addr.port());
 
-                if let IpAddr::V4(host) = query.peer.addr.ip() {
-                    let from = SocketAddr::V4(SocketAddrV4::new(host, port));
+                let from = SocketAddr::new(query.peer.addr.ip(), port);
 
-                    let remote_cache = CacheKey::Remote(query.target.clone());
+                let remote_cache = CacheKey::Remote(query.target.clone());
 
-                    let local_cache = peer.local_address.as_ref().and_then(|l| {
-                        if l.len() == 6 {
-                            let prefix: [u8; 2] = l[0..2].try_into().unwrap();
-                            let suffix: [u8; 4] = l[2..].try_into().unwrap();
-                            Some((
-                                CacheKey::Local {
-                                    id: query.target.clone(),
-                                    prefix,
-                                },
-                                suffix,
-                            ))
-                        } else {
-                            None
-                        }
-                    });
+                let local_cache = peer.local_address.as_ref().and_then(|l| {
+                    local_cache_key(&query.target, l, &from, self.local_subnet_prefix)
+                });
 
-                    if query.ty == Type::Query {
-                        let local_peers = if let Some((local_cache, suffix)) = local_cache {
-                            self.peers.get(&local_cache).and_then(|addrs| {
-                                addrs.iter_locals().map(|locals| {
-                                    locals
-                                        .filter(|s| **s != suffix)
-                                        .flat_map(|s| s.iter())
-                                        .cloned()
-                                        .take(32)
-                                        .collect::<Vec<_>>()
-                                })
-                            })
-                        } else {
-                            None
-                        };
+                if query.ty == Type::Query {
+                    let family = AddrFamily::from_wire(peer.family);
+                    let local_peers = local_cache.and_then(|(key, suffix)| {
+                        encode_local_peers(&mut self.peers, &key, suffix)
+                    });
 
-                        let peers = if let Some(remotes) = self
-                            .peers
-                            .get(&remote_cache)
-                            .and_then(|addrs| addrs.remotes())
-                        {
-                            let num = cmp::min(
-                                remotes.len(),
-                                128 - local_peers.as_ref().map(|l| l.len()).unwrap_or_default(),
-                            );
-                            let mut buf = Vec::with_capacity(num * 6);
-
-                            for addr in remotes.iter().filter(|addr| **addr != from).take(num) {
-                                if let IpAddr::V4(ip) = addr.ip() {
-                                    buf.extend_from_slice(&ip.octets()[..]);
-                                    buf.extend_from_slice(&addr.port().to_be_bytes()[..]);
-                                }
-                            }
-                            Some(buf)
-                        } else {
-                            None
-                        };
+                    // the freshest announcements are the most likely to be reachable
+                    let (peers, peers6) = if let Some(remotes) = self
+                        .peers
+                        .get(&remote_cache)
+                        .and_then(|addrs| addrs.iter_remotes())
+                    {
+                        let num = 128 - local_peers.as_ref().map(|l| l.len()).unwrap_or_default();
+                        let remotes = remotes
+                            .filter(|addr| **addr != from && family.matches(addr))
+                            .take(num)
+                            .collect::<Vec<_>>();
+
+                        // ipv4 remotes stay in the legacy field
+                        let mut buf = Vec::with_capacity(num * 6);
+                        for addr in remotes.iter().filter(|addr| addr.is_ipv4()) {
+                            buf.extend_from_slice(&addr.encode());
+                        }
+                        let peers6 = encode_peers6(remotes.iter().cloned());
 
-                        let output = PeersOutput { peers, local_peers };
-                        let mut buf = Vec::with_capacity(output.encoded_len());
+                        (
+                            Some(buf).filter(|_| family != AddrFamily::V6),
+                            Some(peers6).filter(|buf| !buf.is_empty()),
+                        )
+                    } else {
+                        (None, None)
+                    };
+
+                    let output = PeersOutput {
+                        peers,
+                        local_peers,
+                        peers6,
+                    };
+                    let mut buf = Vec::with_capacity(output.encoded_len());
+
+                    // fits safe in vec
+                    output.encode(&mut buf).unwrap();
+                    query.value = Some(buf.into());
+                    self.inner.reply_command(query);
+                    return;
+                }
 
-                        // fits safe in vec
-                        output.encode(&mut buf).unwrap();
-                        query.value = Some(buf);
-                        self.inner.reply_command(query);
-                        return;
+                if peer.unannounce.unwrap_or_default() {
+                    // remove from cache
+                    self.peers.remove_addr(&remote_cache, from);
+                    if let Some(local) = local_cache {
+                        self.peers.remove_addr(&local.0, local.1);
                     }
-
-                    if peer.unannounce.unwrap_or_default() {
-                        // remove from cache
-                        self.peers.remove_addr(&remote_cache, from);
-                        if let Some(local) = local_cache {
-                            self.peers.remove_addr(&local.0, local.1);
-                        }
-                    } else {
-                        // add the new record
-                        self.peers.insert(remote_cache, from);
-                        if let Some(local) = local_cache {
-                            self.peers.insert(local.0, local.1);
-                        }
+                } else {
+                    // add the new record
+                    self.peers.insert(remote_cache, from);
+                    if let Some(local) = local_cache {
+                        self.peers.insert(local.0, local.1);
                     }
                 }
                 let _ = query.value.take();
@@ -389,6 +598,68 @@ impl HyperDht {
         }
     }
 
+    /// Starts shutting the node down gracefully.
+    ///
+    /// All topics this node announced are unannounced, then the running
+    /// queries are driven until they finished or the drain timeout of
+    /// [`DhtConfig::set_drain_timeout`] elapsed. In the meantime all incoming
+    /// requests are answered with an error and queries started afterwards
+    /// are cancelled right away. Once done, the stream yields
+    /// [`HyperDhtEvent::ShutdownComplete`] and ends.
+    ///
+    /// Dropping the node without a shutdown leaves its announcements with the
+    /// remotes until they expire.
+    pub fn start_shutdown(&mut self) {
+        if self.shutdown.is_some() || self.shut_down {
+            return;
+        }
+        for opts in std::mem::take(&mut self.announced) {
+            self.unannounce(opts);
+        }
+        self.inner.start_shutdown();
+        self.shutdown = Some(Delay::new(self.inner.drain_timeout()));
+    }
+
+    /// Shuts the node down gracefully, see [`HyperDht::start_shutdown`].
+    ///
+    /// The socket is closed once the node is dropped at the end.
+    pub async fn shutdown(mut self) -> io::Result<()> {
+        self.start_shutdown();
+        while let Some(event) = self.next().await {
+            if let HyperDhtEvent::ShutdownComplete = event {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Initiates an iterative query for the closest nodes to `id`.
+    ///
+    /// The result of the query is delivered in a
+    /// [`HyperDhtEvent::CommandResult`].
+    pub fn find_node(&mut self, id: impl Into<IdBytes>) -> QueryId {
+        self.query(Command::FindNode, id, None)
+    }
+
+    /// Initiates an iterative query with the command `cmd` to the closest
+    /// peers to `target`, e.g. a custom command the remote nodes
+    /// registered.
+    ///
+    /// The result of the query is delivered in a
+    /// [`HyperDhtEvent::CommandResult`].
+    pub fn query(
+        &mut self,
+        cmd: impl Into<Command>,
+        target: impl Into<IdBytes>,
+        value: Option<Vec<u8>>,
+    ) -> QueryId {
+        let id = self
+            .inner
+            .query(cmd, kbucket::Key::new(target.into()), value);
+        self.commands.insert(id);
+        id
+    }
+
     /// Initiates an iterative query to the closest peers to lookup the topic.
     ///
     /// The result of the query is delivered in a
@@ -400,15 +671,24 @@ impl HyperDht {
             port: opts.port,
             local_address: opts.local_addr_encoded(),
             unannounce: None,
+            family: Some(opts.family.to_wire()),
         };
         let buf = encode_input(&peers);
 
-        let id = self
-            .inner
-            .query(PEERS_CMD, kbucket::Key::new(opts.topic.clone()), Some(buf));
+        let id = self.inner.query_with_limits(
+            PEERS_CMD,
+            kbucket::Key::new(opts.topic.clone()),
+            Some(buf),
+            QueryType::Query,
+            opts.limits,
+        );
         self.queries.insert(
             id,
-            QueryStreamType::LookUp(QueryStreamInner::new(opts.topic, opts.local_addr)),
+            QueryStreamType::LookUp(QueryStreamInner::new(
+                opts.topic,
+                opts.local_addr,
+                opts.family,
+            )),
         );
         id
     }
@@ -419,22 +699,33 @@ impl HyperDht {
     /// [`HyperDhtEvent::AnnounceResult`].
     pub fn announce(&mut self, opts: impl Into<QueryOpts>) -> QueryId {
         let opts = opts.into();
+        // the announcement of a shutting down node is cancelled
+        if !self.inner.is_shutting_down() && !self.announced.contains(&opts) {
+            self.announced.push(opts.clone());
+        }
 
         let peers = PeersInput {
             port: opts.port,
             local_address: opts.local_addr_encoded(),
             unannounce: None,
+            family: Some(opts.family.to_wire()),
         };
         let buf = encode_input(&peers);
 
-        let id = self.inner.query_and_update(
+        let id = self.inner.query_with_limits(
             PEERS_CMD,
             kbucket::Key::new(opts.topic.clone()),
             Some(buf),
+            QueryType::QueryUpdate,
+            opts.limits,
         );
         self.queries.insert(
             id,
-            QueryStreamType::Announce(QueryStreamInner::new(opts.topic, opts.local_addr)),
+            QueryStreamType::Announce(QueryStreamInner::new(
+                opts.topic,
+                opts.local_addr,
+                opts.family,
+            )),
         );
         id
     }
@@ -446,34 +737,113 @@ impl HyperDht {
     /// [`HyperDhtEvent::UnAnnounceResult`].
     pub fn unannounce(&mut self, opts: impl Into<QueryOpts>) -> QueryId {
         let opts = opts.into();
+        // remotes remove the announcement of the topic for our address
+        self.announced
+            .retain(|a| a.topic != opts.topic || a.port != opts.port);
 
         let peers = PeersInput {
             port: opts.port,
             local_address: opts.local_addr_encoded(),
             unannounce: Some(true),
+            family: Some(opts.family.to_wire()),
         };
         let buf = encode_input(&peers);
 
-        let id = self
-            .inner
-            .update(PEERS_CMD, kbucket::Key::new(opts.topic.clone()), Some(buf));
+        let id = self.inner.query_with_limits(
+            PEERS_CMD,
+            kbucket::Key::new(opts.topic.clone()),
+            Some(buf),
+            QueryType::Update,
+            opts.limits,
+        );
         self.queries.insert(
             id,
-            QueryStreamType::UnAnnounce(QueryStreamInner::new(opts.topic, opts.local_addr)),
+            QueryStreamType::UnAnnounce(QueryStreamInner::new(
+                opts.topic,
+                opts.local_addr,
+                opts.family,
+            )),
         );
         id
     }
 
+    /// Keeps the topic joined until the returned handle is dropped or
+    /// [`TopicHandle::leave`] is called.
+    ///
+    /// A joined topic is announced again before the remotes forget about it,
+    /// at half of [`DhtConfig::set_peers_max_age`], and looked up every
+    /// [`JoinOpts::lookup_interval`]. Only one query runs for the topic at a
+    /// time. Instead of the events of the queries, every peer that was found
+    /// is reported once in a [`HyperDhtEvent::PeerDiscovered`] until
+    /// [`JoinOpts::rediscover_after`] passed. Leaving the topic stops the
+    /// running query and unannounces the topic if it was announced.
+    pub fn join(&mut self, opts: impl Into<QueryOpts>, join: JoinOpts) -> TopicHandle {
+        self.topics.join(opts.into(), join)
+    }
+
+    fn on_topic_action(&mut self, action: TopicAction) {
+        match action {
+            TopicAction::Announce(opts) => {
+                let topic = opts.topic.clone();
+                let id = self.announce(opts);
+                self.topics.started(&topic, id);
+            }
+            TopicAction::Lookup(opts) => {
+                let topic = opts.topic.clone();
+                let id = self.lookup(opts);
+                self.topics.started(&topic, id);
+            }
+            TopicAction::Leave {
+                opts,
+                query,
+                unannounce,
+            } => {
+                if let Some(id) = query {
+                    self.queries.remove(&id);
+                    self.inner.cancel_query(&id);
+                }
+                if unannounce {
+                    let topic = opts.topic.clone();
+                    let id = self.unannounce(opts);
+                    self.topics.started(&topic, id);
+                }
+            }
+        }
+    }
+
     fn inject_response(&mut self, resp: Response) {
+        let resp_query = resp.query;
         if let Some(query) = self.queries.get_mut(&resp.query) {
             match query {
-                QueryStreamType::LookUp(inner)
-                | QueryStreamType::Announce(inner)
-                | QueryStreamType::UnAnnounce(inner) => inner.inject_response(resp),
+                QueryStreamType::LookUp(inner) | QueryStreamType::Announce(inner) => {
+                    if let Some(peers) = inner.inject_response(resp) {
+                        if self.topics.contains_query(&resp_query) {
+                            let now = time::now();
+                            for (peer, local) in self.topics.discovered(&resp_query, &peers, now) {
+                                self.queued_events.push_back(HyperDhtEvent::PeerDiscovered {
+                                    topic: inner.topic.clone(),
+                                    peer,
+                                    local,
+                                    referrer: peers.node,
+                                })
+                            }
+                        } else {
+                            self.queued_events.push_back(HyperDhtEvent::Peers {
+                                peers,
+                                topic: inner.topic.clone(),
+                                query_id: resp_query,
+                            })
+                        }
+                    }
+                }
+                QueryStreamType::UnAnnounce(inner) => {
+                    inner.inject_response(resp);
+                }
                 QueryStreamType::GetImmutable(get) => {
                     if let Some(value) = resp.value {
                         let key = crypto::hash_id(&value);
                         if get.key == key {
+                            let value = value.to_vec();
                             self.store.put_immutable(key, value.clone());
                             get.responses.push(PeerResponseItem {
                                 peer: resp.peer,
@@ -507,7 +877,10 @@ impl HyperDht {
     // A query was completed
     fn query_finished(&mut self, id: QueryId) {
         if let Some(query) = self.queries.remove(&id) {
-            self.queued_events.push_back(query.finalize(id))
+            // queries of joined topics are reported as discovered peers
+            if !self.topics.finished(&id) {
+                self.queued_events.push_back(query.finalize(id))
+            }
         }
     }
 }
@@ -517,6 +890,17 @@ impl Stream for HyperDht {
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let pin = self.get_mut();
+        if pin.shut_down {
+            return Poll::Ready(None);
+        }
+
+        let now = time::now();
+        pin.peers.remove_expired(now);
+        if pin.shutdown.is_none() {
+            while let Poll::Ready(action) = pin.topics.poll(cx, now) {
+                pin.on_topic_action(action);
+            }
+        }
 
         loop {
             // Drain queued events first.
@@ -526,20 +910,88 @@ impl Stream for HyperDht {
 
             while let Poll::Ready(Some(ev)) = Stream::poll_next(Pin::new(&mut pin.inner), cx) {
                 match ev {
-                    RpcDhtEvent::RequestResult(Ok(RequestOk::CustomCommandRequest { query })) => {
-                        pin.on_command(query)
-                    }
+                    RpcDhtEvent::RequestResult(Ok(RequestOk::CustomCommandRequest {
+                        query,
+                        reply,
+                    })) => pin.on_command(query, reply),
                     RpcDhtEvent::ResponseResult(Ok(ResponseOk::Response(resp))) => {
                         pin.inject_response(resp)
                     }
+                    RpcDhtEvent::ResponseResult(Ok(ResponseOk::Holepunched(peer))) => {
+                        pin.on_holepunched(peer)
+                    }
+                    RpcDhtEvent::ResponseResult(Err(ResponseError::HolepunchTimeout(peer))) => {
+                        pin.on_holepunch_timeout(peer)
+                    }
+                    RpcDhtEvent::ResponseResult(Ok(ResponseOk::Pong(peer)))
+                        if pin.pings.remove(&peer.addr) =>
+                    {
+                        return Poll::Ready(Some(HyperDhtEvent::Pong { peer: peer.addr }))
+                    }
+                    RpcDhtEvent::ResponseResult(Err(ResponseError::PingTimeout(peer)))
+                        if pin.pings.remove(&peer.addr) =>
+                    {
+                        return Poll::Ready(Some(HyperDhtEvent::PingTimeout { peer: peer.addr }))
+                    }
                     RpcDhtEvent::Bootstrapped { stats } => {
                         return Poll::Ready(Some(HyperDhtEvent::Bootstrapped { stats }))
                     }
+                    RpcDhtEvent::BootstrapFailed { stats } => {
+                        return Poll::Ready(Some(HyperDhtEvent::BootstrapFailed { stats }))
+                    }
+                    RpcDhtEvent::NetworkSuspect { since, timeouts } => {
+                        return Poll::Ready(Some(HyperDhtEvent::NetworkSuspect { since, timeouts }))
+                    }
+                    RpcDhtEvent::Rebound { addr } => {
+                        pin.topics.refresh();
+                        return Poll::Ready(Some(HyperDhtEvent::Rebound { addr }));
+                    }
+                    RpcDhtEvent::SocketError { err } => {
+                        pin.shut_down = true;
+                        return Poll::Ready(Some(HyperDhtEvent::SocketError { err }));
+                    }
+                    RpcDhtEvent::RefreshCompleted {
+                        bucket_index,
+                        new_nodes,
+                    } => {
+                        return Poll::Ready(Some(HyperDhtEvent::RefreshCompleted {
+                            bucket_index,
+                            new_nodes,
+                        }))
+                    }
                     RpcDhtEvent::QueryResult {
                         id,
-                        cmd: _,
-                        stats: _,
-                    } => pin.query_finished(id),
+                        cmd,
+                        stats,
+                        closest,
+                        values,
+                        errors,
+                    } => {
+                        if pin.commands.remove(&id) {
+                            return Poll::Ready(Some(HyperDhtEvent::CommandResult(
+                                CommandResult {
+                                    cmd,
+                                    closest: closest.into_iter().map(|(peer, _)| peer).collect(),
+                                    values,
+                                    errors,
+                                    stats,
+                                    query_id: id,
+                                },
+                            )));
+                        }
+                        pin.query_finished(id)
+                    }
+                    RpcDhtEvent::QueryCancelled { id, stats, .. } => {
+                        pin.queries.remove(&id);
+                        pin.commands.remove(&id);
+                        // the queries of left topics are stopped silently
+                        if !pin.topics.finished(&id) {
+                            return Poll::Ready(Some(HyperDhtEvent::QueryCancelled {
+                                query_id: id,
+                                stats,
+                            }));
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -548,6 +1000,17 @@ impl Stream for HyperDht {
             // If no new events have been queued either, signal `Pending` to
             // be polled again later.
             if pin.queued_events.is_empty() {
+                if let Some(drain) = pin.shutdown.as_mut() {
+                    let timed_out = Future::poll(Pin::new(drain), cx).is_ready();
+                    if timed_out || pin.inner.is_idle() {
+                        if !pin.inner.is_idle() {
+                            log::debug!("Shutting down with queries still running");
+                        }
+                        pin.shutdown = None;
+                        pin.shut_down = true;
+                        return Poll::Ready(Some(HyperDhtEvent::ShutdownComplete));
+                    }
+                }
                 return Poll::Pending;
             }
         }
@@ -703,6 +1166,11 @@ pub struct QueryOpts {
     /// Optionally announce a LAN address as well. Only people with the same
     /// public IP as you will get these when doing a lookup
     pub local_addr: Option<SocketAddr>,
+    /// The address families of the peers to look up, both by default.
+    pub family: AddrFamily,
+    /// The parallelism and number of results of the query, those of the
+    /// [`DhtConfig`] by default.
+    pub limits: QueryLimits,
 }
 
 impl QueryOpts {
@@ -712,6 +1180,8 @@ impl QueryOpts {
             topic: topic.into(),
             port: None,
             local_addr: None,
+            family: AddrFamily::default(),
+            limits: QueryLimits::default(),
         }
     }
 
@@ -721,6 +1191,8 @@ impl QueryOpts {
             topic: topic.into(),
             port: Some(port),
             local_addr: None,
+            family: AddrFamily::default(),
+            limits: QueryLimits::default(),
         }
     }
 
@@ -730,6 +1202,25 @@ impl QueryOpts {
         self
     }
 
+    /// Set the address families of the peers to look up
+    pub fn family(mut self, family: AddrFamily) -> Self {
+        self.family = family;
+        self
+    }
+
+    /// Set the number of requests of the query that are in flight at once
+    pub fn parallelism(mut self, parallelism: NonZeroUsize) -> Self {
+        self.limits.parallelism = Some(parallelism);
+        self
+    }
+
+    /// Set the number of closest peers that need to respond before the query
+    /// finishes
+    pub fn num_results(mut self, num_results: NonZeroUsize) -> Self {
+        self.limits.num_results = Some(num_results);
+        self
+    }
+
     /// The local addresses as encoded payload
     fn local_addr_encoded(&self) -> Option<Vec<u8>> {
         self.local_addr.as_ref().map(|addr| addr.encode())
@@ -751,16 +1242,20 @@ impl From<&PublicKey> for QueryOpts {
             topic: key.into(),
             port: None,
             local_addr: None,
+            family: AddrFamily::default(),
+            limits: QueryLimits::default(),
         }
     }
 }
 
-impl From<&GenericArray<u8, U32>> for QueryOpts {
-    fn from(digest: &GenericArray<u8, U32>) -> Self {
+impl From<&Output<Sha256>> for QueryOpts {
+    fn from(digest: &Output<Sha256>) -> Self {
         Self {
-            topic: digest.as_slice().try_into().expect("Wrong length"),
+            topic: digest.into(),
             port: None,
             local_addr: None,
+            family: AddrFamily::default(),
+            limits: QueryLimits::default(),
         }
     }
 }
@@ -771,6 +1266,8 @@ impl From<IdBytes> for QueryOpts {
             topic,
             port: None,
             local_addr: None,
+            family: AddrFamily::default(),
+            limits: QueryLimits::default(),
         }
     }
 }
@@ -783,6 +1280,8 @@ impl TryFrom<&[u8]> for QueryOpts {
             topic: value.try_into()?,
             port: None,
             local_addr: None,
+            family: AddrFamily::default(),
+            limits: QueryLimits::default(),
         })
     }
 }
@@ -799,6 +1298,36 @@ pub enum HyperDhtEvent {
         /// Execution statistics from the bootstrap query.
         stats: QueryStats,
     },
+    /// No node responded to the bootstrap query, see
+    /// [`RpcDhtEvent::BootstrapFailed`].
+    BootstrapFailed {
+        /// Execution statistics from the bootstrap query.
+        stats: QueryStats,
+    },
+    /// The peer of [`HyperDht::connect`] responded to the holepunch and can
+    /// be dialed now.
+    HolepunchReady {
+        /// The peer to connect to.
+        peer: SocketAddr,
+        /// The node that relayed the holepunch.
+        referrer: SocketAddr,
+    },
+    /// None of the referrers passed to [`HyperDht::connect`] was able to
+    /// holepunch to the peer.
+    HolepunchFailed {
+        /// The unreachable peer.
+        peer: SocketAddr,
+    },
+    /// The node of [`HyperDht::ping`] responded.
+    Pong {
+        /// The address of the node.
+        peer: SocketAddr,
+    },
+    /// The node of [`HyperDht::ping`] didn't respond.
+    PingTimeout {
+        /// The address of the node.
+        peer: SocketAddr,
+    },
     /// The result of [`HyperDht::announce`].
     AnnounceResult {
         /// The peers that successfully received the announcement
@@ -808,6 +1337,18 @@ pub enum HyperDhtEvent {
         /// Tracking id of the query
         query_id: QueryId,
     },
+    /// Peers a single node returned for a [`HyperDht::lookup`] or
+    /// [`HyperDht::announce`] that is still in progress.
+    ///
+    /// All of them are included in the final result of the query as well.
+    Peers {
+        /// The peers the node returned
+        peers: Peers,
+        /// The topic of the query.
+        topic: IdBytes,
+        /// Tracking id of the query
+        query_id: QueryId,
+    },
     /// The result of [`HyperDht::lookup`].
     LookupResult {
         /// All responses
@@ -824,6 +1365,18 @@ pub enum HyperDhtEvent {
         /// Tracking id of the query
         query_id: QueryId,
     },
+    /// A peer of a topic joined with [`HyperDht::join`] was found.
+    PeerDiscovered {
+        /// The joined topic.
+        topic: IdBytes,
+        /// The address of the peer.
+        peer: SocketAddr,
+        /// Whether the peer is in the same LAN, see [`Peers::local_peers`].
+        local: bool,
+        /// The node that returned the peer, which can relay a holepunch to
+        /// it.
+        referrer: SocketAddr,
+    },
     /// The result of [`HyperDht::put_immutable`].
     PutImmutableResult {
         /// The generated key (hash for that value)
@@ -842,6 +1395,44 @@ pub enum HyperDhtEvent {
     GetImmutableResult(GetResult<Vec<u8>>),
     /// The result of [`HyperDht::get_mutable`].
     GetMutableResult(GetResult<Mutable>),
+    /// The result of [`HyperDht::query`] or [`HyperDht::find_node`].
+    CommandResult(CommandResult),
+    /// Every request timed out for a while although most requests succeeded
+    /// before, the network likely changed and the socket can be bound again
+    /// with [`HyperDht::rebind`].
+    ///
+    /// See [`RpcDhtEvent::NetworkSuspect`].
+    NetworkSuspect {
+        /// When the first of the requests timed out.
+        since: Instant,
+        /// How many requests timed out since.
+        timeouts: u64,
+    },
+    /// The socket was bound again at `addr` after an error of the socket
+    /// itself, joined topics are announced and looked up again.
+    ///
+    /// See [`RpcDhtEvent::Rebound`].
+    Rebound { addr: SocketAddr },
+    /// The socket failed for good, this is the last event of the stream.
+    ///
+    /// See [`RpcDhtEvent::SocketError`].
+    SocketError { err: io::Error },
+    /// A bucket of the routing table without activity was refreshed.
+    ///
+    /// See [`RpcDhtEvent::RefreshCompleted`].
+    RefreshCompleted {
+        /// The index of the bucket, see [`BucketInfo::index`].
+        bucket_index: usize,
+        /// Number of nodes the refresh added to the bucket.
+        new_nodes: usize,
+    },
+    /// A query was stopped by [`HyperDht::cancel_query`].
+    QueryCancelled {
+        /// Tracking id of the query
+        query_id: QueryId,
+        /// Execution statistics until the query was cancelled.
+        stats: QueryStats,
+    },
     /// Received a query with a custom command that is not automatically handled
     /// by the DHT
     CustomCommandQuery {
@@ -851,7 +1442,12 @@ pub enum HyperDhtEvent {
         msg: Box<Message>,
         /// The peer the message originated from.
         peer: Peer,
+        /// Answers the query.
+        reply: ResponseSender,
     },
+    /// The shutdown started by [`HyperDht::start_shutdown`] completed, the
+    /// stream ends after this event.
+    ShutdownComplete,
 }
 
 /// Contains all the successfully received responses
@@ -876,7 +1472,7 @@ impl<T: fmt::Debug> GetResult<T> {
     }
 
     /// Returns an iterator over all received values
-    pub fn values<'a>(&'a self) -> impl Iterator<Item = &'a T> + '_ {
+    pub fn values(&self) -> impl Iterator<Item = &T> + '_ {
         self.responses.iter().map(|r| &r.value)
     }
 
@@ -893,6 +1489,32 @@ impl<T: fmt::Debug> GetResult<T> {
     }
 }
 
+impl GetResult<Mutable> {
+    /// Returns the value with the highest sequence number, i.e. the most
+    /// recent one.
+    pub fn latest(&self) -> Option<&Mutable> {
+        self.values().max_by_key(|m| m.seq.unwrap_or_default())
+    }
+}
+
+/// Result of a [`HyperDht::query`].
+#[derive(Debug)]
+pub struct CommandResult {
+    /// The command of the query.
+    pub cmd: Command,
+    /// The closest nodes to the target that responded, closest first.
+    pub closest: Vec<PeerId>,
+    /// The values of all responses with the peer that sent them, in the
+    /// order they arrived.
+    pub values: Vec<(SocketAddr, bytes::Bytes)>,
+    /// The errors of all responses with the peer that sent them.
+    pub errors: Vec<(SocketAddr, String)>,
+    /// Execution statistics of the query.
+    pub stats: QueryStats,
+    /// Tracking id of the query
+    pub query_id: QueryId,
+}
+
 /// Represents the response received from a peer
 #[derive(Debug)]
 pub struct PeerResponseItem<T: fmt::Debug> {
@@ -915,32 +1537,42 @@ pub struct Lookup {
 
 impl Lookup {
     /// Returns an iterator over all the nodes that sent data for this look
-    pub fn origins<'a>(
-        &'a self,
-    ) -> impl Iterator<Item = (&'a SocketAddr, Option<&'a IdBytes>)> + '_ {
+    pub fn origins(&self) -> impl Iterator<Item = (&SocketAddr, Option<&IdBytes>)> + '_ {
         self.peers
             .iter()
             .map(|peer| (&peer.node, peer.peer_id.as_ref()))
     }
 
     /// Returns an iterator over all remote peers that announced the topic hash
-    pub fn remotes<'a>(&'a self) -> impl Iterator<Item = &'a SocketAddr> + '_ {
+    pub fn remotes(&self) -> impl Iterator<Item = &SocketAddr> + '_ {
         self.peers.iter().flat_map(|peer| peer.peers.iter())
     }
 
     /// Returns an iterator over all LAN peers that announced the topic hash
-    pub fn locals<'a>(&'a self) -> impl Iterator<Item = &'a SocketAddr> + '_ {
+    pub fn locals(&self) -> impl Iterator<Item = &SocketAddr> + '_ {
         self.peers.iter().flat_map(|peer| peer.local_peers.iter())
     }
 
     /// Returns an iterator over all peers (remote and LAN) that announced the
     /// topic hash.
-    pub fn all_peers<'a>(&'a self) -> impl Iterator<Item = &'a SocketAddr> + '_ {
+    pub fn all_peers(&self) -> impl Iterator<Item = &SocketAddr> + '_ {
         self.peers
             .iter()
             .flat_map(|peer| peer.peers.iter().chain(peer.local_peers.iter()))
     }
 
+    /// Returns an iterator over all nodes that returned the `peer`, which can
+    /// relay a holepunch to it.
+    pub fn referrers<'a>(
+        &'a self,
+        peer: &'a SocketAddr,
+    ) -> impl Iterator<Item = &'a SocketAddr> + 'a {
+        self.peers
+            .iter()
+            .filter(move |p| p.peers.contains(peer) || p.local_peers.contains(peer))
+            .map(|p| &p.node)
+    }
+
     /// Amount peers matched the lookup
     #[inline]
     pub fn len(&self) -> usize {
@@ -965,6 +1597,9 @@ pub struct Peers {
     pub peers: Vec<SocketAddr>,
     /// List of LAN peers that announced the topic hash
     pub local_peers: Vec<SocketAddr>,
+    /// The nodes that returned `node` during the query, which can relay a
+    /// holepunch to it, e.g. with [`HyperDht::connect`].
+    pub referrers: Vec<SocketAddr>,
 }
 
 /// Type to keep track of the responses for queries in progress.
@@ -1026,25 +1661,39 @@ struct QueryStreamInner {
     topic: IdBytes,
     responses: Vec<Peers>,
     local_address: Option<SocketAddr>,
+    family: AddrFamily,
 }
 
 impl QueryStreamInner {
-    fn new(topic: IdBytes, local_address: Option<SocketAddr>) -> Self {
+    fn new(topic: IdBytes, local_address: Option<SocketAddr>, family: AddrFamily) -> Self {
         Self {
             topic,
             responses: Vec::new(),
             local_address,
+            family,
         }
     }
 
     /// Store the decoded peers from the `Response` value
-    fn inject_response(&mut self, resp: Response) {
+    ///
+    /// Returns the peers of the response, if it contained any.
+    fn inject_response(&mut self, resp: Response) -> Option<Peers> {
         if let Some(val) = resp
             .value
             .as_ref()
-            .and_then(|val| PeersOutput::decode(val.as_slice()).ok())
+            .and_then(|val| PeersOutput::decode(&val[..]).ok())
         {
-            let peers = val.peers.as_ref().map(decode_peers).unwrap_or_default();
+            // legacy remotes ignore the requested family
+            let family = self.family;
+            let peers = val
+                .peers
+                .as_ref()
+                .map(decode_peers)
+                .unwrap_or_default()
+                .into_iter()
+                .chain(val.peers6.as_ref().map(decode_peers6).unwrap_or_default())
+                .filter(|addr| family.matches(addr))
+                .collect::<Vec<_>>();
 
             let local_peers = val
                 .local_peers
@@ -1059,18 +1708,64 @@ impl QueryStreamInner {
                 .unwrap_or_default();
 
             if peers.is_empty() && local_peers.is_empty() {
-                return;
+                return None;
             }
-            self.responses.push(Peers {
+            let peers = Peers {
                 node: resp.peer,
                 peer_id: resp.peer_id,
                 peers,
                 local_peers,
-            })
+                referrers: resp.referrers,
+            };
+            self.responses.push(peers.clone());
+            return Some(peers);
+        }
+        None
+    }
+}
+
+/// The cache key of the local address `local` announced or looked up from
+/// `from`, along with the encoded suffix of the address.
+///
+/// Local addresses share the first two bytes of the local address and the
+/// public subnet of the announcer, so that they are only revealed to peers
+/// that are likely behind the same NAT.
+fn local_cache_key(
+    id: &IdBytes,
+    local: &[u8],
+    from: &SocketAddr,
+    subnet_prefix: u8,
+) -> Option<(CacheKey, [u8; 4])> {
+    match from {
+        SocketAddr::V4(from) if local.len() == 6 => {
+            let prefix: [u8; 2] = local[0..2].try_into().unwrap();
+            let suffix: [u8; 4] = local[2..].try_into().unwrap();
+            let key = CacheKey::Local {
+                id: id.clone(),
+                prefix,
+                subnet: ipv4_subnet(from.ip(), subnet_prefix),
+            };
+            Some((key, suffix))
         }
+        _ => None,
     }
 }
 
+/// The encoded local peers stored for `key`, except the one of `suffix` that
+/// asks for them.
+fn encode_local_peers(peers: &mut PeerCache, key: &CacheKey, suffix: [u8; 4]) -> Option<Vec<u8>> {
+    peers.get(key).and_then(|addrs| {
+        addrs.iter_locals().map(|locals| {
+            locals
+                .filter(|s| **s != suffix)
+                .flat_map(|s| s.iter())
+                .cloned()
+                .take(32)
+                .collect::<Vec<_>>()
+        })
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use futures::{FutureExt, SinkExt, StreamExt};
@@ -1342,6 +2037,288 @@ mod tests {
         }
     }
 
+    #[async_std::test]
+    async fn announce_socket_port() -> Result<(), Box<dyn std::error::Error>> {
+        let bs_addr = bootstrap_dht!();
+        let config = || DhtConfig::default().set_bootstrap_nodes(&[bs_addr]);
+
+        // stores the announcement
+        let mut state = HyperDht::with_config(config()).await?;
+        loop {
+            if let Some(HyperDhtEvent::Bootstrapped { .. }) = state.next().await {
+                break;
+            }
+        }
+        async_std::task::spawn(async move { while state.next().await.is_some() {} });
+
+        // without a port, remotes store the port the announce came from
+        let opts = QueryOpts::new(IdBytes::random());
+        let mut announcer = HyperDht::with_config(config().ephemeral()).await?;
+        let announcer_addr = announcer.local_addr()?;
+        loop {
+            match announcer.next().await {
+                Some(HyperDhtEvent::Bootstrapped { .. }) => {
+                    announcer.announce(opts.clone());
+                }
+                Some(HyperDhtEvent::AnnounceResult { .. }) => break,
+                _ => {}
+            }
+        }
+        async_std::task::spawn(async move { while announcer.next().await.is_some() {} });
+
+        let mut node = HyperDht::with_config(config().ephemeral()).await?;
+        loop {
+            match node.next().await {
+                Some(HyperDhtEvent::Bootstrapped { .. }) => {
+                    node.lookup(opts.topic.clone());
+                }
+                Some(HyperDhtEvent::LookupResult { lookup, .. }) => {
+                    let remotes = lookup.remotes().cloned().collect::<Vec<_>>();
+                    assert_eq!(remotes, vec![announcer_addr]);
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn shutdown_unannounces() -> Result<(), Box<dyn std::error::Error>> {
+        let bs_addr = bootstrap_dht!();
+        spawn_dhts!(2, &[&bs_addr]);
+
+        let opts = QueryOpts::new(IdBytes::random()).port(12345);
+        let lookup = |mut node: HyperDht, topic: IdBytes| async move {
+            node.lookup(topic);
+            loop {
+                match node.next().await {
+                    Some(HyperDhtEvent::LookupResult { lookup, .. }) => return lookup,
+                    Some(_) => {}
+                    None => panic!("expected lookup result"),
+                }
+            }
+        };
+
+        let mut node = HyperDht::with_config(
+            DhtConfig::default()
+                .ephemeral()
+                .set_bootstrap_nodes(&[bs_addr]),
+        )
+        .await?;
+        loop {
+            match node.next().await {
+                Some(HyperDhtEvent::Bootstrapped { .. }) => {
+                    node.announce(opts.clone());
+                }
+                Some(HyperDhtEvent::AnnounceResult { .. }) => break,
+                _ => {}
+            }
+        }
+
+        let other = || {
+            HyperDht::with_config(
+                DhtConfig::default()
+                    .ephemeral()
+                    .set_bootstrap_nodes(&[bs_addr]),
+            )
+        };
+        assert_eq!(lookup(other().await?, opts.topic.clone()).await.len(), 2);
+
+        node.shutdown().await?;
+        assert!(lookup(other().await?, opts.topic.clone()).await.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn shutdown_ends_stream() -> Result<(), Box<dyn std::error::Error>> {
+        let bs_addr = bootstrap_dht!();
+        let mut node = HyperDht::with_config(
+            DhtConfig::default()
+                .ephemeral()
+                .set_bootstrap_nodes(&[bs_addr]),
+        )
+        .await?;
+        // still bootstrapping
+        node.lookup(IdBytes::random());
+        node.start_shutdown();
+
+        let mut complete = false;
+        let drive = async {
+            while let Some(event) = node.next().await {
+                assert!(!complete, "event after the shutdown completed");
+                complete = matches!(event, HyperDhtEvent::ShutdownComplete);
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(5), drive).await?;
+        assert!(complete);
+        assert!(node.next().await.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn announce_during_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+        let bs_addr = bootstrap_dht!();
+        let mut node = HyperDht::with_config(
+            DhtConfig::default()
+                .ephemeral()
+                .set_bootstrap_nodes(&[bs_addr]),
+        )
+        .await?;
+        node.start_shutdown();
+        let announce = node.announce(QueryOpts::new(IdBytes::random()).port(12345));
+        let lookup = node.lookup(IdBytes::random());
+        assert!(node.announced.is_empty());
+
+        let mut cancelled = Vec::new();
+        let drive = async {
+            while let Some(event) = node.next().await {
+                match event {
+                    HyperDhtEvent::QueryCancelled { query_id, .. } => cancelled.push(query_id),
+                    HyperDhtEvent::AnnounceResult { .. } | HyperDhtEvent::LookupResult { .. } => {
+                        panic!("query ran during the shutdown")
+                    }
+                    _ => {}
+                }
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(5), drive).await?;
+        assert_eq!(cancelled, [announce, lookup]);
+        assert!(node.queries.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn lookup_streams_peers() -> Result<(), Box<dyn std::error::Error>> {
+        let bs_addr = bootstrap_dht!();
+        spawn_dhts!(2, &[&bs_addr]);
+
+        let opts = QueryOpts::new(IdBytes::random()).port(12345);
+
+        let mut node = HyperDht::with_config(
+            DhtConfig::default()
+                .ephemeral()
+                .set_bootstrap_nodes(&[bs_addr]),
+        )
+        .await?;
+        loop {
+            match node.next().await {
+                Some(HyperDhtEvent::Bootstrapped { .. }) => {
+                    node.announce(opts.clone());
+                }
+                Some(HyperDhtEvent::AnnounceResult { .. }) => break,
+                _ => {}
+            }
+        }
+
+        let query_id = node.lookup(opts.topic.clone());
+        let mut streamed = Vec::new();
+        let lookup = loop {
+            match node.next().await {
+                Some(HyperDhtEvent::Peers {
+                    peers,
+                    topic,
+                    query_id: id,
+                }) => {
+                    assert_eq!(id, query_id);
+                    assert_eq!(topic, opts.topic);
+                    streamed.push(peers);
+                }
+                Some(HyperDhtEvent::LookupResult { lookup, .. }) => break lookup,
+                _ => {}
+            }
+        };
+        assert!(!streamed.is_empty());
+        assert_eq!(streamed.len(), lookup.len());
+        for peers in streamed {
+            assert_eq!(peers.peers.len(), 1);
+            assert_eq!(peers.peers[0].port(), 12345);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn connect_via_referrer() -> Result<(), Box<dyn std::error::Error>> {
+        use async_std::net::UdpSocket;
+        use std::time::Duration;
+
+        let relay = bootstrap_dht!(false);
+        // a referrer that never relays anything
+        let unreachable = UdpSocket::bind("127.0.0.1:0").await?;
+        let unreachable = unreachable.local_addr()?;
+
+        // the target only answers holepunches relayed to it
+        let target = UdpSocket::bind("127.0.0.1:0").await?;
+        let target_addr = target.local_addr()?;
+        async_std::task::spawn(async move {
+            let mut buf = vec![0; 1500];
+            while let Ok((n, _)) = target.recv_from(&mut buf).await {
+                let msg = match Message::decode(&buf[..n]) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+                let from = msg
+                    .decode_holepunch()
+                    .and_then(|holepunch| holepunch.decode_from_peer());
+                if let Some(from) = from {
+                    let resp = Message {
+                        version: Some(rpc::io::VERSION),
+                        r#type: Type::Response.id(),
+                        rid: msg.rid,
+                        to: Some(from.encode()),
+                        id: None,
+                        target: None,
+                        closer_nodes: None,
+                        roundtrip_token: None,
+                        command: None,
+                        error: None,
+                        value: None,
+                        closer_nodes6: None,
+                        unknown_fields: Vec::new(),
+                    };
+                    let mut buf = Vec::with_capacity(resp.encoded_len());
+                    resp.encode(&mut buf).unwrap();
+                    let _ = target.send_to(&buf, from).await;
+                }
+            }
+        });
+
+        let mut node = HyperDht::with_config(
+            DhtConfig::default()
+                .empty_bootstrap_nodes()
+                .set_request_timeout(Duration::from_millis(20))
+                .set_request_retries(1),
+        )
+        .await?;
+        assert!(!node.connect(target_addr, None));
+
+        // falls back to the relay after the first referrer timed out
+        assert!(node.connect(target_addr, vec![unreachable, relay]));
+        loop {
+            match node.next().await {
+                Some(HyperDhtEvent::HolepunchReady { peer, referrer }) => {
+                    assert_eq!(peer, target_addr);
+                    assert_eq!(referrer, relay);
+                    break;
+                }
+                Some(HyperDhtEvent::HolepunchFailed { .. }) => panic!("holepunch failed"),
+                _ => {}
+            }
+        }
+
+        assert!(node.connect(target_addr, Some(unreachable)));
+        loop {
+            match node.next().await {
+                Some(HyperDhtEvent::HolepunchFailed { peer }) => {
+                    assert_eq!(peer, target_addr);
+                    break;
+                }
+                Some(HyperDhtEvent::HolepunchReady { .. }) => panic!("unexpected holepunch"),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     #[test]
     fn verify_mutable() {
         let mut opts = PutOpts::with_keypair(crypto::keypair());
@@ -1352,4 +2329,68 @@ mod tests {
         let b = opts.mutable(b"v2".to_vec());
         assert!(verify(&key, &b).is_ok());
     }
+
+    #[test]
+    fn local_address_round_trip() {
+        let local: SocketAddr = "192.168.1.20:4000".parse().unwrap();
+        let opts = QueryOpts::new(IdBytes::random()).local_addr(local);
+        let input = PeersInput {
+            local_address: opts.local_addr_encoded(),
+            ..Default::default()
+        };
+        let input = PeersInput::decode(&*encode_input(&input)).unwrap();
+
+        let from = "203.0.113.5:49737".parse().unwrap();
+        let local_address = input.local_address.unwrap();
+        let (_, suffix) = local_cache_key(&opts.topic, &local_address, &from, 24).unwrap();
+
+        let output = PeersOutput {
+            local_peers: Some(suffix.to_vec()),
+            ..Default::default()
+        };
+        let mut buf = Vec::with_capacity(output.encoded_len());
+        output.encode(&mut buf).unwrap();
+        let output = PeersOutput::decode(&*buf).unwrap();
+
+        let requester = "192.168.1.30:4000".parse().unwrap();
+        let peers = decode_local_peers(&requester, output.local_peers.unwrap());
+        assert_eq!(peers, vec![local]);
+    }
+
+    #[test]
+    fn reveal_local_addresses_in_subnet() {
+        let mut peers = PeerCache::new(16, Duration::from_secs(60));
+        let topic = IdBytes::random();
+        let key = |local: &str, from: &str, prefix| {
+            let local = local.parse::<SocketAddr>().unwrap().encode();
+            local_cache_key(&topic, &local, &from.parse().unwrap(), prefix).unwrap()
+        };
+
+        let (announcer, suffix) = key("192.168.1.20:4000", "203.0.113.5:49737", 24);
+        peers.insert(announcer, suffix);
+        let encoded = suffix.to_vec();
+
+        // a requester behind the same NAT
+        let (same, own) = key("192.168.1.30:4000", "203.0.113.5:50000", 24);
+        assert_eq!(
+            encode_local_peers(&mut peers, &same, own),
+            Some(encoded.clone())
+        );
+        // the announcer doesn't learn its own address
+        let (same, own) = key("192.168.1.20:4000", "203.0.113.5:49737", 24);
+        assert_eq!(encode_local_peers(&mut peers, &same, own), Some(vec![]));
+        // another public address in the same subnet
+        let (subnet, own) = key("192.168.1.30:4000", "203.0.113.99:50000", 24);
+        assert_eq!(encode_local_peers(&mut peers, &subnet, own), Some(encoded));
+        // a requester elsewhere with the same private network
+        let (other, own) = key("192.168.1.30:4000", "198.51.100.5:50000", 24);
+        assert_eq!(encode_local_peers(&mut peers, &other, own), None);
+        // a narrower prefix excludes the neighbours of the public address
+        let (narrow, own) = key("192.168.1.30:4000", "203.0.113.99:50000", 32);
+        assert_eq!(encode_local_peers(&mut peers, &narrow, own), None);
+        // ipv6 requesters don't share the local ipv4 network
+        let local = "192.168.1.30:4000".parse::<SocketAddr>().unwrap().encode();
+        let from = "[2001:db8::1]:50000".parse().unwrap();
+        assert!(local_cache_key(&topic, &local, &from, 24).is_none());
+    }
 }
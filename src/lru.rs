@@ -1,7 +1,7 @@
 use std::borrow::Borrow;
 use std::cmp::Ordering;
 use std::hash::Hash;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::time::Duration;
 
 use fnv::FnvHashMap;
@@ -9,6 +9,11 @@ use smallvec::alloc::collections::VecDeque;
 use wasm_timer::Instant;
 
 use crate::rpc::IdBytes;
+use crate::time;
+
+/// Default number of addresses stored per key, so that a single busy topic
+/// can't push out the announcements of all others.
+pub const KEY_CAPACITY: usize = 1024;
 
 #[derive(Debug, Clone)]
 pub enum Address {
@@ -30,7 +35,13 @@ impl From<[u8; 4]> for Address {
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum CacheKey {
-    Local { id: IdBytes, prefix: [u8; 2] },
+    /// Local addresses, by the first two bytes of the local address and the
+    /// public subnet of the announcer.
+    Local {
+        id: IdBytes,
+        prefix: [u8; 2],
+        subnet: Ipv4Addr,
+    },
     Remote(IdBytes),
 }
 
@@ -55,14 +66,16 @@ impl Ord for CacheKey {
         if let CacheKey::Local {
             id: id1,
             prefix: r1,
+            subnet: s1,
         } = self
         {
             if let CacheKey::Local {
                 id: id2,
                 prefix: r2,
+                subnet: s2,
             } = other
             {
-                return id1.0.cmp(&id2.0).then(r1.cmp(&r2));
+                return id1.0.cmp(&id2.0).then(r1.cmp(r2)).then(s1.cmp(s2));
             }
         }
         self.id().0.cmp(&other.id().0)
@@ -101,12 +114,12 @@ impl AddressCache {
         match key {
             Address::Remote(addr) => {
                 if let AddressCache::Remote(cache) = self {
-                    return cache.remove(&addr).map(Address::from);
+                    return cache.remove(addr).map(Address::from);
                 }
             }
             Address::Local(addr) => {
                 if let AddressCache::Local(cache) = self {
-                    return cache.remove(&addr).map(Address::from);
+                    return cache.remove(addr).map(Address::from);
                 }
             }
         }
@@ -160,17 +173,6 @@ impl AddressCache {
         }
     }
 
-    fn set_expiration(&mut self, expiration: Instant) {
-        match self {
-            AddressCache::Remote(c) => {
-                c.expiration = expiration;
-            }
-            AddressCache::Local(c) => {
-                c.expiration = expiration;
-            }
-        }
-    }
-
     fn len(&self) -> usize {
         match self {
             AddressCache::Remote(c) => c.len(),
@@ -185,25 +187,19 @@ impl AddressCache {
         }
     }
 
-    pub fn iter_locals<'a>(&'a self) -> Option<impl Iterator<Item = &[u8; 4]> + 'a> {
+    /// The local addresses, the most recently announced first.
+    pub fn iter_locals(&self) -> Option<impl Iterator<Item = &[u8; 4]> + '_> {
         if let AddressCache::Local(cache) = self {
-            Some(cache.list.iter())
-        } else {
-            None
-        }
-    }
-
-    pub fn iter_remotes<'a>(&'a self) -> Option<impl Iterator<Item = &SocketAddr> + 'a> {
-        if let AddressCache::Remote(cache) = self {
-            Some(cache.list.iter())
+            Some(cache.list.iter().rev())
         } else {
             None
         }
     }
 
-    pub fn remotes(&self) -> Option<&VecDeque<SocketAddr>> {
+    /// The remote addresses, the most recently announced first.
+    pub fn iter_remotes(&self) -> Option<impl Iterator<Item = &SocketAddr> + '_> {
         if let AddressCache::Remote(cache) = self {
-            Some(&cache.list)
+            Some(cache.list.iter().rev())
         } else {
             None
         }
@@ -292,6 +288,8 @@ pub struct PeerCache {
     list: VecDeque<CacheKey>,
     /// maximum allowed capacity of this cache
     capacity: usize,
+    /// maximum number of addresses per key
+    key_capacity: usize,
     /// currently stored addresses
     cnt: usize,
     /// How long an entry is valid
@@ -304,12 +302,21 @@ impl PeerCache {
             map: Default::default(),
             list: Default::default(),
             capacity,
+            key_capacity: capacity,
             cnt: 0,
             age,
         }
     }
 
-    fn remove_expired(&mut self, now: Instant) {
+    /// Limits the addresses of a single key to `key_capacity`, the oldest
+    /// address of the key is dropped first.
+    pub fn with_key_capacity(mut self, key_capacity: usize) -> Self {
+        self.key_capacity = key_capacity;
+        self
+    }
+
+    /// Removes all addresses that are expired at `now`.
+    pub fn remove_expired(&mut self, now: Instant) {
         let (map, list) = (&mut self.map, &mut self.list);
 
         let mut expired_keys = 0;
@@ -335,13 +342,15 @@ impl PeerCache {
     }
 
     // Move `key` in the ordered list to the last
-    fn update_key<Key, Q: ?Sized>(list: &mut VecDeque<Key>, key: &Q)
+    fn update_key<Key, Q>(list: &mut VecDeque<Key>, key: &Q)
     where
         Key: Borrow<Q>,
-        Q: Ord,
+        Q: Ord + ?Sized,
     {
         if let Some(pos) = list.iter().position(|k| k.borrow() == key) {
-            let _ = list.remove(pos).map(|it| list.push_back(it));
+            if let Some(it) = list.remove(pos) {
+                list.push_back(it);
+            }
         }
     }
 
@@ -381,12 +390,16 @@ impl PeerCache {
 
     pub fn insert(&mut self, key: CacheKey, addr: impl Into<Address>) {
         let addr = addr.into();
-        let now = Instant::now();
+        let now = time::now();
         self.remove_expired(now);
         if let Some(addrs) = self.map.get_mut(&key) {
             Self::update_key(&mut self.list, &key);
             if addrs.insert(addr, now + self.age) {
-                self.cnt += 1;
+                if addrs.len() > self.key_capacity {
+                    addrs.remove_lru();
+                } else {
+                    self.cnt += 1;
+                }
             }
         } else {
             self.remove_lru(now);
@@ -397,28 +410,23 @@ impl PeerCache {
         }
     }
 
+    /// Returns the addresses stored for `key` that are not expired yet.
+    ///
+    /// Reading doesn't extend the lifetime of the addresses, only
+    /// [`PeerCache::insert`] does.
     pub fn get(&mut self, key: &CacheKey) -> Option<&mut AddressCache> {
-        let now = Instant::now();
-        self.remove_expired(now);
-
-        let expiration = now + self.age;
-        let list = &mut self.list;
-        self.map.get_mut(key).map(|addrs| {
-            Self::update_key(list, key);
-            addrs.set_expiration(expiration);
-            addrs
-        })
+        self.remove_expired(time::now());
+        self.map.get_mut(key)
     }
 
     pub fn remove_addr(&mut self, key: &CacheKey, addr: impl Into<Address>) -> Option<Address> {
-        if let Some(addrs) = self.map.get_mut(key) {
-            let addr = addr.into();
-            if let Some(value) = addrs.remove(&addr) {
-                self.cnt -= 1;
-                return Some(value);
-            }
+        let addrs = self.map.get_mut(key)?;
+        let value = addrs.remove(&addr.into())?;
+        self.cnt -= 1;
+        if addrs.is_empty() {
+            self.remove(key);
         }
-        None
+        Some(value)
     }
 
     pub fn remove(&mut self, key: &CacheKey) -> Option<AddressCache> {
@@ -478,7 +486,7 @@ mod tests {
             let key = CacheKey::Remote(IdBytes::random());
             let addr = Address::Remote("127.0.0.1:0".parse().unwrap());
 
-            let _ = lru_cache.insert(key, addr);
+            lru_cache.insert(key, addr);
 
             if i < size {
                 assert_eq!(lru_cache.len(), i + 1);
@@ -494,4 +502,86 @@ mod tests {
 
         assert_eq!(lru_cache.len(), 1);
     }
+
+    #[test]
+    fn key_capacity() {
+        let mut lru_cache = PeerCache::new(100, Duration::from_secs(60)).with_key_capacity(3);
+        let busy = CacheKey::Remote(IdBytes::random());
+        let other = CacheKey::Remote(IdBytes::random());
+        lru_cache.insert(other.clone(), "127.0.0.1:0".parse::<SocketAddr>().unwrap());
+        for port in 1..=10 {
+            let addr = SocketAddr::from(([127, 0, 0, 1], port));
+            lru_cache.insert(busy.clone(), addr);
+        }
+        assert_eq!(lru_cache.len(), 4);
+
+        // the most recent addresses are kept
+        let ports = lru_cache
+            .get(&busy)
+            .unwrap()
+            .iter_remotes()
+            .unwrap()
+            .map(|addr| addr.port())
+            .collect::<Vec<_>>();
+        assert_eq!(ports, [10, 9, 8]);
+        assert!(lru_cache.get(&other).is_some());
+    }
+
+    #[test]
+    fn freshest_first() {
+        let mut lru_cache = PeerCache::new(100, Duration::from_secs(60));
+        let key = CacheKey::Remote(IdBytes::random());
+        for port in 1..=5 {
+            lru_cache.insert(key.clone(), SocketAddr::from(([127, 0, 0, 1], port)));
+        }
+        // re-announcing moves an address to the front again
+        lru_cache.insert(key.clone(), SocketAddr::from(([127, 0, 0, 1], 2)));
+
+        let ports = lru_cache
+            .get(&key)
+            .unwrap()
+            .iter_remotes()
+            .unwrap()
+            .map(|addr| addr.port())
+            .take(3)
+            .collect::<Vec<_>>();
+        assert_eq!(ports, [2, 5, 4]);
+    }
+
+    #[test]
+    fn get_does_not_extend() {
+        let ttl = Duration::from_millis(50);
+        let mut lru_cache = PeerCache::new(10, ttl);
+
+        let key = CacheKey::Remote(IdBytes::random());
+        lru_cache.insert(key.clone(), "127.0.0.1:0".parse::<SocketAddr>().unwrap());
+
+        sleep(30);
+        assert!(lru_cache.get(&key).is_some());
+        sleep(30);
+        assert!(lru_cache.get(&key).is_none());
+        assert!(lru_cache.is_empty());
+    }
+
+    #[test]
+    fn remove_last_addr() {
+        let mut lru_cache = PeerCache::new(10, Duration::from_secs(60));
+
+        let key = CacheKey::Remote(IdBytes::random());
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        lru_cache.insert(key.clone(), addr);
+        lru_cache.insert(key.clone(), "127.0.0.1:1".parse::<SocketAddr>().unwrap());
+        assert_eq!(lru_cache.len(), 2);
+
+        assert!(lru_cache.remove_addr(&key, addr).is_some());
+        assert!(lru_cache.remove_addr(&key, addr).is_none());
+        assert_eq!(lru_cache.get(&key).unwrap().len(), 1);
+
+        assert!(lru_cache
+            .remove_addr(&key, "127.0.0.1:1".parse::<SocketAddr>().unwrap())
+            .is_some());
+        assert!(lru_cache.get(&key).is_none());
+        assert!(lru_cache.list.is_empty());
+        assert!(lru_cache.is_empty());
+    }
 }
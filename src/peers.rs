@@ -1,57 +1,129 @@
 use std::convert::{TryFrom, TryInto};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use smallvec::alloc::borrow::Borrow;
 
 use crate::kbucket::{self, EntryView};
 use crate::rpc::{IdBytes, Node, Peer, PeerId};
 
-#[derive(Debug, Clone, Default)]
-pub struct PeersCodec {
-    id_length: usize,
+pub trait PeersEncoding {
+    fn encode(&self) -> Vec<u8>;
+}
+
+/// Which address families a lookup is interested in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddrFamily {
+    /// Only IPv4 addresses, the legacy behaviour.
+    V4,
+    /// Only IPv6 addresses.
+    V6,
+    /// IPv4 and IPv6 addresses.
+    #[default]
+    Both,
 }
 
-impl PeersCodec {
-    pub fn new(id_length: usize) -> Self {
-        Self { id_length }
+impl AddrFamily {
+    /// Whether `addr` belongs to this family.
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            AddrFamily::V4 => addr.is_ipv4(),
+            AddrFamily::V6 => addr.is_ipv6(),
+            AddrFamily::Both => true,
+        }
+    }
+
+    /// The value of the `family` field of a peers request.
+    pub fn to_wire(self) -> u32 {
+        match self {
+            AddrFamily::V4 => 4,
+            AddrFamily::V6 => 6,
+            AddrFamily::Both => 0,
+        }
+    }
+
+    /// Decode the `family` field of a peers request, remotes that don't set
+    /// it only understand IPv4.
+    pub fn from_wire(family: Option<u32>) -> Self {
+        match family {
+            Some(6) => AddrFamily::V6,
+            Some(0) => AddrFamily::Both,
+            _ => AddrFamily::V4,
+        }
     }
 }
 
-pub trait PeersEncoding {
-    fn encode(&self) -> Vec<u8>;
+/// The encoded closer nodes of a response.
+///
+/// IPv4 nodes go into the legacy `closer_nodes` field with 38 bytes per
+/// node, IPv6 nodes into `closer_nodes6` with 50 bytes per node.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CloserNodes {
+    pub nodes: Vec<u8>,
+    pub nodes6: Option<Vec<u8>>,
 }
 
 impl PeersEncoding for &[Peer] {
+    /// Only the IPv4 peers, see [`encode_peers6`] for the others.
     fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(self.len() * 6);
-        for peer in self.iter() {
-            // TODO what to do with IPV6?
-            if let IpAddr::V4(ip) = peer.addr.ip() {
-                buf.extend_from_slice(&ip.octets()[..]);
-                buf.extend_from_slice(&peer.addr.port().to_be_bytes()[..]);
-            }
+        for peer in self.iter().filter(|peer| peer.addr.is_ipv4()) {
+            encode_addr(&peer.addr, &mut buf);
         }
         buf
     }
 }
 
-fn decode_addr(peer: &[u8]) -> Option<SocketAddr> {
-    if peer.len() != 6 {
-        return None;
+fn encode_addr(addr: &SocketAddr, buf: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()[..]),
+        IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()[..]),
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes()[..]);
+}
+
+/// Whether a remote node could be reachable at `addr`.
+///
+/// Rules out port 0 and unspecified, multicast and broadcast addresses.
+/// Loopback and private addresses are kept, nodes on the same host or network
+/// use them.
+pub fn is_routable(addr: &SocketAddr) -> bool {
+    if addr.port() == 0 || addr.ip().is_unspecified() || addr.ip().is_multicast() {
+        return false;
+    }
+    match addr.ip() {
+        IpAddr::V4(ip) => !ip.is_broadcast(),
+        IpAddr::V6(_) => true,
     }
+}
 
-    let octects: [u8; 4] = peer[0..4].try_into().unwrap();
-    let ip = Ipv4Addr::from(octects);
-    let port: [u8; 2] = peer[4..6].try_into().unwrap();
-    let port = u16::from_be_bytes(port);
-    Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+/// Decode a single address, either 6 bytes for IPv4 or 18 bytes for IPv6.
+pub fn decode_addr(peer: &[u8]) -> Option<SocketAddr> {
+    match peer.len() {
+        6 => {
+            let octects: [u8; 4] = peer[0..4].try_into().unwrap();
+            let ip = Ipv4Addr::from(octects);
+            let port: [u8; 2] = peer[4..6].try_into().unwrap();
+            let port = u16::from_be_bytes(port);
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        }
+        18 => {
+            let octects: [u8; 16] = peer[0..16].try_into().unwrap();
+            let ip = Ipv6Addr::from(octects);
+            let port: [u8; 2] = peer[16..18].try_into().unwrap();
+            let port = u16::from_be_bytes(port);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)))
+        }
+        _ => None,
+    }
 }
 
-pub fn decode_peer_ids(buf: impl AsRef<[u8]>) -> Vec<PeerId> {
-    let buf = buf.as_ref();
-    let mut peers = Vec::with_capacity(buf.len() / 38);
+fn decode_chunks<T>(buf: &[u8], size: usize) -> Vec<T>
+where
+    for<'a> T: TryFrom<&'a [u8]>,
+{
+    let mut peers = Vec::with_capacity(buf.len() / size);
 
-    for chunk in buf.chunks(38) {
+    for chunk in buf.chunks_exact(size) {
         if let Ok(peer) = chunk.try_into() {
             peers.push(peer);
         }
@@ -60,11 +132,21 @@ pub fn decode_peer_ids(buf: impl AsRef<[u8]>) -> Vec<PeerId> {
     peers
 }
 
+/// Decode the 38 byte IPv4 nodes of the `closer_nodes` field.
+pub fn decode_peer_ids(buf: impl AsRef<[u8]>) -> Vec<PeerId> {
+    decode_chunks(buf.as_ref(), 38)
+}
+
+/// Decode the 50 byte IPv6 nodes of the `closer_nodes6` field.
+pub fn decode_peer_ids6(buf: impl AsRef<[u8]>) -> Vec<PeerId> {
+    decode_chunks(buf.as_ref(), 50)
+}
+
 impl TryFrom<&[u8]> for PeerId {
     type Error = ();
 
     fn try_from(buf: &[u8]) -> Result<Self, Self::Error> {
-        if buf.len() != 38 {
+        if buf.len() != 38 && buf.len() != 50 {
             return Err(());
         }
 
@@ -75,32 +157,52 @@ impl TryFrom<&[u8]> for PeerId {
     }
 }
 
+fn decode_addrs(buf: &[u8], size: usize) -> Vec<SocketAddr> {
+    buf.chunks_exact(size).filter_map(decode_addr).collect()
+}
+
+/// Decode 6 byte IPv4 addresses.
 pub fn decode_peers(buf: impl AsRef<[u8]>) -> Vec<SocketAddr> {
-    let buf = buf.as_ref();
-    let mut peers = Vec::with_capacity(buf.len() / 6);
+    decode_addrs(buf.as_ref(), 6)
+}
 
-    for peer in buf.chunks(6) {
-        if let Some(addr) = decode_addr(peer) {
-            peers.push(addr);
+/// Decode 18 byte IPv6 addresses.
+pub fn decode_peers6(buf: impl AsRef<[u8]>) -> Vec<SocketAddr> {
+    decode_addrs(buf.as_ref(), 18)
+}
+
+/// Encode the IPv6 addresses of `addrs` with 18 bytes each.
+pub fn encode_peers6<'a>(addrs: impl IntoIterator<Item = &'a SocketAddr>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for addr in addrs.into_iter().filter(|addr| addr.is_ipv6()) {
+        encode_addr(addr, &mut buf);
+    }
+    buf
+}
+
+/// Encode the nodes of `family` with their id, IPv4 nodes take 38 bytes and
+/// IPv6 nodes 50 bytes.
+fn encode_nodes(nodes: &[EntryView<kbucket::Key<IdBytes>, Node>], family: AddrFamily) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(nodes.len() * (32 + 6));
+    for peer in nodes.iter() {
+        let addr = &peer.node.value.addr;
+        if family.matches(addr) {
+            buf.extend_from_slice(peer.node.key.preimage().borrow());
+            encode_addr(addr, &mut buf);
         }
     }
-    peers
+    buf
+}
+
+/// Encode the IPv6 nodes of `nodes` for the `closer_nodes6` field.
+pub fn encode_nodes6(nodes: &[EntryView<kbucket::Key<IdBytes>, Node>]) -> Vec<u8> {
+    encode_nodes(nodes, AddrFamily::V6)
 }
 
 impl PeersEncoding for Vec<EntryView<kbucket::Key<IdBytes>, Node>> {
+    /// Only the IPv4 nodes, see [`encode_nodes6`] for the others.
     fn encode(&self) -> Vec<u8> {
-        // TODO refactor
-        let mut buf = Vec::with_capacity(self.len() * (32 + 6));
-
-        for peer in self.iter() {
-            let addr = &peer.node.value.addr;
-            if let IpAddr::V4(ip) = addr.ip() {
-                buf.extend_from_slice(peer.node.key.preimage().borrow());
-                buf.extend_from_slice(&ip.octets()[..]);
-                buf.extend_from_slice(&addr.port().to_be_bytes()[..]);
-            }
-        }
-        buf
+        encode_nodes(self, AddrFamily::V4)
     }
 }
 
@@ -111,21 +213,26 @@ impl PeersEncoding for Peer {
 }
 
 impl PeersEncoding for SocketAddr {
+    /// 6 bytes for IPv4 and 18 bytes for IPv6.
     fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(6);
-        // TODO what to do with IPV6?
-        if let IpAddr::V4(ip) = self.ip() {
-            buf.extend_from_slice(&ip.octets()[..]);
-            buf.extend_from_slice(&self.port().to_be_bytes()[..]);
-        }
+        let mut buf = Vec::with_capacity(18);
+        encode_addr(self, &mut buf);
         buf
     }
 }
 
+/// The subnet of `ip` with a prefix of `prefix` bits.
+pub fn ipv4_subnet(ip: &Ipv4Addr, prefix: u8) -> Ipv4Addr {
+    let mask = u32::MAX
+        .checked_shl(32 - u32::from(prefix.min(32)))
+        .unwrap_or_default();
+    Ipv4Addr::from(u32::from(*ip) & mask)
+}
+
 /// Decode local peers from a buffer.
 pub fn decode_local_peers(local: &SocketAddrV4, buf: impl AsRef<[u8]>) -> Vec<SocketAddr> {
     let buf = buf.as_ref();
-    if buf.len().trailing_zeros() >= 2 {
+    if buf.len() % 4 != 0 {
         return vec![];
     }
 
@@ -145,3 +252,70 @@ pub fn decode_local_peers(local: &SocketAddrV4, buf: impl AsRef<[u8]>) -> Vec<So
     }
     peers
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addr_roundtrip() {
+        let v4: SocketAddr = ([127, 0, 0, 1], 3000).into();
+        let v6: SocketAddr = ("::1".parse::<Ipv6Addr>().unwrap(), 3000).into();
+        assert_eq!(v4.encode().len(), 6);
+        assert_eq!(v6.encode().len(), 18);
+        assert_eq!(decode_addr(&v4.encode()), Some(v4));
+        assert_eq!(decode_addr(&v6.encode()), Some(v6));
+
+        let addrs = vec![v4, v6, ([10, 0, 0, 1], 1).into()];
+        let peers = addrs.iter().cloned().map(Peer::from).collect::<Vec<_>>();
+        // the legacy encoding only carries ipv4
+        let buf = PeersEncoding::encode(&peers.as_slice());
+        assert_eq!(buf.len(), 12);
+        assert_eq!(decode_peers(&buf), vec![addrs[0], addrs[2]]);
+        assert_eq!(decode_peers6(encode_peers6(&addrs)), vec![v6]);
+    }
+
+    #[test]
+    fn peer_id_roundtrip() {
+        let v4 = PeerId {
+            addr: ([127, 0, 0, 1], 3000).into(),
+            id: IdBytes::random(),
+        };
+        let v6 = PeerId {
+            addr: ("::1".parse::<Ipv6Addr>().unwrap(), 3000).into(),
+            id: IdBytes::random(),
+        };
+        let encode = |peer: &PeerId| {
+            let mut buf = peer.id.to_vec();
+            buf.extend(peer.addr.encode());
+            buf
+        };
+        assert_eq!(decode_peer_ids(encode(&v4)), vec![v4.clone()]);
+        assert_eq!(decode_peer_ids6(encode(&v6)), vec![v6.clone()]);
+        // ipv4 entries are not mistaken for ipv6 ones
+        assert!(decode_peer_ids6(encode(&v4)).is_empty());
+    }
+
+    #[test]
+    fn subnet_of_prefix() {
+        let ip = Ipv4Addr::new(203, 0, 113, 77);
+        assert_eq!(ipv4_subnet(&ip, 24), Ipv4Addr::new(203, 0, 113, 0));
+        assert_eq!(ipv4_subnet(&ip, 20), Ipv4Addr::new(203, 0, 112, 0));
+        assert_eq!(ipv4_subnet(&ip, 32), ip);
+        assert_eq!(ipv4_subnet(&ip, 40), ip);
+        assert_eq!(ipv4_subnet(&ip, 0), Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn decode_local_peers_of_any_length() {
+        let local = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 3000);
+        for len in 0..16 {
+            let peers = decode_local_peers(&local, vec![1; len]);
+            assert_eq!(peers.len(), if len % 4 == 0 { len / 4 } else { 0 });
+        }
+        assert_eq!(
+            decode_local_peers(&local, [1, 3, 0x0b, 0xb8]),
+            vec![SocketAddr::from(([192, 168, 1, 3], 3000))]
+        );
+    }
+}
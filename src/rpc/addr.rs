@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use crate::rpc::nat::NatMapping;
+
+/// Number of distinct peers that need to report the same address before it is
+/// confirmed as our external address.
+pub const CONFIRMATIONS: usize = 3;
+
+/// Maximum number of reports that are kept.
+const MAX_REPORTS: usize = 64;
+
+/// Tracks the addresses remote peers saw us at, as reported in the `to` field
+/// of their responses.
+#[derive(Debug)]
+pub struct ExternalAddr {
+    /// The latest reported address by peer, oldest first.
+    reports: VecDeque<(SocketAddr, SocketAddr)>,
+    /// How many distinct peers need to report an address.
+    confirmations: usize,
+    /// The currently confirmed address.
+    confirmed: Option<SocketAddr>,
+    /// The confirmed address that too few peers still report.
+    invalidated: Option<SocketAddr>,
+}
+
+impl ExternalAddr {
+    pub fn new(confirmations: usize) -> Self {
+        Self {
+            reports: VecDeque::with_capacity(MAX_REPORTS),
+            confirmations,
+            confirmed: None,
+            invalidated: None,
+        }
+    }
+
+    /// The address that was reported by enough peers.
+    pub fn confirmed(&self) -> Option<SocketAddr> {
+        self.confirmed
+    }
+
+    /// The confirmed address, or the one that was confirmed last if it has
+    /// been invalidated since.
+    pub fn last_confirmed(&self) -> Option<SocketAddr> {
+        self.confirmed.or(self.invalidated)
+    }
+
+    /// Records that `peer` saw us at `addr`.
+    ///
+    /// Returns the new address if this report confirmed a different address
+    /// than before, e.g. after the NAT changed our external port. The
+    /// confirmed address is invalidated once fewer peers than needed still
+    /// report it, until an address is confirmed again.
+    pub fn report(&mut self, peer: SocketAddr, addr: SocketAddr) -> Option<SocketAddr> {
+        // only the latest report of every peer counts
+        self.reports.retain(|(p, _)| *p != peer);
+        if self.reports.len() >= MAX_REPORTS {
+            self.reports.pop_front();
+        }
+        self.reports.push_back((peer, addr));
+
+        if self.confirmed == Some(addr) {
+            return None;
+        }
+        let reports = &self.reports;
+        let count = |addr: SocketAddr| reports.iter().filter(|(_, a)| *a == addr).count();
+        if let Some(confirmed) = self.confirmed {
+            if count(confirmed) < self.confirmations {
+                self.invalidated = self.confirmed.take();
+            }
+        }
+        let reported = count(addr);
+        if reported >= self.confirmations && self.confirmed.map(count).is_none_or(|c| reported > c)
+        {
+            self.confirmed = Some(addr);
+            self.invalidated = None;
+            Some(addr)
+        } else {
+            None
+        }
+    }
+
+    /// How the NAT maps our socket: consistently once an address is
+    /// confirmed, symmetric if as many peers as needed for a confirmation
+    /// report the latest ip with different ports.
+    pub fn mapping(&self) -> NatMapping {
+        if self.confirmed.is_some() {
+            return NatMapping::Cone;
+        }
+        let ip = match self.reports.back() {
+            Some((_, addr)) => addr.ip(),
+            None => return NatMapping::Unknown,
+        };
+        let mut ports = self
+            .reports
+            .iter()
+            .filter(|(_, addr)| addr.ip() == ip)
+            .map(|(_, addr)| addr.port())
+            .collect::<Vec<_>>();
+        ports.sort_unstable();
+        ports.dedup();
+        if ports.len() >= self.confirmations {
+            NatMapping::Symmetric
+        } else {
+            NatMapping::Unknown
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        ([10, 0, 0, 1], port).into()
+    }
+
+    #[test]
+    fn confirm_after_threshold() {
+        let addr: SocketAddr = ([1, 2, 3, 4], 5000).into();
+        let mut external = ExternalAddr::new(CONFIRMATIONS);
+
+        assert_eq!(external.report(peer(1), addr), None);
+        // reports of the same peer are counted once
+        assert_eq!(external.report(peer(1), addr), None);
+        assert_eq!(external.report(peer(2), ([1, 2, 3, 4], 6000).into()), None);
+        assert_eq!(external.report(peer(3), addr), None);
+        assert_eq!(external.confirmed(), None);
+
+        assert_eq!(external.report(peer(4), addr), Some(addr));
+        assert_eq!(external.confirmed(), Some(addr));
+        assert_eq!(external.report(peer(5), addr), None);
+    }
+
+    #[test]
+    fn nat_mapping() {
+        let mut external = ExternalAddr::new(2);
+        assert_eq!(external.mapping(), NatMapping::Unknown);
+        external.report(peer(1), ([1, 2, 3, 4], 5000).into());
+        assert_eq!(external.mapping(), NatMapping::Unknown);
+        external.report(peer(2), ([1, 2, 3, 4], 5000).into());
+        assert_eq!(external.mapping(), NatMapping::Cone);
+
+        let mut external = ExternalAddr::new(2);
+        external.report(peer(1), ([1, 2, 3, 4], 5000).into());
+        // another ip doesn't count as another mapping
+        external.report(peer(2), ([5, 6, 7, 8], 6000).into());
+        assert_eq!(external.mapping(), NatMapping::Unknown);
+        external.report(peer(3), ([5, 6, 7, 8], 7000).into());
+        assert_eq!(external.mapping(), NatMapping::Symmetric);
+    }
+
+    #[test]
+    fn confirm_changed_addr() {
+        let old: SocketAddr = ([1, 2, 3, 4], 5000).into();
+        let new: SocketAddr = ([1, 2, 3, 4], 6000).into();
+        let mut external = ExternalAddr::new(2);
+        external.report(peer(1), old);
+        external.report(peer(2), old);
+        external.report(peer(3), old);
+        assert_eq!(external.confirmed(), Some(old));
+
+        // the new address needs more reports than the old one
+        assert_eq!(external.report(peer(4), new), None);
+        assert_eq!(external.report(peer(5), new), None);
+        assert_eq!(external.report(peer(1), new), Some(new));
+        assert_eq!(external.confirmed(), Some(new));
+    }
+
+    #[test]
+    fn invalidate_disagreeing_addr() {
+        let old: SocketAddr = ([1, 2, 3, 4], 5000).into();
+        let mut external = ExternalAddr::new(2);
+        external.report(peer(1), old);
+        external.report(peer(2), old);
+        assert_eq!(external.confirmed(), Some(old));
+
+        // the peers see us elsewhere, but don't agree on where
+        assert_eq!(external.report(peer(1), ([1, 2, 3, 4], 6000).into()), None);
+        assert_eq!(external.confirmed(), None);
+        assert_eq!(external.last_confirmed(), Some(old));
+        assert_eq!(external.report(peer(2), ([1, 2, 3, 4], 7000).into()), None);
+        assert_eq!(external.confirmed(), None);
+
+        // confirmed again
+        assert_eq!(external.report(peer(3), old), None);
+        assert_eq!(external.report(peer(4), old), Some(old));
+        assert_eq!(external.confirmed(), Some(old));
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use wasm_timer::Instant;
+
+/// Default time every request needs to time out in before the network is
+/// suspected to be gone.
+pub const SUSPECT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Number of timeouts without any response in between that are needed before
+/// the network is suspected to be gone.
+const MIN_TIMEOUTS: u64 = 3;
+
+/// Watches for outgoing requests that all time out, e.g. because the network
+/// changed and the socket silently stopped working.
+#[derive(Debug)]
+pub struct NetworkHealth {
+    /// How long all requests need to time out.
+    window: Duration,
+    /// Responses and timeouts before the current outage.
+    responses: u64,
+    timeouts: u64,
+    /// The first timeout since the last response and how many followed.
+    outage: Option<(Instant, u64)>,
+    /// Whether the current outage was reported already.
+    suspected: bool,
+}
+
+impl NetworkHealth {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            responses: 0,
+            timeouts: 0,
+            outage: None,
+            suspected: false,
+        }
+    }
+
+    /// A response was received, which ends the outage.
+    pub fn on_response(&mut self) {
+        if let Some((_, timeouts)) = self.outage.take() {
+            self.timeouts += timeouts;
+        }
+        self.responses += 1;
+        self.suspected = false;
+    }
+
+    /// A request timed out.
+    ///
+    /// Returns the start of the outage and the number of timeouts since, if
+    /// every request of the window timed out while most requests succeeded
+    /// before. Every outage is reported once.
+    pub fn on_timeout(&mut self, now: Instant) -> Option<(Instant, u64)> {
+        let (since, timeouts) = self.outage.get_or_insert((now, 0));
+        *timeouts += 1;
+        let healthy = self.responses > 0 && self.responses >= self.timeouts;
+        if !self.suspected
+            && healthy
+            && *timeouts >= MIN_TIMEOUTS
+            && now.duration_since(*since) >= self.window
+        {
+            self.suspected = true;
+            Some((*since, *timeouts))
+        } else {
+            None
+        }
+    }
+
+    /// Forgets about the current outage, e.g. after the socket was rebound.
+    pub fn reset(&mut self) {
+        self.outage = None;
+        self.suspected = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suspect_after_window() {
+        let window = Duration::from_secs(10);
+        let mut health = NetworkHealth::new(window);
+        let start = Instant::now();
+
+        // never heard from the network
+        for i in 0..5 {
+            assert_eq!(health.on_timeout(start + window * i), None);
+        }
+        health.on_response();
+
+        for _ in 0..10 {
+            health.on_response();
+        }
+        assert_eq!(health.on_timeout(start), None);
+        assert_eq!(health.on_timeout(start + window / 2), None);
+        assert_eq!(health.on_timeout(start + window), Some((start, 3)));
+        // reported once
+        assert_eq!(health.on_timeout(start + window * 2), None);
+
+        health.on_response();
+        let later = start + window * 3;
+        health.on_timeout(later);
+        health.on_timeout(later);
+        assert_eq!(health.on_timeout(later + window), Some((later, 3)));
+    }
+
+    #[test]
+    fn no_suspect_without_healthy_rate() {
+        let window = Duration::from_secs(10);
+        let mut health = NetworkHealth::new(window);
+        let start = Instant::now();
+        health.on_response();
+        // an outage that ended, the rate is not healthy anymore
+        for _ in 0..3 {
+            health.on_timeout(start);
+        }
+        health.on_response();
+        for _ in 0..3 {
+            health.on_timeout(start);
+        }
+        health.on_timeout(start + window);
+        health.on_response();
+
+        assert_eq!(health.on_timeout(start), None);
+        assert_eq!(health.on_timeout(start + window), None);
+        assert_eq!(health.on_timeout(start + window), None);
+    }
+}
@@ -1,5 +1,6 @@
-use std::collections::VecDeque;
+use std::collections::{hash_map::Entry, VecDeque};
 use std::fmt;
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
 use std::ops::Deref;
@@ -7,27 +8,31 @@ use std::pin::Pin;
 use std::time::Duration;
 
 use async_std::{net::UdpSocket, stream::Stream};
-use blake2::crypto_mac::generic_array::{typenum::U64, GenericArray};
+use blake2::digest::Output;
 use blake2::{Blake2b, Digest};
-use fnv::FnvHashMap;
+use bytes::Bytes;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::{
     task::{Context, Poll},
     Sink,
 };
+use lru::LruCache;
 use prost::Message as ProtoMessage;
 use wasm_timer::Instant;
 
-use crate::rpc::udp::UdpFramed;
+use crate::rpc::rtt::RttTable;
+use crate::rpc::udp::{Transport, UdpFramed};
 use crate::rpc::IdBytes;
+use crate::time::{self, Delay};
 use crate::{
     kbucket::Key,
-    peers::PeersEncoding,
+    peers::{CloserNodes, PeersEncoding},
     rpc::{
         fill_random_bytes,
         message::Holepunch,
         message::{Command, Message, Type},
         protocol::DhtRpcCodec,
-        Peer, RequestId,
+        Peer, RequestId, MAX_MESSAGE_SIZE, MAX_VALUE_SIZE,
     },
 };
 
@@ -35,6 +40,35 @@ pub const VERSION: u64 = 1;
 
 const ROTATE_INTERVAL: u64 = 300_000;
 
+/// Milliseconds to wait for a response before a request is sent again.
+const REQUEST_TIMEOUT: u64 = 1_000;
+
+/// How often a request is sent again before it is considered failed.
+const REQUEST_RETRIES: usize = 3;
+
+/// Milliseconds an adaptive request timeout is at least.
+const MIN_REQUEST_TIMEOUT: u64 = 100;
+
+/// Maximum number of times the timeout of a request is doubled.
+const MAX_BACKOFF: usize = 6;
+
+/// Maximum number of messages waiting to be sent.
+pub const SEND_QUEUE_CAPACITY: usize = 1024;
+
+/// Maximum number of requests of queries waiting for a response at once.
+pub const MAX_REQUESTS_IN_FLIGHT: usize = 64;
+
+/// Number of answered requests that are remembered to tell duplicate responses
+/// apart from unmatched ones.
+const ANSWERED_CAPACITY: usize = 1024;
+
+/// Number of relayed holepunches that are remembered to send the requests to
+/// their target again once it responds.
+const PUNCH_CAPACITY: usize = 256;
+
+/// Error returned for updates without a valid roundtrip token.
+pub const ERR_INVALID_TOKEN: &str = "Invalid roundtrip token";
+
 #[derive(Debug, Clone)]
 struct Request<TUserData: fmt::Debug + Clone> {
     /// The message send
@@ -43,27 +77,27 @@ struct Request<TUserData: fmt::Debug + Clone> {
     peer: Peer,
     /// Timestamp when the request was sent
     timestamp: Instant,
+    /// Timestamp when the request was sent the first time
+    first_sent: Instant,
+    /// How often the request was sent again
+    retries: usize,
     user_data: TUserData,
 }
 
 impl<TUserData: fmt::Debug + Clone> Request<TUserData> {
-    fn into_event(self) -> Result<MessageEvent<TUserData>, Self> {
-        if let Ok(ty) = self.message.get_type() {
-            match ty {
-                Type::Query => Ok(MessageEvent::Query {
-                    msg: self.message,
-                    peer: self.peer,
-                    user_data: self.user_data,
-                }),
-                Type::Update => Ok(MessageEvent::Update {
-                    msg: self.message,
-                    peer: self.peer,
-                    user_data: self.user_data,
-                }),
-                _ => Err(self),
-            }
-        } else {
-            Err(self)
+    fn into_event(self) -> Option<MessageEvent<TUserData>> {
+        match self.message.get_type() {
+            Ok(Type::Query) => Some(MessageEvent::Query {
+                msg: self.message,
+                peer: self.peer,
+                user_data: self.user_data,
+            }),
+            Ok(Type::Update) => Some(MessageEvent::Update {
+                msg: self.message,
+                peer: self.peer,
+                user_data: self.user_data,
+            }),
+            _ => None,
         }
     }
 }
@@ -84,7 +118,12 @@ pub enum MessageEvent<TUserData: fmt::Debug + Clone> {
         msg: Message,
         peer: Peer,
     },
-    // TODO FireAndForget like Response?
+    /// A request that isn't waited for, like a relayed holepunch, which the
+    /// peer it is sent to answers to someone else.
+    Untracked {
+        msg: Message,
+        peer: Peer,
+    },
 }
 
 impl<TUserData: fmt::Debug + Clone> MessageEvent<TUserData> {
@@ -92,7 +131,9 @@ impl<TUserData: fmt::Debug + Clone> MessageEvent<TUserData> {
         match self {
             MessageEvent::Update { peer, msg, .. } => (msg, peer),
             MessageEvent::Query { peer, msg, .. } => (msg, peer),
-            MessageEvent::Response { peer, msg } => (msg, peer),
+            MessageEvent::Response { peer, msg } | MessageEvent::Untracked { peer, msg } => {
+                (msg, peer)
+            }
         }
     }
 
@@ -100,7 +141,9 @@ impl<TUserData: fmt::Debug + Clone> MessageEvent<TUserData> {
         match self {
             MessageEvent::Update { peer, msg, .. } => (msg, peer),
             MessageEvent::Query { peer, msg, .. } => (msg, peer),
-            MessageEvent::Response { peer, msg } => (msg, peer),
+            MessageEvent::Response { peer, msg } | MessageEvent::Untracked { peer, msg } => {
+                (msg, peer)
+            }
         }
     }
 }
@@ -108,25 +151,100 @@ impl<TUserData: fmt::Debug + Clone> MessageEvent<TUserData> {
 #[derive(Debug)]
 pub struct IoHandler<TUserData: fmt::Debug + Clone> {
     id: Option<Key<IdBytes>>,
-    socket: UdpFramed<DhtRpcCodec>,
+    socket: Box<dyn Transport>,
     /// Messages to send
     pending_send: VecDeque<MessageEvent<TUserData>>,
     /// Current message
     pending_flush: Option<MessageEvent<TUserData>>,
     /// Sent requests we currently wait for a response
     pending_recv: FnvHashMap<RequestId, Request<TUserData>>,
+    /// The requests that are queued or wait for their response, of those
+    /// that `counts_in_flight` returns true for
+    in_flight: FnvHashSet<RequestId>,
+    /// Whether a request counts as in flight, by its user data
+    counts_in_flight: fn(&TUserData) -> bool,
     secrets: ([u8; 32], [u8; 32]),
 
     next_req_id: RequestId,
+    /// Number of responses that did not match any pending request
+    unmatched_responses: u64,
+    /// The peers of the recently answered requests
+    answered: LruCache<RequestId, SocketAddr>,
+    /// Number of responses to requests that were answered already, e.g. the
+    /// responses to a request and its retry
+    duplicate_responses: u64,
+    /// Number of received packets that were dropped because they were
+    /// malformed
+    malformed_messages: u64,
+    /// Counters of the received and sent messages
+    traffic: Traffic,
+    /// Maximum size of the value of a message
+    max_value_size: usize,
+    /// Maximum size of a received message, used for the codec of the socket.
+    max_message_size: usize,
+    /// Maximum number of messages in `pending_send`
+    max_send_queue: usize,
+    /// Number of requests in flight above which queries hold back new ones
+    max_in_flight: usize,
+    /// Number of queued messages that were dropped because the queue was full
+    dropped_messages: u64,
 
     rotation: Duration,
     last_rotation: Instant,
+
+    request_timeout: Duration,
+    max_retries: usize,
+    /// Wakes up the task once the next pending request times out
+    timeout_timer: Option<(Instant, Delay)>,
+    /// Whether request timeouts follow the round trip times of the nodes
+    adaptive_timeout: bool,
+    min_request_timeout: Duration,
+    /// Round trip times of the addresses that responded
+    rtt: RttTable<SocketAddr>,
+    /// Number of requests that were sent again
+    retried_requests: u64,
+    /// holepunches relayed because a request to a peer timed out
+    punched: u64,
+    /// Whether to punch before the first request to a peer with a referrer
+    punch_first: bool,
+    /// The targets of the relayed holepunches by their request id
+    punches: LruCache<RequestId, SocketAddr>,
+}
+
+/// Number of messages and their bytes that went over the socket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
 }
 
 #[derive(Debug, Clone, Default)]
 pub struct IoConfig {
     pub rotation: Option<Duration>,
     pub secrets: Option<([u8; 32], [u8; 32])>,
+    /// How long to wait for a response before the request is sent again.
+    pub request_timeout: Option<Duration>,
+    /// How often a request is sent again before it times out.
+    pub max_retries: Option<usize>,
+    /// Whether the timeout of a request follows the round trip time of the
+    /// node it is sent to, `request_timeout` is only used for unknown nodes
+    /// then.
+    pub adaptive_timeout: Option<bool>,
+    /// The least timeout an adaptive timeout is set to.
+    pub min_request_timeout: Option<Duration>,
+    /// Maximum size of the value of a message.
+    pub max_value_size: Option<usize>,
+    /// Maximum number of messages waiting to be sent.
+    pub max_send_queue: Option<usize>,
+    /// Maximum number of requests of queries waiting for a response.
+    pub max_in_flight: Option<usize>,
+    /// Maximum size of a received message, larger ones are dropped undecoded.
+    pub max_message_size: Option<usize>,
+    /// Whether a holepunch is relayed before the first request to a peer with
+    /// a referrer, instead of with its first retry.
+    pub punch_first: Option<bool>,
 }
 
 impl<TUserData> IoHandler<TUserData>
@@ -139,7 +257,17 @@ where
         config: IoConfig,
     ) -> IoHandler<TUserData> {
         let socket = UdpFramed::new(socket, DhtRpcCodec::default());
+        Self::with_transport(id, Box::new(socket), config)
+    }
 
+    /// Creates a handler that sends and receives over the `socket`.
+    pub fn with_transport(
+        id: Option<Key<IdBytes>>,
+        mut socket: Box<dyn Transport>,
+        config: IoConfig,
+    ) -> IoHandler<TUserData> {
+        let max_message_size = config.max_message_size.unwrap_or(MAX_MESSAGE_SIZE);
+        socket.set_max_message_size(max_message_size);
         let secrets = config.secrets.unwrap_or_else(|| {
             let mut k1 = [0; 32];
             let mut k2 = [0; 32];
@@ -148,18 +276,45 @@ where
             (k1, k2)
         });
 
+        let request_timeout = config
+            .request_timeout
+            .unwrap_or_else(|| Duration::from_millis(REQUEST_TIMEOUT));
         Self {
             id,
             socket,
             pending_send: Default::default(),
             pending_flush: None,
             pending_recv: Default::default(),
+            in_flight: Default::default(),
+            counts_in_flight: |_| true,
             secrets,
             next_req_id: Self::random_id(),
+            unmatched_responses: 0,
+            answered: LruCache::new(ANSWERED_CAPACITY),
+            duplicate_responses: 0,
+            malformed_messages: 0,
+            traffic: Traffic::default(),
+            max_value_size: config.max_value_size.unwrap_or(MAX_VALUE_SIZE),
+            max_message_size,
+            max_send_queue: config.max_send_queue.unwrap_or(SEND_QUEUE_CAPACITY),
+            max_in_flight: config.max_in_flight.unwrap_or(MAX_REQUESTS_IN_FLIGHT),
+            dropped_messages: 0,
             rotation: config
                 .rotation
                 .unwrap_or_else(|| Duration::from_millis(ROTATE_INTERVAL)),
-            last_rotation: Instant::now(),
+            last_rotation: time::now(),
+            request_timeout,
+            max_retries: config.max_retries.unwrap_or(REQUEST_RETRIES),
+            timeout_timer: None,
+            adaptive_timeout: config.adaptive_timeout.unwrap_or(true),
+            min_request_timeout: config
+                .min_request_timeout
+                .unwrap_or_else(|| request_timeout.min(Duration::from_millis(MIN_REQUEST_TIMEOUT))),
+            rtt: Default::default(),
+            retried_requests: 0,
+            punched: 0,
+            punch_first: config.punch_first.unwrap_or(false),
+            punches: LruCache::new(PUNCH_CAPACITY),
         }
     }
 
@@ -175,31 +330,190 @@ where
 
     #[inline]
     pub fn is_ephemeral(&self) -> bool {
-        self.id.is_some()
+        self.id.is_none()
     }
 
-    /// Generate the next request id
+    /// Sets the id included in all following messages, `None` makes the node
+    /// ephemeral.
     #[inline]
+    pub(crate) fn set_id(&mut self, id: Option<Key<IdBytes>>) {
+        self.id = id
+    }
+
+    /// Generate the next request id
+    ///
+    /// Ids wrap around, but an id is never reused while its request is still
+    /// waiting for a response.
     fn next_req_id(&mut self) -> RequestId {
-        let rid = self.next_req_id;
-        self.next_req_id = RequestId(self.next_req_id.0.wrapping_add(1));
-        rid
+        loop {
+            let rid = self.next_req_id;
+            self.next_req_id = RequestId(self.next_req_id.0.wrapping_add(1));
+            if !self.pending_recv.contains_key(&rid) {
+                return rid;
+            }
+        }
+    }
+
+    /// Number of received responses that did not match any pending request,
+    /// e.g. late responses to requests that timed out already.
+    pub fn num_unmatched_responses(&self) -> u64 {
+        self.unmatched_responses
+    }
+
+    /// Number of received responses to requests that were answered already,
+    /// they are dropped like unmatched responses.
+    pub fn num_duplicate_responses(&self) -> u64 {
+        self.duplicate_responses
+    }
+
+    /// Number of received packets that were dropped because they didn't
+    /// decode or had fields of invalid lengths.
+    pub fn num_malformed_messages(&self) -> u64 {
+        self.malformed_messages
+    }
+
+    /// Number of messages waiting to be sent.
+    pub fn send_queue_len(&self) -> usize {
+        self.pending_send.len()
+    }
+
+    /// Counts only the requests for whose user data `f` returns true as in
+    /// flight from now on, see [`IoHandler::requests_in_flight`].
+    pub fn count_in_flight(&mut self, f: fn(&TUserData) -> bool) {
+        self.counts_in_flight = f;
+    }
+
+    /// Number of requests that are queued or wait for their response.
+    #[inline]
+    pub fn requests_in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Whether another request of a query may be sent, without exceeding the
+    /// maximum number of requests in flight, see
+    /// [`IoHandler::requests_in_flight`].
+    #[inline]
+    pub fn has_request_capacity(&self) -> bool {
+        self.requests_in_flight() < self.max_in_flight
+    }
+
+    /// Stops counting the request as in flight, unless it still waits for a
+    /// response.
+    fn settle(&mut self, id: RequestId) {
+        if !self.pending_recv.contains_key(&id) {
+            self.in_flight.remove(&id);
+        }
+    }
+
+    /// Number of messages that were dropped because the send queue was full.
+    pub fn num_dropped_messages(&self) -> u64 {
+        self.dropped_messages
+    }
+
+    /// Whether all queued messages were sent.
+    pub fn is_idle(&self) -> bool {
+        self.pending_send.is_empty() && self.pending_flush.is_none()
+    }
+
+    /// The messages received and sent so far, including malformed ones.
+    pub fn traffic(&self) -> Traffic {
+        self.traffic
+    }
+
+    /// The maximum size of the value of a message.
+    pub fn max_value_size(&self) -> usize {
+        self.max_value_size
+    }
+
+    /// The maximum size of a received message.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
     }
 
     /// Returns the local address that this listener is bound to.
     #[inline]
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.socket.get_ref().local_addr()
+        self.socket.local_addr()
+    }
+
+    /// Binds a second transport next to ours, see [`Transport::bind_probe`].
+    #[inline]
+    pub fn bind_probe(&self) -> io::Result<Box<dyn Transport>> {
+        self.socket.bind_probe()
     }
 
-    /// Generate a blake2 hash based on the peer's ip and the provided secret
-    fn token(&self, peer: &Peer, secret: &[u8]) -> GenericArray<u8, U64> {
+    /// Binds a transport that replaces the current one, see
+    /// [`Transport::rebind`].
+    pub fn rebind(&self) -> io::Result<Box<dyn Transport>> {
+        self.socket.rebind()
+    }
+
+    /// Sends and receives over the `socket` from now on, the previous socket
+    /// is closed.
+    ///
+    /// Queued messages are sent over the new socket. The requests that were
+    /// sent already are no longer waited for, they are returned with their
+    /// peer and user data.
+    pub fn set_transport(
+        &mut self,
+        mut socket: Box<dyn Transport>,
+    ) -> Vec<(Message, Peer, TUserData)> {
+        socket.set_max_message_size(self.max_message_size);
+        self.socket = socket;
+        if let Some(event) = self.pending_flush.take() {
+            self.pending_send.push_front(event);
+        }
+        let aborted = self
+            .pending_recv
+            .drain()
+            .map(|(_, req)| req)
+            .collect::<Vec<_>>();
+        for req in &aborted {
+            self.in_flight.remove(&req.message.get_request_id());
+        }
+        // requests that were queued to be sent again are aborted as well
+        self.pending_send.retain(|ev| match ev {
+            MessageEvent::Query { msg, .. } | MessageEvent::Update { msg, .. } => !aborted
+                .iter()
+                .any(|req| req.message.get_request_id() == msg.get_request_id()),
+            MessageEvent::Response { .. } | MessageEvent::Untracked { .. } => true,
+        });
+        self.timeout_timer = None;
+        aborted
+            .into_iter()
+            .map(|req| (req.message, req.peer, req.user_data))
+            .collect()
+    }
+
+    /// Generate a blake2 hash based on the peer's ip and the provided secret,
+    /// bound to the command and target of the request it is issued for.
+    fn token(
+        &self,
+        peer: &Peer,
+        secret: &[u8],
+        command: &str,
+        target: Option<&[u8]>,
+    ) -> Output<Blake2b> {
         let mut context = Blake2b::new();
         context.update(secret);
         context.update(peer.addr.ip().to_string().as_bytes());
+        // length prefixed, so the command can't run into the target
+        context.update((command.len() as u64).to_be_bytes());
+        context.update(command.as_bytes());
+        context.update(target.unwrap_or_default());
         context.finalize()
     }
 
+    /// The roundtrip token for a response to the `request`.
+    fn request_token(&self, peer: &Peer, request: &Message) -> Bytes {
+        Bytes::copy_from_slice(&self.token(
+            peer,
+            &self.secrets.0,
+            request.command.as_deref().unwrap_or_default(),
+            request.target.as_deref(),
+        ))
+    }
+
     fn holepunch(
         &mut self,
         mut msg: Message,
@@ -218,11 +532,102 @@ where
         })
     }
 
+    /// Asks the referrer of the `peer` to relay a holepunch to it, without
+    /// waiting for a response.
+    ///
+    /// The response of the peer only opens the NAT mapping, it doesn't match
+    /// any request.
+    fn punch(&mut self, peer: &Peer) {
+        if let Some(referrer) = peer.referrer {
+            let mut msg = Message {
+                version: Some(VERSION),
+                r#type: Type::Query.id(),
+                rid: self.next_req_id().0,
+                to: Some(referrer.encode()),
+                id: self.msg_id(),
+                target: None,
+                closer_nodes: None,
+                roundtrip_token: None,
+                command: Some(Command::Holepunch.to_string()),
+                error: None,
+                value: None,
+                closer_nodes6: None,
+                unknown_fields: Vec::new(),
+            };
+            msg.set_holepunch(&Holepunch::with_to(peer.addr.encode()));
+            self.punched += 1;
+            self.punches.put(msg.get_request_id(), peer.addr);
+            self.enqueue(MessageEvent::Untracked {
+                msg,
+                peer: Peer::from(referrer),
+            });
+        }
+    }
+
+    /// Number of holepunches that were relayed to peers with a referrer
+    /// because a request to them timed out, or before the first request if
+    /// punching first.
+    pub fn num_punched(&self) -> u64 {
+        self.punched
+    }
+
+    /// Whether a holepunch is relayed before the first request to a peer
+    /// with a referrer.
+    #[inline]
+    pub fn punch_first(&self) -> bool {
+        self.punch_first
+    }
+
+    #[inline]
+    pub fn set_punch_first(&mut self, punch_first: bool) {
+        self.punch_first = punch_first;
+    }
+
+    /// Sends the requests to the punched `addr` again right away, now that
+    /// its NAT lets our messages through.
+    fn resend_punched(&mut self, addr: SocketAddr) {
+        let now = time::now();
+        let max_retries = self.max_retries;
+        let resend = self
+            .pending_recv
+            .values_mut()
+            .filter(|req| req.peer.addr == addr && req.retries < max_retries)
+            .map(|req| {
+                req.retries += 1;
+                req.timestamp = now;
+                req.clone()
+            })
+            .collect::<Vec<_>>();
+        self.retried_requests += resend.len() as u64;
+        for event in resend.into_iter().filter_map(Request::into_event) {
+            self.enqueue(event);
+        }
+    }
+
     fn request(&mut self, mut ev: MessageEvent<TUserData>) {
+        let rid = self.next_req_id();
+        if let MessageEvent::Query { user_data, .. } | MessageEvent::Update { user_data, .. } = &ev
+        {
+            if (self.counts_in_flight)(user_data) {
+                self.in_flight.insert(rid);
+            }
+        }
         let (msg, peer) = ev.inner_mut();
-        msg.rid = self.next_req_id().0;
+        msg.rid = rid.0;
+        trace_event!(
+            rid = msg.rid,
+            peer = %peer.addr,
+            command = ?msg.command,
+            "Sending request"
+        );
 
-        if msg.is_holepunch() && peer.referrer.is_some() {
+        // the peer may be behind a NAT that drops unsolicited requests
+        let punch = (self.punch_first && !msg.is_holepunch())
+            .then(|| peer.clone())
+            .filter(|peer| peer.referrer.is_some());
+        if let Some(peer) = punch {
+            self.punch(&peer);
+        } else if msg.is_holepunch() && peer.referrer.is_some() {
             match &ev {
                 MessageEvent::Update {
                     msg,
@@ -242,15 +647,87 @@ where
                 _ => {}
             }
         }
+        self.enqueue(ev)
+    }
+
+    /// Queues the message to be sent once the socket is writable.
+    ///
+    /// If the queue is full, the oldest request is dropped to make room, the
+    /// remotes waiting for our responses come first. Only if there are nothing
+    /// but responses queued, the message itself is dropped if it is a request,
+    /// or the oldest response otherwise. A dropped request is sent again after
+    /// the request timeout, like a request that got lost on the way, unless it
+    /// is untracked.
+    fn enqueue(&mut self, ev: MessageEvent<TUserData>) {
+        if !self.has_send_capacity() {
+            self.dropped_messages += 1;
+            if let Some(pos) = self
+                .pending_send
+                .iter()
+                .position(|e| !matches!(e, MessageEvent::Response { .. }))
+            {
+                if let Some(dropped) = self.pending_send.remove(pos) {
+                    log::debug!(
+                        "Send queue full, dropping request to {}",
+                        dropped.inner().1.addr
+                    );
+                    self.track_unsent(dropped);
+                }
+            } else if !matches!(ev, MessageEvent::Response { .. }) {
+                log::debug!("Send queue full, dropping request to {}", ev.inner().1.addr);
+                self.track_unsent(ev);
+                return;
+            } else if let Some(dropped) = self.pending_send.pop_front() {
+                log::debug!(
+                    "Send queue full, dropping response to {}",
+                    dropped.inner().1.addr
+                );
+            }
+        }
         self.pending_send.push_back(ev)
     }
 
+    /// Whether another message fits into the send queue without dropping one.
+    pub fn has_send_capacity(&self) -> bool {
+        self.pending_send.len() < self.max_send_queue
+    }
+
+    /// Waits for a response to a request that was never sent, so that it is
+    /// retried after the timeout.
+    fn track_unsent(&mut self, ev: MessageEvent<TUserData>) {
+        if let MessageEvent::Query {
+            msg,
+            peer,
+            user_data,
+        }
+        | MessageEvent::Update {
+            msg,
+            peer,
+            user_data,
+        } = ev
+        {
+            self.pending_recv
+                .entry(msg.get_request_id())
+                .or_insert_with(|| {
+                    let now = time::now();
+                    Request {
+                        message: msg,
+                        peer,
+                        timestamp: now,
+                        first_sent: now,
+                        retries: 0,
+                        user_data,
+                    }
+                });
+        }
+    }
+
     /// Send a new Query message
     pub fn query(
         &mut self,
         cmd: Command,
         target: Option<IdBytes>,
-        value: Option<Vec<u8>>,
+        value: Option<Bytes>,
         peer: Peer,
         user_data: TUserData,
     ) {
@@ -260,12 +737,14 @@ where
             rid: 0,
             to: Some(peer.encode()),
             id: self.msg_id(),
-            target: target.map(|x| x.to_vec()),
+            target: target.map(|x| x.to_vec().into()),
             closer_nodes: None,
             roundtrip_token: None,
             command: Some(cmd.to_string()),
             error: None,
             value,
+            closer_nodes6: None,
+            unknown_fields: Vec::new(),
         };
 
         self.request(MessageEvent::Query {
@@ -279,10 +758,13 @@ where
         &mut self,
         request: Message,
         error: String,
-        value: Option<Vec<u8>>,
-        closer_nodes: Option<Vec<u8>>,
+        value: Option<Bytes>,
+        closer_nodes: Option<CloserNodes>,
         peer: Peer,
     ) {
+        let (closer_nodes, closer_nodes6) = closer_nodes
+            .map(|c| (Some(c.nodes.into()), c.nodes6.map(Bytes::from)))
+            .unwrap_or_default();
         let msg = Message {
             version: Some(VERSION),
             r#type: Type::Response.id(),
@@ -295,27 +777,36 @@ where
             command: None,
             error: Some(error),
             value,
+            closer_nodes6,
+            unknown_fields: Vec::new(),
         };
-        self.pending_send
-            .push_back(MessageEvent::Response { msg, peer })
+        self.enqueue(MessageEvent::Response { msg, peer })
     }
 
-    pub fn reply(&mut self, mut msg: Message, peer: Peer) {
+    /// Send the response to a query of `command` for `target`.
+    pub fn reply(&mut self, mut msg: Message, peer: Peer, command: &str, target: &IdBytes) {
         msg.id = self.msg_id();
         if msg.error.is_none() {
-            msg.roundtrip_token = Some(self.token(&peer, &self.secrets.0[..]).to_vec());
+            msg.roundtrip_token = Some(Bytes::copy_from_slice(&self.token(
+                &peer,
+                &self.secrets.0,
+                command,
+                Some(target.as_ref()),
+            )));
         }
-        self.pending_send
-            .push_back(MessageEvent::Response { msg, peer })
+        self.enqueue(MessageEvent::Response { msg, peer })
     }
 
     pub fn response(
         &mut self,
         request: Message,
-        value: Option<Vec<u8>>,
-        closer_nodes: Option<Vec<u8>>,
+        value: Option<Bytes>,
+        closer_nodes: Option<CloserNodes>,
         peer: Peer,
     ) {
+        let (closer_nodes, closer_nodes6) = closer_nodes
+            .map(|c| (Some(c.nodes.into()), c.nodes6.map(Bytes::from)))
+            .unwrap_or_default();
         let msg = Message {
             version: Some(VERSION),
             r#type: Type::Response.id(),
@@ -324,17 +815,18 @@ where
             id: self.msg_id(),
             target: None,
             closer_nodes,
-            roundtrip_token: Some(self.token(&peer, &self.secrets.0[..]).to_vec()),
+            roundtrip_token: Some(self.request_token(&peer, &request)),
             command: None,
             error: None,
             value,
+            closer_nodes6,
+            unknown_fields: Vec::new(),
         };
-        self.pending_send
-            .push_back(MessageEvent::Response { msg, peer })
+        self.enqueue(MessageEvent::Response { msg, peer })
     }
 
     pub fn send_message(&mut self, msg: MessageEvent<TUserData>) {
-        self.pending_send.push_back(msg)
+        self.enqueue(msg)
     }
 
     /// Send an update message
@@ -342,9 +834,9 @@ where
         &mut self,
         cmd: Command,
         target: Option<IdBytes>,
-        value: Option<Vec<u8>>,
+        value: Option<Bytes>,
         peer: Peer,
-        roundtrip_token: Option<Vec<u8>>,
+        roundtrip_token: Option<Bytes>,
         user_data: TUserData,
     ) {
         let msg = Message {
@@ -353,12 +845,14 @@ where
             rid: 0,
             to: Some(peer.encode()),
             id: self.msg_id(),
-            target: target.map(|x| x.to_vec()),
+            target: target.map(|x| x.to_vec().into()),
             closer_nodes: None,
             roundtrip_token,
             command: Some(cmd.to_string()),
             error: None,
             value,
+            closer_nodes6: None,
+            unknown_fields: Vec::new(),
         };
 
         self.request(MessageEvent::Update {
@@ -369,15 +863,117 @@ where
     }
 
     fn on_response(&mut self, recv: Message, peer: Peer) -> IoHandlerEvent<TUserData> {
-        if let Some(req) = self.pending_recv.remove(&recv.get_request_id()) {
-            return IoHandlerEvent::InResponse {
-                peer,
-                resp: recv,
-                req: Box::new(req.message),
-                user_data: req.user_data,
-            };
+        match self.pending_recv.entry(recv.get_request_id()) {
+            // only the peer the request was sent to can answer it
+            Entry::Occupied(entry) if entry.get().peer.addr == peer.addr => {
+                let req = entry.remove();
+                self.in_flight.remove(&recv.get_request_id());
+                self.answered.put(recv.get_request_id(), peer.addr);
+                let rtt = self.observe_rtt(&req, peer.addr);
+                IoHandlerEvent::InResponse {
+                    peer,
+                    resp: recv,
+                    req: Box::new(req.message),
+                    rtt,
+                    user_data: req.user_data,
+                }
+            }
+            _ => {
+                if self.punches.peek(&recv.get_request_id()) == Some(&peer.addr) {
+                    self.punches.pop(&recv.get_request_id());
+                    self.resend_punched(peer.addr);
+                    return IoHandlerEvent::InHolepunchResponse { peer };
+                }
+                if self.answered.peek(&recv.get_request_id()) == Some(&peer.addr) {
+                    self.duplicate_responses += 1;
+                } else {
+                    self.unmatched_responses += 1;
+                }
+                IoHandlerEvent::InResponseBadRequestId { peer, msg: recv }
+            }
+        }
+    }
+
+    /// Measures the round trip time of the answered request.
+    ///
+    /// A response to a request that was sent again could be the response to
+    /// any of the sends, so by Karn's rule it is not measured. Only for a node
+    /// without a round trip time yet the time since the first send is taken,
+    /// an upper bound, so that a too short `request_timeout` does not retry
+    /// every request to that node forever.
+    fn observe_rtt(&mut self, req: &Request<TUserData>, addr: SocketAddr) -> Option<Duration> {
+        let now = time::now();
+        if req.retries == 0 {
+            let rtt = now - req.timestamp;
+            self.rtt.observe(addr, rtt);
+            Some(rtt)
+        } else {
+            if !self.rtt.contains(&addr) {
+                self.rtt.observe(addr, now - req.first_sent);
+            }
+            None
+        }
+    }
+
+    /// How long to wait for a response to a request to `addr` that was sent
+    /// `retries` times before.
+    ///
+    /// With adaptive timeouts this is the timeout of the node, or of a typical
+    /// node if it is unknown, doubled with every retry.
+    fn request_timeout(&self, addr: &SocketAddr, retries: usize) -> Duration {
+        self.backoff(self.rtt.rto(addr), retries)
+    }
+
+    fn backoff(&self, rto: Option<Duration>, retries: usize) -> Duration {
+        if !self.adaptive_timeout {
+            return self.request_timeout;
         }
-        IoHandlerEvent::InResponseBadRequestId { peer, msg: recv }
+        let timeout = rto
+            .or_else(|| self.rtt.median_rto())
+            .map_or(self.request_timeout, |rto| {
+                rto.max(self.min_request_timeout)
+            });
+        timeout * (1 << retries.min(MAX_BACKOFF))
+    }
+
+    /// The timeout of a request to an unknown node, see
+    /// [`IoConfig::request_timeout`].
+    #[inline]
+    pub fn default_request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    /// How long a request to a typical node takes to time out, including all
+    /// of its retries.
+    pub fn request_deadline(&self) -> Duration {
+        (0..=self.max_retries)
+            .map(|retries| self.backoff(None, retries))
+            .sum()
+    }
+
+    /// Number of requests that were sent again after they timed out.
+    pub fn num_retried_requests(&self) -> u64 {
+        self.retried_requests
+    }
+
+    /// Whether the message has fields of invalid lengths.
+    ///
+    /// Requests with a value beyond the limit or an invalid target are not
+    /// considered malformed so that they can be answered with an error.
+    fn is_malformed(&self, msg: &Message) -> bool {
+        let invalid_key = |key: Option<&[u8]>| key.is_some_and(|k| k.len() != 32);
+        let invalid_nodes = |nodes: &Option<Bytes>, size: usize| {
+            nodes.as_ref().is_some_and(|n| n.len() % size != 0)
+        };
+        invalid_key(msg.id.as_deref())
+            || (msg.is_response() && invalid_key(msg.target.as_deref()))
+            || invalid_nodes(&msg.closer_nodes, 38)
+            || invalid_nodes(&msg.closer_nodes6, 50)
+            || (msg.is_response()
+                && msg
+                    .value
+                    .as_ref()
+                    .is_some_and(|v| v.len() > self.max_value_size))
     }
 
     /// A new `Message` was read from the socket.
@@ -386,17 +982,25 @@ where
         mut msg: Message,
         rinfo: SocketAddr,
     ) -> Option<IoHandlerEvent<TUserData>> {
-        if let Some(ref id) = msg.id {
-            if id.len() != 32 {
-                // TODO Receive Error? clear waiting?
-                return None;
-            }
-            // Force eph if older version
-            if msg.version.unwrap_or_default() < VERSION {
-                msg.id = None
-            }
+        self.traffic.messages_in += 1;
+        self.traffic.bytes_in += msg.encoded_len() as u64;
+        if self.is_malformed(&msg) {
+            log::debug!("Dropping malformed message from {}", rinfo);
+            self.malformed_messages += 1;
+            return None;
+        }
+        // Force eph if older version
+        if msg.id.is_some() && msg.version.unwrap_or_default() < VERSION {
+            msg.id = None
         }
         let peer = Peer::from(rinfo);
+        trace_event!(
+            rid = msg.rid,
+            peer = %rinfo,
+            ty = msg.r#type,
+            command = ?msg.command,
+            "Received message"
+        );
 
         match msg.get_type() {
             Ok(ty) => match ty {
@@ -407,20 +1011,26 @@ where
                     ty: Type::Query,
                 }),
                 Type::Update => {
-                    if let Some(ref rt) = msg.roundtrip_token {
-                        if rt.as_slice() != self.token(&peer, &self.secrets.0).deref()
-                            && rt.as_slice() != self.token(&peer, &self.secrets.1).deref()
-                        {
-                            None
-                        } else {
-                            Some(IoHandlerEvent::InRequest {
-                                peer,
-                                msg,
-                                ty: Type::Update,
-                            })
-                        }
+                    if msg
+                        .roundtrip_token
+                        .as_ref()
+                        .is_some_and(|rt| self.is_valid_update_token(&peer, rt, &msg))
+                    {
+                        Some(IoHandlerEvent::InRequest {
+                            peer,
+                            msg,
+                            ty: Type::Update,
+                        })
                     } else {
-                        None
+                        // the peer didn't query us recently
+                        self.error(
+                            msg.clone(),
+                            ERR_INVALID_TOKEN.to_string(),
+                            None,
+                            None,
+                            peer.clone(),
+                        );
+                        Some(IoHandlerEvent::InRequestInvalidToken { msg, peer })
                     }
                 }
             },
@@ -431,10 +1041,41 @@ where
         }
     }
 
+    /// Whether the `token` was issued to the `peer` with the current or the
+    /// previous secret, for a query of `command` for `target`.
+    fn is_valid_token(
+        &self,
+        peer: &Peer,
+        token: &[u8],
+        command: &str,
+        target: Option<&[u8]>,
+    ) -> bool {
+        token == self.token(peer, &self.secrets.0, command, target).deref()
+            || token == self.token(peer, &self.secrets.1, command, target).deref()
+    }
+
+    /// Whether the token of the `update` was issued for a query of the same
+    /// command and target, or for the `find_node` of its target that an
+    /// update starts with.
+    ///
+    /// A token that was issued for e.g. a ping can't be replayed to authorize
+    /// an update.
+    fn is_valid_update_token(&self, peer: &Peer, token: &[u8], update: &Message) -> bool {
+        let target = update.target.as_deref();
+        let command = update.command.as_deref().unwrap_or_default();
+        self.is_valid_token(peer, token, command, target)
+            || (target.is_some()
+                && self.is_valid_token(peer, token, &Command::FindNode.to_string(), target))
+    }
+
+    /// Replaces the current secret with a new one, the current secret remains
+    /// valid until the next rotation.
     #[inline]
     fn rotate_secrets(&mut self) {
-        std::mem::swap(&mut self.secrets.0, &mut self.secrets.1);
-        self.last_rotation = Instant::now()
+        let mut secret = [0; 32];
+        fill_random_bytes(&mut secret);
+        self.secrets.1 = std::mem::replace(&mut self.secrets.0, secret);
+        self.last_rotation = time::now()
     }
 
     /// Remove the matching request from the sending queue or stop waiting for a
@@ -444,6 +1085,9 @@ where
         rid: RequestId,
         _error: Option<String>,
     ) -> Option<MessageEvent<TUserData>> {
+        // a request that is sent again is queued and pending at the same time
+        let pending = self.pending_recv.remove(&rid).and_then(Request::into_event);
+        self.in_flight.remove(&rid);
         if let Some(s) = self
             .pending_send
             .iter()
@@ -451,23 +1095,206 @@ where
         {
             self.pending_send.remove(s)
         } else {
-            self.pending_recv
-                .remove(&rid)
-                .and_then(|req| req.into_event().ok())
+            pending
+        }
+    }
+
+    /// Stops all outgoing requests for which `f` returns `true`, whether they
+    /// are still queued or already wait for a response.
+    ///
+    /// Responses that arrive later for any of them are dropped as unmatched.
+    /// Returns the number of cancelled requests.
+    pub fn cancel_requests<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&Message, &TUserData) -> bool,
+    {
+        let before = self.pending_recv.len() + self.pending_send.len();
+        let mut cancelled = Vec::new();
+        self.pending_recv.retain(|id, req| {
+            let cancel = f(&req.message, &req.user_data);
+            if cancel {
+                cancelled.push(*id);
+            }
+            !cancel
+        });
+        self.pending_send.retain(|ev| match ev {
+            MessageEvent::Query { msg, user_data, .. }
+            | MessageEvent::Update { msg, user_data, .. } => {
+                let cancel = f(msg, user_data);
+                if cancel {
+                    cancelled.push(msg.get_request_id());
+                }
+                !cancel
+            }
+            MessageEvent::Response { .. } | MessageEvent::Untracked { .. } => true,
+        });
+        for id in cancelled {
+            self.in_flight.remove(&id);
+        }
+        before - (self.pending_recv.len() + self.pending_send.len())
+    }
+
+    /// Sends requests that didn't receive a response in time again, or
+    /// reports them as timed out once they ran out of retries.
+    fn poll_timeouts(&mut self, cx: &mut Context<'_>) -> Option<IoHandlerEvent<TUserData>> {
+        let now = time::now();
+        let expired = self
+            .pending_recv
+            .iter()
+            .filter(|(_, req)| {
+                req.timestamp + self.request_timeout(&req.peer.addr, req.retries) <= now
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            if let Some(req) = self.pending_recv.get_mut(&id) {
+                if req.retries < self.max_retries {
+                    self.retried_requests += 1;
+                    // the peer may be behind a NAT that drops our requests,
+                    // punch a hole via the node that told us about it first
+                    let punch =
+                        (!self.punch_first && req.retries == 0 && !req.message.is_holepunch())
+                            .then(|| req.peer.clone())
+                            .filter(|peer| peer.referrer.is_some());
+                    req.retries += 1;
+                    req.timestamp = now;
+                    trace_event!(
+                        rid = id.0,
+                        peer = %req.peer.addr,
+                        retries = req.retries,
+                        "Retrying request"
+                    );
+                    let event = req.clone().into_event();
+                    if let Some(peer) = punch {
+                        self.punch(&peer);
+                    }
+                    if let Some(event) = event {
+                        self.enqueue(event);
+                    }
+                } else if let Some(req) = self.pending_recv.remove(&id) {
+                    self.in_flight.remove(&id);
+                    trace_event!(rid = id.0, peer = %req.peer.addr, "Request timed out");
+                    return Some(IoHandlerEvent::RequestTimeout {
+                        msg: req.message,
+                        peer: req.peer,
+                        sent: req.timestamp,
+                        user_data: req.user_data,
+                    });
+                }
+            }
+        }
+
+        let deadline = if let Some(deadline) = self
+            .pending_recv
+            .values()
+            .map(|req| req.timestamp + self.request_timeout(&req.peer.addr, req.retries))
+            .min()
+        {
+            deadline
+        } else {
+            self.timeout_timer = None;
+            return None;
+        };
+        match &mut self.timeout_timer {
+            Some((at, _)) if *at == deadline => {}
+            timer => *timer = Some((deadline, Delay::new_at(deadline))),
+        }
+        if let Some((_, delay)) = &mut self.timeout_timer {
+            if Future::poll(Pin::new(delay), cx).is_ready() {
+                self.timeout_timer = None;
+                cx.waker().wake_by_ref();
+            }
         }
+        None
     }
 
-    fn start_send_next(&mut self) -> io::Result<()> {
+    /// Fails the message that couldn't be sent. A request is no longer
+    /// waited for, so that its query moves on right away.
+    fn on_send_error(
+        &mut self,
+        event: MessageEvent<TUserData>,
+        err: io::Error,
+    ) -> IoHandlerEvent<TUserData> {
+        match event {
+            MessageEvent::Query {
+                msg,
+                peer,
+                user_data,
+            }
+            | MessageEvent::Update {
+                msg,
+                peer,
+                user_data,
+            } => {
+                let id = msg.get_request_id();
+                if matches!(self.pending_recv.get(&id), Some(req) if req.peer.addr == peer.addr) {
+                    self.pending_recv.remove(&id);
+                }
+                self.settle(id);
+                IoHandlerEvent::OutRequestErr {
+                    msg,
+                    peer,
+                    user_data,
+                    err,
+                }
+            }
+            MessageEvent::Response { .. } | MessageEvent::Untracked { .. } => {
+                IoHandlerEvent::OutSocketErr { err }
+            }
+        }
+    }
+
+    fn start_send_next(&mut self) -> Option<IoHandlerEvent<TUserData>> {
         if self.pending_flush.is_none() {
             if let Some(event) = self.pending_send.pop_front() {
                 let (msg, peer) = event.inner();
-                let mut buf = Vec::with_capacity(msg.encoded_len());
-                msg.encode(&mut buf)?;
-                Sink::start_send(Pin::new(&mut self.socket), (buf, peer.addr))?;
+                let buf = msg.encode_to_vec(self.is_ephemeral());
+                self.traffic.messages_out += 1;
+                self.traffic.bytes_out += buf.len() as u64;
+                let addr = peer.addr;
+                if let Err(err) = Sink::start_send(Pin::new(&mut *self.socket), (buf, addr)) {
+                    return Some(self.on_send_error(event, err));
+                }
+                // wait for the response right away, it may arrive before the
+                // socket is ready for the next message
+                if let MessageEvent::Query {
+                    msg,
+                    peer,
+                    user_data,
+                }
+                | MessageEvent::Update {
+                    msg,
+                    peer,
+                    user_data,
+                } = &event
+                {
+                    let id = msg.get_request_id();
+                    let now = time::now();
+                    match self.pending_recv.get_mut(&id) {
+                        // the request was sent again
+                        Some(req) if req.peer.addr == peer.addr => req.timestamp = now,
+                        // a holepunch shares the id of the request it
+                        // precedes, which is the one that gets answered
+                        _ => {
+                            self.pending_recv.insert(
+                                id,
+                                Request {
+                                    message: msg.clone(),
+                                    peer: peer.clone(),
+                                    timestamp: now,
+                                    first_sent: now,
+                                    retries: 0,
+                                    user_data: user_data.clone(),
+                                },
+                            );
+                        }
+                    }
+                }
                 self.pending_flush = Some(event);
             }
         }
-        Ok(())
+        None
     }
 }
 
@@ -477,67 +1304,106 @@ impl<TUserData: Unpin + fmt::Debug + Clone> Stream for IoHandler<TUserData> {
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let pin = self.get_mut();
 
-        // queue in the next message if not currently flushing
-        if let Err(err) = pin.start_send_next() {
-            return Poll::Ready(Some(IoHandlerEvent::OutSocketErr { err }));
+        if pin.last_rotation + pin.rotation <= time::now() {
+            pin.rotate_secrets();
         }
 
-        // flush the message
-        if let Some(ev) = pin.pending_flush.take() {
-            if Sink::poll_ready(Pin::new(&mut pin.socket), cx).is_ready() {
-                return match ev {
-                    MessageEvent::Update {
-                        msg,
-                        peer,
-                        user_data,
-                    }
-                    | MessageEvent::Query {
-                        msg,
-                        peer,
-                        user_data,
-                    } => {
-                        let id = msg.get_request_id();
-                        pin.pending_recv.insert(
-                            id,
-                            Request {
-                                message: msg,
-                                peer,
-                                timestamp: Instant::now(),
-                                user_data,
-                            },
-                        );
-                        return Poll::Ready(Some(IoHandlerEvent::OutRequest { id }));
+        loop {
+            // queue in the next message if not currently flushing
+            if let Some(event) = pin.start_send_next() {
+                return Poll::Ready(Some(event));
+            }
+
+            // flush the message
+            if let Some(ev) = pin.pending_flush.take() {
+                if let Poll::Ready(res) = Sink::poll_ready(Pin::new(&mut *pin.socket), cx) {
+                    if let Err(err) = res {
+                        return Poll::Ready(Some(pin.on_send_error(ev, err)));
                     }
-                    MessageEvent::Response { msg, peer } => {
-                        Poll::Ready(Some(IoHandlerEvent::OutResponse { msg, peer }))
+                    match ev {
+                        MessageEvent::Update { msg, .. } | MessageEvent::Query { msg, .. } => {
+                            return Poll::Ready(Some(IoHandlerEvent::OutRequest {
+                                id: msg.get_request_id(),
+                            }));
+                        }
+                        MessageEvent::Response { msg, peer } => {
+                            return Poll::Ready(Some(IoHandlerEvent::OutResponse { msg, peer }));
+                        }
+                        // nothing to report, go on with the next message
+                        MessageEvent::Untracked { .. } => continue,
                     }
-                };
-            } else {
-                pin.pending_flush = Some(ev);
+                } else {
+                    pin.pending_flush = Some(ev);
+                }
             }
+            break;
         }
 
-        // read from socket
-        match Stream::poll_next(Pin::new(&mut pin.socket), cx) {
-            Poll::Ready(Some(Ok((msg, rinfo)))) => {
-                if let Some(event) = pin.on_message(msg, rinfo) {
-                    return Poll::Ready(Some(event));
+        // read from socket until it would block, so that dropped packets
+        // don't leave the socket without a registered waker
+        loop {
+            match Stream::poll_next(Pin::new(&mut *pin.socket), cx) {
+                Poll::Ready(Some(Ok((msg, rinfo)))) => {
+                    if let Some(event) = pin.on_message(msg, rinfo) {
+                        return Poll::Ready(Some(event));
+                    }
                 }
+                Poll::Ready(Some(Err(err))) if err.kind() == io::ErrorKind::InvalidData => {
+                    // the packet didn't decode
+                    trace_event!(error = %err, "Dropping undecodable message");
+                    pin.traffic.messages_in += 1;
+                    pin.malformed_messages += 1;
+                }
+                Poll::Ready(Some(Err(err))) if is_transient(&err) => {
+                    // e.g. an ICMP error of an earlier send, the socket is fine
+                    trace_event!(error = %err, "Ignoring transient socket error");
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    return Poll::Ready(Some(IoHandlerEvent::InSocketErr { err }));
+                }
+                _ => break,
             }
-            Poll::Ready(Some(Err(err))) => {
-                return Poll::Ready(Some(IoHandlerEvent::InSocketErr { err }));
-            }
-            _ => {}
         }
 
-        if pin.last_rotation + pin.rotation > Instant::now() {
-            pin.rotate_secrets();
+        if let Some(event) = pin.poll_timeouts(cx) {
+            return Poll::Ready(Some(event));
         }
 
         Poll::Pending
     }
 }
 
+/// Whether the socket error is caused by a single destination or a passing
+/// condition of the network, rather than by the socket itself.
+///
+/// After such an error the socket keeps working, e.g. a host being
+/// unreachable while an interface flaps, or the ICMP error of an earlier
+/// datagram that some platforms report on the next receive. The address of
+/// the socket no longer being available is not transient, the socket has to
+/// be bound again.
+pub fn is_transient(err: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    matches!(
+        err.kind(),
+        ConnectionRefused
+            | ConnectionReset
+            | ConnectionAborted
+            | NetworkUnreachable
+            | HostUnreachable
+            | NetworkDown
+            | TimedOut
+            | Interrupted
+            | WouldBlock
+    )
+}
+
+/// Like [`is_transient`] for an error of a send, which also fails with
+/// [`io::ErrorKind::PermissionDenied`] if a local firewall rule rejects the
+/// destination.
+pub fn is_transient_send(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::PermissionDenied || is_transient(err)
+}
+
 /// Event generated by the IO handler
 #[derive(Debug)]
 pub enum IoHandlerEvent<TUserData> {
@@ -550,12 +1416,24 @@ pub enum IoHandlerEvent<TUserData> {
         req: Box<Message>,
         resp: Message,
         peer: Peer,
+        /// The round trip time of the request, if it was sent only once.
+        rtt: Option<Duration>,
         user_data: TUserData,
     },
     /// Response successfully read from socket.
     InRequest { msg: Message, peer: Peer, ty: Type },
-    /// Error while start sending.
+    /// Received an update without a valid roundtrip token, which was already
+    /// answered with an error.
+    InRequestInvalidToken { msg: Message, peer: Peer },
+    /// Failed to send a response or an untracked request.
     OutSocketErr { err: io::Error },
+    /// Failed to send a request, it is no longer waited for.
+    OutRequestErr {
+        msg: Message,
+        peer: Peer,
+        user_data: TUserData,
+        err: io::Error,
+    },
     /// Failed to get a response for this request
     RequestTimeout {
         msg: Message,
@@ -565,9 +1443,726 @@ pub enum IoHandlerEvent<TUserData> {
     },
     /// Error while decoding from socket
     InMessageErr { err: io::Error, peer: Peer },
-    /// Error while reading from socket
+    /// Error while reading from socket that is not [transient](is_transient).
     InSocketErr { err: io::Error },
     /// Received a response with a request id that was doesn't match any pending
     /// responses.
     InResponseBadRequestId { msg: Message, peer: Peer },
+    /// The target of a relayed holepunch responded, the requests to it were
+    /// sent again.
+    InHolepunchResponse { peer: Peer },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::StreamExt;
+
+    use crate::testing::harness::{drive, next_event};
+    use crate::testing::{timeout, Faults, Faulty, Network, Simulation};
+
+    fn io_handler<T: fmt::Debug + Clone>(network: &Network) -> IoHandler<T> {
+        IoHandler::with_transport(None, Box::new(network.bind()), IoConfig::default())
+    }
+
+    fn retrying_io_handler(network: &Network, max_retries: usize) -> IoHandler<()> {
+        let config = IoConfig {
+            request_timeout: Some(Duration::from_millis(20)),
+            max_retries: Some(max_retries),
+            ..Default::default()
+        };
+        IoHandler::with_transport(None, Box::new(network.bind()), config)
+    }
+
+    async fn expect_request(io: &mut IoHandler<()>) -> (Message, Peer) {
+        match io.next().await {
+            Some(IoHandlerEvent::InRequest { msg, peer, .. }) => (msg, peer),
+            ev => panic!("Unexpected event {:?}", ev),
+        }
+    }
+
+    async fn expect_sent(io: &mut IoHandler<()>) -> RequestId {
+        match io.next().await {
+            Some(IoHandlerEvent::OutRequest { id }) => id,
+            ev => panic!("Unexpected event {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn match_responses() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(60);
+            let mut a = io_handler::<u32>(&network);
+            let mut b = io_handler::<()>(&network);
+            let mut c = io_handler::<()>(&network);
+            let peer = Peer::from(b.local_addr()?);
+
+            // two requests for different queries
+            a.query(Command::Ping, None, None, peer.clone(), 1);
+            a.query(Command::Ping, None, None, peer, 2);
+            a.next().await;
+            a.next().await;
+            let (first, from) = expect_request(&mut b).await;
+            let (second, _) = expect_request(&mut b).await;
+
+            // a response with the right id from the wrong peer is not accepted
+            c.response(second.clone(), None, None, from.clone());
+            c.next().await;
+            match a.next().await {
+                Some(IoHandlerEvent::InResponseBadRequestId { msg, .. }) => {
+                    assert_eq!(msg.rid, second.rid)
+                }
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+
+            // answer in reverse order
+            b.response(second, None, None, from.clone());
+            b.response(first.clone(), None, None, from.clone());
+            b.next().await;
+            b.next().await;
+            for query in [2, 1] {
+                match a.next().await {
+                    Some(IoHandlerEvent::InResponse {
+                        req,
+                        resp,
+                        user_data,
+                        ..
+                    }) => {
+                        assert_eq!(user_data, query);
+                        assert_eq!(req.rid, resp.rid);
+                    }
+                    ev => panic!("Unexpected event {:?}", ev),
+                }
+            }
+
+            // a duplicate response is dropped
+            b.response(first, None, None, from);
+            b.next().await;
+            assert!(matches!(
+                a.next().await,
+                Some(IoHandlerEvent::InResponseBadRequestId { .. })
+            ));
+            assert_eq!(a.num_unmatched_responses(), 1);
+            assert_eq!(a.num_duplicate_responses(), 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn request_id_wrap_around() -> Result<(), Box<dyn std::error::Error>> {
+        let network = Network::new(61);
+        let mut io = io_handler::<()>(&network);
+        io.next_req_id = RequestId(u64::MAX);
+        let pending = Request {
+            message: update(None),
+            peer: Peer::from(([127, 0, 0, 1], 1000)),
+            timestamp: time::now(),
+            first_sent: time::now(),
+            retries: 0,
+            user_data: (),
+        };
+        io.pending_recv.insert(RequestId(0), pending);
+
+        assert_eq!(io.next_req_id(), RequestId(u64::MAX));
+        // skips the id of the request still waiting for a response
+        assert_eq!(io.next_req_id(), RequestId(1));
+        assert_eq!(io.next_req_id(), RequestId(2));
+        Ok(())
+    }
+
+    #[test]
+    fn retry_lost_requests() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(62);
+            let mut a = retrying_io_handler(&network, 3);
+            let mut b = io_handler(&network);
+            a.query(Command::Ping, None, None, Peer::from(b.local_addr()?), ());
+
+            // the first two requests get lost
+            let rid = expect_sent(&mut a).await;
+            for _ in 0..2 {
+                let (msg, _) = expect_request(&mut b).await;
+                assert_eq!(msg.get_request_id(), rid);
+                assert_eq!(expect_sent(&mut a).await, rid);
+            }
+
+            let (msg, peer) = expect_request(&mut b).await;
+            b.response(msg, None, None, peer);
+            assert!(matches!(
+                b.next().await,
+                Some(IoHandlerEvent::OutResponse { .. })
+            ));
+
+            match a.next().await {
+                Some(IoHandlerEvent::InResponse { resp, req, .. }) => {
+                    assert_eq!(resp.get_request_id(), rid);
+                    assert_eq!(req.get_request_id(), rid);
+                }
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+            assert!(a.pending_recv.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn late_response_cancels_retries() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(63);
+            let mut a = retrying_io_handler(&network, 3);
+            let mut b = io_handler(&network);
+            a.query(Command::Ping, None, None, Peer::from(b.local_addr()?), ());
+
+            // the response to the first request arrives after it was retried
+            let rid = expect_sent(&mut a).await;
+            let (first, peer) = expect_request(&mut b).await;
+            assert_eq!(expect_sent(&mut a).await, rid);
+            b.response(first, None, None, peer.clone());
+            b.next().await;
+            match a.next().await {
+                Some(IoHandlerEvent::InResponse { resp, .. }) => {
+                    assert_eq!(resp.get_request_id(), rid)
+                }
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+            assert!(a.pending_recv.is_empty());
+            assert_eq!(a.num_retried_requests(), 1);
+
+            // the response to the retry is no second success
+            let (retry, _) = expect_request(&mut b).await;
+            assert_eq!(retry.get_request_id(), rid);
+            b.response(retry, None, None, peer);
+            b.next().await;
+            assert!(matches!(
+                a.next().await,
+                Some(IoHandlerEvent::InResponseBadRequestId { .. })
+            ));
+            assert_eq!(a.num_duplicate_responses(), 1);
+            // and no further retries are sent
+            let next = timeout(Duration::from_millis(100), a.next()).await;
+            assert!(next.is_err());
+            assert_eq!(a.num_retried_requests(), 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn timeout_after_retries() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(64);
+            let mut a = retrying_io_handler(&network, 2);
+            let mut b = io_handler(&network);
+            let addr = b.local_addr()?;
+            a.query(Command::Ping, None, None, Peer::from(addr), ());
+
+            let rid = expect_sent(&mut a).await;
+            assert_eq!(expect_sent(&mut a).await, rid);
+            assert_eq!(expect_sent(&mut a).await, rid);
+            match a.next().await {
+                Some(IoHandlerEvent::RequestTimeout { msg, peer, .. }) => {
+                    assert_eq!(msg.get_request_id(), rid);
+                    assert_eq!(peer.addr, addr);
+                }
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+            assert!(a.pending_recv.is_empty());
+            assert!(a.pending_send.is_empty());
+
+            // the request was sent once and retried twice
+            for _ in 0..3 {
+                let (msg, _) = expect_request(&mut b).await;
+                assert_eq!(msg.get_request_id(), rid);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn punch_via_referrer_on_timeout() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(65);
+            let mut a = retrying_io_handler(&network, 2);
+            let mut referrer = io_handler(&network);
+            let addr: SocketAddr = ([127, 0, 0, 1], 9).into();
+            let peer = Peer::new(addr, Some(referrer.local_addr()?));
+            a.query(Command::Ping, None, None, peer, ());
+
+            loop {
+                if let Some(IoHandlerEvent::RequestTimeout { .. }) = a.next().await {
+                    break;
+                }
+            }
+            // punched once, with the first retry
+            assert_eq!(a.num_punched(), 1);
+            let (msg, _) = expect_request(&mut referrer).await;
+            assert!(msg.is_holepunch());
+            let punch = msg.decode_holepunch().unwrap();
+            assert_eq!(punch.decode_to_peer(), Some(addr));
+            assert!(a.pending_recv.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn punch_first_and_resend() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(66);
+            let config = IoConfig {
+                punch_first: Some(true),
+                ..Default::default()
+            };
+            let mut a: IoHandler<()> =
+                IoHandler::with_transport(None, Box::new(network.bind()), config);
+            let mut referrer = io_handler(&network);
+            let mut b = io_handler(&network);
+            let peer = Peer::new(b.local_addr()?, Some(referrer.local_addr()?));
+            a.query(Command::Ping, None, None, peer, ());
+            // nothing waits for a response to the holepunch itself
+            assert!(matches!(
+                a.pending_send.front(),
+                Some(MessageEvent::Untracked { msg, .. }) if msg.is_holepunch()
+            ));
+            let (punch, _) = drive(&mut a, expect_request(&mut referrer)).await;
+            assert!(punch.is_holepunch());
+            let (req, _) = drive(&mut a, expect_request(&mut b)).await;
+            assert!(req.is_ping());
+            assert_eq!(a.num_punched(), 1);
+
+            // the target answers the relayed holepunch, the request follows
+            b.response(punch, None, None, Peer::from(a.local_addr()?));
+            let punched = next_event(&mut a, |event| match event {
+                IoHandlerEvent::InHolepunchResponse { peer } => Some(peer),
+                _ => None,
+            });
+            let peer = drive(&mut b, punched).await;
+            assert_eq!(peer.addr, b.local_addr()?);
+            let (retry, _) = drive(&mut a, expect_request(&mut b)).await;
+            assert_eq!(retry.get_request_id(), req.get_request_id());
+            assert_eq!(a.num_retried_requests(), 1);
+            assert_eq!(a.num_unmatched_responses(), 0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn send_queue_drops_requests_first() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(67);
+            let config = IoConfig {
+                request_timeout: Some(Duration::from_millis(50)),
+                max_send_queue: Some(4),
+                ..Default::default()
+            };
+            let mut a = IoHandler::<()>::with_transport(None, Box::new(network.bind()), config);
+            let b = io_handler::<()>(&network);
+            let peer = Peer::from(b.local_addr()?);
+            let request = |rid| Message {
+                rid,
+                ..update(None)
+            };
+
+            // nothing is sent until `a` is polled
+            a.query(Command::Ping, None, None, peer.clone(), ());
+            a.query(Command::Ping, None, None, peer.clone(), ());
+            a.response(request(0), None, None, peer.clone());
+            a.response(request(1), None, None, peer.clone());
+            assert_eq!(a.send_queue_len(), 4);
+            let requests = a
+                .pending_send
+                .iter()
+                .take(2)
+                .map(|ev| ev.inner().0.get_request_id())
+                .collect::<Vec<_>>();
+
+            // responses push out the requests
+            a.response(request(2), None, None, peer.clone());
+            a.response(request(3), None, None, peer.clone());
+            assert_eq!(a.num_dropped_messages(), 2);
+            // a request doesn't replace a response
+            a.query(Command::Ping, None, None, peer.clone(), ());
+            assert_eq!(a.num_dropped_messages(), 3);
+            // but a response the oldest response
+            a.response(request(4), None, None, peer.clone());
+            assert_eq!(a.num_dropped_messages(), 4);
+            assert_eq!(a.send_queue_len(), 4);
+
+            // the queue is flushed in order
+            for rid in 1..=4 {
+                match a.next().await {
+                    Some(IoHandlerEvent::OutResponse { msg, .. }) => assert_eq!(msg.rid, rid),
+                    ev => panic!("Unexpected event {:?}", ev),
+                }
+            }
+            assert_eq!(a.send_queue_len(), 0);
+
+            // the dropped requests are sent after the timeout
+            let mut retried = Vec::new();
+            for _ in 0..3 {
+                retried.push(expect_sent(&mut a).await);
+            }
+            assert!(requests.iter().all(|rid| retried.contains(rid)));
+            Ok(())
+        })
+    }
+
+    fn update(token: Option<Vec<u8>>) -> Message {
+        Message {
+            version: Some(VERSION),
+            r#type: Type::Update.id(),
+            rid: 1,
+            to: None,
+            id: None,
+            target: Some(IdBytes::random().to_vec().into()),
+            closer_nodes: None,
+            roundtrip_token: token.map(Bytes::from),
+            command: Some("test".to_string()),
+            error: None,
+            value: None,
+            closer_nodes6: None,
+            unknown_fields: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn token_rotation() -> Result<(), Box<dyn std::error::Error>> {
+        let network = Network::new(68);
+        let mut io: IoHandler<()> = io_handler(&network);
+        let peer = Peer::from(([127, 0, 0, 1], 1000));
+        let other = Peer::from(([127, 0, 0, 2], 1000));
+
+        let token = io.token(&peer, &io.secrets.0, "peers", None).to_vec();
+        assert!(io.is_valid_token(&peer, &token, "peers", None));
+        assert!(!io.is_valid_token(&other, &token, "peers", None));
+        assert!(!io.is_valid_token(&peer, &[0; 64], "peers", None));
+
+        // still valid during the next rotation window
+        io.rotate_secrets();
+        assert!(io.is_valid_token(&peer, &token, "peers", None));
+        assert_ne!(
+            io.token(&peer, &io.secrets.0, "peers", None).to_vec(),
+            token
+        );
+
+        io.rotate_secrets();
+        assert!(!io.is_valid_token(&peer, &token, "peers", None));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_token() -> Result<(), Box<dyn std::error::Error>> {
+        let network = Network::new(69);
+        let mut io: IoHandler<()> = io_handler(&network);
+        let peer = Peer::from(([127, 0, 0, 1], 1000));
+        let msg = update(None);
+        let token = io.token(&peer, &io.secrets.0, "test", msg.target.as_deref());
+        let msg = Message {
+            roundtrip_token: Some(Bytes::copy_from_slice(&token)),
+            ..msg
+        };
+
+        let event = io.on_message(msg, peer.addr);
+        assert!(matches!(
+            event,
+            Some(IoHandlerEvent::InRequest {
+                ty: Type::Update,
+                ..
+            })
+        ));
+        assert!(io.pending_send.is_empty());
+
+        for token in [None, Some(vec![1; 64])] {
+            assert!(matches!(
+                io.on_message(update(token), peer.addr),
+                Some(IoHandlerEvent::InRequestInvalidToken { .. })
+            ));
+            match io.pending_send.pop_front() {
+                Some(MessageEvent::Response { msg, peer: to }) => {
+                    assert_eq!(to, peer);
+                    assert_eq!(msg.rid, 1);
+                    assert_eq!(msg.error.as_deref(), Some(ERR_INVALID_TOKEN));
+                }
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+        }
+        Ok(())
+    }
+
+    /// The roundtrip token `io` sends in its response to the `request`.
+    fn issued_token(io: &mut IoHandler<()>, request: Message, peer: &Peer) -> Bytes {
+        io.response(request, None, None, peer.clone());
+        match io.pending_send.pop_back() {
+            Some(MessageEvent::Response { msg, .. }) => msg.roundtrip_token.unwrap(),
+            ev => panic!("Unexpected event {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn reject_replayed_token() -> Result<(), Box<dyn std::error::Error>> {
+        let network = Network::new(70);
+        let mut io: IoHandler<()> = io_handler(&network);
+        let peer = Peer::from(([127, 0, 0, 1], 1000));
+        let announce = update(None);
+        let query = |command: Command, target: Option<Bytes>| Message {
+            r#type: Type::Query.id(),
+            command: Some(command.to_string()),
+            target,
+            ..update(None)
+        };
+        let accepted = |io: &mut IoHandler<()>, token: Bytes, update: &Message| {
+            let msg = Message {
+                roundtrip_token: Some(token),
+                ..update.clone()
+            };
+            let event = io.on_message(msg, peer.addr);
+            io.pending_send.clear();
+            matches!(event, Some(IoHandlerEvent::InRequest { .. }))
+        };
+
+        // a token of a ping can't authorize an update
+        let ping = issued_token(&mut io, query(Command::Ping, None), &peer);
+        assert!(!accepted(&mut io, ping, &announce));
+
+        // neither can a token of the same command for another target
+        let other = query(
+            Command::from("test"),
+            Some(IdBytes::random().to_vec().into()),
+        );
+        let other = issued_token(&mut io, other, &peer);
+        assert!(!accepted(&mut io, other, &announce));
+
+        // the query of the same command and target, or the lookup of the
+        // target that updates start with
+        for command in [Command::from("test"), Command::FindNode] {
+            let req = query(command, announce.target.clone());
+            let token = issued_token(&mut io, req, &peer);
+            assert!(accepted(&mut io, token, &announce));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_expired_and_foreign_token() -> Result<(), Box<dyn std::error::Error>> {
+        let network = Network::new(71);
+        let mut io: IoHandler<()> = io_handler(&network);
+        let peer = Peer::from(([127, 0, 0, 1], 1000));
+        let other = Peer::from(([127, 0, 0, 2], 1000));
+        let announce = update(None);
+        let lookup = Message {
+            r#type: Type::Query.id(),
+            ..announce.clone()
+        };
+        let accepted = |io: &mut IoHandler<()>, token: &Bytes, from: &Peer| {
+            let msg = Message {
+                roundtrip_token: Some(token.clone()),
+                ..announce.clone()
+            };
+            let event = io.on_message(msg, from.addr);
+            io.pending_send.clear();
+            matches!(event, Some(IoHandlerEvent::InRequest { .. }))
+        };
+
+        let token = issued_token(&mut io, lookup, &peer);
+        assert!(accepted(&mut io, &token, &peer));
+        // issued for another address
+        assert!(!accepted(&mut io, &token, &other));
+
+        io.rotate_secrets();
+        assert!(accepted(&mut io, &token, &peer));
+        // two rotations old
+        io.rotate_secrets();
+        assert!(!accepted(&mut io, &token, &peer));
+        Ok(())
+    }
+
+    /// Checks that the message can be inspected without panicking.
+    fn inspect(msg: &Message) {
+        let _ = msg.decode_closer_nodes();
+        let _ = msg.decode_to_peer();
+        let _ = msg.decode_holepunch();
+        let _ = msg.valid_id_bytes();
+        let _ = msg.valid_target_id_bytes();
+    }
+
+    fn decode(buf: &[u8]) -> Option<Message> {
+        use futures_codec::Decoder;
+        DhtRpcCodec::default()
+            .decode(&mut bytes::BytesMut::from(buf))
+            .ok()
+            .flatten()
+    }
+
+    /// An io handler over a transport that fails as injected.
+    fn faulty_io_handler(network: &Network) -> (IoHandler<()>, Faults) {
+        let (socket, faults) = Faulty::new(network.bind());
+        let io = IoHandler::with_transport(None, Box::new(socket), IoConfig::default());
+        (io, faults)
+    }
+
+    #[test]
+    fn fail_request_on_send_error() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(72);
+            let (mut a, faults) = faulty_io_handler(&network);
+            let mut b = io_handler::<()>(&network);
+            let unreachable: SocketAddr = ([127, 0, 0, 1], 1).into();
+            faults.fail_send_to(unreachable, Some(io::ErrorKind::HostUnreachable));
+
+            a.query(Command::Ping, None, None, Peer::from(unreachable), ());
+            a.query(Command::Ping, None, None, Peer::from(b.local_addr()?), ());
+            match a.next().await {
+                Some(IoHandlerEvent::OutRequestErr { peer, err, .. }) => {
+                    assert_eq!(peer.addr, unreachable);
+                    assert!(is_transient(&err));
+                }
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+            // the requests to other peers are not affected
+            expect_sent(&mut a).await;
+            assert_eq!(a.requests_in_flight(), 1);
+            let (_, from) = expect_request(&mut b).await;
+            assert_eq!(from.addr, a.local_addr()?);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn skip_transient_recv_errors() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(73);
+            let (mut a, faults) = faulty_io_handler(&network);
+            let mut b = io_handler::<()>(&network);
+            faults.fail_recv(io::ErrorKind::ConnectionRefused);
+            faults.fail_recv(io::ErrorKind::ConnectionReset);
+
+            b.query(Command::Ping, None, None, Peer::from(a.local_addr()?), ());
+            expect_sent(&mut b).await;
+            expect_request(&mut a).await;
+
+            faults.fail_recv(io::ErrorKind::Other);
+            match a.next().await {
+                Some(IoHandlerEvent::InSocketErr { err }) => assert!(!is_transient(&err)),
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn drop_malformed_messages() -> Result<(), Box<dyn std::error::Error>> {
+        let network = Network::new(74);
+        let mut io: IoHandler<()> = io_handler(&network);
+        let addr: SocketAddr = ([127, 0, 0, 1], 1000).into();
+
+        let mut query = update(None);
+        query.r#type = Type::Query.id();
+        let malformed = [
+            Message {
+                closer_nodes: Some(vec![0; 37].into()),
+                ..query.clone()
+            },
+            Message {
+                closer_nodes6: Some(vec![0; 38].into()),
+                ..query.clone()
+            },
+            Message {
+                r#type: Type::Response.id(),
+                target: Some(vec![0; 31].into()),
+                ..query.clone()
+            },
+            Message {
+                id: Some(vec![0; 33]),
+                ..query.clone()
+            },
+            Message {
+                r#type: Type::Response.id(),
+                value: Some(vec![0; MAX_VALUE_SIZE + 1].into()),
+                ..query.clone()
+            },
+        ];
+        for (i, msg) in malformed.iter().enumerate() {
+            assert!(io.on_message(msg.clone(), addr).is_none());
+            assert_eq!(io.num_malformed_messages(), i as u64 + 1);
+        }
+        assert!(io.pending_send.is_empty());
+
+        // oversized requests and invalid targets are passed on to be answered
+        // with an error
+        let invalid = [
+            Message {
+                value: Some(vec![0; MAX_VALUE_SIZE + 1].into()),
+                ..query.clone()
+            },
+            Message {
+                target: Some(vec![0; 31].into()),
+                ..query
+            },
+        ];
+        for msg in invalid {
+            assert!(matches!(
+                io.on_message(msg, addr),
+                Some(IoHandlerEvent::InRequest { .. })
+            ));
+        }
+        assert_eq!(io.num_malformed_messages(), malformed.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_random_packets() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(75);
+            use rand::Rng;
+            let mut io: IoHandler<()> = io_handler(&network);
+            io.query(
+                Command::Ping,
+                None,
+                None,
+                Peer::from(([127, 0, 0, 1], 1000)),
+                (),
+            );
+            expect_sent(&mut io).await;
+            let pending = io.pending_recv.keys().cloned().collect::<Vec<_>>();
+
+            let mut rng = rand::thread_rng();
+            for _ in 0..1000 {
+                let len = rng.gen_range(0, 256);
+                let buf = (0..len).map(|_| rng.gen()).collect::<Vec<u8>>();
+                if let Some(msg) = decode(&buf) {
+                    inspect(&msg);
+                    let _ = io.on_message(msg, ([127, 0, 0, 2], 1000).into());
+                }
+            }
+            // nothing sent from another address can resolve our request
+            assert_eq!(io.pending_recv.keys().cloned().collect::<Vec<_>>(), pending);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn decode_truncated_packets() -> Result<(), Box<dyn std::error::Error>> {
+        let network = Network::new(76);
+        let mut io: IoHandler<()> = io_handler(&network);
+        let msg = Message {
+            to: Some(vec![127, 0, 0, 1, 3, 232]),
+            id: Some(IdBytes::random().to_vec()),
+            closer_nodes: Some(vec![1; 38 * 3].into()),
+            closer_nodes6: Some(vec![1; 50 * 2].into()),
+            roundtrip_token: Some(vec![1; 64].into()),
+            value: Some(Bytes::from_static(b"value")),
+            ..update(None)
+        };
+        let mut buf = Vec::new();
+        prost::Message::encode(&msg, &mut buf)?;
+        assert_eq!(decode(&buf), Some(msg));
+
+        for len in 0..buf.len() {
+            if let Some(msg) = decode(&buf[..len]) {
+                inspect(&msg);
+                let _ = io.on_message(msg, ([127, 0, 0, 1], 1000).into());
+            }
+        }
+        assert!(io.pending_recv.is_empty());
+        Ok(())
+    }
 }
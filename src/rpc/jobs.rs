@@ -3,7 +3,9 @@ use std::time::Duration;
 
 use futures::task::{Context, Poll};
 use futures::Future;
-use wasm_timer::{Delay, Instant};
+use wasm_timer::Instant;
+
+use crate::time::Delay;
 
 /// Periodic job.
 #[derive(Debug)]
@@ -24,6 +26,8 @@ impl PeriodicJob {
         if let Poll::Ready(Ok(_)) = Delay::poll(Pin::new(&mut self.inner), cx) {
             let deadline = now + self.interval;
             self.inner = Delay::new_at(deadline);
+            // make sure we get woken up again once the next deadline is reached
+            let _ = Delay::poll(Pin::new(&mut self.inner), cx);
             Poll::Ready(())
         } else {
             Poll::Pending
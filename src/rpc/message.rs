@@ -5,10 +5,15 @@ use std::net::SocketAddr;
 
 use prost::Message as ProstMessage;
 
-use crate::kbucket;
-use crate::peers::{decode_peer_ids, decode_peers};
+use crate::kbucket::{self, K_VALUE};
+use crate::peers::{decode_addr, decode_peer_ids, decode_peer_ids6};
 use crate::rpc::{IdBytes, Peer, PeerId, RequestId};
 
+/// The maximum number of closer nodes that are decoded from a message.
+pub const MAX_CLOSER_NODES: usize = 20 * K_VALUE.get();
+
+pub mod codec;
+
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Holepunch {
     #[prost(bytes, optional, tag = "2")]
@@ -41,46 +46,37 @@ impl Holepunch {
 
     /// Decode the `to` field into [`SocketAddr`]
     pub fn decode_to_peer(&self) -> Option<SocketAddr> {
-        self.to
-            .as_ref()
-            .and_then(|to| decode_peers(to.as_slice()).into_iter().next())
+        self.to.as_ref().and_then(|to| decode_addr(to))
     }
 
     /// Decode the `from` field into [`SocketAddr`]
     pub fn decode_from_peer(&self) -> Option<SocketAddr> {
-        self.from
-            .as_ref()
-            .and_then(|from| decode_peers(from.as_slice()).into_iter().next())
+        self.from.as_ref().and_then(|from| decode_addr(from))
     }
 }
 
-#[derive(Clone, PartialEq, ::prost::Message)]
+/// A dht-rpc message, see [`codec`] for its encoding.
+#[derive(Clone, PartialEq, Default, Debug)]
 pub struct Message {
-    #[prost(uint64, optional, tag = "11")]
     pub version: ::std::option::Option<u64>,
     /// request/response type + id
-    #[prost(enumeration = "Type", required, tag = "1")]
     pub r#type: i32,
-    #[prost(uint64, required, tag = "2")]
     pub rid: u64,
-    #[prost(bytes, optional, tag = "10")]
     pub to: ::std::option::Option<std::vec::Vec<u8>>,
     /// kademlia stuff
-    #[prost(bytes, optional, tag = "3")]
     pub id: ::std::option::Option<std::vec::Vec<u8>>,
-    #[prost(bytes, optional, tag = "4")]
-    pub target: ::std::option::Option<std::vec::Vec<u8>>,
-    #[prost(bytes, optional, tag = "5")]
-    pub closer_nodes: ::std::option::Option<std::vec::Vec<u8>>,
-    #[prost(bytes, optional, tag = "6")]
-    pub roundtrip_token: ::std::option::Option<std::vec::Vec<u8>>,
+    pub target: ::std::option::Option<::bytes::Bytes>,
+    pub closer_nodes: ::std::option::Option<::bytes::Bytes>,
+    pub roundtrip_token: ::std::option::Option<::bytes::Bytes>,
     /// rpc stuff
-    #[prost(string, optional, tag = "7")]
     pub command: ::std::option::Option<std::string::String>,
-    #[prost(string, optional, tag = "8")]
     pub error: ::std::option::Option<std::string::String>,
-    #[prost(bytes, optional, tag = "9")]
-    pub value: ::std::option::Option<std::vec::Vec<u8>>,
+    pub value: ::std::option::Option<::bytes::Bytes>,
+    /// IPv6 closer nodes, only sent to IPv6 requesters
+    pub closer_nodes6: ::std::option::Option<::bytes::Bytes>,
+    /// Encoded fields this implementation does not know, so that they are
+    /// passed on unchanged.
+    pub unknown_fields: std::vec::Vec<u8>,
 }
 
 impl Message {
@@ -96,10 +92,10 @@ impl Message {
         self.r#type == Type::Update.id()
     }
 
-    fn valid_key_bytes(key: Option<&Vec<u8>>) -> Option<IdBytes> {
+    fn valid_key_bytes(key: Option<&[u8]>) -> Option<IdBytes> {
         if let Some(id) = key {
             if id.len() == 32 {
-                return Some(IdBytes::try_from(id.as_slice()).expect("s.a."));
+                return Some(IdBytes::try_from(id).expect("s.a."));
             }
         }
         None
@@ -107,9 +103,7 @@ impl Message {
 
     /// The decoded address in `to`, if any
     pub fn get_to_addr(&self) -> Option<SocketAddr> {
-        self.to
-            .as_ref()
-            .and_then(|to| decode_peers(to).into_iter().next())
+        self.to.as_ref().and_then(|to| decode_addr(to))
     }
 
     pub fn get_type(&self) -> Result<Type, i32> {
@@ -122,11 +116,7 @@ impl Message {
     }
 
     pub fn get_command(&self) -> Option<Command> {
-        if let Some(ref s) = self.command {
-            Some(Command::from(s))
-        } else {
-            None
-        }
+        self.command.as_ref().map(Command::from)
     }
 
     fn cmd_eq(&self, name: &str) -> bool {
@@ -164,17 +154,21 @@ impl Message {
 
     /// Decode the `to` field into `PeerId`
     pub(crate) fn decode_to_peer(&self) -> Option<SocketAddr> {
-        self.to
-            .as_ref()
-            .and_then(|to| decode_peers(to.as_slice()).into_iter().next())
+        self.to.as_ref().and_then(|to| decode_addr(to))
     }
 
-    /// Decode the `to` field into `PeerId`
+    /// Decode the `closer_nodes` and `closer_nodes6` fields into `PeerId`
     pub(crate) fn decode_closer_nodes(&self) -> Vec<PeerId> {
-        self.closer_nodes
+        let mut nodes = self
+            .closer_nodes
             .as_ref()
             .map(decode_peer_ids)
-            .unwrap_or_default()
+            .unwrap_or_default();
+        if let Some(nodes6) = self.closer_nodes6.as_ref() {
+            nodes.extend(decode_peer_ids6(nodes6));
+        }
+        nodes.truncate(MAX_CLOSER_NODES);
+        nodes
     }
 
     /// Decode the messages value into [`Holepunch`]
@@ -184,23 +178,21 @@ impl Message {
 
     /// Decodes an instance of the message from the message's value.
     pub fn decode_value<T: prost::Message + Default>(&self) -> Option<T> {
-        self.value
-            .as_ref()
-            .and_then(|val| T::decode(val.as_slice()).ok())
+        self.value.as_ref().and_then(|val| T::decode(&val[..]).ok())
     }
 
     pub fn set_holepunch(&mut self, holepunch: &Holepunch) {
         let mut buf = Vec::with_capacity(holepunch.encoded_len());
         holepunch.encode(&mut buf).unwrap();
-        self.value = Some(buf);
+        self.value = Some(buf.into());
     }
 
     pub(crate) fn valid_id_bytes(&self) -> Option<IdBytes> {
-        Self::valid_key_bytes(self.id.as_ref())
+        Self::valid_key_bytes(self.id.as_deref())
     }
 
     pub(crate) fn valid_target_id_bytes(&self) -> Option<IdBytes> {
-        Self::valid_key_bytes(self.target.as_ref())
+        Self::valid_key_bytes(self.target.as_deref())
     }
 }
 
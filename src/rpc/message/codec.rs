@@ -0,0 +1,516 @@
+//! The wire format of [`Message`], following the field numbers and types of
+//! the `Message` in dht-rpc's protobuf schema.
+//!
+//! Fields are written in the order they are declared in the schema, so that
+//! a decoded message encodes to the exact same bytes. The encoding is not yet
+//! checked against messages captured from a JS node. Fields this
+//! implementation does not know are kept as they are and written after the
+//! known fields.
+//!
+//! [`Message::decode_bytes`] decodes the `target`, `closer_nodes`,
+//! `roundtrip_token`, `value` and `closer_nodes6` fields as slices of the
+//! received packet instead of copying them.
+
+use ::bytes::{Buf, BufMut, Bytes};
+use prost::encoding::{
+    bytes, check_wire_type, decode_key, decode_varint, encode_key, encode_varint,
+    encoded_len_varint, int32, key_len, skip_field, string, uint64, DecodeContext, WireType,
+};
+use prost::DecodeError;
+use prost::Message as _;
+
+use crate::rpc::message::Message;
+
+pub const TYPE: u32 = 1;
+pub const RID: u32 = 2;
+pub const ID: u32 = 3;
+pub const TARGET: u32 = 4;
+pub const CLOSER_NODES: u32 = 5;
+pub const ROUNDTRIP_TOKEN: u32 = 6;
+pub const COMMAND: u32 = 7;
+pub const ERROR: u32 = 8;
+pub const VALUE: u32 = 9;
+pub const TO: u32 = 10;
+pub const VERSION: u32 = 11;
+/// IPv6 closer nodes, not part of the dht-rpc schema and ignored by the JS
+/// implementation.
+pub const CLOSER_NODES6: u32 = 12;
+
+impl Message {
+    /// Encodes the message into a new buffer.
+    ///
+    /// An ephemeral node does not reveal its id, so if `ephemeral` is set the
+    /// `id` field is never written, no matter what the message contains.
+    pub fn encode_to_vec(&self, ephemeral: bool) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.fields_len(!ephemeral));
+        self.encode_fields(!ephemeral, &mut buf);
+        buf
+    }
+
+    /// Decodes a message from `buf`.
+    ///
+    /// Unlike [`prost::Message::decode`] the `target`, `closer_nodes`,
+    /// `roundtrip_token`, `value` and `closer_nodes6` fields are not copied but
+    /// share the memory of `buf`, which stays allocated as long as one of them
+    /// is alive. Values that are kept around for long should be copied.
+    pub fn decode_bytes(mut buf: Bytes) -> Result<Self, DecodeError> {
+        let mut msg = Message::default();
+        while buf.has_remaining() {
+            let (tag, wire_type) = decode_key(&mut buf)?;
+            let field = match tag {
+                TARGET => &mut msg.target,
+                CLOSER_NODES => &mut msg.closer_nodes,
+                ROUNDTRIP_TOKEN => &mut msg.roundtrip_token,
+                VALUE => &mut msg.value,
+                CLOSER_NODES6 => &mut msg.closer_nodes6,
+                _ => {
+                    msg.merge_field(tag, wire_type, &mut buf, DecodeContext::default())?;
+                    continue;
+                }
+            };
+            check_wire_type(WireType::LengthDelimited, wire_type)?;
+            let len = decode_varint(&mut buf)? as usize;
+            if len > buf.remaining() {
+                return Err(DecodeError::new("buffer underflow"));
+            }
+            *field = Some(buf.split_to(len));
+        }
+        Ok(msg)
+    }
+
+    fn encode_fields<B: BufMut>(&self, with_id: bool, buf: &mut B) {
+        if let Some(ref version) = self.version {
+            uint64::encode(VERSION, version, buf);
+        }
+        int32::encode(TYPE, &self.r#type, buf);
+        uint64::encode(RID, &self.rid, buf);
+        if let Some(ref to) = self.to {
+            bytes::encode(TO, to, buf);
+        }
+        if let Some(id) = self.id.as_ref().filter(|_| with_id) {
+            bytes::encode(ID, id, buf);
+        }
+        if let Some(ref target) = self.target {
+            encode_bytes(TARGET, target, buf);
+        }
+        if let Some(ref closer_nodes) = self.closer_nodes {
+            encode_bytes(CLOSER_NODES, closer_nodes, buf);
+        }
+        if let Some(ref token) = self.roundtrip_token {
+            encode_bytes(ROUNDTRIP_TOKEN, token, buf);
+        }
+        if let Some(ref command) = self.command {
+            string::encode(COMMAND, command, buf);
+        }
+        if let Some(ref error) = self.error {
+            string::encode(ERROR, error, buf);
+        }
+        if let Some(ref value) = self.value {
+            encode_bytes(VALUE, value, buf);
+        }
+        if let Some(ref closer_nodes6) = self.closer_nodes6 {
+            encode_bytes(CLOSER_NODES6, closer_nodes6, buf);
+        }
+        buf.put_slice(&self.unknown_fields);
+    }
+
+    fn fields_len(&self, with_id: bool) -> usize {
+        self.version
+            .as_ref()
+            .map_or(0, |v| uint64::encoded_len(VERSION, v))
+            + int32::encoded_len(TYPE, &self.r#type)
+            + uint64::encoded_len(RID, &self.rid)
+            + self.to.as_ref().map_or(0, |v| bytes::encoded_len(TO, v))
+            + self
+                .id
+                .as_ref()
+                .filter(|_| with_id)
+                .map_or(0, |v| bytes::encoded_len(ID, v))
+            + self.target.as_ref().map_or(0, |v| bytes_len(TARGET, v))
+            + self
+                .closer_nodes
+                .as_ref()
+                .map_or(0, |v| bytes_len(CLOSER_NODES, v))
+            + self
+                .roundtrip_token
+                .as_ref()
+                .map_or(0, |v| bytes_len(ROUNDTRIP_TOKEN, v))
+            + self
+                .command
+                .as_ref()
+                .map_or(0, |v| string::encoded_len(COMMAND, v))
+            + self
+                .error
+                .as_ref()
+                .map_or(0, |v| string::encoded_len(ERROR, v))
+            + self.value.as_ref().map_or(0, |v| bytes_len(VALUE, v))
+            + self
+                .closer_nodes6
+                .as_ref()
+                .map_or(0, |v| bytes_len(CLOSER_NODES6, v))
+            + self.unknown_fields.len()
+    }
+
+    /// Keeps an unknown field as it is, so that it is written again when the
+    /// message is encoded.
+    fn keep_unknown<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        let len = match wire_type {
+            WireType::Varint => {
+                let value = decode_varint(buf)?;
+                encode_key(tag, wire_type, &mut self.unknown_fields);
+                encode_varint(value, &mut self.unknown_fields);
+                return Ok(());
+            }
+            WireType::ThirtyTwoBit => 4,
+            WireType::SixtyFourBit => 8,
+            WireType::LengthDelimited => decode_varint(buf)? as usize,
+            // groups are deprecated and never used by dht-rpc
+            WireType::StartGroup | WireType::EndGroup => {
+                return skip_field(wire_type, tag, buf, ctx);
+            }
+        };
+        if len > buf.remaining() {
+            return Err(DecodeError::new("buffer underflow"));
+        }
+        encode_key(tag, wire_type, &mut self.unknown_fields);
+        if wire_type == WireType::LengthDelimited {
+            encode_varint(len as u64, &mut self.unknown_fields);
+        }
+        let start = self.unknown_fields.len();
+        self.unknown_fields.resize(start + len, 0);
+        buf.copy_to_slice(&mut self.unknown_fields[start..]);
+        Ok(())
+    }
+}
+
+fn encode_bytes<B: BufMut>(tag: u32, value: &Bytes, buf: &mut B) {
+    encode_key(tag, WireType::LengthDelimited, buf);
+    encode_varint(value.len() as u64, buf);
+    buf.put_slice(value);
+}
+
+fn bytes_len(tag: u32, value: &Bytes) -> usize {
+    key_len(tag) + encoded_len_varint(value.len() as u64) + value.len()
+}
+
+/// Copies a length delimited field out of a buffer of unknown type.
+fn merge_bytes<B: Buf>(
+    wire_type: WireType,
+    field: &mut Option<Bytes>,
+    buf: &mut B,
+) -> Result<(), DecodeError> {
+    check_wire_type(WireType::LengthDelimited, wire_type)?;
+    let len = decode_varint(buf)? as usize;
+    if len > buf.remaining() {
+        return Err(DecodeError::new("buffer underflow"));
+    }
+    let mut value = vec![0; len];
+    buf.copy_to_slice(&mut value);
+    *field = Some(value.into());
+    Ok(())
+}
+
+impl prost::Message for Message {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        self.encode_fields(true, buf)
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            VERSION => uint64::merge(
+                wire_type,
+                self.version.get_or_insert_with(Default::default),
+                buf,
+                ctx,
+            ),
+            TYPE => int32::merge(wire_type, &mut self.r#type, buf, ctx),
+            RID => uint64::merge(wire_type, &mut self.rid, buf, ctx),
+            TO => bytes::merge(wire_type, self.to.get_or_insert_with(Vec::new), buf, ctx),
+            ID => bytes::merge(wire_type, self.id.get_or_insert_with(Vec::new), buf, ctx),
+            TARGET => merge_bytes(wire_type, &mut self.target, buf),
+            CLOSER_NODES => merge_bytes(wire_type, &mut self.closer_nodes, buf),
+            ROUNDTRIP_TOKEN => merge_bytes(wire_type, &mut self.roundtrip_token, buf),
+            COMMAND => string::merge(
+                wire_type,
+                self.command.get_or_insert_with(String::new),
+                buf,
+                ctx,
+            ),
+            ERROR => string::merge(
+                wire_type,
+                self.error.get_or_insert_with(String::new),
+                buf,
+                ctx,
+            ),
+            VALUE => merge_bytes(wire_type, &mut self.value, buf),
+            CLOSER_NODES6 => merge_bytes(wire_type, &mut self.closer_nodes6, buf),
+            _ => self.keep_unknown(tag, wire_type, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.fields_len(true)
+    }
+
+    fn clear(&mut self) {
+        *self = Message::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message as ProstMessage;
+
+    use crate::allocs;
+    use crate::rpc::message::Type;
+
+    use super::*;
+
+    /// Fixtures of encoded messages, in the field order of the schema.
+    ///
+    /// They were written by hand from the schema rather than captured from a
+    /// JS node, so they pin down our own encoding and don't prove
+    /// compatibility with dht-rpc.
+    // TODO check these against the messages `js/capture-fixtures.js` records
+    // from the dht-rpc version in `js/package-lock.json` into `tests/fixtures/`
+    fn fixture(hex: &str) -> Vec<u8> {
+        let hex = hex.trim();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn id(start: u8) -> Vec<u8> {
+        (start..start + 32).collect()
+    }
+
+    fn to() -> Option<Vec<u8>> {
+        Some(vec![127, 0, 0, 1, 0x30, 0x39])
+    }
+
+    /// Decodes the fixture, compares it to `expected` and encodes it again.
+    fn assert_roundtrip(hex: &str, expected: Message) {
+        let buf = fixture(hex);
+        let msg = Message::decode(buf.as_slice()).unwrap();
+        assert_eq!(msg, expected);
+        assert_eq!(Message::decode_bytes(buf.clone().into()).unwrap(), msg);
+        assert_eq!(msg.encoded_len(), buf.len());
+        assert_eq!(msg.encode_to_vec(false), buf);
+
+        let mut encoded = Vec::new();
+        msg.encode(&mut encoded).unwrap();
+        assert_eq!(encoded, buf);
+    }
+
+    fn message(ty: Type, rid: u64) -> Message {
+        Message {
+            version: None,
+            r#type: ty.id(),
+            rid,
+            to: None,
+            id: None,
+            target: None,
+            closer_nodes: None,
+            roundtrip_token: None,
+            command: None,
+            error: None,
+            value: None,
+            closer_nodes6: None,
+            unknown_fields: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ping_query() {
+        assert_roundtrip(
+            include_str!("testdata/ping_query.hex"),
+            Message {
+                version: Some(1),
+                to: to(),
+                id: Some(id(1)),
+                command: Some("_ping".to_string()),
+                ..message(Type::Query, 42)
+            },
+        );
+    }
+
+    #[test]
+    fn ping_response() {
+        // the value is the address the requester was seen at
+        assert_roundtrip(
+            include_str!("testdata/ping_response.hex"),
+            Message {
+                version: Some(1),
+                to: to(),
+                id: Some(id(101)),
+                value: to().map(Bytes::from),
+                ..message(Type::Response, 42)
+            },
+        );
+    }
+
+    #[test]
+    fn decode_any_field_order() {
+        // other encoders may write the fields in a different order, which
+        // decodes to the same message and is written in the schema order again
+        let expected = fixture(include_str!("testdata/ping_response.hex"));
+        let msg = Message::decode(expected.as_slice()).unwrap();
+        let mut reordered = Vec::new();
+        bytes::encode(ID, msg.id.as_ref().unwrap(), &mut reordered);
+        encode_bytes(VALUE, msg.value.as_ref().unwrap(), &mut reordered);
+        uint64::encode(RID, &msg.rid, &mut reordered);
+        bytes::encode(TO, msg.to.as_ref().unwrap(), &mut reordered);
+        int32::encode(TYPE, &msg.r#type, &mut reordered);
+        uint64::encode(VERSION, msg.version.as_ref().unwrap(), &mut reordered);
+        assert_ne!(reordered, expected);
+
+        let decoded = Message::decode(reordered.as_slice()).unwrap();
+        assert_eq!(decoded, msg);
+        assert_eq!(Message::decode_bytes(reordered.into()).unwrap(), msg);
+        assert_eq!(decoded.encode_to_vec(false), expected);
+    }
+
+    #[test]
+    fn find_node_response() {
+        let mut closer_nodes = id(201);
+        closer_nodes.extend_from_slice(&[10, 0, 0, 1, 0xc2, 0x49]);
+        closer_nodes.extend(id(1));
+        closer_nodes.extend_from_slice(&[192, 168, 1, 20, 0x1a, 0xe1]);
+        let expected = Message {
+            version: Some(1),
+            to: to(),
+            id: Some(id(101)),
+            closer_nodes: Some(closer_nodes.into()),
+            roundtrip_token: Some(vec![0xab; 32].into()),
+            ..message(Type::Response, 65535)
+        };
+        assert_roundtrip(
+            include_str!("testdata/find_node_response.hex"),
+            expected.clone(),
+        );
+        let nodes = expected.decode_closer_nodes();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].addr, ([10, 0, 0, 1], 49737).into());
+        assert_eq!(nodes[1].addr, ([192, 168, 1, 20], 6881).into());
+    }
+
+    #[test]
+    fn error_response() {
+        assert_roundtrip(
+            include_str!("testdata/error_response.hex"),
+            Message {
+                version: Some(1),
+                to: to(),
+                id: Some(id(101)),
+                error: Some("Unsupported command".to_string()),
+                ..message(Type::Response, 7)
+            },
+        );
+    }
+
+    #[test]
+    fn announce_update() {
+        assert_roundtrip(
+            include_str!("testdata/announce_update.hex"),
+            Message {
+                version: Some(1),
+                to: to(),
+                id: Some(id(1)),
+                target: Some(id(201).into()),
+                roundtrip_token: Some(vec![0xab; 32].into()),
+                command: Some("announce".to_string()),
+                value: Some(vec![0x08, 0x90, 0x3f, 0x12, 0x06, 10, 0, 0, 2, 0x1a, 0xe1].into()),
+                ..message(Type::Update, 300)
+            },
+        );
+    }
+
+    #[test]
+    fn legacy_query() {
+        // nodes before the version field was introduced
+        assert_roundtrip(
+            include_str!("testdata/legacy_query.hex"),
+            Message {
+                target: Some(id(201).into()),
+                command: Some("_find_node".to_string()),
+                ..message(Type::Query, 1)
+            },
+        );
+    }
+
+    #[test]
+    fn keep_unknown_fields() {
+        let buf = fixture(include_str!("testdata/unknown_fields.hex"));
+        let msg = Message::decode(buf.as_slice()).unwrap();
+        assert_eq!(msg.rid, 9);
+        assert_eq!(msg.command.as_deref(), Some("_ping"));
+        // a varint, a length delimited and a fixed size field
+        assert_eq!(msg.unknown_fields.len(), 4 + 11 + 6);
+        assert_eq!(msg.encode_to_vec(false), buf);
+    }
+
+    #[test]
+    fn decode_without_copy() {
+        let assert_shared = |buf: &Bytes, field: &Option<Bytes>| {
+            let range = buf.as_ptr_range();
+            let field = field.as_ref().unwrap().as_ptr_range();
+            assert!(range.start <= field.start && field.end <= range.end);
+        };
+        let buf = Bytes::from(fixture(include_str!("testdata/announce_update.hex")));
+        let msg = Message::decode_bytes(buf.clone()).unwrap();
+        for field in [&msg.target, &msg.roundtrip_token, &msg.value] {
+            assert_shared(&buf, field);
+        }
+        let nodes = Bytes::from(fixture(include_str!("testdata/find_node_response.hex")));
+        let msg = Message::decode_bytes(nodes.clone()).unwrap();
+        assert_shared(&nodes, &msg.closer_nodes);
+
+        // truncated fields
+        assert!(Message::decode_bytes(buf.slice(..buf.len() - 1)).is_err());
+    }
+
+    #[test]
+    fn decode_with_fewer_allocations() {
+        // only `to`, `id` and `command` are still copied out of the packet
+        for (hex, copies, shares) in [
+            (include_str!("testdata/announce_update.hex"), 6, 3),
+            (include_str!("testdata/find_node_response.hex"), 4, 2),
+        ] {
+            let buf = Bytes::from(fixture(hex));
+            let packet = buf.clone();
+            let (copied, copying) = allocs::count(|| Message::decode(&buf[..]).unwrap());
+            let (shared, sharing) = allocs::count(|| Message::decode_bytes(packet).unwrap());
+            assert_eq!(copied, shared);
+            assert_eq!((copying, sharing), (copies, shares));
+
+            // a single buffer of the exact size
+            let (encoded, allocations) = allocs::count(|| shared.encode_to_vec(false));
+            assert_eq!(allocations, 1);
+            assert_eq!(encoded, buf);
+        }
+    }
+
+    #[test]
+    fn ephemeral_omits_id() {
+        let msg =
+            Message::decode(fixture(include_str!("testdata/ping_query.hex")).as_slice()).unwrap();
+        let buf = msg.encode_to_vec(true);
+        assert_eq!(buf.len(), msg.encoded_len() - 34);
+        let decoded = Message::decode(buf.as_slice()).unwrap();
+        assert_eq!(decoded.id, None);
+        assert_eq!(decoded, Message { id: None, ..msg });
+    }
+}
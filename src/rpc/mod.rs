@@ -3,61 +3,211 @@
 use std::borrow::Borrow;
 use std::collections::{HashSet, VecDeque};
 use std::convert::{TryFrom, TryInto};
+use std::fmt;
 use std::hash::Hash;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
 use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
 use std::pin::Pin;
 use std::time::Duration;
 
 use async_std::net::UdpSocket;
+use bytes::Bytes;
 use ed25519_dalek::{PublicKey, PUBLIC_KEY_LENGTH};
+use fnv::FnvHashMap;
 use futures::{
+    channel::mpsc,
+    future::Future,
     stream::Stream,
     task::{Context, Poll},
 };
-use sha2::digest::generic_array::{typenum::U32, GenericArray};
+use lru::LruCache;
+use sha2::{digest::Output, Sha256};
 use wasm_timer::Instant;
 
 pub use crate::rpc::message::*;
-use crate::rpc::query::CommandQueryResponse;
+use crate::rpc::query::{CommandQueryResponse, FixedPeersOutcome, Reply, ResponseSender};
+use crate::time::{self, Delay};
 use crate::{
     kbucket::{self, Entry, KBucketsTable, Key, KeyBytes, NodeStatus, K_VALUE},
-    peers::PeersEncoding,
+    peers::{encode_nodes6, CloserNodes, PeersEncoding},
     rpc::{
+        addr::ExternalAddr,
+        health::NetworkHealth,
         io::{IoConfig, IoHandler, IoHandlerEvent, MessageEvent, VERSION},
         jobs::PeriodicJob,
+        nat::NatProbe,
+        protocol::DhtRpcCodec,
         query::{
-            table::PeerState, CommandQuery, QueryConfig, QueryEvent, QueryId, QueryPool,
-            QueryPoolState, QueryStats, QueryStream, QueryType,
+            table::PeerState, CommandQuery, QueryConfig, QueryEvent, QueryId, QueryLimits,
+            QueryPool, QueryPoolState, QueryResponses, QueryStats, QueryStream, QueryType,
         },
+        ratelimit::RateLimiter,
+        refresh::{BucketRefresh, Refresh},
+        udp::{Transport, UdpFramed},
     },
 };
 
+mod addr;
+mod health;
 pub mod io;
-mod jobs;
+pub(crate) mod jobs;
 pub mod message;
+pub mod nat;
 pub mod protocol;
 pub mod query;
+mod ratelimit;
+mod refresh;
+mod rtt;
 pub mod udp;
 
+pub use crate::rpc::health::SUSPECT_WINDOW;
+pub use crate::rpc::io::ERR_INVALID_TOKEN;
+pub use crate::rpc::nat::{NatMapping, NatStatus, NAT_PROBE_TIMEOUT};
+pub use crate::rpc::ratelimit::RateLimit;
+pub use crate::rpc::refresh::BUCKET_REFRESH_INTERVAL;
+
+/// Maximum number of claimed ids and addresses that wait for a confirming
+/// ping.
+const MAX_UNCONFIRMED: usize = 256;
+
+/// Number of requests to a node that may time out in a row, including their
+/// retries, before the node is removed from the routing table.
+pub const MAX_NODE_TIMEOUTS: u32 = 3;
+
+/// Number of times in a row the socket is bound again after a fatal socket
+/// error before the node gives up, see [`RpcDhtEvent::SocketError`].
+pub const MAX_REBIND_ATTEMPTS: usize = 3;
+
+/// Maximum number of socket events handled in a single poll, so that a busy
+/// socket doesn't starve the queries or other tasks.
+const MAX_IO_EVENTS_PER_POLL: usize = 64;
+
+/// Error sent for requests with a command that is not registered.
+pub const ERR_UNSUPPORTED_COMMAND: &str = "Unsupported command";
+
+/// Error sent for requests without a valid target.
+pub const ERR_TARGET_REQUIRED: &str = "Target required";
+
+/// Error sent for requests with a value larger than the maximum value size.
+pub const ERR_VALUE_TOO_LARGE: &str = "Value too large";
+
+/// Error sent for all requests while the node is shutting down.
+pub const ERR_SHUTTING_DOWN: &str = "Shutting down";
+
+/// Error sent for custom command requests that were dropped without a reply.
+pub const ERR_NO_REPLY: &str = "No reply";
+
+/// How long an adaptive node stays ephemeral at least, see
+/// [`DhtConfig::adaptive`].
+pub const ADAPTIVE_UPTIME: Duration = Duration::from_secs(20 * 60);
+
+/// The default maximum size of the value of a message, see
+/// [`DhtConfig::set_max_value_size`].
+pub const MAX_VALUE_SIZE: usize = 4096;
+
+/// The default maximum size of a received message, see
+/// [`DhtConfig::set_max_message_size`].
+pub const MAX_MESSAGE_SIZE: usize = 8 * 1024;
+
+/// How many more pings and find node requests nodes of our routing table may
+/// send compared to the configured [`RateLimit`].
+pub const KNOWN_NODE_ALLOWANCE: f64 = 4.0;
+
 #[derive(Debug)]
 pub struct RpcDht {
     /// Identifier of this node
     id: Key<IdBytes>,
     // TODO change Key to Key<PeerId>
     kbuckets: KBucketsTable<Key<IdBytes>, Node>,
-    io: IoHandler<QueryId>,
+    io: IoHandler<Option<QueryId>>,
     bootstrap_job: PeriodicJob,
     ping_job: PeriodicJob,
+    /// Whether nodes in the routing table are pinged and stale ones removed.
+    ping_nodes: bool,
+    /// How long a node may stay silent before it is removed.
+    node_stale_timeout: Duration,
     /// The currently active (i.e. in-progress) queries.
     queries: QueryPool,
+    /// Wakes up the dht once the next query times out.
+    query_timer: Option<(Instant, Delay)>,
+    /// The address remote peers see us at
+    external_addr: ExternalAddr,
     /// Custom commands
     commands: HashSet<String>,
+    /// Custom command requests that were not answered yet, with the deadline
+    /// for the reply once their [`ResponseSender`] was dropped.
+    pending_replies: FnvHashMap<(SocketAddr, RequestId), Option<(Instant, CommandQuery)>>,
+    /// The deadlines of the dropped replies in the order they were dropped,
+    /// replies answered in the meantime are skipped once their turn comes.
+    dropped_replies: VecDeque<(Instant, (SocketAddr, RequestId))>,
+    /// Replies sent by [`ResponseSender`]s.
+    reply_tx: mpsc::UnboundedSender<Reply>,
+    reply_rx: mpsc::UnboundedReceiver<Reply>,
     /// Queued events to return when being polled.
     queued_events: VecDeque<RpcDhtEvent>,
     /// Nodes to bootstrap from
     bootstrap_nodes: Vec<SocketAddr>,
     bootstrapped: bool,
+    /// How long to wait for running queries when shutting down.
+    drain_timeout: Duration,
+    /// Whether the node is shutting down.
+    shutting_down: bool,
+    /// Limits the incoming requests per source address.
+    rate_limiter: RateLimiter,
+    rate_limit: Option<RateLimit>,
+    /// Decides which nodes this node talks to.
+    peer_filter: Option<FilterFn>,
+    /// Number of requests that were dropped by the peer filter.
+    filtered_requests: u64,
+    /// Ids that contacted us from an address that doesn't match the routing
+    /// table, by the address that was pinged to confirm them.
+    unconfirmed: LruCache<SocketAddr, IdBytes>,
+    /// The ids of the nodes in the routing table by their address, which may
+    /// still hold nodes that were dropped since, see [`RpcDht::node_at`].
+    node_addrs: FnvHashMap<SocketAddr, IdBytes>,
+    /// Detects that all requests time out.
+    health: NetworkHealth,
+    /// What is known about our NAT.
+    nat_status: NatStatus,
+    /// Checks whether unsolicited requests reach us, once our external
+    /// address is confirmed.
+    nat_probe: Option<NatProbe>,
+    /// Refreshes buckets without activity.
+    refresh: BucketRefresh,
+    /// Number of rebinds after fatal socket errors without a message received
+    /// in between.
+    rebind_attempts: usize,
+    /// Whether the socket failed for good, the stream ends once the queued
+    /// events are drained.
+    socket_failed: bool,
+    /// Whether the node sends over a custom transport instead of a UDP
+    /// socket, which is not replaced after an error of the socket.
+    custom_transport: bool,
+    /// Whether to punch first depending on the NAT status, if not configured.
+    auto_punch_first: bool,
+    /// Elapses once an adaptive node may turn persistent.
+    adaptive: Option<Delay>,
+}
+
+/// Decides whether to talk to a node, given its id and address.
+///
+/// The id is empty for requests of ephemeral nodes.
+pub type PeerFilter = Box<dyn Fn(&[u8], &SocketAddr) -> bool + Send>;
+
+struct FilterFn(PeerFilter);
+
+impl FilterFn {
+    #[inline]
+    fn allows(&self, id: &[u8], addr: &SocketAddr) -> bool {
+        (self.0)(id, addr)
+    }
+}
+
+impl fmt::Debug for FilterFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PeerFilter")
+    }
 }
 
 #[derive(Debug)]
@@ -69,11 +219,21 @@ pub struct DhtConfig {
     io_config: IoConfig,
     bootstrap_interval: Duration,
     ping_interval: Duration,
-    connection_idle_timeout: Duration,
+    ping_nodes: bool,
+    node_stale_timeout: Duration,
     ephemeral: bool,
-    pub(crate) adaptive: bool,
+    adaptive: bool,
+    pub(crate) peers_max_age: Duration,
+    pub(crate) local_subnet_prefix: u8,
     bootstrap_nodes: Option<Vec<SocketAddr>>,
     socket: Option<UdpSocket>,
+    transport: Option<Box<dyn Transport>>,
+    drain_timeout: Duration,
+    known_nodes: Vec<(IdBytes, SocketAddr)>,
+    rate_limit: Option<RateLimit>,
+    peer_filter: Option<FilterFn>,
+    suspect_window: Duration,
+    bucket_refresh_interval: Duration,
 }
 
 impl Default for DhtConfig {
@@ -84,13 +244,23 @@ impl Default for DhtConfig {
             commands: Default::default(),
             query_config: Default::default(),
             ping_interval: Duration::from_secs(40),
+            ping_nodes: true,
+            node_stale_timeout: Duration::from_secs(120),
             bootstrap_interval: Duration::from_secs(320),
-            connection_idle_timeout: Duration::from_secs(10),
             ephemeral: false,
             adaptive: false,
+            peers_max_age: Duration::from_secs(60 * 12),
+            local_subnet_prefix: 24,
             bootstrap_nodes: None,
             socket: None,
+            transport: None,
             io_config: Default::default(),
+            drain_timeout: Duration::from_secs(5),
+            known_nodes: Vec::new(),
+            rate_limit: Some(Default::default()),
+            peer_filter: None,
+            suspect_window: SUSPECT_WINDOW,
+            bucket_refresh_interval: BUCKET_REFRESH_INTERVAL,
         }
     }
 }
@@ -102,13 +272,33 @@ impl DhtConfig {
         self
     }
 
-    /// Use an existing UDP socket.
-    pub fn set_socket(mut self, socket: UdpSocket) -> Self {
-        self.socket = Some(socket);
+    /// Use an existing UDP socket, e.g. one that was bound with
+    /// `SO_REUSEPORT` to share its port.
+    ///
+    /// Accepts a [`std::net::UdpSocket`] as well, which is how sockets with
+    /// custom options are created.
+    pub fn set_socket(mut self, socket: impl Into<UdpSocket>) -> Self {
+        self.socket = Some(socket.into());
+        self
+    }
+
+    /// Use a custom transport instead of a UDP socket, e.g. the in-memory
+    /// network of the `testing` module.
+    ///
+    /// Takes precedence over [`DhtConfig::set_socket`]. Unlike a UDP socket
+    /// the transport is not bound again after it failed, the node ends with
+    /// [`RpcDhtEvent::SocketError`] instead.
+    pub fn set_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Box::new(transport));
         self
     }
 
     /// Create a new UDP socket and attempt to bind it to the addr provided.
+    ///
+    /// Whether an IPv6 address like `[::]:0` also talks to IPv4 nodes depends
+    /// on the default of `IPV6_V6ONLY` of the OS: Linux and macOS bind a
+    /// dual-stack socket, Windows and OpenBSD an IPv6 only one. Pass a socket
+    /// configured as needed to [`DhtConfig::set_socket`] otherwise.
     pub async fn bind<A: async_std::net::ToSocketAddrs>(
         mut self,
         addr: A,
@@ -135,6 +325,181 @@ impl DhtConfig {
         self
     }
 
+    /// Sets how long to wait for a response before a request is sent again.
+    ///
+    /// With adaptive timeouts this is only the timeout of requests to nodes
+    /// without a measured round trip time, while no node has one.
+    ///
+    /// The default is 1 second.
+    pub fn set_request_timeout(mut self, timeout: Duration) -> Self {
+        self.io_config.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets whether the timeout of a request follows the round trip time of
+    /// the node it is sent to, `3 × smoothed rtt + 4 × rtt variance`, and
+    /// doubles with every retry.
+    ///
+    /// The default is `true`.
+    pub fn set_adaptive_timeouts(mut self, adaptive: bool) -> Self {
+        self.io_config.adaptive_timeout = Some(adaptive);
+        self
+    }
+
+    /// Sets the least timeout of a request with adaptive timeouts.
+    ///
+    /// The default is 100 milliseconds, or the request timeout if shorter.
+    pub fn set_min_request_timeout(mut self, timeout: Duration) -> Self {
+        self.io_config.min_request_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how often a request is sent again before it is considered
+    /// failed.
+    ///
+    /// The default is 3 retries.
+    pub fn set_request_retries(mut self, retries: usize) -> Self {
+        self.io_config.max_retries = Some(retries);
+        self
+    }
+
+    /// Sets the maximum size of the value of a message.
+    ///
+    /// Requests with a larger value are answered with an error, responses
+    /// with a larger value are dropped.
+    ///
+    /// The default is [`MAX_VALUE_SIZE`].
+    pub fn set_max_value_size(mut self, max_value_size: usize) -> Self {
+        self.io_config.max_value_size = Some(max_value_size);
+        self
+    }
+
+    /// Relays a holepunch before the first request to a node that we only
+    /// know of through another node, instead of only once the request timed
+    /// out.
+    ///
+    /// Nodes behind a NAT or firewall drop requests from addresses they
+    /// never sent to. The requests to a node are sent again as soon as it
+    /// responds to the holepunch.
+    ///
+    /// Unless set, the node punches first while its [`RpcDht::nat_status`]
+    /// is a symmetric NAT or a firewall.
+    pub fn punch_first(mut self) -> Self {
+        self.io_config.punch_first = Some(true);
+        self
+    }
+
+    /// Sets the maximum size of a received message.
+    ///
+    /// Larger messages are dropped without decoding them and counted as
+    /// malformed.
+    ///
+    /// The default is [`MAX_MESSAGE_SIZE`].
+    pub fn set_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.io_config.max_message_size = Some(max_message_size);
+        self
+    }
+
+    /// Sets how many messages may wait to be sent.
+    ///
+    /// Once the queue is full, the oldest queued requests are dropped in
+    /// favor of newer messages and sent again after their timeout, responses
+    /// only if no request is queued. Queries hold back new requests while the
+    /// queue is full.
+    ///
+    /// The default is [`io::SEND_QUEUE_CAPACITY`].
+    pub fn set_send_queue_capacity(mut self, capacity: usize) -> Self {
+        self.io_config.max_send_queue = Some(capacity);
+        self
+    }
+
+    /// Sets how many requests of queries may wait for a response at once,
+    /// across all queries. Pings and holepunches don't count towards the
+    /// limit.
+    ///
+    /// Once the limit is reached queries hold back new requests until
+    /// responses arrive or requests time out, so that many concurrent queries
+    /// don't flood the network or the NAT in front of us. The running queries
+    /// take turns in sending.
+    ///
+    /// The default is [`io::MAX_REQUESTS_IN_FLIGHT`].
+    pub fn set_max_requests_in_flight(mut self, max: usize) -> Self {
+        self.io_config.max_in_flight = Some(max);
+        self
+    }
+
+    /// Pre-populates the routing table with nodes from a previous run, see
+    /// [`RpcDht::snapshot_nodes`].
+    ///
+    /// The nodes are not trusted blindly, they are contacted by the first
+    /// bootstrap and removed if they don't respond. Entries without a valid
+    /// 32 byte id are ignored.
+    pub fn with_known_nodes<I>(mut self, nodes: I) -> Self
+    where
+        I: IntoIterator<Item = (Vec<u8>, SocketAddr)>,
+    {
+        self.known_nodes.extend(
+            nodes.into_iter().filter_map(|(id, addr)| {
+                IdBytes::try_from(id.as_slice()).ok().map(|id| (id, addr))
+            }),
+        );
+        self
+    }
+
+    /// Sets how many requests a single address may send.
+    ///
+    /// Requests beyond the limit are dropped without a response. Pings and
+    /// find node requests of nodes in the routing table are allowed
+    /// [`KNOWN_NODE_ALLOWANCE`] times as many.
+    ///
+    /// The default is 20 requests per second with bursts of 50.
+    pub fn set_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Answers every request, no matter how many a single address sends.
+    pub fn disable_rate_limit(mut self) -> Self {
+        self.rate_limit = None;
+        self
+    }
+
+    /// Only talk to the nodes the `filter` allows.
+    ///
+    /// Requests of other nodes are dropped without a response and they are
+    /// never added to the routing table or contacted by queries. The filter is
+    /// called for every request, so it should be cheap.
+    pub fn with_peer_filter(mut self, filter: PeerFilter) -> Self {
+        self.peer_filter = Some(FilterFn(filter));
+        self
+    }
+
+    /// Sets how long a shutdown waits for the running queries to finish.
+    ///
+    /// The default is 5 seconds.
+    pub fn set_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Sets how long every request needs to time out before
+    /// [`RpcDhtEvent::NetworkSuspect`] is emitted.
+    ///
+    /// The default is [`SUSPECT_WINDOW`].
+    pub fn set_network_suspect_window(mut self, window: Duration) -> Self {
+        self.suspect_window = window;
+        self
+    }
+
+    /// Sets how long a bucket of the routing table may go without activity
+    /// before it is refreshed, see [`RpcDhtEvent::RefreshCompleted`].
+    ///
+    /// The default is [`BUCKET_REFRESH_INTERVAL`].
+    pub fn set_bucket_refresh_interval(mut self, interval: Duration) -> Self {
+        self.bucket_refresh_interval = interval;
+        self
+    }
+
     /// Sets the timeout for a single query.
     ///
     /// > **Note**: A single query usually comprises at least as many requests
@@ -146,10 +511,37 @@ impl DhtConfig {
         self
     }
 
+    /// Sets how many queries are running at the same time.
+    ///
+    /// Further queries are queued and started in order once a running query
+    /// finished or timed out.
+    ///
+    /// The default is 16.
+    pub fn set_max_active_queries(mut self, max: usize) -> Self {
+        self.query_config.max_active_queries = max;
+        self
+    }
+
+    /// Sets the timeout after which a node pending insertion into a full
+    /// bucket replaces the least-recently seen node, should that node not
+    /// respond to our ping in the meantime.
+    ///
+    /// The default is 60 seconds.
+    pub fn set_kbucket_pending_timeout(mut self, timeout: Duration) -> Self {
+        self.kbucket_pending_timeout = timeout;
+        self
+    }
+
     /// Sets the replication factor to use.
     ///
     /// The replication factor determines to how many closest peers
-    /// a record is replicated. The default is [`K_VALUE`].
+    /// a record is replicated. The default is [`K_VALUE`], which is also the
+    /// maximum.
+    ///
+    /// This is the `k` parameter in the Kademlia paper. It also determines
+    /// the size of the kbuckets, the number of closer nodes included in our
+    /// responses and after how many of the closest peers responded a query
+    /// finishes.
     pub fn set_replication_factor(mut self, replication_factor: NonZeroUsize) -> Self {
         self.query_config.replication_factor = replication_factor;
         self
@@ -172,6 +564,19 @@ impl DhtConfig {
         self
     }
 
+    /// Sets whether among the closest peers of a query that fall into the
+    /// same bucket the one with the lowest round trip time is contacted
+    /// first.
+    ///
+    /// Round trip times are measured either way. Disabling this makes the
+    /// order of the requests only depend on the distance to the target.
+    ///
+    /// The default is `true`.
+    pub fn set_rtt_selection(mut self, enabled: bool) -> Self {
+        self.query_config.rtt_selection = enabled;
+        self
+    }
+
     /// Sets the (re-)replication interval for `bootstrap` query.
     pub fn bootstrap_interval(mut self, interval: Duration) -> Self {
         self.bootstrap_interval = interval;
@@ -196,6 +601,23 @@ impl DhtConfig {
         self
     }
 
+    /// Never ping the nodes of the routing table, so they are also never
+    /// removed for being stale.
+    pub fn disable_ping(mut self) -> Self {
+        self.ping_nodes = false;
+        self
+    }
+
+    /// Sets the duration after which a node we haven't heard from is removed
+    /// from the routing table.
+    ///
+    /// Nodes are pinged every `ping_interval`, so this should be a multiple
+    /// of the ping interval. The default is 120 seconds.
+    pub fn set_node_stale_timeout(mut self, timeout: Duration) -> Self {
+        self.node_stale_timeout = timeout;
+        self
+    }
+
     /// Set ephemeral: true so other peers do not add us to the peer list,
     /// simply bootstrap.
     ///
@@ -211,7 +633,30 @@ impl DhtConfig {
         self
     }
 
+    /// Sets how long peers announced to this node are stored, unless they
+    /// announce themselves again.
+    ///
+    /// The default is 12 minutes.
+    pub fn set_peers_max_age(mut self, max_age: Duration) -> Self {
+        self.peers_max_age = max_age;
+        self
+    }
+
+    /// Sets the length of the prefix of the public IPv4 address that a
+    /// requester has to share with an announcer to learn its local address.
+    ///
+    /// Peers behind the same NAT share their public address, a shorter prefix
+    /// also covers networks with several public addresses. The default is 24
+    /// bits.
+    pub fn set_local_subnet_prefix(mut self, prefix: u8) -> Self {
+        self.local_subnet_prefix = prefix.min(32);
+        self
+    }
+
+    /// Starts ephemeral and turns persistent once the node was up for
+    /// [`ADAPTIVE_UPTIME`] and is not firewalled, see [`RpcDht::nat_status`].
     pub fn adaptive(mut self) -> Self {
+        self.ephemeral = true;
         self.adaptive = true;
         self
     }
@@ -246,7 +691,10 @@ impl RpcDht {
     ///
     /// If no socket was created within then `DhtConfig`, a new socket at a
     /// random port will be created.
-    pub async fn with_config(config: DhtConfig) -> std::io::Result<Self> {
+    pub async fn with_config(mut config: DhtConfig) -> std::io::Result<Self> {
+        // the routing table can't hold more than `K_VALUE` nodes per bucket
+        config.query_config.replication_factor =
+            config.query_config.replication_factor.min(K_VALUE);
         let local_id = Key::new(config.local_id.unwrap_or_else(IdBytes::random));
 
         let query_id = if config.ephemeral {
@@ -255,31 +703,72 @@ impl RpcDht {
             Some(local_id.clone())
         };
 
-        let socket = if let Some(socket) = config.socket {
-            socket
+        let custom_transport = config.transport.is_some();
+        let auto_punch_first = config.io_config.punch_first.is_none();
+        let mut io = if let Some(transport) = config.transport {
+            IoHandler::with_transport(query_id, transport, config.io_config)
         } else {
-            UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(
-                Ipv4Addr::new(127, 0, 0, 1),
-                0,
-            )))
-            .await?
+            let socket = if let Some(socket) = config.socket {
+                socket
+            } else {
+                UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::new(127, 0, 0, 1),
+                    0,
+                )))
+                .await?
+            };
+            IoHandler::new(query_id, socket, config.io_config)
         };
+        // pings and the like don't hold back the queries
+        io.count_in_flight(Option::is_some);
 
-        let io = IoHandler::new(query_id, socket, config.io_config);
-
+        let (reply_tx, reply_rx) = mpsc::unbounded();
         let mut dht = Self {
             id: local_id.clone(),
-            kbuckets: KBucketsTable::new(local_id.clone(), config.kbucket_pending_timeout),
+            kbuckets: KBucketsTable::with_bucket_size(
+                local_id.clone(),
+                config.kbucket_pending_timeout,
+                config.query_config.replication_factor,
+            ),
             io,
             bootstrap_job: PeriodicJob::new(config.bootstrap_interval),
             ping_job: PeriodicJob::new(config.ping_interval),
+            ping_nodes: config.ping_nodes,
+            node_stale_timeout: config.node_stale_timeout,
             queries: QueryPool::new(local_id, config.query_config),
+            query_timer: None,
+            external_addr: ExternalAddr::new(addr::CONFIRMATIONS),
             commands: config.commands,
+            pending_replies: Default::default(),
+            dropped_replies: Default::default(),
+            reply_tx,
+            reply_rx,
             queued_events: Default::default(),
             bootstrap_nodes: config.bootstrap_nodes.unwrap_or_default(),
             bootstrapped: false,
+            drain_timeout: config.drain_timeout,
+            shutting_down: false,
+            rate_limiter: RateLimiter::new(ratelimit::MAX_ADDRS),
+            rate_limit: config.rate_limit,
+            peer_filter: config.peer_filter,
+            filtered_requests: 0,
+            unconfirmed: LruCache::new(MAX_UNCONFIRMED),
+            node_addrs: Default::default(),
+            health: NetworkHealth::new(config.suspect_window),
+            nat_status: NatStatus::default(),
+            nat_probe: None,
+            refresh: BucketRefresh::new(config.bucket_refresh_interval),
+            rebind_attempts: 0,
+            socket_failed: false,
+            custom_transport,
+            auto_punch_first,
+            adaptive: config.adaptive.then(|| Delay::new(ADAPTIVE_UPTIME)),
         };
 
+        for (id, addr) in config.known_nodes {
+            dht.restore_node(id, addr);
+        }
+
         dht.bootstrap();
         Ok(dht)
     }
@@ -292,15 +781,36 @@ impl RpcDht {
         self.io.is_ephemeral()
     }
 
+    /// Turns an ephemeral node into a persistent one, e.g. once it has
+    /// verified its external address.
+    ///
+    /// The id is included in all following messages, so that remote peers
+    /// add this node to their routing tables. The node bootstraps again to
+    /// tell the nodes closest to it about itself.
+    pub fn persistent(&mut self) {
+        if self.is_ephemeral() {
+            self.io.set_id(Some(self.id.clone()));
+            self.bootstrap();
+        }
+    }
+
+    /// Starts a `find_node` query for our own id, seeded with the configured
+    /// bootstrap nodes.
+    ///
+    /// Returns `None` if there are no bootstrap nodes, in which case the node
+    /// is considered bootstrapped right away.
     #[inline]
-    pub fn bootstrap(&mut self) {
-        if !self.bootstrap_nodes.is_empty() {
-            self.query(Command::FindNode, self.id.clone(), None);
-        } else if !self.bootstrapped {
-            self.queued_events.push_back(RpcDhtEvent::Bootstrapped {
-                stats: QueryStats::empty(),
-            });
-            self.bootstrapped = true;
+    pub fn bootstrap(&mut self) -> Option<QueryId> {
+        if !self.bootstrap_nodes.is_empty() || self.kbuckets.iter().next().is_some() {
+            Some(self.query(Command::FindNode, self.id.clone(), None))
+        } else {
+            if !self.bootstrapped {
+                self.queued_events.push_back(RpcDhtEvent::Bootstrapped {
+                    stats: QueryStats::empty(),
+                });
+                self.bootstrapped = true;
+            }
+            None
         }
     }
 
@@ -330,43 +840,318 @@ impl RpcDht {
         self.io.local_addr()
     }
 
+    /// The addresses requests to this node arrive at: the bind address, the
+    /// loopback address if bound to all interfaces, and the confirmed
+    /// external address.
+    fn own_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = Vec::with_capacity(3);
+        if let Ok(local) = self.local_addr() {
+            addrs.push(local);
+            if local.ip().is_unspecified() {
+                let loopback: IpAddr = if local.is_ipv4() {
+                    Ipv4Addr::LOCALHOST.into()
+                } else {
+                    Ipv6Addr::LOCALHOST.into()
+                };
+                addrs.push(SocketAddr::new(loopback, local.port()));
+            }
+        }
+        addrs.extend(self.external_addr.confirmed());
+        addrs
+    }
+
+    /// The bootstrap nodes to start queries with, except for ourselves.
+    fn bootstrap_peers(&self) -> Vec<Peer> {
+        let own_addrs = self.own_addrs();
+        self.bootstrap_nodes
+            .iter()
+            .filter(|addr| !own_addrs.contains(addr))
+            .cloned()
+            .map(Peer::from)
+            .collect()
+    }
+
     /// Returns the id used to identify this node.
     #[inline]
     pub fn local_id(&self) -> &IdBytes {
         self.id.preimage()
     }
 
+    /// Closes the socket and binds a new UDP socket, e.g. after the network
+    /// changed and the old socket silently stopped working.
+    ///
+    /// The socket is bound to `addr`, or to the address of the current socket
+    /// if `None`. If its port can't be bound again a random port is used.
+    ///
+    /// Requests that are still waiting for a response are failed right away,
+    /// so that their queries continue over the new socket, and the external
+    /// address has to be confirmed again. The node bootstraps again in the
+    /// background.
+    pub fn rebind(&mut self, addr: Option<SocketAddr>) -> std::io::Result<()> {
+        let codec = DhtRpcCodec::new(self.io.max_message_size());
+        let udp = |socket: std::net::UdpSocket| -> Box<dyn Transport> {
+            Box::new(UdpFramed::new(UdpSocket::from(socket), codec.clone()))
+        };
+        if let Some(addr) = addr {
+            let socket = std::net::UdpSocket::bind(addr)?;
+            self.custom_transport = false;
+            self.rebind_transport_boxed(udp(socket));
+            return Ok(());
+        }
+        let old = self.local_addr()?;
+        let socket = std::net::UdpSocket::bind(SocketAddr::new(old.ip(), 0))?;
+        // the port of the old socket is free once it is closed
+        self.custom_transport = false;
+        self.rebind_transport_boxed(udp(socket));
+        if let Ok(socket) = std::net::UdpSocket::bind(old) {
+            self.io.set_transport(udp(socket));
+        }
+        Ok(())
+    }
+
+    /// Like [`RpcDht::rebind`], but sends and receives over the `transport`
+    /// from now on.
+    pub fn rebind_transport(&mut self, transport: impl Transport + 'static) {
+        self.custom_transport = true;
+        self.rebind_transport_boxed(Box::new(transport))
+    }
+
+    fn rebind_transport_boxed(&mut self, transport: Box<dyn Transport>) {
+        for (msg, peer, id) in self.io.set_transport(transport) {
+            self.report_unanswered(&msg, &peer);
+            if let Some(query) = id.and_then(|id| self.queries.get_mut(&id)) {
+                query.on_aborted(peer);
+            }
+        }
+        self.external_addr = ExternalAddr::new(addr::CONFIRMATIONS);
+        self.nat_probe = None;
+        self.set_nat_status(NatStatus::default());
+        self.health.reset();
+        self.bootstrap();
+    }
+
+    /// Returns the address remote peers see this node at, once enough of them
+    /// reported the same address.
+    ///
+    /// It is `None` again once too few of them still report it, e.g. after
+    /// the NAT rebound our mapping.
+    #[inline]
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        self.external_addr.confirmed()
+    }
+
+    /// Returns what is known about the NAT in front of this node, see
+    /// [`RpcDhtEvent::NatStatus`].
+    ///
+    /// The mapping follows the addresses the peers report, whether the node
+    /// is firewalled is probed once the external address is confirmed, by
+    /// pinging it from a second socket.
+    #[inline]
+    pub fn nat_status(&self) -> NatStatus {
+        self.nat_status
+    }
+
+    fn set_nat_status(&mut self, status: NatStatus) {
+        if status != self.nat_status {
+            self.nat_status = status;
+            if self.auto_punch_first {
+                // peers behind a NAT like ours drop our unsolicited requests
+                self.io.set_punch_first(
+                    status.mapping == NatMapping::Symmetric || status.firewalled == Some(true),
+                );
+            }
+            self.queued_events
+                .push_back(RpcDhtEvent::NatStatus { status });
+        }
+    }
+
+    /// Turns an adaptive node persistent once it was up long enough and is
+    /// reachable.
+    fn poll_adaptive(&mut self, cx: &mut Context<'_>) {
+        if let Some(delay) = self.adaptive.as_mut() {
+            let elapsed = Future::poll(Pin::new(delay), cx).is_ready();
+            if elapsed && self.nat_status.firewalled == Some(false) {
+                self.adaptive = None;
+                self.persistent();
+            }
+        }
+    }
+
+    /// Pings the external `addr` from a second socket of the transport, see
+    /// [`Transport::bind_probe`].
+    fn probe_nat(&mut self, addr: SocketAddr) {
+        match self
+            .io
+            .bind_probe()
+            .and_then(|socket| NatProbe::start(socket, addr))
+        {
+            Ok(probe) => self.nat_probe = Some(probe),
+            Err(err) => log::debug!("Failed to probe the external address {}: {}", addr, err),
+        }
+    }
+
+    /// Returns the smoothed round trip time of the node with the given id,
+    /// if it responded to one of our requests before.
+    #[inline]
+    pub fn peer_rtt(&self, id: &IdBytes) -> Option<Duration> {
+        self.queries.rtt().get(id)
+    }
+
+    /// Starts shutting down the node.
+    ///
+    /// The periodic bootstrap and ping jobs stop and all further requests are
+    /// answered with [`ERR_SHUTTING_DOWN`], while the running queries are
+    /// still driven to completion. Queries started afterwards are cancelled
+    /// right away, see [`RpcDht::cancel_query`].
+    pub fn start_shutdown(&mut self) {
+        self.shutting_down = true;
+    }
+
+    /// Whether [`RpcDht::start_shutdown`] was called.
+    #[inline]
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down
+    }
+
+    /// How long a shutdown waits for the running queries to finish.
+    #[inline]
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    /// Whether no queries are running and all messages were sent.
+    pub fn is_idle(&self) -> bool {
+        self.queries.is_empty() && self.io.is_idle()
+    }
+
+    /// Stops the query with the given ID and emits
+    /// [`RpcDhtEvent::QueryCancelled`] with what the query found so far.
+    ///
+    /// Its pending requests are dropped, responses that still arrive for them
+    /// are ignored. Updates of a query that already reached its update phase
+    /// are still delivered, but no new ones are sent.
+    ///
+    /// Returns `false` if no such query is running or queued.
+    pub fn cancel_query(&mut self, id: &QueryId) -> bool {
+        let query = if let Some(query) = self.queries.cancel(id) {
+            query
+        } else {
+            return false;
+        };
+        let keep_updates = query.is_updating();
+        self.io.cancel_requests(|msg, query| {
+            *query == Some(*id) && !(keep_updates && msg.get_type() == Ok(Type::Update))
+        });
+        let result = query.into_result();
+        self.queued_events.push_back(RpcDhtEvent::QueryCancelled {
+            id: *id,
+            cmd: result.cmd,
+            stats: result.stats,
+            closest: result.closest,
+            values: result.values,
+        });
+        true
+    }
+
+    /// Returns statistics about this node.
+    ///
+    /// A pending node whose bucket would take it in by now is only counted
+    /// once the node is polled again.
+    pub fn stats(&self) -> DhtStats {
+        let buckets = self
+            .kbuckets
+            .bucket_sizes()
+            .map(|(nodes, _)| nodes)
+            .collect::<Vec<_>>();
+        let traffic = self.io.traffic();
+        DhtStats {
+            nodes: buckets.iter().sum(),
+            buckets,
+            active_queries: self.queries.len(),
+            pending_queries: self.queries.pending(),
+            queries: self.queries.finished_stats().clone(),
+            finished_queries: self.queries.num_finished(),
+            messages_in: traffic.messages_in,
+            messages_out: traffic.messages_out,
+            bytes_in: traffic.bytes_in,
+            bytes_out: traffic.bytes_out,
+            malformed_messages: self.io.num_malformed_messages(),
+            rate_limited: self.rate_limiter.num_dropped(),
+            filtered_requests: self.filtered_requests,
+            retried_requests: self.io.num_retried_requests(),
+            send_queue: self.io.send_queue_len(),
+            requests_in_flight: self.io.requests_in_flight(),
+            dropped_messages: self.io.num_dropped_messages(),
+            announcements: 0,
+            stored_values: 0,
+            external_addr: self.external_addr(),
+            median_rtt: self.queries.rtt().median(),
+        }
+    }
+
+    /// Returns the stats of a running or pending query.
+    ///
+    /// Once the query finished its stats are part of the
+    /// [`RpcDhtEvent::QueryResult`].
+    pub fn query_stats(&self, id: &QueryId) -> Option<&QueryStats> {
+        self.queries.get(id).map(|query| query.stats())
+    }
+
     /// Ping a remote
     pub fn ping(&mut self, peer: &PeerId) {
         self.io.query(
             Command::Ping,
             None,
-            Some(peer.id.clone().to_vec()),
+            Some(peer.id.to_vec().into()),
             Peer::from(peer.addr),
-            // TODO refactor ping handling, no query id required
-            self.queries.next_query_id(),
+            None,
         )
     }
 
+    /// Pings the node at `addr`, whose id doesn't need to be known.
+    ///
+    /// Emits [`ResponseOk::Pong`] once it responds, or
+    /// [`ResponseError::PingTimeout`] if it doesn't.
+    pub fn ping_addr(&mut self, addr: SocketAddr) {
+        self.io
+            .query(Command::Ping, None, None, Peer::from(addr), None)
+    }
+
     fn ping_some(&mut self) {
         let cnt = if self.queries.len() > 2 { 3 } else { 5 };
-        let now = Instant::now();
-        for peer in self
+        let now = time::now();
+
+        // drop all nodes that didn't respond to any of the previous pings
+        let stale_timeout = self.node_stale_timeout;
+        let stale = self
             .kbuckets
             .iter()
-            .filter_map(|entry| {
-                if now > entry.node.value.next_ping {
-                    Some(PeerId::new(
-                        entry.node.value.addr,
-                        entry.node.key.preimage().clone(),
-                    ))
-                } else {
-                    None
-                }
+            .filter(|entry| now > entry.node.value.last_seen + stale_timeout)
+            .map(|entry| entry.node.key.clone())
+            .collect::<Vec<_>>();
+        for key in stale {
+            if let Some(entry) = self.remove_peer(&key) {
+                self.queued_events.push_back(RpcDhtEvent::NodeRemoved {
+                    peer: PeerId::new(entry.node.value.addr, entry.node.key.into_preimage()),
+                });
+            }
+        }
+        self.prune_node_addrs();
+
+        // the nodes we haven't heard from the longest first
+        let mut due = self
+            .kbuckets
+            .iter()
+            .filter(|entry| now > entry.node.value.next_ping)
+            .map(|entry| {
+                (
+                    entry.node.value.last_seen,
+                    PeerId::new(entry.node.value.addr, entry.node.key.preimage().clone()),
+                )
             })
-            .take(cnt)
-            .collect::<Vec<_>>()
-        {
+            .collect::<Vec<_>>();
+        due.sort_by_key(|(last_seen, _)| *last_seen);
+        for (_, peer) in due.into_iter().take(cnt) {
             self.ping(&peer)
         }
     }
@@ -385,34 +1170,111 @@ impl RpcDht {
         self.run_command(cmd, target, value, QueryType::QueryUpdate)
     }
 
-    fn run_command(
+    /// Like [`RpcDht::query_and_update`], but the closest nodes are updated
+    /// with `update_value` instead of the value of the query phase.
+    pub fn query_and_update_with(
         &mut self,
         cmd: impl Into<Command>,
         target: Key<IdBytes>,
-        value: Option<Vec<u8>>,
-        query_type: QueryType,
+        query_value: Option<Vec<u8>>,
+        update_value: Option<Vec<u8>>,
     ) -> QueryId {
-        let peers = self
+        let id = self.run_command(cmd, target, query_value, QueryType::QueryUpdate);
+        if let Some(query) = self.queries.get_mut(&id) {
+            query.set_update_value(update_value.map(Bytes::from));
+        }
+        id
+    }
+
+    /// Refreshes the buckets without activity for the refresh interval, by
+    /// looking up a random id that falls into them.
+    ///
+    /// Buckets closer than the closest bucket with a node are skipped, the
+    /// lookup of our own id when bootstrapping covers them.
+    fn refresh_buckets(&mut self, now: Instant) {
+        let closest = match self.kbuckets.bucket_sizes().position(|(n, _)| n > 0) {
+            Some(closest) => closest,
+            None => return,
+        };
+        let idle = self
             .kbuckets
-            .closest(&target)
-            .take(usize::from(K_VALUE))
-            .map(|e| PeerId::new(e.node.value.addr, e.node.key.preimage().clone()))
-            .map(Key::new)
+            .idle_buckets(now, self.refresh.interval)
+            .filter(|index| *index >= closest && !self.refresh.is_running(*index))
             .collect::<Vec<_>>();
-        self.queries.add_stream(
-            cmd,
-            peers,
-            query_type,
-            target,
-            value,
-            self.bootstrap_nodes.iter().cloned().map(Peer::from),
-        )
+        for index in idle {
+            self.kbuckets.touch_bucket(index);
+            let target = Key::new(refresh::random_target(self.id.preimage(), index));
+            let peers = self.closest_peers(&target, self.queries.replication_factor());
+            let id = self
+                .queries
+                .add_maintenance(peers, target, self.bootstrap_peers());
+            let known = self.bucket_keys(index);
+            self.refresh.started(id, Refresh { index, known });
+        }
     }
 
-    pub fn holepunch(&mut self, peer: Peer) -> bool {
-        if peer.referrer.is_some() {
-            let id = self.queries.next_query_id();
-            self.io.query(Command::Holepunch, None, None, peer, id);
+    /// The keys of the nodes in the bucket at `index`.
+    fn bucket_keys(&self, index: usize) -> Vec<Key<IdBytes>> {
+        self.kbuckets
+            .iter_ref()
+            .map(|e| e.node.key)
+            .filter(|key| self.id.distance(*key).ilog2() == Some(index as u32))
+            .cloned()
+            .collect()
+    }
+
+    /// The `num` nodes of the routing table a query for `target` starts with.
+    fn closest_peers(&mut self, target: &Key<IdBytes>, num: NonZeroUsize) -> Vec<Key<PeerId>> {
+        self.kbuckets
+            .closest(target)
+            .take(usize::from(num))
+            .map(|e| PeerId::new(e.node.value.addr, e.node.key.preimage().clone()))
+            .map(Key::new)
+            .collect()
+    }
+
+    fn run_command(
+        &mut self,
+        cmd: impl Into<Command>,
+        target: Key<IdBytes>,
+        value: Option<Vec<u8>>,
+        query_type: QueryType,
+    ) -> QueryId {
+        self.query_with_limits(cmd, target, value, query_type, QueryLimits::default())
+    }
+
+    /// Runs a query with its own limits of parallelism and of the number of
+    /// closest peers that need to respond.
+    pub fn query_with_limits(
+        &mut self,
+        cmd: impl Into<Command>,
+        target: Key<IdBytes>,
+        value: Option<Vec<u8>>,
+        query_type: QueryType,
+        limits: QueryLimits,
+    ) -> QueryId {
+        let num = limits
+            .num_results
+            .unwrap_or_else(|| self.queries.replication_factor());
+        let peers = self.closest_peers(&target, num);
+        let id = self.queries.add_with_limits(
+            cmd,
+            peers,
+            query_type,
+            target,
+            value.map(Bytes::from),
+            self.bootstrap_peers(),
+            limits,
+        );
+        if self.shutting_down {
+            self.cancel_query(&id);
+        }
+        id
+    }
+
+    pub fn holepunch(&mut self, peer: Peer) -> bool {
+        if peer.referrer.is_some() {
+            self.io.query(Command::Holepunch, None, None, peer, None);
             true
         } else {
             false
@@ -428,6 +1290,14 @@ impl RpcDht {
         self.run_command(cmd, target, value, QueryType::Query)
     }
 
+    /// Returns a stream of all further responses to the query, which ends
+    /// once the query finished.
+    ///
+    /// Returns `None` if the query already finished.
+    pub fn responses(&mut self, id: &QueryId) -> Option<QueryResponses> {
+        self.queries.responses(id)
+    }
+
     pub fn update(
         &mut self,
         cmd: impl Into<Command>,
@@ -441,41 +1311,112 @@ impl RpcDht {
         &mut self,
         id: IdBytes,
         peer: Peer,
-        roundtrip_token: Option<Vec<u8>>,
+        roundtrip_token: Option<Bytes>,
+        to: Option<SocketAddr>,
+    ) {
+        let verified = roundtrip_token.is_some();
+        self.add_contact(id, peer, roundtrip_token, to, verified)
+    }
+
+    /// Adds a node that contacted us to the routing table.
+    ///
+    /// Source addresses of requests can be spoofed, so the table is not
+    /// changed by a contact that doesn't match it:
+    ///
+    /// * a known id at a new address is only moved there once a ping to the
+    ///   new address is answered with the same id, see
+    ///   [`RpcDhtEvent::NodeAddressChanged`].
+    /// * an address of a node with a different id only changes hands if the
+    ///   contact is `verified`, i.e. a response to one of our requests or a
+    ///   request with a valid roundtrip token, or a ping to the address is
+    ///   answered with the new id, see [`RpcDhtEvent::NodeIdChanged`].
+    fn add_contact(
+        &mut self,
+        id: IdBytes,
+        peer: Peer,
+        roundtrip_token: Option<Bytes>,
         to: Option<SocketAddr>,
+        verified: bool,
     ) {
+        if !self.allows(&id.0, &peer.addr) {
+            return;
+        }
         let key = kbucket::Key::new(id);
+        self.kbuckets.touch(&key);
+        let known_addr = self.kbuckets.entry(&key).value().map(|n| n.addr);
+        if known_addr.is_some_and(|addr| addr != peer.addr) {
+            self.confirm_contact(key.into_preimage(), peer.addr);
+            return;
+        }
+        if known_addr.is_none() {
+            if let Some(old_id) = self.node_at(&peer.addr) {
+                if !verified {
+                    self.confirm_contact(key.into_preimage(), peer.addr);
+                    return;
+                }
+                self.replace_node_id(old_id, key.preimage().clone(), peer.addr);
+            }
+        }
+
         match self.kbuckets.entry(&key) {
             Entry::Present(mut entry, _) => {
-                entry.value().next_ping = Instant::now() + self.ping_job.interval;
+                entry.value().seen(self.ping_job.interval);
+                entry.update(NodeStatus::Connected);
             }
             Entry::Pending(mut entry, _) => {
-                let n = entry.value();
-                n.addr = peer.addr;
-                n.next_ping = Instant::now() + self.ping_job.interval;
+                entry.value().seen(self.ping_job.interval);
             }
             Entry::Absent(entry) => {
+                let now = time::now();
                 let node = Node {
                     addr: peer.addr,
-                    roundtrip_token,
+                    // a copy, so that the table doesn't keep the received
+                    // packet alive
+                    roundtrip_token: roundtrip_token.map(|t| Bytes::copy_from_slice(&t)),
                     to,
-                    next_ping: Instant::now() + self.ping_job.interval,
+                    next_ping: now + self.ping_job.interval,
+                    last_seen: now,
                     referrers: vec![],
+                    timeouts: 0,
                 };
 
-                match entry.insert(node, NodeStatus::Connected) {
+                self.node_addrs.insert(peer.addr, key.preimage().clone());
+                match entry.insert(node.clone(), NodeStatus::Connected) {
                     kbucket::InsertResult::Inserted => {
+                        trace_event!(peer = %peer.addr, "Added node to the routing table");
                         self.queued_events.push_back(RpcDhtEvent::RoutingUpdated {
                             peer,
                             old_peer: None,
                         });
                     }
                     kbucket::InsertResult::Full => {
-                        log::debug!("Bucket full. Peer not added to routing table: {:?}", peer)
+                        // all nodes of the bucket are connected, challenge the
+                        // least-recently seen one
+                        match self.kbuckets.least_recently_connected(&key) {
+                            Some(oldest) if oldest.status == NodeStatus::Connected => {
+                                if let Entry::Present(entry, _) =
+                                    self.kbuckets.entry(&oldest.node.key)
+                                {
+                                    entry.update(NodeStatus::Disconnected);
+                                }
+                                if let Entry::Absent(entry) = self.kbuckets.entry(&key) {
+                                    if let kbucket::InsertResult::Pending { disconnected } =
+                                        entry.insert(node, NodeStatus::Connected)
+                                    {
+                                        self.ping_disconnected(&disconnected);
+                                    }
+                                }
+                            }
+                            _ => {
+                                log::debug!(
+                                    "Bucket full. Peer not added to routing table: {:?}",
+                                    peer
+                                )
+                            }
+                        }
                     }
-                    kbucket::InsertResult::Pending { disconnected: _ } => {
-
-                        // TODO dial remote
+                    kbucket::InsertResult::Pending { disconnected } => {
+                        self.ping_disconnected(&disconnected);
                     }
                 }
             }
@@ -483,6 +1424,67 @@ impl RpcDht {
         }
     }
 
+    /// Pings a node that was marked as disconnected to make room for a pending
+    /// node.
+    ///
+    /// If the node doesn't respond before the pending timeout, it is evicted
+    /// in favor of the pending node.
+    fn ping_disconnected(&mut self, key: &Key<IdBytes>) {
+        if let Some(addr) = self.kbuckets.entry(key).value().map(|node| node.addr) {
+            self.ping(&PeerId::new(addr, key.preimage().clone()));
+        }
+    }
+
+    /// Returns the id of the node in the routing table at `addr`.
+    ///
+    /// A node that is still pending insertion is kept in the index, but not
+    /// returned.
+    fn node_at(&mut self, addr: &SocketAddr) -> Option<IdBytes> {
+        let key = Key::new(self.node_addrs.get(addr)?.clone());
+        let mut entry = self.kbuckets.entry(&key);
+        let present = matches!(entry, Entry::Present(..));
+        if entry.value().is_some_and(|node| node.addr == *addr) {
+            return Some(key.into_preimage()).filter(|_| present);
+        }
+        self.node_addrs.remove(addr);
+        None
+    }
+
+    /// Drops the addresses of the nodes that left the routing table from the
+    /// index of [`RpcDht::node_at`].
+    fn prune_node_addrs(&mut self) {
+        let kbuckets = &mut self.kbuckets;
+        self.node_addrs.retain(|addr, id| {
+            kbuckets
+                .entry(&Key::new(id.clone()))
+                .value()
+                .is_some_and(|node| node.addr == *addr)
+        });
+    }
+
+    /// Pings `addr` to confirm that the node with `id` is reachable there.
+    fn confirm_contact(&mut self, id: IdBytes, addr: SocketAddr) {
+        if self.unconfirmed.peek(&addr) == Some(&id) {
+            // already pinged
+            return;
+        }
+        log::debug!(
+            "Confirming {:?} at {} before updating the routing table",
+            id,
+            addr
+        );
+        self.ping(&PeerId::new(addr, id.clone()));
+        self.unconfirmed.put(addr, id);
+    }
+
+    /// Evicts the node `old_id` at `addr`, so that the node with `id` can
+    /// take its place.
+    fn replace_node_id(&mut self, old_id: IdBytes, id: IdBytes, addr: SocketAddr) {
+        self.remove_peer(&Key::new(old_id.clone()));
+        self.queued_events
+            .push_back(RpcDhtEvent::NodeIdChanged { addr, old_id, id });
+    }
+
     /// Removes a peer from the routing table.
     ///
     /// Returns `None` if the peer was not in the routing table,
@@ -491,55 +1493,168 @@ impl RpcDht {
         &mut self,
         key: &Key<IdBytes>,
     ) -> Option<kbucket::EntryView<kbucket::Key<IdBytes>, Node>> {
-        match self.kbuckets.entry(key) {
+        let removed = match self.kbuckets.entry(key) {
             kbucket::Entry::Present(entry, _) => Some(entry.remove()),
             kbucket::Entry::Pending(entry, _) => Some(entry.remove()),
             kbucket::Entry::Absent(..) | kbucket::Entry::SelfEntry => None,
+        };
+        if let Some(entry) = &removed {
+            let addr = entry.node.value.addr;
+            if self.node_addrs.get(&addr) == Some(key.preimage()) {
+                self.node_addrs.remove(&addr);
+            }
+        }
+        #[cfg(feature = "tracing")]
+        if let Some(entry) = &removed {
+            trace_event!(peer = %entry.node.value.addr, "Removed node from the routing table");
         }
+        removed
     }
 
-    fn remove_node(
-        &mut self,
-        peer: &Peer,
-    ) -> Option<kbucket::EntryView<kbucket::Key<IdBytes>, Node>> {
-        let id = self
-            .kbuckets
+    /// Inserts a node of a previous run into the routing table.
+    ///
+    /// The node is disconnected until it responds, so that it is the first to
+    /// be replaced.
+    fn restore_node(&mut self, id: IdBytes, addr: SocketAddr) {
+        if !self.allows(&id.0, &addr) {
+            return;
+        }
+        if let Entry::Absent(entry) = self.kbuckets.entry(&Key::new(id.clone())) {
+            let now = time::now();
+            self.node_addrs.insert(addr, id);
+            let node = Node {
+                addr,
+                roundtrip_token: None,
+                to: None,
+                next_ping: now + self.ping_job.interval,
+                last_seen: now,
+                referrers: vec![],
+                timeouts: 0,
+            };
+            let _ = entry.insert(node, NodeStatus::Disconnected);
+        }
+    }
+
+    /// Returns the ids and addresses of all nodes in the routing table, e.g.
+    /// to restore them with [`DhtConfig::with_known_nodes`] after a restart.
+    pub fn snapshot_nodes(&mut self) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.kbuckets
             .iter()
-            .filter_map(|e| {
-                if e.node.value.addr == peer.addr {
-                    Some(e.node.key.clone())
-                } else {
-                    None
-                }
+            .map(|e| (e.node.key.preimage().to_vec(), e.node.value.addr))
+            .collect()
+    }
+
+    /// Returns an iterator over all nodes in the routing table.
+    ///
+    /// Nodes that wait for a free slot in a full bucket are not included.
+    pub fn nodes(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        self.kbuckets
+            .iter_ref()
+            .map(move |e| self.node_info(e.node.key.preimage(), e.node.value))
+    }
+
+    /// Returns the number of nodes in the routing table, without the ones
+    /// that wait for a free slot.
+    pub fn num_nodes(&self) -> usize {
+        self.kbuckets.bucket_sizes().map(|(nodes, _)| nodes).sum()
+    }
+
+    /// Returns up to `num` nodes of the routing table that are closest to
+    /// `target` in the keyspace, closest first.
+    ///
+    /// These are the nodes a `find_node` request for `target` is answered
+    /// with.
+    pub fn closest(&self, target: &[u8; 32], num: usize) -> Vec<NodeInfo> {
+        self.kbuckets
+            .closest_ref(&Key::new(IdBytes::from(*target)), num)
+            .into_iter()
+            .map(|e| self.node_info(e.node.key.preimage(), e.node.value))
+            .collect()
+    }
+
+    /// Returns the occupancy of every bucket of the routing table, ordered by
+    /// increasing distance to the local id.
+    pub fn bucket_info(&self) -> Vec<BucketInfo> {
+        self.kbuckets
+            .bucket_sizes()
+            .enumerate()
+            .map(|(index, (nodes, pending))| BucketInfo {
+                index,
+                nodes,
+                pending,
             })
-            .next();
+            .collect()
+    }
 
-        if let Some(id) = id {
-            self.remove_peer(&id)
-        } else {
-            None
+    fn node_info(&self, id: &IdBytes, node: &Node) -> NodeInfo {
+        NodeInfo {
+            id: id.clone(),
+            addr: node.addr,
+            last_seen: node.last_seen,
+            rtt: self.peer_rtt(id),
+        }
+    }
+
+    /// Marks the node with the peer's address as disconnected, so that it is
+    /// the first to be replaced once its bucket is full.
+    fn disconnect_node(&mut self, peer: &Peer) {
+        let key = match self
+            .kbuckets
+            .iter()
+            .find(|e| e.node.value.addr == peer.addr)
+            .map(|e| e.node.key.clone())
+        {
+            Some(key) => key,
+            None => return,
+        };
+        if let Entry::Present(mut entry, _) = self.kbuckets.entry(&key) {
+            entry.value().timeouts += 1;
+            if entry.value().timeouts < MAX_NODE_TIMEOUTS {
+                entry.update(NodeStatus::Disconnected);
+                return;
+            }
+        }
+        // the node is gone, don't wait for it to become stale
+        if let Some(entry) = self.remove_peer(&key) {
+            self.queued_events.push_back(RpcDhtEvent::NodeRemoved {
+                peer: PeerId::new(entry.node.value.addr, entry.node.key.into_preimage()),
+            });
         }
     }
 
     /// Handle a response for our Ping command
     fn on_pong(&mut self, msg: Message, peer: Peer) {
         if let Some(id) = msg.valid_id_bytes() {
-            match self.kbuckets.entry(&Key::new(id)) {
+            if self.unconfirmed.peek(&peer.addr) == Some(&id) {
+                self.unconfirmed.pop(&peer.addr);
+                self.on_confirmed(id.clone(), peer.addr, msg.decode_to_peer());
+            }
+            let key = Key::new(id);
+            self.kbuckets.touch(&key);
+            match self.kbuckets.entry(&key) {
                 Entry::Present(mut entry, _) => {
-                    entry.value().next_ping = Instant::now() + self.ping_job.interval;
+                    entry.value().seen(self.ping_job.interval);
+                    let addr = entry.value().addr;
+                    // a responsive node is not evicted in favor of a pending node
+                    entry.update(NodeStatus::Connected);
                     self.queued_events.push_back(RpcDhtEvent::ResponseResult(Ok(
-                        ResponseOk::Pong(Peer::from(entry.value().addr)),
+                        ResponseOk::Pong(Peer::from(addr)),
                     )));
                     return;
                 }
                 Entry::Pending(mut entry, _) => {
-                    entry.value().next_ping = Instant::now() + self.ping_job.interval;
+                    entry.value().seen(self.ping_job.interval);
                     self.queued_events.push_back(RpcDhtEvent::ResponseResult(Ok(
                         ResponseOk::Pong(Peer::from(entry.value().addr)),
                     )));
                     return;
                 }
-                _ => {}
+                // a node that isn't in the routing table
+                _ => {
+                    self.queued_events
+                        .push_back(RpcDhtEvent::ResponseResult(Ok(ResponseOk::Pong(peer))));
+                    return;
+                }
             }
         }
 
@@ -549,18 +1664,107 @@ impl RpcDht {
             )))
     }
 
+    /// The node `id` answered a ping to `addr` that confirms a contact which
+    /// didn't match the routing table.
+    fn on_confirmed(&mut self, id: IdBytes, addr: SocketAddr, to: Option<SocketAddr>) {
+        let key = Key::new(id);
+        if let Some(node) = self.kbuckets.entry(&key).value() {
+            let old_addr = std::mem::replace(&mut node.addr, addr);
+            if old_addr != addr {
+                node.roundtrip_token = None;
+                node.to = to;
+                self.node_addrs.remove(&old_addr);
+                self.node_addrs.insert(addr, key.preimage().clone());
+                self.queued_events
+                    .push_back(RpcDhtEvent::NodeAddressChanged {
+                        id: key.into_preimage(),
+                        old_addr,
+                        addr,
+                    });
+            }
+        } else {
+            self.add_contact(key.into_preimage(), Peer::from(addr), None, to, true);
+        }
+    }
+
     /// Process a response.
-    fn on_response(&mut self, req: Box<Message>, resp: Message, peer: Peer, id: QueryId) {
+    fn on_response(
+        &mut self,
+        req: Box<Message>,
+        resp: Message,
+        peer: Peer,
+        rtt: Option<Duration>,
+        id: Option<QueryId>,
+    ) {
+        if resp.valid_id_bytes().as_ref() == Some(self.local_id()) {
+            // e.g. a bootstrap node at our own address
+            log::debug!("Dropping response from ourselves at {}", peer.addr);
+            if let Some(query) = id.and_then(|id| self.queries.get_mut(&id)) {
+                query.on_rejected(peer);
+            }
+            return;
+        }
+
+        if let (Some(node), Some(rtt)) = (resp.valid_id_bytes(), rtt) {
+            self.queries.observe_rtt(node, rtt);
+        }
+
+        if let Some(to) = resp.decode_to_peer() {
+            let old_addr = self.external_addr.last_confirmed();
+            if let Some(addr) = self.external_addr.report(peer.addr, to) {
+                let old_addr = old_addr.filter(|old| *old != addr);
+                self.queued_events
+                    .push_back(RpcDhtEvent::ExternalAddrConfirmed { addr, old_addr });
+                self.probe_nat(addr);
+            }
+            let mapping = self.external_addr.mapping();
+            self.set_nat_status(NatStatus {
+                mapping,
+                ..self.nat_status
+            });
+        }
+
         if req.is_ping() {
             self.on_pong(resp, peer);
             return;
         }
 
-        if let Some(query) = self.queries.get_mut(&id) {
-            if let Some(resp) = query.inject_response(resp, peer) {
+        if req.is_holepunch() {
+            self.queued_events
+                .push_back(RpcDhtEvent::ResponseResult(Ok(ResponseOk::Holepunched(
+                    peer,
+                ))));
+            return;
+        }
+
+        let own_addrs = self.own_addrs();
+        let queries = &mut self.queries;
+        let query = id.and_then(|id| queries.get_mut(&id).map(|query| (id, query)));
+        if let Some((id, query)) = query {
+            let error = resp.error.clone();
+            let filter = &self.peer_filter;
+            let resp = query.inject_response_filtered(resp, peer.clone(), &own_addrs, |node| {
+                filter
+                    .as_ref()
+                    .is_none_or(|filter| filter.allows(&node.id.0, &node.addr))
+            });
+            // bucket refreshes only update the routing table
+            if query.is_maintenance() {
+                return;
+            }
+            if let Some(resp) = resp {
                 self.queued_events
                     .push_back(RpcDhtEvent::ResponseResult(Ok(ResponseOk::Response(resp))))
+            } else if let Some(error) = error {
+                self.queued_events
+                    .push_back(RpcDhtEvent::ResponseResult(Err(ResponseError::Remote {
+                        query: id,
+                        peer,
+                        error,
+                    })))
             }
+        } else if id.is_some() {
+            log::debug!("Dropping response from {} for finished query", peer.addr);
         }
     }
 
@@ -569,11 +1773,12 @@ impl RpcDht {
     /// # Note
     ///
     /// This only checks if this custom `command` query is currently registered,
-    /// but does not reply. Instead the incoming query is delegated to via
-    /// [`Stream::poll`] as [`CommandQuery`] in
+    /// but does not reply, unless the command is not supported. Instead the incoming query is delegated to the
+    /// registrar via [`Stream::poll`] as [`CommandQuery`] in
     /// [`RpcDhtEvent::RequestResult::RequestOk::CustomCommandRequest`].
     /// It is the command registrar's responsibility to process this query and
-    /// eventually reply.
+    /// eventually reply, either with [`RpcDht::reply_command`] or the
+    /// [`ResponseSender`] of the event.
     fn on_command_req(&mut self, ty: Type, command: String, msg: Message, peer: Peer) {
         if let Some(target) = msg.valid_target_id_bytes() {
             if self.commands.contains(&command) {
@@ -585,17 +1790,48 @@ impl RpcDht {
                     target,
                     value: msg.value,
                 };
+                self.pending_replies
+                    .insert((query.peer.addr, query.rid), None);
+                let reply = ResponseSender::new(query.clone(), self.reply_tx.clone());
                 self.queued_events.push_back(RpcDhtEvent::RequestResult(Ok(
-                    RequestOk::CustomCommandRequest { query },
+                    RequestOk::CustomCommandRequest { query, reply },
                 )));
             } else {
+                // let the remote know right away instead of having it wait
+                // for a response that never comes
+                let closer_nodes = self.closer_nodes(
+                    target,
+                    usize::from(self.queries.replication_factor()),
+                    &peer,
+                );
+                self.io.error(
+                    msg.clone(),
+                    ERR_UNSUPPORTED_COMMAND.to_string(),
+                    None,
+                    Some(closer_nodes),
+                    peer.clone(),
+                );
                 self.queued_events.push_back(RpcDhtEvent::RequestResult(Err(
-                    RequestError::UnsupportedCommand { command, msg, peer },
+                    RequestError::UnsupportedCommand {
+                        command,
+                        msg: Box::new(msg),
+                        peer,
+                    },
                 )));
             }
         } else {
+            self.io.error(
+                msg.clone(),
+                ERR_TARGET_REQUIRED.to_string(),
+                None,
+                None,
+                peer.clone(),
+            );
             self.queued_events.push_back(RpcDhtEvent::RequestResult(Err(
-                RequestError::MissingTarget { msg, peer },
+                RequestError::MissingTarget {
+                    msg: Box::new(msg),
+                    peer,
+                },
             )));
         }
     }
@@ -603,9 +1839,49 @@ impl RpcDht {
     /// Handle an incoming request.
     ///
     /// Eventually send a response.
-    fn on_request(&mut self, mut msg: Message, peer: Peer, ty: Type) {
+    fn on_request(&mut self, msg: Message, peer: Peer, ty: Type) {
+        if !self.allows(msg.id.as_deref().unwrap_or_default(), &peer.addr) {
+            self.filtered_requests += 1;
+            return;
+        }
+
+        // no response at all, so we can't be used to amplify traffic
+        if !self.check_rate_limit(&msg, &peer) {
+            log::trace!("Dropping request from {} over the rate limit", peer.addr);
+            return;
+        }
+
+        if self.shutting_down {
+            self.io
+                .error(msg, ERR_SHUTTING_DOWN.to_string(), None, None, peer);
+            return;
+        }
+
         if let Some(id) = msg.valid_id_bytes() {
-            self.add_node(id, peer.clone(), None, msg.decode_to_peer());
+            // the io handler only passes on updates with a valid roundtrip token
+            let verified = ty == Type::Update;
+            self.add_contact(id, peer.clone(), None, msg.decode_to_peer(), verified);
+        }
+
+        if msg
+            .value
+            .as_ref()
+            .is_some_and(|v| v.len() > self.io.max_value_size())
+        {
+            self.io.error(
+                msg.clone(),
+                ERR_VALUE_TOO_LARGE.to_string(),
+                None,
+                None,
+                peer.clone(),
+            );
+            self.queued_events.push_back(RpcDhtEvent::RequestResult(Err(
+                RequestError::ValueTooLarge {
+                    msg: Box::new(msg),
+                    peer,
+                },
+            )));
+            return;
         }
 
         if let Some(cmd) = msg.get_command() {
@@ -615,45 +1891,104 @@ impl RpcDht {
                 Command::Holepunch => self.on_holepunch(msg, peer),
                 Command::Unknown(s) => self.on_command_req(ty, s, msg, peer),
             };
-        } else {
-            // TODO refactor with oncommand fn
-            if msg.target.is_none() {
-                self.queued_events.push_back(RpcDhtEvent::RequestResult(Err(
-                    RequestError::MissingTarget { peer, msg },
-                )));
-                return;
-            }
-            if let Some(key) = msg.valid_target_id_bytes() {
-                msg.error = Some("Unsupported command".to_string());
-                self.reply(msg, peer.clone(), key);
-            }
+        } else if let Some(key) = msg.valid_target_id_bytes() {
+            let closer_nodes =
+                self.closer_nodes(key, usize::from(self.queries.replication_factor()), &peer);
+            self.io.error(
+                msg,
+                ERR_UNSUPPORTED_COMMAND.to_string(),
+                None,
+                Some(closer_nodes),
+                peer.clone(),
+            );
             self.queued_events.push_back(RpcDhtEvent::RequestResult(Err(
                 RequestError::MissingCommand { peer },
             )));
+        } else {
+            self.io.error(
+                msg.clone(),
+                ERR_TARGET_REQUIRED.to_string(),
+                None,
+                None,
+                peer.clone(),
+            );
+            self.queued_events.push_back(RpcDhtEvent::RequestResult(Err(
+                RequestError::MissingTarget {
+                    peer,
+                    msg: Box::new(msg),
+                },
+            )));
         }
     }
 
     /// Handle a ping request
     fn on_ping(&mut self, msg: Message, peer: Peer) {
         if let Some(ref val) = msg.value {
-            if self.id.preimage() != val {
+            if self.id.preimage().0[..] != val[..] {
                 // ping wasn't meant for this node
                 self.queued_events.push_back(RpcDhtEvent::RequestResult(Err(
-                    RequestError::InvalidValue { peer, msg },
+                    RequestError::InvalidValue {
+                        peer,
+                        msg: Box::new(msg),
+                    },
                 )));
                 return;
             }
         }
-        self.io.response(msg, Some(peer.encode()), None, peer);
+        self.io
+            .response(msg, Some(peer.encode().into()), None, peer);
+    }
+
+    /// Whether the request is within the rate limit of its source address.
+    /// Whether the peer filter, if any, allows to talk to the node.
+    #[inline]
+    fn allows(&self, id: &[u8], addr: &SocketAddr) -> bool {
+        self.peer_filter
+            .as_ref()
+            .is_none_or(|filter| filter.allows(id, addr))
+    }
+
+    fn check_rate_limit(&mut self, msg: &Message, peer: &Peer) -> bool {
+        let mut limit = match self.rate_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        if msg.is_ping() || msg.is_find_node() {
+            if let Some(id) = msg.valid_id_bytes() {
+                if let Entry::Present(mut entry, _) = self.kbuckets.entry(&Key::new(id)) {
+                    if entry.value().addr == peer.addr {
+                        limit = limit.scale(KNOWN_NODE_ALLOWANCE);
+                    }
+                }
+            }
+        }
+        self.rate_limiter.check(peer.addr, limit, time::now())
     }
 
     /// Handle an incoming find peers request.
     ///
     /// Reply only if the remote provided a target to get the closest nodes for.
+    /// Replies with the closest nodes we know of, even if there are none, so
+    /// that the requester learns our id and its own address.
     fn on_findnode(&mut self, msg: Message, peer: Peer) {
         if let Some(key) = msg.valid_target_id_bytes() {
-            let closer_nodes = self.closer_nodes(key, usize::from(K_VALUE));
+            let closer_nodes =
+                self.closer_nodes(key, usize::from(self.queries.replication_factor()), &peer);
             self.io.response(msg, None, Some(closer_nodes), peer);
+        } else {
+            self.io.error(
+                msg.clone(),
+                ERR_TARGET_REQUIRED.to_string(),
+                None,
+                None,
+                peer.clone(),
+            );
+            self.queued_events.push_back(RpcDhtEvent::RequestResult(Err(
+                RequestError::MissingTarget {
+                    msg: Box::new(msg),
+                    peer,
+                },
+            )));
         }
     }
 
@@ -661,15 +1996,20 @@ impl RpcDht {
         if let Some(value) = msg.decode_holepunch() {
             if value.to.is_some() {
                 if let Some(to) = value.decode_to_peer() {
-                    if to == peer.addr {
-                        // don't forward to self
+                    if to == peer.addr || self.local_addr().is_ok_and(|addr| addr == to) {
+                        // don't forward back to the requester or to ourselves
                         return;
                     }
+                    // relay the request to the target, which then responds
+                    // to the requester directly
                     msg.version = Some(VERSION);
                     msg.id = self.io.msg_id();
                     msg.to = Some(to.encode());
                     msg.set_holepunch(&Holepunch::with_from(peer.encode()));
-                    self.io.send_message(MessageEvent::Response { msg, peer });
+                    self.io.send_message(MessageEvent::Untracked {
+                        msg,
+                        peer: Peer::from(to),
+                    });
                     return;
                 } else {
                     return;
@@ -685,40 +2025,244 @@ impl RpcDht {
     /// Reply to a custom command query.
     pub fn reply_command(&mut self, resp: impl Into<CommandQueryResponse>) {
         let resp = resp.into();
-        self.reply(resp.msg, resp.peer, resp.target)
+        self.pending_replies
+            .remove(&(resp.peer.addr, resp.msg.get_request_id()));
+        self.reply(resp.msg, resp.peer, &resp.command, resp.target)
+    }
+
+    /// Handles a reply of a [`ResponseSender`].
+    ///
+    /// A dropped sender may still be answered with [`RpcDht::reply_command`]
+    /// for half of our request timeout, then the request is answered with
+    /// [`ERR_NO_REPLY`] while a requester with the same timeout still waits
+    /// for it.
+    fn on_reply(&mut self, reply: Reply, now: Instant) {
+        match reply {
+            Reply::Response(resp) => self.reply_command(*resp),
+            Reply::Dropped(query) => {
+                let key = (query.peer.addr, query.rid);
+                if let Some(pending) = self.pending_replies.get_mut(&key) {
+                    let deadline = now + self.io.default_request_timeout() / 2;
+                    *pending = Some((deadline, query));
+                    self.dropped_replies.push_back((deadline, key));
+                }
+            }
+        }
+    }
+
+    /// Answers the requests that were dropped without a reply for too long
+    /// with [`ERR_NO_REPLY`].
+    fn expire_replies(&mut self, now: Instant) {
+        while let Some((deadline, key)) = self.dropped_replies.front().copied() {
+            if deadline > now {
+                break;
+            }
+            self.dropped_replies.pop_front();
+            // a request of the same peer and id may have arrived again since
+            let expired = matches!(
+                self.pending_replies.get(&key),
+                Some(Some((deadline, _))) if *deadline <= now
+            );
+            if !expired {
+                continue;
+            }
+            if let Some(Some((_, query))) = self.pending_replies.remove(&key) {
+                log::debug!(
+                    "Request {} from {} was dropped without a reply",
+                    query.rid.0,
+                    key.0
+                );
+                self.reply_command(query.into_response_with_error(ERR_NO_REPLY));
+            }
+        }
     }
 
-    fn reply(&mut self, mut msg: Message, peer: Peer, key: IdBytes) {
-        msg.closer_nodes = Some(self.closer_nodes(key, usize::from(K_VALUE)));
+    fn reply(&mut self, mut msg: Message, peer: Peer, command: &str, key: IdBytes) {
+        let closer_nodes = self.closer_nodes(
+            key.clone(),
+            usize::from(self.queries.replication_factor()),
+            &peer,
+        );
+        msg.closer_nodes = Some(closer_nodes.nodes.into());
+        msg.closer_nodes6 = closer_nodes.nodes6.map(Bytes::from);
         if msg.error.is_some() {
             let _ = msg.value.take();
         }
-        self.io.reply(msg, peer)
+        self.io.reply(msg, peer, command, &key)
     }
 
-    /// Get the `num` closest nodes in the bucket.
-    fn closer_nodes(&mut self, key: IdBytes, num: usize) -> Vec<u8> {
-        let nodes = self
+    /// Get the `num` closest nodes in the bucket of every address family the
+    /// requester can reach.
+    ///
+    /// IPv6 nodes are only included for IPv6 requesters, so that legacy IPv4
+    /// remotes never see them. The nodes with the lowest round trip time come
+    /// first.
+    ///
+    /// For our own id we come first ourselves, so that a lookup of our id
+    /// finds us even if the nodes on the way don't know us.
+    fn closer_nodes(&mut self, key: IdBytes, num: usize, requester: &Peer) -> CloserNodes {
+        let own = if key == *self.id.preimage() {
+            self.own_node_addr()
+        } else {
+            None
+        };
+        let encode_own = |addr: SocketAddr| [self.id.preimage().as_ref(), &addr.encode()].concat();
+        let own_v4 = own.filter(SocketAddr::is_ipv4).map(encode_own);
+        let own_v6 = own.filter(SocketAddr::is_ipv6).map(encode_own);
+
+        let key = KeyBytes::new(key);
+        let rtt = self.queries.selection_rtt();
+        let fastest = |entry: &kbucket::EntryView<Key<IdBytes>, Node>| {
+            rtt.and_then(|rtt| rtt.get(entry.node.key.preimage()))
+                .unwrap_or(Duration::MAX)
+        };
+        let mut nodes = self
             .kbuckets
-            .closest(&KeyBytes::new(key))
-            .take(num)
+            .closest(&key)
+            .filter(|entry| entry.node.value.addr.is_ipv4())
+            .take(num.saturating_sub(own_v4.is_some() as usize))
             .collect::<Vec<_>>();
-        PeersEncoding::encode(&nodes)
+        nodes.sort_by_key(fastest);
+        let nodes6 = if requester.addr.is_ipv6() {
+            let mut nodes6 = self
+                .kbuckets
+                .closest(&key)
+                .filter(|entry| entry.node.value.addr.is_ipv6())
+                .take(num.saturating_sub(own_v6.is_some() as usize))
+                .collect::<Vec<_>>();
+            nodes6.sort_by_key(fastest);
+            let mut buf = own_v6.unwrap_or_default();
+            buf.extend(encode_nodes6(&nodes6));
+            Some(buf).filter(|buf| !buf.is_empty())
+        } else {
+            None
+        };
+        let mut buf = own_v4.unwrap_or_default();
+        buf.extend(PeersEncoding::encode(&nodes));
+        CloserNodes { nodes: buf, nodes6 }
+    }
+
+    /// The address to answer lookups of our own id with: the confirmed
+    /// external address, otherwise the local address of the socket.
+    ///
+    /// `None` for an ephemeral node, which no one should add to their
+    /// routing table.
+    fn own_node_addr(&self) -> Option<SocketAddr> {
+        if self.is_ephemeral() {
+            return None;
+        }
+        self.external_addr()
+            .or_else(|| self.local_addr().ok())
+            .filter(|addr| !addr.ip().is_unspecified())
+    }
+
+    /// Binds the socket again after an error of the socket itself, e.g. its
+    /// file descriptor was closed.
+    ///
+    /// After [`MAX_REBIND_ATTEMPTS`] rebinds in a row without a message
+    /// received in between the socket is considered failed and the stream
+    /// ends after [`RpcDhtEvent::SocketError`]. A custom transport is
+    /// replaced by its [`Transport::rebind`], or considered failed right away
+    /// if it can't be bound again.
+    fn on_socket_error(&mut self, err: std::io::Error) {
+        if self.socket_failed {
+            return;
+        }
+        if self.rebind_attempts >= MAX_REBIND_ATTEMPTS {
+            return self.give_up_socket(err);
+        }
+        let rebound = if self.custom_transport {
+            // a custom transport is replaced by one of its own kind
+            match self.io.rebind() {
+                Ok(transport) => {
+                    self.rebind_transport_boxed(transport);
+                    Ok(())
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                    return self.give_up_socket(err);
+                }
+                Err(e) => Err(e),
+            }
+        } else {
+            self.rebind(None)
+        };
+        self.rebind_attempts += 1;
+        log::warn!("Binding the socket again after: {}", err);
+        match rebound.and_then(|_| self.local_addr()) {
+            Ok(addr) => self.queued_events.push_back(RpcDhtEvent::Rebound { addr }),
+            Err(err) => log::warn!("Failed to bind the socket again: {}", err),
+        }
+    }
+
+    fn give_up_socket(&mut self, err: std::io::Error) {
+        log::error!("Giving up on the socket: {}", err);
+        self.socket_failed = true;
+        self.queued_events
+            .push_back(RpcDhtEvent::SocketError { err });
+    }
+
+    /// Reports a holepunch or a ping that won't be answered.
+    fn report_unanswered(&mut self, msg: &Message, peer: &Peer) {
+        let err = if msg.is_holepunch() {
+            ResponseError::HolepunchTimeout(peer.clone())
+        } else if msg.is_ping() {
+            ResponseError::PingTimeout(peer.clone())
+        } else {
+            return;
+        };
+        self.queued_events
+            .push_back(RpcDhtEvent::ResponseResult(Err(err)));
     }
 
     /// Handle the event generated from the underlying IO
-    fn inject_event(&mut self, event: IoHandlerEvent<QueryId>) {
+    fn inject_event(&mut self, event: IoHandlerEvent<Option<QueryId>>) {
         match event {
             IoHandlerEvent::OutResponse { .. } => {}
-            IoHandlerEvent::OutSocketErr { .. } => {}
+            IoHandlerEvent::OutSocketErr { err } => {
+                log::debug!("Failed to send a response or untracked request: {}", err);
+                if !io::is_transient_send(&err) {
+                    self.on_socket_error(err);
+                }
+            }
+            IoHandlerEvent::OutRequestErr {
+                msg,
+                peer,
+                user_data,
+                err,
+            } => {
+                log::debug!("Failed to send a request to {}: {}", peer.addr, err);
+                self.report_unanswered(&msg, &peer);
+                if let Some(query) = user_data.and_then(|id| self.queries.get_mut(&id)) {
+                    query.on_rejected(peer.clone());
+                }
+                self.disconnect_node(&peer);
+                if !io::is_transient_send(&err) {
+                    self.on_socket_error(err);
+                }
+            }
             IoHandlerEvent::InRequest { msg, peer, ty } => {
+                self.rebind_attempts = 0;
                 self.on_request(msg, peer, ty);
             }
+            IoHandlerEvent::InRequestInvalidToken { msg, peer } => {
+                self.queued_events.push_back(RpcDhtEvent::RequestResult(Err(
+                    RequestError::InvalidToken {
+                        msg: Box::new(msg),
+                        peer,
+                    },
+                )));
+            }
             IoHandlerEvent::InMessageErr { .. } => {}
-            IoHandlerEvent::InSocketErr { .. } => {}
-            IoHandlerEvent::InResponseBadRequestId { peer, .. } => {
-                // received a response that did not match any issued requests
-                self.remove_node(&peer);
+            IoHandlerEvent::InSocketErr { err } => self.on_socket_error(err),
+            IoHandlerEvent::InHolepunchResponse { .. } => {}
+            IoHandlerEvent::InResponseBadRequestId { peer, msg } => {
+                // received a response that did not match any issued requests,
+                // e.g. a late or duplicate response to a retried request
+                log::debug!(
+                    "Dropping response with unknown request id {} from {}",
+                    msg.rid,
+                    peer.addr
+                );
             }
             IoHandlerEvent::OutRequest { .. } => {
                 // sent a request
@@ -727,18 +2271,27 @@ impl RpcDht {
                 req,
                 resp,
                 peer,
+                rtt,
                 user_data,
             } => {
-                self.on_response(req, resp, peer, user_data);
+                self.rebind_attempts = 0;
+                self.health.on_response();
+                self.on_response(req, resp, peer, rtt, user_data);
             }
             IoHandlerEvent::RequestTimeout {
-                msg: _,
+                msg,
                 peer,
                 sent: _,
                 user_data,
             } => {
-                if let Some(query) = self.queries.get_mut(&user_data) {
-                    query.on_timeout(peer);
+                self.report_unanswered(&msg, &peer);
+                if let Some(query) = user_data.and_then(|id| self.queries.get_mut(&id)) {
+                    query.on_timeout(peer.clone());
+                }
+                self.disconnect_node(&peer);
+                if let Some((since, timeouts)) = self.health.on_timeout(time::now()) {
+                    self.queued_events
+                        .push_back(RpcDhtEvent::NetworkSuspect { since, timeouts });
                 }
             }
         }
@@ -753,7 +2306,7 @@ impl RpcDht {
                 target,
                 value,
             } => {
-                self.io.query(command, Some(target), value, peer, id);
+                self.io.query(command, Some(target), value, peer, Some(id));
             }
             QueryEvent::RemoveNode { id } => {
                 self.remove_peer(&Key::new(id));
@@ -769,7 +2322,7 @@ impl RpcDht {
                 token,
             } => {
                 self.io
-                    .update(command, Some(target), value, peer, token, id);
+                    .update(command, Some(target), value, peer, token, Some(id));
             }
         }
     }
@@ -784,7 +2337,11 @@ impl RpcDht {
         for (peer, state) in result.peers {
             match state {
                 PeerState::Failed => {
-                    self.remove_peer(&Key::new(peer.id));
+                    if let Some(entry) = self.remove_peer(&Key::new(peer.id)) {
+                        self.queued_events.push_back(RpcDhtEvent::NodeRemoved {
+                            peer: PeerId::new(peer.addr, entry.node.key.into_preimage()),
+                        });
+                    }
                 }
                 PeerState::Succeeded {
                     roundtrip_token,
@@ -796,9 +2353,30 @@ impl RpcDht {
             }
         }
 
+        if let Some(refresh) = self.refresh.finished(&result.inner) {
+            let new_nodes = self
+                .bucket_keys(refresh.index)
+                .iter()
+                .filter(|key| !refresh.known.contains(key))
+                .count();
+            return RpcDhtEvent::RefreshCompleted {
+                bucket_index: refresh.index,
+                new_nodes,
+            };
+        }
+
+        let bootstrap_failed = result.bootstrap == Some(FixedPeersOutcome::Failed);
+        if bootstrap_failed {
+            log::warn!("None of the bootstrap nodes responded");
+        }
+
         // first `find_node` query is issued as bootstrap
-        if is_find_node && !self.bootstrapped {
-            self.bootstrapped = true;
+        if is_find_node && !self.bootstrapped && bootstrap_failed && result.closest.is_empty() {
+            RpcDhtEvent::BootstrapFailed {
+                stats: result.stats,
+            }
+        } else if is_find_node && !self.bootstrapped {
+            self.bootstrapped = true;
             RpcDhtEvent::Bootstrapped {
                 stats: result.stats,
             }
@@ -807,6 +2385,37 @@ impl RpcDht {
                 id: result.inner,
                 cmd: result.cmd,
                 stats: result.stats,
+                closest: result.closest,
+                values: result.values,
+                errors: result.errors,
+            }
+        }
+    }
+
+    /// Makes sure the task is woken up once the next query or dropped reply
+    /// times out, even if no message arrives in the meantime.
+    fn poll_query_timer(&mut self, cx: &mut Context<'_>) {
+        let next_reply = self.dropped_replies.front().map(|(deadline, _)| *deadline);
+        let deadline = if let Some(deadline) = self
+            .queries
+            .next_timeout()
+            .into_iter()
+            .chain(next_reply)
+            .min()
+        {
+            deadline
+        } else {
+            self.query_timer = None;
+            return;
+        };
+        match &mut self.query_timer {
+            Some((at, _)) if *at == deadline => {}
+            timer => *timer = Some((deadline, Delay::new_at(deadline))),
+        }
+        if let Some((_, delay)) = &mut self.query_timer {
+            if Future::poll(Pin::new(delay), cx).is_ready() {
+                self.query_timer = None;
+                cx.waker().wake_by_ref();
             }
         }
     }
@@ -822,43 +2431,108 @@ impl Stream for RpcDht {
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let pin = self.get_mut();
+        if pin.socket_failed {
+            return Poll::Ready(pin.queued_events.pop_front());
+        }
 
-        let now = Instant::now();
+        let now = time::now();
 
-        if let Poll::Ready(()) = pin.bootstrap_job.poll(cx, now) {
-            if pin.kbuckets.iter().count() < 20 {
-                pin.bootstrap();
+        if !pin.shutting_down {
+            if let Poll::Ready(()) = pin.bootstrap_job.poll(cx, now) {
+                if pin.kbuckets.iter().count() < 20 {
+                    pin.bootstrap();
+                }
             }
+
+            if let Poll::Ready(()) = pin.ping_job.poll(cx, now) {
+                if pin.ping_nodes {
+                    pin.ping_some()
+                }
+            }
+
+            if let Poll::Ready(()) = pin.refresh.job.poll(cx, now) {
+                pin.refresh_buckets(now);
+            }
+        }
+
+        while let Poll::Ready(Some(reply)) = Stream::poll_next(Pin::new(&mut pin.reply_rx), cx) {
+            pin.on_reply(reply, now);
         }
 
-        if let Poll::Ready(()) = pin.ping_job.poll(cx, now) {
-            pin.ping_some()
+        if let Some(Poll::Ready(reachable)) = pin.nat_probe.as_mut().map(|probe| probe.poll(cx)) {
+            pin.nat_probe = None;
+            pin.set_nat_status(NatStatus {
+                firewalled: Some(!reachable),
+                ..pin.nat_status
+            });
         }
+        if !pin.shutting_down {
+            pin.poll_adaptive(cx);
+        }
+        pin.expire_replies(now);
 
         loop {
+            // Pending nodes that replaced unresponsive nodes
+            while let Some(applied) = pin.kbuckets.take_applied_pending() {
+                trace_event!(
+                    peer = %applied.inserted.value.addr,
+                    evicted = ?applied.evicted.as_ref().map(|n| n.value.addr),
+                    "Added pending node to the routing table"
+                );
+                pin.queued_events.push_back(RpcDhtEvent::RoutingUpdated {
+                    peer: Peer::from(applied.inserted.value.addr),
+                    old_peer: applied
+                        .evicted
+                        .map(|n| PeerId::new(n.value.addr, n.key.into_preimage())),
+                });
+            }
+
             // Drain queued events first.
             if let Some(event) = pin.queued_events.pop_front() {
                 return Poll::Ready(Some(event));
             }
 
             // Look for a sent/received message
+            let mut budget = MAX_IO_EVENTS_PER_POLL;
             loop {
-                if let Poll::Ready(Some(event)) = Stream::poll_next(Pin::new(&mut pin.io), cx) {
+                let io_event = if budget > 0 {
+                    Stream::poll_next(Pin::new(&mut pin.io), cx)
+                } else {
+                    Poll::Pending
+                };
+                if let Poll::Ready(Some(event)) = io_event {
+                    budget -= 1;
                     pin.inject_event(event);
                     if let Some(event) = pin.queued_events.pop_front() {
                         return Poll::Ready(Some(event));
                     }
                 } else {
-                    match pin.queries.poll(now) {
+                    // the next request of a query would only push out another
+                    // message, hold it back until the socket caught up, or until
+                    // responses free up slots of the requests in flight, but
+                    // keep reporting queries that finished or timed out
+                    pin.queries.set_request_deadline(pin.io.request_deadline());
+                    // pings and holepunches don't belong to a query
+                    let dispatch = pin.io.has_send_capacity() && pin.io.has_request_capacity();
+                    let state = if dispatch {
+                        pin.queries.poll(now)
+                    } else {
+                        pin.queries.poll_finished(now)
+                    };
+                    match state {
                         QueryPoolState::Waiting(Some((query, event))) => {
                             let id = query.id();
+                            #[cfg(feature = "tracing")]
+                            let _span = query.span().clone().entered();
                             pin.inject_query_event(id, event);
                         }
                         QueryPoolState::Finished(q) => {
+                            trace_event!(parent: q.span(), stats = ?q.stats(), "Query finished");
                             let event = pin.query_finished(q);
                             return Poll::Ready(Some(event));
                         }
                         QueryPoolState::Timeout(q) => {
+                            trace_event!(parent: q.span(), stats = ?q.stats(), "Query timed out");
                             let event = pin.query_timeout(q);
                             return Poll::Ready(Some(event));
                         }
@@ -873,6 +2547,11 @@ impl Stream for RpcDht {
             // If no new events have been queued either, signal `Pending` to
             // be polled again later.
             if pin.queued_events.is_empty() {
+                if budget == 0 {
+                    // the socket was not drained, continue after other tasks
+                    cx.waker().wake_by_ref();
+                }
+                pin.poll_query_timer(cx);
                 return Poll::Pending;
             }
         }
@@ -886,15 +2565,15 @@ pub struct Peer {
     pub referrer: Option<SocketAddr>,
 }
 
-impl Into<Holepunch> for &Peer {
-    fn into(self) -> Holepunch {
-        Holepunch::with_from(self.encode())
+impl From<&Peer> for Holepunch {
+    fn from(peer: &Peer) -> Self {
+        Holepunch::with_from(peer.encode())
     }
 }
 
-impl Into<Holepunch> for SocketAddr {
-    fn into(self) -> Holepunch {
-        let peer = Peer::from(self);
+impl From<SocketAddr> for Holepunch {
+    fn from(addr: SocketAddr) -> Self {
+        let peer = Peer::from(addr);
         (&peer).into()
     }
 }
@@ -932,6 +2611,47 @@ impl IdBytes {
     pub fn to_vec(&self) -> Vec<u8> {
         self.0.to_vec()
     }
+
+    /// The id of a public key, its 32 byte `blake2b` hash as used by
+    /// hyperswarm for topics and mutable values.
+    pub fn hash_public_key(key: &PublicKey) -> Self {
+        crate::crypto::hash_id(key.as_bytes())
+    }
+}
+
+/// Displays the id as 64 hex characters.
+impl fmt::Display for IdBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        crate::trace::Hex(&self.0).fmt(f)
+    }
+}
+
+/// Error parsing an [`IdBytes`] from anything but 64 hex characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseIdError;
+
+impl fmt::Display for ParseIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("id must be 64 hex characters")
+    }
+}
+
+impl std::error::Error for ParseIdError {}
+
+impl std::str::FromStr for IdBytes {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // `from_str_radix` would accept a leading '+' of each pair
+        if s.len() != PUBLIC_KEY_LENGTH * 2 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseIdError);
+        }
+        let mut id = [0; PUBLIC_KEY_LENGTH];
+        for (i, b) in id.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| ParseIdError)?;
+        }
+        Ok(Self(id))
+    }
 }
 
 impl PartialEq<Vec<u8>> for IdBytes {
@@ -958,9 +2678,15 @@ impl From<&PublicKey> for IdBytes {
     }
 }
 
-impl From<&GenericArray<u8, U32>> for IdBytes {
-    fn from(digest: &GenericArray<u8, U32>) -> Self {
-        Self(digest.as_slice().try_into().expect("Wrong length"))
+impl From<&Output<Sha256>> for IdBytes {
+    fn from(digest: &Output<Sha256>) -> Self {
+        Self((*digest).into())
+    }
+}
+
+impl From<Output<Sha256>> for IdBytes {
+    fn from(digest: Output<Sha256>) -> Self {
+        Self(digest.into())
     }
 }
 
@@ -978,18 +2704,39 @@ impl TryFrom<&[u8]> for IdBytes {
     }
 }
 
+impl From<IdBytes> for Vec<u8> {
+    fn from(id: IdBytes) -> Self {
+        id.0.to_vec()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Node {
     /// Address of the peer.
     pub addr: SocketAddr,
     /// last roundtrip token in a req/resp exchanged with the peer
-    pub roundtrip_token: Option<Vec<u8>>,
+    pub roundtrip_token: Option<Bytes>,
     /// Decoded address of the `to` message field
     pub to: Option<SocketAddr>,
     /// When a new ping is due
     pub next_ping: Instant,
+    /// When we last heard from the peer
+    pub last_seen: Instant,
     /// Known referrers available for holepunching
     pub referrers: Vec<SocketAddr>,
+    /// Number of requests to the peer that timed out since we last heard
+    /// from it
+    pub timeouts: u32,
+}
+
+impl Node {
+    /// Records that we heard from the peer and postpones its next ping.
+    fn seen(&mut self, ping_interval: Duration) {
+        let now = time::now();
+        self.last_seen = now;
+        self.next_ping = now + ping_interval;
+        self.timeouts = 0;
+    }
 }
 
 impl Peer {
@@ -1022,16 +2769,17 @@ pub struct Response {
     pub peer: SocketAddr,
     /// Included identifier of the peer.
     pub peer_id: Option<IdBytes>,
+    /// The nodes that returned `peer` as one of their closer nodes during the
+    /// query, which can relay a holepunch to it.
+    pub referrers: Vec<SocketAddr>,
     /// response payload
-    pub value: Option<Vec<u8>>,
+    pub value: Option<Bytes>,
 }
 
 impl Response {
     /// Decodes an instance of the message from the response's value.
     pub fn decode_value<T: prost::Message + Default>(&self) -> Option<T> {
-        self.value
-            .as_ref()
-            .and_then(|val| T::decode(val.as_slice()).ok())
+        self.value.as_ref().and_then(|val| T::decode(&val[..]).ok())
     }
 }
 
@@ -1054,10 +2802,98 @@ pub enum RpcDhtEvent {
         /// room for the new peer, if any.
         old_peer: Option<PeerId>,
     },
+    /// A node of the routing table moved to a new address, after it answered
+    /// a ping there.
+    NodeAddressChanged {
+        /// The id of the node.
+        id: IdBytes,
+        /// The address the node was known at before.
+        old_addr: SocketAddr,
+        /// The new address of the node.
+        addr: SocketAddr,
+    },
+    /// A node with a new id took over the address of a node of the routing
+    /// table, which was evicted.
+    NodeIdChanged {
+        /// The address of the node.
+        addr: SocketAddr,
+        /// The id of the evicted node.
+        old_id: IdBytes,
+        /// The new id at the address.
+        id: IdBytes,
+    },
+    /// A node was removed from the routing table because it didn't respond
+    /// to our pings within the stale timeout, or [`MAX_NODE_TIMEOUTS`] of our
+    /// requests to it timed out in a row.
+    NodeRemoved {
+        /// The removed peer.
+        peer: PeerId,
+    },
     Bootstrapped {
         /// Execution statistics from the bootstrap query.
         stats: QueryStats,
     },
+    /// Neither a bootstrap node nor a node of the routing table responded to
+    /// the bootstrap query. The node bootstraps again after the
+    /// [`DhtConfig::bootstrap_interval`].
+    BootstrapFailed {
+        /// Execution statistics from the bootstrap query.
+        stats: QueryStats,
+    },
+    /// Enough remote peers reported the same address they see us at.
+    ExternalAddrConfirmed {
+        /// Our external address.
+        addr: SocketAddr,
+        /// The previously confirmed address, if the external address changed.
+        old_addr: Option<SocketAddr>,
+    },
+    /// What is known about our NAT changed, see [`RpcDht::nat_status`].
+    NatStatus { status: NatStatus },
+    /// Every request timed out for the configured window, see
+    /// [`DhtConfig::set_network_suspect_window`], although most requests
+    /// succeeded before.
+    ///
+    /// The network likely changed, the socket can be bound again with
+    /// [`RpcDht::rebind`].
+    NetworkSuspect {
+        /// When the first of the requests timed out.
+        since: Instant,
+        /// How many requests timed out since.
+        timeouts: u64,
+    },
+    /// The socket was bound again at `addr` after an error of the socket
+    /// itself.
+    ///
+    /// The requests that were in flight failed, the node bootstraps again.
+    Rebound { addr: SocketAddr },
+    /// The socket failed for good, binding it again didn't help, see
+    /// [`MAX_REBIND_ATTEMPTS`], or it is a custom transport that isn't bound
+    /// again, see [`DhtConfig::set_transport`].
+    ///
+    /// This is the last event of the stream.
+    SocketError { err: std::io::Error },
+    /// A bucket of the routing table without activity was refreshed, see
+    /// [`DhtConfig::set_bucket_refresh_interval`].
+    RefreshCompleted {
+        /// The index of the bucket, see [`BucketInfo::index`].
+        bucket_index: usize,
+        /// Number of nodes the refresh added to the bucket.
+        new_nodes: usize,
+    },
+    /// A query was stopped by [`RpcDht::cancel_query`].
+    QueryCancelled {
+        /// The ID of the cancelled query.
+        id: QueryId,
+        /// The command of the cancelled query.
+        cmd: Command,
+        /// Execution statistics until the query was cancelled.
+        stats: QueryStats,
+        /// The closest nodes that responded until the query was cancelled,
+        /// closest first.
+        closest: Vec<(PeerId, Bytes)>,
+        /// The values of the responses until the query was cancelled.
+        values: Vec<(SocketAddr, Bytes)>,
+    },
     /// A completed query.
     ///
     /// No more responses are expected for this query
@@ -1068,9 +2904,137 @@ pub enum RpcDhtEvent {
         cmd: Command,
         /// Execution statistics from the query.
         stats: QueryStats,
+        /// The closest nodes that responded with their roundtrip tokens,
+        /// closest first.
+        closest: Vec<(PeerId, Bytes)>,
+        /// The values of all responses with the node that sent them, in the
+        /// order they arrived.
+        values: Vec<(SocketAddr, Bytes)>,
+        /// The errors of all responses with the node that sent them, in the
+        /// order they arrived.
+        errors: Vec<(SocketAddr, String)>,
     },
 }
 
+/// A persistable snapshot of the routing table, see
+/// [`RpcDht::snapshot_nodes`].
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NodesSnapshot {
+    /// The id and address of every node.
+    pub nodes: Vec<(Vec<u8>, SocketAddr)>,
+}
+
+impl From<Vec<(Vec<u8>, SocketAddr)>> for NodesSnapshot {
+    fn from(nodes: Vec<(Vec<u8>, SocketAddr)>) -> Self {
+        Self { nodes }
+    }
+}
+
+impl IntoIterator for NodesSnapshot {
+    type Item = (Vec<u8>, SocketAddr);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.nodes.into_iter()
+    }
+}
+
+/// A node in the routing table, see [`RpcDht::nodes`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeInfo {
+    /// The id of the node.
+    pub id: IdBytes,
+    /// Address of the node.
+    pub addr: SocketAddr,
+    /// When we last heard from the node.
+    pub last_seen: Instant,
+    /// The smoothed round trip time, if the node responded to one of our
+    /// requests before.
+    pub rtt: Option<Duration>,
+}
+
+/// A bucket of the routing table, see [`RpcDht::bucket_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketInfo {
+    /// The index of the bucket, it holds the nodes whose XOR distance to the
+    /// local node in the keyspace has its highest bit at `index`.
+    pub index: usize,
+    /// Number of nodes in the bucket.
+    pub nodes: usize,
+    /// Whether a node waits for a slot in the full bucket.
+    pub pending: bool,
+}
+
+impl BucketInfo {
+    /// The smallest and the largest distance of a node in this bucket, as
+    /// big-endian bytes.
+    pub fn distances(&self) -> RangeInclusive<[u8; 32]> {
+        let (byte, bit) = (31 - self.index / 8, self.index % 8);
+        let mut min = [0u8; 32];
+        min[byte] = 1 << bit;
+        let mut max = [0u8; 32];
+        max[byte] = u8::MAX >> (7 - bit);
+        max[byte + 1..].fill(u8::MAX);
+        min..=max
+    }
+}
+
+/// Statistics about a running node.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DhtStats {
+    /// Number of nodes in the routing table.
+    pub nodes: usize,
+    /// Number of nodes in every bucket of the routing table.
+    pub buckets: Vec<usize>,
+    /// Number of currently running queries.
+    pub active_queries: usize,
+    /// Number of queries that wait for a running query to finish.
+    pub pending_queries: usize,
+    /// The merged stats of all queries that finished or timed out.
+    pub queries: QueryStats,
+    /// Number of queries that finished or timed out.
+    pub finished_queries: u64,
+    /// Number of received messages.
+    pub messages_in: u64,
+    /// Number of sent messages.
+    pub messages_out: u64,
+    /// Number of bytes of the received messages.
+    pub bytes_in: u64,
+    /// Number of bytes of the sent messages.
+    pub bytes_out: u64,
+    /// Number of received packets that were dropped because they were
+    /// malformed.
+    pub malformed_messages: u64,
+    /// Number of requests that were dropped because their source address
+    /// exceeded the rate limit.
+    pub rate_limited: u64,
+    /// Number of requests that were dropped by the peer filter.
+    pub filtered_requests: u64,
+    /// Number of requests that were sent again after they timed out.
+    pub retried_requests: u64,
+    /// Number of messages waiting to be sent.
+    pub send_queue: usize,
+    /// Number of requests of queries that are queued or wait for their
+    /// response.
+    pub requests_in_flight: usize,
+    /// Number of outgoing messages that were dropped because the send queue
+    /// was full.
+    pub dropped_messages: u64,
+    /// Number of stored announcements, only tracked by
+    /// [`HyperDht`](crate::HyperDht).
+    pub announcements: usize,
+    /// Number of stored mutable and immutable values, only tracked by
+    /// [`HyperDht`](crate::HyperDht).
+    pub stored_values: usize,
+    /// The confirmed external address.
+    pub external_addr: Option<SocketAddr>,
+    /// The median of the smoothed round trip times of all nodes that
+    /// responded.
+    pub median_rtt: Option<Duration>,
+}
+
 pub type RequestResult = Result<RequestOk, RequestError>;
 
 #[derive(Debug)]
@@ -1084,6 +3048,8 @@ pub enum RequestOk {
     CustomCommandRequest {
         /// The query we received and need to respond to
         query: CommandQuery,
+        /// Answers the query, e.g. from another task.
+        reply: ResponseSender,
     },
 }
 
@@ -1094,27 +3060,74 @@ pub enum RequestError {
         /// The unknown command
         command: String,
         /// The message we received from the peer.
-        msg: Message,
+        msg: Box<Message>,
         /// The peer the message originated from.
         peer: Peer,
     },
     /// The `target` field of message was required but was empty
-    MissingTarget { msg: Message, peer: Peer },
+    MissingTarget { msg: Box<Message>, peer: Peer },
     /// Received a message with a type other than [`Type::Query`],
     /// [`Type::Response`], [`Type::Update`]
-    InvalidType { ty: i32, msg: Message, peer: Peer },
+    InvalidType {
+        ty: i32,
+        msg: Box<Message>,
+        peer: Peer,
+    },
     /// Received a request with no command attached.
     MissingCommand { peer: Peer },
     /// Ignored Request due to message's value being this peer's id.
-    InvalidValue { msg: Message, peer: Peer },
+    InvalidValue { msg: Box<Message>, peer: Peer },
+    /// Received an update without a valid roundtrip token.
+    InvalidToken { msg: Box<Message>, peer: Peer },
+    /// Received a request with a value larger than the maximum value size.
+    ValueTooLarge { msg: Box<Message>, peer: Peer },
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::UnsupportedCommand { command, peer, .. } => {
+                write!(f, "Unsupported command `{}` from {}", command, peer.addr)
+            }
+            RequestError::MissingTarget { peer, .. } => {
+                write!(f, "Request without a valid target from {}", peer.addr)
+            }
+            RequestError::InvalidType { ty, peer, .. } => {
+                write!(f, "Message with invalid type {} from {}", ty, peer.addr)
+            }
+            RequestError::MissingCommand { peer } => {
+                write!(f, "Request without a command from {}", peer.addr)
+            }
+            RequestError::InvalidValue { peer, .. } => {
+                write!(f, "Request with an invalid value from {}", peer.addr)
+            }
+            RequestError::InvalidToken { peer, .. } => {
+                write!(
+                    f,
+                    "Update without a valid roundtrip token from {}",
+                    peer.addr
+                )
+            }
+            RequestError::ValueTooLarge { msg, peer } => write!(
+                f,
+                "Request with a value of {} bytes from {}",
+                msg.value.as_ref().map(Bytes::len).unwrap_or_default(),
+                peer.addr
+            ),
+        }
+    }
 }
 
+impl std::error::Error for RequestError {}
+
 pub type ResponseResult = Result<ResponseOk, ResponseError>;
 
 #[derive(Debug)]
 pub enum ResponseOk {
     /// Received a pong response to our ping request.
     Pong(Peer),
+    /// The target of our holepunch request responded directly.
+    Holepunched(Peer),
     /// A remote peer successfully responded to our query
     Response(Response),
 }
@@ -1123,8 +3136,38 @@ pub enum ResponseOk {
 pub enum ResponseError {
     /// We received a bad pong to our ping request
     InvalidPong(Peer),
+    /// The target of our holepunch request didn't respond.
+    HolepunchTimeout(Peer),
+    /// The peer didn't respond to our ping request.
+    PingTimeout(Peer),
+    /// A remote peer answered a request of a query with an error.
+    Remote {
+        /// The query the request belonged to.
+        query: QueryId,
+        /// The peer that sent the error.
+        peer: Peer,
+        /// The error text of the response.
+        error: String,
+    },
 }
 
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseError::InvalidPong(peer) => write!(f, "Invalid pong from {}", peer.addr),
+            ResponseError::HolepunchTimeout(peer) => {
+                write!(f, "Holepunch to {} timed out", peer.addr)
+            }
+            ResponseError::PingTimeout(peer) => write!(f, "Ping to {} timed out", peer.addr),
+            ResponseError::Remote { peer, error, .. } => {
+                write!(f, "Error response from {}: {}", peer.addr, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
 /// Fill the slice with random bytes
 #[inline]
 pub(crate) fn fill_random_bytes(dest: &mut [u8]) {
@@ -1133,6 +3176,1922 @@ pub(crate) fn fill_random_bytes(dest: &mut [u8]) {
         rngs::{OsRng, StdRng},
         RngCore,
     };
-    let mut rng = StdRng::from_rng(OsRng::default()).unwrap();
+    let mut rng = StdRng::from_rng(OsRng).unwrap();
     rng.fill_bytes(dest)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::peers::{decode_peer_ids, decode_peer_ids6};
+    use crate::testing::harness::{
+        self, bootstrapped, config, drive, next_event, pong, until, Remote,
+    };
+    use crate::testing::{sleep, spawn, timeout, Faulty, MemorySocket, Network, Simulation};
+
+    #[test]
+    fn bootstrap_populates_kbuckets() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(1);
+            let mut bs = harness::node(&network).await?;
+            assert!(bs.bootstrap().is_none());
+            match bs.next().await {
+                Some(RpcDhtEvent::Bootstrapped { stats }) => assert_eq!(stats.num_requests(), 0),
+                _ => panic!("expected bootstrap result first"),
+            }
+            let bs_addr = bs.local_addr()?;
+            let bs_id = bs.local_id().clone();
+            spawn(async move { while bs.next().await.is_some() {} });
+
+            let mut node =
+                RpcDht::with_config(config(&network).ephemeral().set_bootstrap_nodes(&[bs_addr]))
+                    .await?;
+            assert_eq!(bootstrapped(&mut node).await.num_successes(), 1);
+
+            let entries = node
+                .kbuckets
+                .iter()
+                .map(|e| (e.node.key.preimage().clone(), e.node.value.addr))
+                .collect::<Vec<_>>();
+            assert_eq!(entries, vec![(bs_id, bs_addr)]);
+
+            // any further bootstrap is a regular query
+            assert!(node.bootstrap().is_some());
+            Ok(())
+        })
+    }
+
+    #[async_std::test]
+    async fn bootstrap_over_ipv6() -> Result<(), Box<dyn std::error::Error>> {
+        async fn bootstrapped(config: DhtConfig) -> Result<RpcDht, Box<dyn std::error::Error>> {
+            let config = config.bind("[::1]:0").await.map_err(|(_, err)| err)?;
+            let mut dht = RpcDht::with_config(config).await?;
+            loop {
+                match dht.next().await {
+                    Some(RpcDhtEvent::Bootstrapped { .. }) => return Ok(dht),
+                    Some(_) => {}
+                    None => panic!("expected bootstrap result"),
+                }
+            }
+        }
+        let mut bs = bootstrapped(DhtConfig::default().empty_bootstrap_nodes()).await?;
+        let bs_addr = bs.local_addr()?;
+        let bs_id = bs.local_id().clone();
+        async_std::task::spawn(async move { while bs.next().await.is_some() {} });
+
+        let mut node = bootstrapped(DhtConfig::default().set_bootstrap_nodes(&[bs_addr])).await?;
+        let node_addr = node.local_addr()?;
+        let node_id = node.local_id().clone();
+        async_std::task::spawn(async move { while node.next().await.is_some() {} });
+
+        // learns about the other node from the `closer_nodes6` of the
+        // bootstrap node
+        let client = bootstrapped(
+            DhtConfig::default()
+                .ephemeral()
+                .set_bootstrap_nodes(&[bs_addr]),
+        )
+        .await?;
+        let mut entries = client
+            .kbuckets
+            .iter_ref()
+            .map(|e| (e.node.key.preimage().clone(), e.node.value.addr))
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(_, addr)| *addr);
+        let mut expected = vec![(bs_id, bs_addr), (node_id, node_addr)];
+        expected.sort_by_key(|(_, addr)| *addr);
+        assert_eq!(entries, expected);
+        assert!(bs_addr.is_ipv6());
+        Ok(())
+    }
+
+    #[test]
+    fn query_timeout_wakes_up() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(2);
+            // nobody is listening on the bootstrap address
+            let bs_addr = network.bind().addr();
+            let mut node = RpcDht::with_config(
+                config(&network)
+                    .set_request_timeout(Duration::from_secs(10))
+                    .set_query_timeout(Duration::from_millis(100))
+                    .set_bootstrap_nodes(&[bs_addr]),
+            )
+            .await?;
+
+            let start = time::now();
+            let stats = bootstrapped(&mut node).await;
+            assert_eq!(stats.num_successes(), 0);
+            assert!(stats.duration().unwrap() >= Duration::from_millis(100));
+            // long before the request would have timed out
+            assert!(time::now() - start < Duration::from_secs(1));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn query_timeout_while_requests_held_back() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(3);
+            // the bootstrap nodes never respond, the first request of each
+            // query takes one of the two slots and the second one is held back
+            let remotes = [Remote::bind(&network), Remote::bind(&network)];
+            let addrs = [remotes[0].addr(), remotes[1].addr()];
+            let mut node = RpcDht::with_config(
+                config(&network)
+                    .set_max_requests_in_flight(2)
+                    .set_request_timeout(Duration::from_secs(10))
+                    .set_query_timeout(Duration::from_millis(200))
+                    .set_bootstrap_nodes(&addrs),
+            )
+            .await?;
+            // pings don't count towards the limit
+            node.ping(&remotes[0].peer_id());
+            let query = node.query(Command::FindNode, Key::new(IdBytes::random()), None);
+
+            let start = time::now();
+            let (mut bootstrapped, mut queried) = (None, None);
+            while bootstrapped.is_none() || queried.is_none() {
+                match node.next().await {
+                    Some(RpcDhtEvent::Bootstrapped { stats }) => bootstrapped = Some(stats),
+                    Some(RpcDhtEvent::QueryResult { id, stats, .. }) if id == query => {
+                        queried = Some(stats)
+                    }
+                    Some(_) => {}
+                    None => panic!("the node stopped"),
+                }
+                assert!(node.stats().requests_in_flight <= 2);
+            }
+            // both time out long before their requests do
+            assert!(time::now() - start < Duration::from_secs(5));
+            assert_eq!(bootstrapped.unwrap().num_requests(), 1);
+            assert_eq!(queried.unwrap().num_requests(), 1);
+            Ok(())
+        })
+    }
+
+    /// Creates `num` ids that all fall into the farthest bucket of the dht.
+    fn farthest_bucket_ids(dht: &mut RpcDht, num: usize) -> Vec<IdBytes> {
+        let mut ids = Vec::with_capacity(num);
+        while ids.len() < num {
+            let id = IdBytes::random();
+            let distance = dht.id.distance(&Key::new(id.clone()));
+            if dht.kbuckets.buckets().last().unwrap().contains(&distance) {
+                ids.push(id);
+            }
+        }
+        ids
+    }
+
+    /// Sends a `find_node` request for `target` from the `remote` to the
+    /// `node` and returns the response.
+    async fn find_node(
+        node: &mut RpcDht,
+        remote: &mut Remote,
+        target: Vec<u8>,
+    ) -> Result<Message, Box<dyn std::error::Error>> {
+        let msg = Message {
+            rid: 1,
+            target: Some(target.into()),
+            ..harness::query(Command::FindNode, None)
+        };
+        remote.send(&msg, node.local_addr()?).await?;
+        Ok(drive(node, remote.recv()).await?.0)
+    }
+
+    #[test]
+    fn find_node_empty_table() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(4);
+            let mut dht = harness::node(&network).await?;
+            let id = dht.local_id().clone();
+            let mut remote = Remote::bind(&network);
+
+            let resp = find_node(&mut dht, &mut remote, IdBytes::random().to_vec()).await?;
+            assert_eq!(resp.error, None);
+            assert_eq!(resp.id, Some(id.to_vec()));
+            assert_eq!(resp.decode_to_peer(), Some(remote.addr()));
+            assert!(resp.decode_closer_nodes().is_empty());
+
+            let resp = find_node(&mut dht, &mut remote, vec![0; 16]).await?;
+            assert_eq!(resp.error.as_deref(), Some(ERR_TARGET_REQUIRED));
+            assert_eq!(resp.decode_to_peer(), Some(remote.addr()));
+            Ok(())
+        })
+    }
+
+    async fn full_bucket_dht(network: &Network) -> std::io::Result<(RpcDht, Vec<IdBytes>)> {
+        let mut dht = RpcDht::with_config(
+            config(network)
+                .empty_bootstrap_nodes()
+                .set_kbucket_pending_timeout(Duration::from_millis(50)),
+        )
+        .await?;
+        let ids = farthest_bucket_ids(&mut dht, K_VALUE.get() + 1);
+        for (port, id) in ids.iter().enumerate() {
+            let addr: SocketAddr = ([127, 0, 0, 1], 1000 + port as u16).into();
+            dht.add_node(id.clone(), Peer::from(addr), None, None);
+        }
+        Ok((dht, ids))
+    }
+
+    #[test]
+    fn evict_unresponsive_node() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(5);
+            let (mut dht, ids) = full_bucket_dht(&network).await?;
+            let oldest = Key::new(ids[0].clone());
+            let newest = Key::new(ids[K_VALUE.get()].clone());
+
+            assert!(matches!(
+                dht.kbuckets.entry(&oldest),
+                Entry::Present(_, NodeStatus::Disconnected)
+            ));
+            assert!(matches!(dht.kbuckets.entry(&newest), Entry::Pending(..)));
+
+            // the oldest node never responds to the ping
+            let old_peer = next_event(&mut dht, |event| match event {
+                RpcDhtEvent::RoutingUpdated {
+                    old_peer: Some(old_peer),
+                    ..
+                } => Some(old_peer),
+                _ => None,
+            })
+            .await;
+            assert_eq!(old_peer.id, ids[0]);
+            assert!(matches!(dht.kbuckets.entry(&oldest), Entry::Absent(_)));
+            assert!(matches!(dht.kbuckets.entry(&newest), Entry::Present(..)));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn keep_responsive_node() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(6);
+            let (mut dht, ids) = full_bucket_dht(&network).await?;
+            let oldest = Key::new(ids[0].clone());
+            let newest = Key::new(ids[K_VALUE.get()].clone());
+
+            dht.on_pong(pong(&ids[0]), Peer::from(([127, 0, 0, 1], 1000)));
+            assert!(matches!(
+                dht.kbuckets.entry(&oldest),
+                Entry::Present(_, NodeStatus::Connected)
+            ));
+
+            // the pending node expires instead of taking the place
+            sleep(Duration::from_millis(100)).await;
+            assert!(matches!(dht.kbuckets.entry(&oldest), Entry::Present(..)));
+            assert!(matches!(dht.kbuckets.entry(&newest), Entry::Absent(_)));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn remove_stale_nodes() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(7);
+            let live = harness::spawn_node(config(&network).empty_bootstrap_nodes()).await?;
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .ping_interval(Duration::from_millis(20))
+                    .set_node_stale_timeout(Duration::from_millis(100)),
+            )
+            .await?;
+
+            // nobody is listening on the address of the dead node
+            let dead_addr = network.bind().addr();
+            let dead_id = IdBytes::random();
+            dht.add_node(live.id.clone(), Peer::from(live.addr), None, None);
+            dht.add_node(dead_id.clone(), Peer::from(dead_addr), None, None);
+
+            let removed = next_event(&mut dht, |event| match event {
+                RpcDhtEvent::NodeRemoved { peer } => Some(peer),
+                _ => None,
+            })
+            .await;
+            assert_eq!(removed.id, dead_id);
+            assert_eq!(removed.addr, dead_addr);
+
+            let remaining = dht
+                .kbuckets
+                .iter()
+                .map(|e| e.node.key.preimage().clone())
+                .collect::<Vec<_>>();
+            assert_eq!(remaining, vec![live.id]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn refresh_emits_no_responses() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(8);
+            let bs = harness::spawn_node(config(&network).empty_bootstrap_nodes()).await?;
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .set_bucket_refresh_interval(Duration::from_millis(100)),
+            )
+            .await?;
+            dht.add_node(bs.id, Peer::from(bs.addr), None, None);
+
+            let event = next_event(&mut dht, |event| match event {
+                RpcDhtEvent::RefreshCompleted { .. } | RpcDhtEvent::ResponseResult(_) => {
+                    Some(event)
+                }
+                _ => None,
+            })
+            .await;
+            assert!(matches!(event, RpcDhtEvent::RefreshCompleted { .. }));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn ping_oldest_first() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(9);
+            let mut dht =
+                RpcDht::with_config(config(&network).empty_bootstrap_nodes().disable_ping())
+                    .await?;
+            let now = time::now();
+            let mut remotes = Vec::new();
+            for i in 0..7 {
+                let remote = Remote::bind(&network);
+                let id = remote.id().clone();
+                dht.add_node(id.clone(), Peer::from(remote.addr()), None, None);
+                if let Entry::Present(mut entry, _) = dht.kbuckets.entry(&Key::new(id)) {
+                    entry.value().next_ping = now - Duration::from_secs(1);
+                    entry.value().last_seen = now - Duration::from_secs(7 - i);
+                }
+                remotes.push(remote);
+            }
+            dht.ping_some();
+
+            // the five nodes seen longest ago are pinged, all at once
+            drive(&mut dht, async {
+                for remote in &mut remotes[..5] {
+                    while !remote.recv().await?.0.is_ping() {}
+                }
+                Ok::<_, std::io::Error>(())
+            })
+            .await?;
+            let pinged = remotes
+                .iter_mut()
+                .map(|remote| remote.received().iter().any(Message::is_ping))
+                .collect::<Vec<_>>();
+            assert_eq!(pinged, [false; 7]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn disabled_ping_keeps_stale_nodes() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(10);
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .ping_interval(Duration::from_millis(20))
+                    .set_node_stale_timeout(Duration::from_millis(50))
+                    .disable_ping(),
+            )
+            .await?;
+            let mut remote = Remote::bind(&network);
+            let id = remote.id().clone();
+            dht.add_node(id.clone(), Peer::from(remote.addr()), None, None);
+
+            // several ping intervals pass without a ping
+            let pinged = timeout(Duration::from_millis(200), drive(&mut dht, remote.recv())).await;
+            assert!(pinged.is_err());
+            assert!(matches!(
+                dht.kbuckets.entry(&Key::new(id)),
+                Entry::Present(..)
+            ));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn remove_node_after_timeouts() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(11);
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .set_request_timeout(Duration::from_millis(20)),
+            )
+            .await?;
+            let dead = PeerId::new(network.bind().addr(), IdBytes::random());
+            dht.add_node(dead.id.clone(), Peer::from(dead.addr), None, None);
+
+            for _ in 1..MAX_NODE_TIMEOUTS {
+                dht.ping(&dead);
+            }
+            for _ in 1..MAX_NODE_TIMEOUTS {
+                next_event(&mut dht, |event| match event {
+                    RpcDhtEvent::ResponseResult(Err(ResponseError::PingTimeout(_))) => Some(()),
+                    RpcDhtEvent::NodeRemoved { .. } => panic!("removed too early"),
+                    _ => None,
+                })
+                .await;
+            }
+            assert_eq!(dht.nodes().count(), 1);
+
+            dht.ping(&dead);
+            let removed = next_event(&mut dht, |event| match event {
+                RpcDhtEvent::NodeRemoved { peer } => Some(peer),
+                _ => None,
+            })
+            .await;
+            assert_eq!(removed, dead);
+            assert_eq!(dht.nodes().count(), 0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn report_nodes_failed_in_query() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(12);
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .set_request_timeout(Duration::from_millis(20))
+                    .set_request_retries(0),
+            )
+            .await?;
+            let dead = PeerId::new(network.bind().addr(), IdBytes::random());
+            dht.add_node(dead.id.clone(), Peer::from(dead.addr), None, None);
+            assert_eq!(dht.num_nodes(), 1);
+
+            dht.query(Command::FindNode, Key::new(IdBytes::random()), None);
+            let removed = next_event(&mut dht, |event| match event {
+                RpcDhtEvent::NodeRemoved { peer } => Some(peer),
+                _ => None,
+            })
+            .await;
+            assert_eq!(removed, dead);
+            assert_eq!(dht.num_nodes(), 0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn holepunch_relay() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(13);
+            let relay = harness::spawn_node(config(&network).empty_bootstrap_nodes()).await?;
+            let target = harness::spawn_node(config(&network).empty_bootstrap_nodes()).await?;
+
+            let mut node = harness::node(&network).await?;
+            assert!(!node.holepunch(Peer::from(target.addr)));
+            assert!(node.holepunch(Peer::new(target.addr, Some(relay.addr))));
+            let peer = next_event(&mut node, |event| match event {
+                RpcDhtEvent::ResponseResult(Ok(ResponseOk::Holepunched(peer))) => Some(peer),
+                _ => None,
+            })
+            .await;
+            assert_eq!(peer.addr, target.addr);
+            Ok(())
+        })
+    }
+
+    /// Drops the messages from addresses it never sent to, like a NAT or
+    /// firewall.
+    #[derive(Debug)]
+    struct Firewall {
+        inner: MemorySocket,
+        contacted: HashSet<SocketAddr>,
+    }
+
+    impl Firewall {
+        fn bind(network: &Network) -> Self {
+            Self {
+                inner: network.bind(),
+                contacted: Default::default(),
+            }
+        }
+    }
+
+    impl Stream for Firewall {
+        type Item = std::io::Result<(Message, SocketAddr)>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            loop {
+                match Stream::poll_next(Pin::new(&mut self.inner), cx) {
+                    Poll::Ready(Some(Ok((_, from)))) if !self.contacted.contains(&from) => {}
+                    poll => return poll,
+                }
+            }
+        }
+    }
+
+    impl futures::Sink<(Vec<u8>, SocketAddr)> for Firewall {
+        type Error = std::io::Error;
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            futures::Sink::poll_ready(Pin::new(&mut self.inner), cx)
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            item: (Vec<u8>, SocketAddr),
+        ) -> std::io::Result<()> {
+            self.contacted.insert(item.1);
+            futures::Sink::start_send(Pin::new(&mut self.inner), item)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            futures::Sink::poll_flush(Pin::new(&mut self.inner), cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            futures::Sink::poll_close(Pin::new(&mut self.inner), cx)
+        }
+    }
+
+    impl Transport for Firewall {
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            Transport::local_addr(&self.inner)
+        }
+
+        fn set_max_message_size(&mut self, max_message_size: usize) {
+            self.inner.set_max_message_size(max_message_size)
+        }
+
+        fn bind_probe(&self) -> std::io::Result<Box<dyn Transport>> {
+            self.inner.bind_probe()
+        }
+    }
+
+    /// Spawns `n` nodes without bootstrap nodes and returns their addresses.
+    async fn spawn_nodes(
+        network: &Network,
+        n: usize,
+    ) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
+        let mut addrs = Vec::new();
+        for _ in 0..n {
+            let node = harness::spawn_node(config(network).empty_bootstrap_nodes()).await?;
+            addrs.push(node.addr);
+        }
+        Ok(addrs)
+    }
+
+    async fn probed_nat_status(dht: &mut RpcDht) -> NatStatus {
+        let status = next_event(dht, |event| match event {
+            RpcDhtEvent::NatStatus { status } if status.firewalled.is_some() => Some(status),
+            _ => None,
+        })
+        .await;
+        assert_eq!(dht.nat_status(), status);
+        status
+    }
+
+    #[test]
+    fn detect_firewall() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(14);
+            let addrs = spawn_nodes(&network, addr::CONFIRMATIONS).await?;
+
+            let mut open =
+                RpcDht::with_config(config(&network).set_bootstrap_nodes(&addrs)).await?;
+            assert_eq!(open.nat_status(), NatStatus::default());
+            let status = probed_nat_status(&mut open).await;
+            assert_eq!(status.mapping, NatMapping::Cone);
+            assert_eq!(status.firewalled, Some(false));
+            assert!(!open.io.punch_first());
+
+            let config = config(&network)
+                .set_transport(Firewall::bind(&network))
+                .set_bootstrap_nodes(&addrs);
+            let mut firewalled = RpcDht::with_config(config).await?;
+            let status = probed_nat_status(&mut firewalled).await;
+            assert_eq!(status.mapping, NatMapping::Cone);
+            assert_eq!(status.firewalled, Some(true));
+            // peers behind firewalls like ours are punched first
+            assert!(firewalled.io.punch_first());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn punch_firewalled_node() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(15);
+            let relay = spawn_nodes(&network, 1).await?[0];
+            let config = config(&network)
+                .set_transport(Firewall::bind(&network))
+                .set_bootstrap_nodes(&[relay]);
+            let mut firewalled = RpcDht::with_config(config).await?;
+            let firewalled_id = firewalled.local_id().clone();
+            bootstrapped(&mut firewalled).await;
+            spawn(async move { while firewalled.next().await.is_some() {} });
+
+            // only the holepunch gets the query through before it times out
+            let config = harness::config(&network)
+                .punch_first()
+                .set_request_timeout(Duration::from_secs(10))
+                .set_bootstrap_nodes(&[relay]);
+            let mut node = RpcDht::with_config(config).await?;
+            let stats = bootstrapped(&mut node).await;
+            assert_eq!(stats.num_successes(), 2);
+            assert_eq!(node.io.num_punched(), 1);
+            assert_eq!(node.io.num_retried_requests(), 1);
+            assert!(node
+                .kbuckets
+                .iter()
+                .any(|e| e.node.key.preimage() == &firewalled_id));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reply_unsupported_command() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(16);
+            let bs = harness::spawn_node(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .register_commands(["supported"]),
+            )
+            .await?;
+
+            let mut node =
+                RpcDht::with_config(config(&network).ephemeral().set_bootstrap_nodes(&[bs.addr]))
+                    .await?;
+            let supported = node.query("supported", Key::new(IdBytes::random()), None);
+            let unsupported = node.query("unsupported", Key::new(IdBytes::random()), None);
+
+            let (id, stats) = next_event(&mut node, |event| match event {
+                RpcDhtEvent::QueryResult { id, stats, .. } => Some((id, stats)),
+                _ => None,
+            })
+            .await;
+            assert_eq!(
+                id, unsupported,
+                "the supported command is only answered after the dropped reply timed out"
+            );
+            assert_eq!(stats.num_failures(), 1);
+            assert!(node.queries.get(&supported).is_some());
+            Ok(())
+        })
+    }
+
+    /// Spawns a node that handles the custom command requests for `command`
+    /// with `on_request`.
+    async fn spawn_responder(
+        network: &Network,
+        command: &str,
+        on_request: impl Fn(ResponseSender) + Send + 'static,
+    ) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+        let mut node = RpcDht::with_config(
+            config(network)
+                .empty_bootstrap_nodes()
+                .register_commands([command]),
+        )
+        .await?;
+        let addr = node.local_addr()?;
+        spawn(async move {
+            while let Some(event) = node.next().await {
+                if let RpcDhtEvent::RequestResult(Ok(RequestOk::CustomCommandRequest {
+                    reply,
+                    ..
+                })) = event
+                {
+                    on_request(reply);
+                }
+            }
+        });
+        Ok(addr)
+    }
+
+    #[test]
+    fn reply_from_task() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(17);
+            let bs_addr = spawn_responder(&network, "lookup", |reply| {
+                spawn(async move {
+                    sleep(Duration::from_millis(20)).await;
+                    assert_eq!(reply.query().command, "lookup");
+                    reply.send(Some(b"value".to_vec()));
+                });
+            })
+            .await?;
+
+            let mut node =
+                RpcDht::with_config(config(&network).ephemeral().set_bootstrap_nodes(&[bs_addr]))
+                    .await?;
+            let id = node.query("lookup", Key::new(IdBytes::random()), None);
+            let values = next_event(&mut node, |event| match event {
+                RpcDhtEvent::QueryResult {
+                    id: result, values, ..
+                } if result == id => Some(values),
+                _ => None,
+            })
+            .await;
+            assert_eq!(values, vec![(bs_addr, Bytes::from_static(b"value"))]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reply_error_for_dropped_reply() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(18);
+            let bs_addr = spawn_responder(&network, "lookup", drop).await?;
+
+            let mut node = RpcDht::with_config(
+                config(&network)
+                    .set_request_timeout(Duration::from_secs(1))
+                    .ephemeral()
+                    .set_bootstrap_nodes(&[bs_addr]),
+            )
+            .await?;
+            let id = node.query("lookup", Key::new(IdBytes::random()), None);
+            let (errors, stats) = next_event(&mut node, |event| match event {
+                RpcDhtEvent::QueryResult {
+                    id: result,
+                    errors,
+                    stats,
+                    ..
+                } if result == id => Some((errors, stats)),
+                _ => None,
+            })
+            .await;
+            assert_eq!(errors, vec![(bs_addr, ERR_NO_REPLY.to_string())]);
+            // the error arrives before the request timed out
+            assert_eq!(stats.num_timeouts(), 0);
+            Ok(())
+        })
+    }
+
+    /// Sends a ping from the `node` and returns the message as it arrives at
+    /// the `remote`.
+    async fn recv_ping(node: &mut RpcDht, remote: &mut Remote) -> std::io::Result<Message> {
+        node.ping(&remote.peer_id());
+        Ok(drive(node, remote.recv()).await?.0)
+    }
+
+    /// Like [`recv_ping`], for a `remote` on a UDP socket.
+    async fn recv_udp_ping(node: &mut RpcDht, remote: &UdpSocket) -> std::io::Result<Message> {
+        node.ping(&PeerId::new(remote.local_addr()?, IdBytes::random()));
+        let mut buf = vec![0; 1500];
+        let (n, _) = drive(node, remote.recv_from(&mut buf)).await?;
+        Ok(prost::Message::decode(&buf[..n])?)
+    }
+
+    #[test]
+    fn ephemeral_omits_id() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(19);
+            let mut remote = Remote::bind(&network);
+            let mut node =
+                RpcDht::with_config(config(&network).empty_bootstrap_nodes().ephemeral()).await?;
+            assert!(node.is_ephemeral());
+            let msg = recv_ping(&mut node, &mut remote).await?;
+            assert!(msg.is_ping());
+            assert_eq!(msg.id, None);
+
+            node.persistent();
+            assert!(!node.is_ephemeral());
+            let msg = recv_ping(&mut node, &mut remote).await?;
+            assert_eq!(msg.id, Some(node.local_id().to_vec()));
+            Ok(())
+        })
+    }
+
+    /// Drives the `remote` along until the `node` finished its next query.
+    async fn finish_next_query(node: &mut RpcDht, remote: &mut RpcDht) {
+        futures::future::poll_fn(|cx| {
+            while remote.poll_next_unpin(cx).is_ready() {}
+            while let Poll::Ready(Some(event)) = node.poll_next_unpin(cx) {
+                if let RpcDhtEvent::Bootstrapped { .. } | RpcDhtEvent::QueryResult { .. } = event {
+                    return Poll::Ready(());
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    #[test]
+    fn ephemeral_not_added() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(20);
+            let mut bs = harness::node(&network).await?;
+            let bs_addr = bs.local_addr()?;
+            let mut node =
+                RpcDht::with_config(config(&network).ephemeral().set_bootstrap_nodes(&[bs_addr]))
+                    .await?;
+            let node_addr = node.local_addr()?;
+
+            // the ephemeral node can look up, but is never added by the remote
+            finish_next_query(&mut node, &mut bs).await;
+            assert_eq!(node.closest(&IdBytes::random().0, 1)[0].addr, bs_addr);
+            assert!(bs.closest(&node.local_id().0, 1).is_empty());
+
+            node.persistent();
+            finish_next_query(&mut node, &mut bs).await;
+            assert_eq!(bs.closest(&node.local_id().0, 1)[0].addr, node_addr);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn ping_each_other() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(21);
+            let mut a = harness::node(&network).await?;
+            let mut b = harness::node(&network).await?;
+            let (a_addr, b_addr) = (a.local_addr()?, b.local_addr()?);
+            a.add_node(b.local_id().clone(), Peer::from(b_addr), None, None);
+            b.add_node(a.local_id().clone(), Peer::from(a_addr), None, None);
+            a.ping(&PeerId::new(b_addr, b.local_id().clone()));
+            b.ping(&PeerId::new(a_addr, a.local_id().clone()));
+
+            let mut ponged = [None, None];
+            futures::future::poll_fn(|cx| {
+                for (dht, pong) in vec![&mut a, &mut b].into_iter().zip(ponged.iter_mut()) {
+                    while let Poll::Ready(Some(event)) = dht.poll_next_unpin(cx) {
+                        if let RpcDhtEvent::ResponseResult(Ok(ResponseOk::Pong(peer))) = event {
+                            *pong = Some(peer.addr);
+                        }
+                    }
+                }
+                if ponged.iter().all(Option::is_some) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await;
+            assert_eq!(ponged, [Some(b_addr), Some(a_addr)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn busy_socket_yields() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(22);
+            let mut dht = harness::node(&network).await?;
+            // the pings arrive right away
+            let mut remote = Remote::bind(&network);
+            for _ in 0..MAX_IO_EVENTS_PER_POLL * 2 {
+                remote.send(&harness::ping(), dht.local_addr()?).await?;
+            }
+
+            // more pings than a single poll handles, the poll that stops asks
+            // to be polled again right away
+            struct Woken(std::sync::atomic::AtomicBool);
+            impl futures::task::ArcWake for Woken {
+                fn wake_by_ref(arc_self: &std::sync::Arc<Self>) {
+                    arc_self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+                }
+            }
+            let woken = std::sync::Arc::new(Woken(Default::default()));
+            let waker = futures::task::waker(woken.clone());
+            let mut cx = Context::from_waker(&waker);
+            while dht.poll_next_unpin(&mut cx).is_ready() {}
+            assert!(woken.0.load(std::sync::atomic::Ordering::SeqCst));
+            Ok(())
+        })
+    }
+
+    #[async_std::test]
+    async fn bootstrap_off_each_other() -> Result<(), Box<dyn std::error::Error>> {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let b_addr = socket.local_addr()?;
+        let a = DhtConfig::default()
+            .bind("127.0.0.1:0")
+            .await
+            .map_err(|(_, err)| err)?;
+        let mut a = RpcDht::with_config(a.set_bootstrap_nodes(&[b_addr])).await?;
+        let a_addr = a.local_addr()?;
+        assert_ne!(a_addr.port(), 0);
+        let mut b = RpcDht::with_config(
+            DhtConfig::default()
+                .set_socket(socket)
+                .set_bootstrap_nodes(&[a_addr]),
+        )
+        .await?;
+        assert_eq!(b.local_addr()?, b_addr);
+
+        let mut bootstrapped = [false, false];
+        futures::future::poll_fn(|cx| {
+            for (dht, done) in vec![&mut a, &mut b]
+                .into_iter()
+                .zip(bootstrapped.iter_mut())
+            {
+                while let Poll::Ready(Some(event)) = dht.poll_next_unpin(cx) {
+                    *done |= matches!(event, RpcDhtEvent::Bootstrapped { .. });
+                }
+            }
+            if bootstrapped == [true, true] {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+
+        // every node knows the other by the port it was bound to
+        let addrs = |dht: &RpcDht| dht.nodes().map(|n| n.addr).collect::<Vec<_>>();
+        assert_eq!(addrs(&a), [b_addr]);
+        assert_eq!(addrs(&b), [a_addr]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rebind_keeps_port() -> Result<(), Box<dyn std::error::Error>> {
+        let remote = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut node = RpcDht::with_config(DhtConfig::default().empty_bootstrap_nodes()).await?;
+        let addr = node.local_addr()?;
+        node.rebind(None)?;
+        assert_eq!(node.local_addr()?, addr);
+        assert!(recv_udp_ping(&mut node, &remote).await?.is_ping());
+
+        // the port is taken, an explicit address fails
+        let other = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        assert!(node.rebind(Some(other.local_addr()?)).is_err());
+        assert_eq!(node.local_addr()?, addr);
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_transient_socket_errors() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(30);
+            let remote = Remote::bind(&network);
+            let (socket, faults) = Faulty::new(network.bind());
+            let mut node = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .set_transport(socket),
+            )
+            .await?;
+
+            // an unreachable peer only fails its request
+            faults.fail_send_to(remote.addr(), Some(std::io::ErrorKind::NetworkUnreachable));
+            node.ping(&remote.peer_id());
+            let peer = next_event(&mut node, |event| match event {
+                RpcDhtEvent::ResponseResult(Err(ResponseError::PingTimeout(peer))) => Some(peer),
+                RpcDhtEvent::Rebound { .. } | RpcDhtEvent::SocketError { .. } => {
+                    panic!("unexpected {:?}", event)
+                }
+                _ => None,
+            })
+            .await;
+            assert_eq!(peer.addr, remote.addr());
+            assert_eq!(node.stats().requests_in_flight, 0);
+            Ok(())
+        })
+    }
+
+    #[async_std::test]
+    async fn rebind_after_socket_error() -> Result<(), Box<dyn std::error::Error>> {
+        let remote = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut node = RpcDht::with_config(DhtConfig::default().empty_bootstrap_nodes()).await?;
+        let addr = node.local_addr()?;
+
+        node.on_socket_error(std::io::Error::other("socket closed"));
+        loop {
+            match node.next().await {
+                Some(RpcDhtEvent::Rebound { addr: rebound }) => {
+                    assert_eq!(rebound, addr);
+                    break;
+                }
+                Some(_) => {}
+                None => panic!("the node stopped"),
+            }
+        }
+        assert!(recv_udp_ping(&mut node, &remote).await?.is_ping());
+
+        // gives up if no message arrives in between
+        for _ in 1..MAX_REBIND_ATTEMPTS {
+            node.on_socket_error(std::io::Error::other("socket closed"));
+        }
+        node.on_socket_error(std::io::Error::other("still closed"));
+        let mut rebinds = 0;
+        loop {
+            match node.next().await {
+                Some(RpcDhtEvent::Rebound { .. }) => rebinds += 1,
+                Some(RpcDhtEvent::SocketError { err }) => {
+                    assert_eq!(err.to_string(), "still closed");
+                    break;
+                }
+                Some(_) => {}
+                None => panic!("the node stopped without an error"),
+            }
+        }
+        assert_eq!(rebinds, MAX_REBIND_ATTEMPTS - 1);
+        assert!(node.next().await.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rebind_custom_transport() -> Result<(), Box<dyn std::error::Error>> {
+        let remote = UdpSocket::bind("127.0.0.1:0").await?;
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let (socket, faults) = Faulty::new(UdpFramed::new(socket, DhtRpcCodec::default()));
+        let mut node = RpcDht::with_config(
+            DhtConfig::default()
+                .set_transport(socket)
+                .empty_bootstrap_nodes(),
+        )
+        .await?;
+        let addr = node.local_addr()?;
+
+        // the address of the socket is gone, unlike a rejected destination
+        faults.fail_recv(std::io::ErrorKind::AddrNotAvailable);
+        let rebound = loop {
+            match node.next().await {
+                Some(RpcDhtEvent::Rebound { addr }) => break addr,
+                Some(RpcDhtEvent::SocketError { err }) => panic!("gave up after {}", err),
+                Some(_) => {}
+                None => panic!("the node stopped"),
+            }
+        };
+        assert_ne!(rebound, addr);
+        assert!(recv_udp_ping(&mut node, &remote).await?.is_ping());
+
+        // the new transport fails as injected as well
+        faults.fail_recv(std::io::ErrorKind::PermissionDenied);
+        loop {
+            match node.next().await {
+                Some(RpcDhtEvent::Rebound { .. }) => break,
+                Some(RpcDhtEvent::SocketError { err }) => panic!("gave up after {}", err),
+                Some(_) => {}
+                None => panic!("the node stopped"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn confirm_external_addr() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(31);
+            let mut dht = harness::node(&network).await?;
+            let external: SocketAddr = ([1, 2, 3, 4], 5000).into();
+            let respond = |dht: &mut RpcDht, port: u16, to: SocketAddr| {
+                let mut resp = pong(&IdBytes::random());
+                resp.to = Some(to.encode());
+                let req = Box::new(Message {
+                    command: Some(Command::Ping.to_string()),
+                    ..pong(dht.local_id())
+                });
+                dht.on_response(req, resp, Peer::from(([127, 0, 0, 1], port)), None, None);
+            };
+
+            for port in 1..addr::CONFIRMATIONS as u16 {
+                respond(&mut dht, port, external);
+            }
+            respond(&mut dht, 100, ([1, 2, 3, 4], 6000).into());
+            assert_eq!(dht.external_addr(), None);
+
+            respond(&mut dht, addr::CONFIRMATIONS as u16, external);
+            assert_eq!(dht.external_addr(), Some(external));
+            let confirmed = dht
+                .queued_events
+                .iter()
+                .filter_map(|ev| match ev {
+                    RpcDhtEvent::ExternalAddrConfirmed { addr, old_addr } => {
+                        Some((*addr, *old_addr))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(confirmed, vec![(external, None)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn find_node_with_small_k() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(32);
+            let k = NonZeroUsize::new(2).unwrap();
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .set_replication_factor(k),
+            )
+            .await?;
+            for port in 0..10 {
+                let addr: SocketAddr = ([127, 0, 0, 1], 1000 + port).into();
+                dht.add_node(IdBytes::random(), Peer::from(addr), None, None);
+            }
+            assert!(dht.kbuckets.buckets().all(|b| b.num_entries() <= k.get()));
+
+            let mut remote = Remote::bind(&network);
+            let resp = find_node(&mut dht, &mut remote, IdBytes::random().to_vec()).await?;
+            assert_eq!(resp.decode_closer_nodes().len(), k.get());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn closer_nodes_by_family() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(33);
+            let mut dht = harness::node(&network).await?;
+            for port in 0..3 {
+                let v4: SocketAddr = ([127, 0, 0, 1], 1000 + port).into();
+                let v6: SocketAddr = (Ipv6Addr::LOCALHOST, 1000 + port).into();
+                dht.add_node(IdBytes::random(), Peer::from(v4), None, None);
+                dht.add_node(IdBytes::random(), Peer::from(v6), None, None);
+            }
+            let target = IdBytes::random();
+
+            let v4 = dht.closer_nodes(
+                target.clone(),
+                20,
+                &Peer::from(SocketAddr::from(([10, 0, 0, 1], 1))),
+            );
+            assert_eq!(v4.nodes.len(), 3 * 38);
+            assert!(v4.nodes6.is_none());
+            assert!(decode_peer_ids(&v4.nodes)
+                .iter()
+                .all(|node| node.addr.is_ipv4()));
+
+            let v6 = dht.closer_nodes(
+                target,
+                20,
+                &Peer::from(SocketAddr::from((Ipv6Addr::LOCALHOST, 1))),
+            );
+            assert_eq!(v6.nodes, v4.nodes);
+            let nodes6 = decode_peer_ids6(v6.nodes6.unwrap());
+            assert_eq!(nodes6.len(), 3);
+            assert!(nodes6.iter().all(|node| node.addr.is_ipv6()));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn closer_nodes_include_ourselves() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(34);
+            let mut dht = harness::node(&network).await?;
+            for port in 0..3 {
+                let addr: SocketAddr = ([127, 0, 0, 1], 1000 + port).into();
+                dht.add_node(IdBytes::random(), Peer::from(addr), None, None);
+            }
+            let own_id = dht.local_id().clone();
+            let requester = Peer::from(SocketAddr::from(([10, 0, 0, 1], 1)));
+
+            let nodes = decode_peer_ids(dht.closer_nodes(own_id.clone(), 20, &requester).nodes);
+            assert_eq!(nodes.len(), 4);
+            assert_eq!((&nodes[0].id, nodes[0].addr), (&own_id, dht.local_addr()?));
+            // we take the place of the furthest node
+            let nodes = decode_peer_ids(dht.closer_nodes(own_id.clone(), 3, &requester).nodes);
+            assert_eq!(nodes.len(), 3);
+            assert_eq!(nodes[0].id, own_id);
+            let nodes = decode_peer_ids(dht.closer_nodes(IdBytes::random(), 20, &requester).nodes);
+            assert!(nodes.iter().all(|node| node.id != own_id));
+
+            // the confirmed external address is the one to reach us at
+            let external: SocketAddr = ([203, 0, 113, 1], 4000).into();
+            for i in 1..=10 {
+                dht.external_addr
+                    .report(([10, 0, 0, i], 1).into(), external);
+            }
+            assert_eq!(dht.external_addr(), Some(external));
+            let nodes = decode_peer_ids(dht.closer_nodes(own_id.clone(), 20, &requester).nodes);
+            assert_eq!((&nodes[0].id, nodes[0].addr), (&own_id, external));
+
+            // ephemeral nodes stay out of routing tables
+            let mut ephemeral =
+                RpcDht::with_config(config(&network).empty_bootstrap_nodes().ephemeral()).await?;
+            let own_id = ephemeral.local_id().clone();
+            let nodes = ephemeral.closer_nodes(own_id, 20, &requester).nodes;
+            assert!(nodes.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn find_node_by_id() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(35);
+            let a = harness::spawn_node(config(&network).empty_bootstrap_nodes()).await?;
+            let mut b =
+                RpcDht::with_config(config(&network).set_bootstrap_nodes(&[a.addr])).await?;
+            // the first `find_node` query is reported as the bootstrap
+            bootstrapped(&mut b).await;
+            let id = b.query(Command::FindNode, Key::new(a.id.clone()), None);
+            let closest = next_event(&mut b, |event| match event {
+                RpcDhtEvent::QueryResult {
+                    id: result,
+                    closest,
+                    ..
+                } if result == id => Some(closest),
+                _ => None,
+            })
+            .await;
+            assert_eq!(closest[0].0, a);
+            Ok(())
+        })
+    }
+
+    fn answer_ping(dht: &mut RpcDht, id: &IdBytes, from: SocketAddr) {
+        let req = Box::new(Message {
+            command: Some(Command::Ping.to_string()),
+            ..pong(dht.local_id())
+        });
+        dht.on_response(req, pong(id), Peer::from(from), None, None);
+    }
+
+    fn table(dht: &RpcDht) -> Vec<(IdBytes, SocketAddr)> {
+        dht.nodes().map(|n| (n.id, n.addr)).collect()
+    }
+
+    #[test]
+    fn confirm_address_change() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(36);
+            let mut dht = harness::node(&network).await?;
+            let id = IdBytes::random();
+            let addr: SocketAddr = ([127, 0, 0, 1], 1000).into();
+            let spoofed: SocketAddr = ([127, 0, 0, 1], 2000).into();
+            dht.add_node(id.clone(), Peer::from(addr), None, None);
+            dht.queued_events.clear();
+
+            // a request with the known id from a spoofed source address
+            dht.add_contact(id.clone(), Peer::from(spoofed), None, None, false);
+            assert_eq!(table(&dht), vec![(id.clone(), addr)]);
+            assert_eq!(dht.unconfirmed.peek(&spoofed), Some(&id));
+
+            // whoever is at the address answers with a different id
+            answer_ping(&mut dht, &IdBytes::random(), spoofed);
+            assert_eq!(table(&dht), vec![(id.clone(), addr)]);
+            assert!(!dht
+                .queued_events
+                .iter()
+                .any(|ev| matches!(ev, RpcDhtEvent::NodeAddressChanged { .. })));
+
+            // the node really moved
+            let moved: SocketAddr = ([127, 0, 0, 1], 3000).into();
+            dht.add_contact(id.clone(), Peer::from(moved), None, None, false);
+            answer_ping(&mut dht, &id, moved);
+            assert_eq!(table(&dht), vec![(id.clone(), moved)]);
+            assert_eq!(dht.node_at(&moved), Some(id.clone()));
+            assert_eq!(dht.node_at(&addr), None);
+            assert!(dht.queued_events.iter().any(|ev| matches!(
+                ev,
+                RpcDhtEvent::NodeAddressChanged { id: i, old_addr, addr: a }
+                    if *i == id && *old_addr == addr && *a == moved
+            )));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn index_nodes_by_addr() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(37);
+            let mut dht = harness::node(&network).await?;
+            let (id, other) = (IdBytes::random(), IdBytes::random());
+            let addr: SocketAddr = ([127, 0, 0, 1], 1000).into();
+            let other_addr: SocketAddr = ([127, 0, 0, 1], 2000).into();
+            dht.add_node(id.clone(), Peer::from(addr), None, None);
+            dht.add_node(other.clone(), Peer::from(other_addr), None, None);
+            assert_eq!(dht.node_at(&addr), Some(id.clone()));
+            assert_eq!(dht.node_at(&other_addr), Some(other.clone()));
+
+            dht.remove_peer(&Key::new(id));
+            assert_eq!(dht.node_at(&addr), None);
+            assert_eq!(dht.node_addrs.len(), 1);
+
+            // a stale address is dropped once it is looked up or pruned
+            dht.node_addrs.insert(addr, IdBytes::random());
+            dht.prune_node_addrs();
+            assert_eq!(dht.node_addrs.keys().collect::<Vec<_>>(), vec![&other_addr]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn confirm_id_change() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(38);
+            let mut dht = harness::node(&network).await?;
+            let old_id = IdBytes::random();
+            let addr: SocketAddr = ([127, 0, 0, 1], 1000).into();
+            dht.add_node(old_id.clone(), Peer::from(addr), None, None);
+
+            let id = IdBytes::random();
+            dht.add_contact(id.clone(), Peer::from(addr), None, None, false);
+            assert_eq!(table(&dht), vec![(old_id.clone(), addr)]);
+
+            answer_ping(&mut dht, &id, addr);
+            assert_eq!(table(&dht), vec![(id.clone(), addr)]);
+            assert_eq!(dht.node_at(&addr), Some(id.clone()));
+            assert!(dht.queued_events.iter().any(|ev| matches!(
+                ev,
+                RpcDhtEvent::NodeIdChanged { addr: a, old_id: o, id: i }
+                    if *a == addr && *o == old_id && *i == id
+            )));
+
+            // a response to one of our requests takes over the address right away
+            let newer = IdBytes::random();
+            dht.add_node(
+                newer.clone(),
+                Peer::from(addr),
+                Some(vec![0; 32].into()),
+                None,
+            );
+            assert_eq!(table(&dht), vec![(newer, addr)]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn inspect_routing_table() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(39);
+            let mut dht = harness::node(&network).await?;
+            for port in 0..30 {
+                let addr: SocketAddr = ([127, 0, 0, 1], 1000 + port).into();
+                dht.add_node(IdBytes::random(), Peer::from(addr), None, None);
+            }
+            let nodes = dht.nodes().collect::<Vec<_>>();
+            let buckets = dht.bucket_info();
+            assert_eq!(buckets.len(), 256);
+            assert_eq!(buckets.iter().map(|b| b.nodes).sum::<usize>(), nodes.len());
+            assert!(nodes.iter().all(|n| n.rtt.is_none()));
+
+            let target = Key::new(IdBytes::random());
+            let mut expected = nodes.clone();
+            expected.sort_by_key(|n| target.distance(&Key::new(n.id.clone())));
+            expected.truncate(10);
+            assert_eq!(dht.closest(&target.preimage().0, 10), expected);
+
+            let local = Key::new(dht.local_id().clone());
+            for node in nodes {
+                let distance = local.distance(&Key::new(node.id));
+                assert!(buckets[distance.ilog2().unwrap() as usize].nodes > 0);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn id_conversions() {
+        let id = IdBytes::random();
+        assert_eq!(IdBytes::try_from(&id.0[..]).unwrap(), id);
+        assert!(IdBytes::try_from(&id.0[..31]).is_err());
+        assert!(IdBytes::try_from(&[0; 33][..]).is_err());
+        assert_eq!(IdBytes::from(Output::<Sha256>::from(id.0)), id);
+        assert_eq!(Vec::from(id.clone()), id.to_vec());
+
+        let hex = id.to_string();
+        assert_eq!(hex.len(), 64);
+        assert_eq!(hex.parse::<IdBytes>(), Ok(id.clone()));
+        assert_eq!(hex.to_uppercase().parse::<IdBytes>(), Ok(id));
+        assert_eq!(hex[..62].parse::<IdBytes>(), Err(ParseIdError));
+        assert_eq!(
+            format!("{}zz", &hex[..62]).parse::<IdBytes>(),
+            Err(ParseIdError)
+        );
+        assert_eq!("é".repeat(32).parse::<IdBytes>(), Err(ParseIdError));
+        assert_eq!(
+            format!("+f{}", &hex[2..]).parse::<IdBytes>(),
+            Err(ParseIdError)
+        );
+
+        let key = crate::crypto::keypair().public;
+        assert_eq!(
+            IdBytes::hash_public_key(&key),
+            crate::crypto::hash_id(key.as_bytes())
+        );
+        assert_ne!(IdBytes::hash_public_key(&key), IdBytes::from(&key));
+    }
+
+    #[test]
+    fn bucket_distances() {
+        let bucket = |index| BucketInfo {
+            index,
+            nodes: 0,
+            pending: false,
+        };
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        assert_eq!(bucket(0).distances(), one..=one);
+
+        let (mut min, mut max) = ([0u8; 32], [0u8; 32]);
+        min[30] = 2;
+        max[30] = 3;
+        max[31] = 255;
+        assert_eq!(bucket(9).distances(), min..=max);
+
+        let mut min = [0u8; 32];
+        min[0] = 128;
+        assert_eq!(bucket(255).distances(), min..=[u8::MAX; 32]);
+    }
+
+    #[test]
+    fn closer_nodes_fastest_first() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(40);
+            let mut dht = harness::node(&network).await?;
+            let ids = (0..3).map(|_| IdBytes::random()).collect::<Vec<_>>();
+            for (port, id) in ids.iter().enumerate() {
+                let addr: SocketAddr = ([127, 0, 0, 1], 1000 + port as u16).into();
+                dht.add_node(id.clone(), Peer::from(addr), None, None);
+            }
+            let requester = Peer::from(SocketAddr::from(([10, 0, 0, 1], 1)));
+            let target = IdBytes::random();
+            let by_distance =
+                decode_peer_ids(dht.closer_nodes(target.clone(), 20, &requester).nodes);
+
+            let fastest = by_distance[2].id.clone();
+            dht.queries
+                .observe_rtt(by_distance[1].id.clone(), Duration::from_millis(50));
+            dht.queries
+                .observe_rtt(fastest.clone(), Duration::from_millis(5));
+            let nodes = decode_peer_ids(dht.closer_nodes(target, 20, &requester).nodes);
+            let ids = nodes.into_iter().map(|node| node.id).collect::<Vec<_>>();
+            // nodes without a round trip time keep their order at the end
+            assert_eq!(
+                ids,
+                vec![
+                    fastest,
+                    by_distance[1].id.clone(),
+                    by_distance[0].id.clone()
+                ]
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn unsupported_command_error() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(41);
+            let bs_addr = harness::spawn_bootstrap(&network).await?;
+            let mut node =
+                RpcDht::with_config(config(&network).set_bootstrap_nodes(&[bs_addr])).await?;
+            bootstrapped(&mut node).await;
+
+            let id = node.query("unknown", Key::new(IdBytes::random()), None);
+            let mut error = None;
+            let (stats, errors) = next_event(&mut node, |event| match event {
+                RpcDhtEvent::ResponseResult(Err(ResponseError::Remote {
+                    query,
+                    peer,
+                    error: err,
+                })) => {
+                    assert_eq!(query, id);
+                    assert_eq!(peer.addr, bs_addr);
+                    error = Some(err);
+                    None
+                }
+                RpcDhtEvent::QueryResult {
+                    id: query,
+                    stats,
+                    errors,
+                    ..
+                } if query == id => Some((stats, errors)),
+                _ => None,
+            })
+            .await;
+            assert_eq!(stats.num_failures(), 1);
+            assert_eq!(errors, [(bs_addr, ERR_UNSUPPORTED_COMMAND.to_string())]);
+            assert_eq!(error.as_deref(), Some(ERR_UNSUPPORTED_COMMAND));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn restore_snapshot() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(42);
+            let mut dht = harness::node(&network).await?;
+            for port in 0..5 {
+                let addr: SocketAddr = ([127, 0, 0, 1], 1000 + port).into();
+                dht.add_node(IdBytes::random(), Peer::from(addr), None, None);
+            }
+            let mut snapshot = dht.snapshot_nodes();
+            assert_eq!(snapshot.len(), 5);
+
+            let mut restored = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .with_known_nodes(NodesSnapshot::from(snapshot.clone())),
+            )
+            .await?;
+            let mut nodes = restored.snapshot_nodes();
+            nodes.sort();
+            snapshot.sort();
+            assert_eq!(nodes, snapshot);
+
+            // the nodes can be handed out before anything was sent
+            let closer = restored.closer_nodes(
+                IdBytes::random(),
+                20,
+                &Peer::from(SocketAddr::from(([10, 0, 0, 1], 1))),
+            );
+            assert_eq!(decode_peer_ids(&closer.nodes).len(), 5);
+            assert_eq!(restored.stats().messages_out, 0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn evict_unresponsive_known_nodes() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(43);
+            let addr = network.bind().addr();
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .set_request_timeout(Duration::from_millis(20))
+                    .set_request_retries(0)
+                    .with_known_nodes(vec![(IdBytes::random().to_vec(), addr)]),
+            )
+            .await?;
+            assert_eq!(dht.kbuckets.iter().count(), 1);
+            assert_eq!(bootstrapped(&mut dht).await.num_failures(), 1);
+            assert_eq!(dht.kbuckets.iter().count(), 0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn replayed_response_counts_once() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(44);
+            let mut dht = harness::node(&network).await?;
+            let mut remote = Remote::bind(&network);
+            dht.add_node(remote.id().clone(), Peer::from(remote.addr()), None, None);
+            let id = dht.query(Command::FindNode, Key::new(IdBytes::random()), None);
+            let (req, _) = drive(&mut dht, async {
+                loop {
+                    match remote.recv().await {
+                        Ok((msg, from)) if msg.is_find_node() => return Ok((msg, from)),
+                        Ok(_) => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+            })
+            .await?;
+
+            // the remote answers the request and a retry of it with a node
+            // that never answers
+            let closer = PeerId::new(([127, 0, 0, 1], 1).into(), IdBytes::random());
+            let mut closer_nodes = closer.id.to_vec();
+            closer_nodes.extend_from_slice(&closer.addr.encode());
+            let resp = Message {
+                closer_nodes: Some(closer_nodes.into()),
+                roundtrip_token: Some(vec![1; 32].into()),
+                ..pong(remote.id())
+            };
+            for _ in 0..2 {
+                remote.reply(&req, dht.local_addr()?, resp.clone()).await?;
+            }
+            until(&mut dht, |dht| dht.io.num_duplicate_responses() == 1).await;
+
+            assert_eq!(dht.io.num_unmatched_responses(), 0);
+            let stats = dht.queries.get(&id).unwrap().stats();
+            assert_eq!(stats.num_successes(), 1);
+            // the closer node was contacted once
+            assert_eq!(stats.num_requests(), 2);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn skip_ourselves_as_closer_node() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(45);
+            let mut dht = harness::node(&network).await?;
+            let mut remote = Remote::bind(&network);
+            let local_addr = dht.local_addr()?;
+            dht.bootstrap_nodes = vec![local_addr, remote.addr()];
+            let id = dht.query(Command::FindNode, Key::new(IdBytes::random()), None);
+            let (req, _) = drive(&mut dht, remote.recv()).await?;
+
+            // the bootstrap node tells us about ourselves, by address and by id
+            let mut closer_nodes = IdBytes::random().to_vec();
+            closer_nodes.extend_from_slice(&local_addr.encode());
+            closer_nodes.extend_from_slice(&dht.local_id().0);
+            closer_nodes.extend_from_slice(&SocketAddr::from(([127, 0, 0, 1], 1)).encode());
+            let resp = Message {
+                closer_nodes: Some(closer_nodes.into()),
+                ..pong(remote.id())
+            };
+            remote.reply(&req, local_addr, resp).await?;
+
+            let stats = harness::finish_query(&mut dht, id).await;
+            // neither our own address nor the closer nodes were contacted
+            assert_eq!(stats.num_requests(), 1);
+            assert_eq!(stats.num_dropped_nodes(), 2);
+            assert_eq!(dht.io.traffic().messages_in, 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn drop_response_from_ourselves() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(46);
+            let mut dht = harness::node(&network).await?;
+            let local_addr = SocketAddr::from(([127, 0, 0, 1], dht.local_addr()?.port()));
+            // a node with another id at our own address
+            dht.add_node(IdBytes::random(), Peer::from(local_addr), None, None);
+            let id = dht.query(Command::FindNode, Key::new(IdBytes::random()), None);
+            let stats = loop {
+                if let Some(RpcDhtEvent::QueryResult {
+                    id: result, stats, ..
+                }) = dht.next().await
+                {
+                    assert_eq!(result, id);
+                    break stats;
+                }
+            };
+            assert_eq!(stats.num_requests(), 1);
+            assert_eq!(stats.num_successes(), 0);
+            assert_eq!(stats.num_failures(), 1);
+            let local_id = dht.local_id().clone();
+            assert!(dht
+                .kbuckets
+                .iter()
+                .all(|e| e.node.key.preimage() != &local_id));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn find_own_id() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(47);
+            let bs_addr = harness::spawn_bootstrap(&network).await?;
+            let mut other =
+                RpcDht::with_config(config(&network).set_bootstrap_nodes(&[bs_addr])).await?;
+            bootstrapped(&mut other).await;
+            spawn(async move { while other.next().await.is_some() {} });
+
+            let mut dht =
+                RpcDht::with_config(config(&network).set_bootstrap_nodes(&[bs_addr])).await?;
+            bootstrapped(&mut dht).await;
+            let local_id = dht.local_id().clone();
+            let id = dht.query(Command::FindNode, Key::new(local_id.clone()), None);
+            let (stats, closest) = next_event(&mut dht, |event| match event {
+                RpcDhtEvent::QueryResult {
+                    id: result,
+                    stats,
+                    closest,
+                    ..
+                } if result == id => Some((stats, closest)),
+                _ => None,
+            })
+            .await;
+            // the other nodes return us as the closest node to our id
+            assert!(stats.num_dropped_nodes() > 0);
+            assert_eq!(stats.num_requests(), 2);
+            assert_eq!(stats.num_failures(), 0);
+            assert_eq!(closest.len(), 2);
+            assert!(closest.iter().all(|(peer, _)| peer.id != local_id));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn query_and_node_stats() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(48);
+            let bs = harness::spawn_node(config(&network).empty_bootstrap_nodes()).await?;
+            let mut dht = harness::node(&network).await?;
+            dht.add_node(bs.id, Peer::from(bs.addr), None, None);
+            let id = dht.query(Command::FindNode, Key::new(IdBytes::random()), None);
+            assert_eq!(dht.query_stats(&id).map(|s| s.num_requests()), Some(0));
+
+            let stats = harness::finish_query(&mut dht, id).await;
+            assert!(dht.query_stats(&id).is_none());
+            assert_eq!(stats.num_requests(), 1);
+            assert_eq!(stats.num_successes(), 1);
+            assert!(stats.duration().is_some());
+
+            let totals = dht.stats();
+            assert_eq!(totals.finished_queries, 1);
+            assert_eq!(totals.queries.num_requests(), 1);
+            assert_eq!(totals.messages_out, 1);
+            assert_eq!(totals.messages_in, 1);
+            assert!(totals.bytes_out > 0 && totals.bytes_in > 0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn cancel_query_mid_flight() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(49);
+            let mut dht = harness::node(&network).await?;
+            bootstrapped(&mut dht).await;
+
+            // a node that never answers on its own
+            let mut remote = Remote::bind(&network);
+            dht.add_node(remote.id().clone(), Peer::from(remote.addr()), None, None);
+            let id = dht.query(Command::FindNode, Key::new(IdBytes::random()), None);
+            let (req, _) = drive(&mut dht, remote.recv()).await?;
+            assert!(req.is_find_node());
+
+            assert!(dht.cancel_query(&id));
+            assert!(!dht.cancel_query(&id));
+            match dht.next().await {
+                Some(RpcDhtEvent::QueryCancelled {
+                    id: query, stats, ..
+                }) => {
+                    assert_eq!(query, id);
+                    assert_eq!(stats.num_requests(), 1);
+                    assert_eq!(stats.num_pending(), 1);
+                }
+                ev => panic!("expected cancelled query, got {:?}", ev),
+            }
+
+            // the late response doesn't match any request anymore
+            remote
+                .reply(&req, dht.local_addr()?, pong(remote.id()))
+                .await?;
+            until(&mut dht, |dht| dht.io.num_unmatched_responses() == 1).await;
+            // and the request is not sent again after its timeout
+            let resent = timeout(Duration::from_millis(200), drive(&mut dht, remote.recv())).await;
+            assert!(resent.is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn cancel_query_while_retrying() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(50);
+            let mut remote = Remote::bind(&network);
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .set_request_retries(3),
+            )
+            .await?;
+            bootstrapped(&mut dht).await;
+            // only known as bootstrap node, not in the routing table
+            dht.bootstrap_nodes.push(remote.addr());
+            let id = dht.query(Command::FindNode, Key::new(IdBytes::random()), None);
+
+            // wait for the first retry of the request to the bootstrap node
+            let (first, _) = drive(&mut dht, remote.recv()).await?;
+            let (req, _) = drive(&mut dht, remote.recv()).await?;
+            assert_eq!(req.rid, first.rid);
+            assert!(dht.cancel_query(&id));
+
+            let resp = Message {
+                roundtrip_token: Some(vec![1; 32].into()),
+                ..pong(remote.id())
+            };
+            remote.reply(&req, dht.local_addr()?, resp).await?;
+            next_event(&mut dht, |event| match event {
+                RpcDhtEvent::QueryCancelled { id: query, .. } if query == id => Some(()),
+                _ => None,
+            })
+            .await;
+            until(&mut dht, |dht| dht.io.num_unmatched_responses() == 1).await;
+
+            // no result and no further retries, and the late response doesn't
+            // add the node
+            let later = timeout(
+                Duration::from_millis(300),
+                next_event(&mut dht, |event| match event {
+                    RpcDhtEvent::QueryResult { id: query, .. } if query == id => Some(()),
+                    _ => None,
+                }),
+            )
+            .await;
+            assert!(later.is_err());
+            assert!(remote.received().is_empty());
+            assert!(matches!(
+                dht.kbuckets.entry(&Key::new(remote.id().clone())),
+                Entry::Absent(_)
+            ));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn peer_filter_drops_blocked_nodes() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(51);
+            let mut blocked = Remote::bind(&network);
+            let mut allowed = Remote::bind(&network);
+            let blocked_addr = blocked.addr();
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .with_peer_filter(Box::new(move |_, addr| *addr != blocked_addr)),
+            )
+            .await?;
+            let addr = dht.local_addr()?;
+
+            for rid in 0..2 {
+                for remote in [&mut blocked, &mut allowed] {
+                    let ping = Message {
+                        rid,
+                        id: Some(remote.id().to_vec()),
+                        ..harness::ping()
+                    };
+                    remote.send(&ping, addr).await?;
+                }
+            }
+            drive(&mut dht, async {
+                for _ in 0..2 {
+                    assert!(allowed.recv().await?.0.is_response());
+                }
+                Ok::<_, std::io::Error>(())
+            })
+            .await?;
+            until(&mut dht, |dht| dht.stats().filtered_requests == 2).await;
+
+            assert!(blocked.received().is_empty());
+            let nodes = dht.snapshot_nodes();
+            assert_eq!(nodes, vec![(allowed.id().to_vec(), allowed.addr())]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn rate_limit_per_addr() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(52);
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .set_rate_limit(RateLimit::new(0.1, 5.0)),
+            )
+            .await?;
+            let addr = dht.local_addr()?;
+            let ping = |rid| Message {
+                rid,
+                ..harness::ping()
+            };
+
+            let mut abusive = Remote::bind(&network);
+            let mut polite = Remote::bind(&network);
+            for rid in 0..20 {
+                abusive.send(&ping(rid), addr).await?;
+            }
+            for rid in 0..2 {
+                polite.send(&ping(rid), addr).await?;
+            }
+            // the pings of the polite remote arrive last
+            drive(&mut dht, async {
+                for _ in 0..2 {
+                    polite.recv().await?;
+                }
+                Ok::<_, std::io::Error>(())
+            })
+            .await?;
+
+            assert_eq!(abusive.received().len(), 5);
+            assert_eq!(dht.stats().rate_limited, 15);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn disable_rate_limit() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(53);
+            let mut dht = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .disable_rate_limit(),
+            )
+            .await?;
+            let addr = dht.local_addr()?;
+            let mut remote = Remote::bind(&network);
+            // well beyond the default burst
+            for rid in 0..200 {
+                let ping = Message {
+                    rid,
+                    ..harness::ping()
+                };
+                remote.send(&ping, addr).await?;
+            }
+            drive(&mut dht, async {
+                for _ in 0..200 {
+                    assert!(remote.recv().await?.0.is_response());
+                }
+                Ok::<_, std::io::Error>(())
+            })
+            .await?;
+            assert_eq!(dht.stats().rate_limited, 0);
+            Ok(())
+        })
+    }
+}
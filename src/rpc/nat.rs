@@ -0,0 +1,115 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures::{
+    future::Future,
+    sink::Sink,
+    stream::Stream,
+    task::{Context, Poll},
+};
+
+use crate::peers::PeersEncoding;
+use crate::rpc::{
+    io::VERSION,
+    message::{Command, Message, Type},
+    udp::Transport,
+};
+use crate::time::Delay;
+
+/// How long the probe waits for the response to its ping.
+pub const NAT_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How the NAT in front of us maps our socket, judged by the addresses the
+/// peers see us at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NatMapping {
+    /// Too few peers reported our address yet.
+    #[default]
+    Unknown,
+    /// The peers agree on our address, e.g. without a NAT or behind a cone
+    /// NAT.
+    Cone,
+    /// The peers see us at different ports, so that the address one peer
+    /// reports is of no use to another.
+    Symmetric,
+}
+
+/// What is known about the reachability of this node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NatStatus {
+    pub mapping: NatMapping,
+    /// Whether requests from addresses we never sent to are dropped on the
+    /// way, `None` until the external address was probed.
+    pub firewalled: Option<bool>,
+}
+
+/// Pings our external address from a second socket to tell whether
+/// unsolicited requests reach us.
+///
+/// This is a heuristic, a NAT that doesn't support hairpinning makes us look
+/// firewalled.
+#[derive(Debug)]
+pub struct NatProbe {
+    socket: Box<dyn Transport>,
+    /// The ping until it was handed to the socket.
+    ping: Option<(Vec<u8>, SocketAddr)>,
+    rid: u64,
+    timeout: Delay,
+}
+
+impl NatProbe {
+    /// Sends the ping to `addr` from the second `socket`.
+    pub fn start(socket: Box<dyn Transport>, addr: SocketAddr) -> io::Result<Self> {
+        use rand::Rng;
+        let rid = rand::thread_rng().gen();
+        let msg = Message {
+            version: Some(VERSION),
+            r#type: Type::Query.id(),
+            rid,
+            to: Some(addr.encode()),
+            command: Some(Command::Ping.to_string()),
+            ..Default::default()
+        };
+        let mut buf = Vec::with_capacity(prost::Message::encoded_len(&msg));
+        prost::Message::encode(&msg, &mut buf)?;
+        Ok(Self {
+            socket,
+            ping: Some((buf, addr)),
+            rid,
+            timeout: Delay::new(NAT_PROBE_TIMEOUT),
+        })
+    }
+
+    /// Resolves to whether the ping was answered in time.
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        if let Err(err) = self.send(cx) {
+            log::debug!("Failed to send the NAT probe: {}", err);
+            return Poll::Ready(false);
+        }
+        while let Poll::Ready(Some(Ok((msg, _)))) =
+            Stream::poll_next(Pin::new(&mut self.socket), cx)
+        {
+            if msg.is_response() && msg.rid == self.rid {
+                return Poll::Ready(true);
+            }
+        }
+        Future::poll(Pin::new(&mut self.timeout), cx).map(|_| false)
+    }
+
+    /// Hands the ping to the socket and flushes it.
+    fn send(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let mut socket = Pin::new(&mut self.socket);
+        if self.ping.is_some() {
+            if let Poll::Ready(ready) = socket.as_mut().poll_ready(cx) {
+                ready?;
+                socket.as_mut().start_send(self.ping.take().unwrap())?;
+            }
+        }
+        if let Poll::Ready(Err(err)) = socket.poll_flush(cx) {
+            return Err(err);
+        }
+        Ok(())
+    }
+}
@@ -2,13 +2,27 @@ use std::io;
 
 use bytes::BytesMut;
 use futures_codec::{Decoder, Encoder};
-use prost::Message as ProstMessage;
 
-use crate::rpc::message::Message;
+use crate::rpc::{message::Message, MAX_MESSAGE_SIZE};
 
 /// Rpc codec for the framing
-#[derive(Debug, Clone, Default)]
-pub(crate) struct DhtRpcCodec;
+#[derive(Debug, Clone)]
+pub(crate) struct DhtRpcCodec {
+    /// Larger messages are rejected before they are decoded.
+    max_message_size: usize,
+}
+
+impl DhtRpcCodec {
+    pub(crate) fn new(max_message_size: usize) -> Self {
+        Self { max_message_size }
+    }
+}
+
+impl Default for DhtRpcCodec {
+    fn default() -> Self {
+        Self::new(MAX_MESSAGE_SIZE)
+    }
+}
 
 impl Decoder for DhtRpcCodec {
     type Item = Message;
@@ -17,7 +31,13 @@ impl Decoder for DhtRpcCodec {
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         // Note: the udpsocket reads an entire datagram message from the remote address.
         // Therefor `src` should include the entire `Message` payload
-        Message::decode(src).map(Some).map_err(invalid_data)
+        if src.len() > self.max_message_size {
+            src.clear();
+            return Err(invalid_data("message too large"));
+        }
+        Message::decode_bytes(src.split().freeze())
+            .map(Some)
+            .map_err(invalid_data)
     }
 }
 
@@ -38,3 +58,32 @@ where
 {
     io::Error::new(io::ErrorKind::InvalidData, e)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(msg: &Message) -> BytesMut {
+        let mut buf = Vec::new();
+        prost::Message::encode(msg, &mut buf).unwrap();
+        BytesMut::from(&buf[..])
+    }
+
+    #[test]
+    fn reject_large_messages() {
+        let msg = Message {
+            value: Some(vec![1; 100].into()),
+            ..Default::default()
+        };
+        let len = encode(&msg).len();
+
+        let mut codec = DhtRpcCodec::new(len);
+        assert_eq!(codec.decode(&mut encode(&msg)).unwrap(), Some(msg.clone()));
+
+        let mut codec = DhtRpcCodec::new(len - 1);
+        let mut buf = encode(&msg);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(buf.is_empty());
+    }
+}
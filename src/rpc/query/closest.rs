@@ -0,0 +1,401 @@
+// Copyright 2019 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+use std::collections::{btree_map::Entry, BTreeMap};
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+use crate::kbucket::{Distance, Key, K_VALUE};
+use crate::rpc::query::peers::PeersIterState;
+use crate::rpc::{IdBytes, Peer, PeerId};
+
+/// A peer iterator for a target key that contacts the closest known peers
+/// first and terminates once the `num_results` closest peers have all
+/// delivered a successful result.
+#[derive(Debug)]
+pub struct ClosestPeersIter {
+    /// The target key of the iterator.
+    target: Key<IdBytes>,
+
+    /// The permitted parallelism, i.e. number of pending results.
+    parallelism: NonZeroUsize,
+
+    /// The number of closest peers for which the iterator must obtain a
+    /// successful result in order to finish.
+    num_results: NonZeroUsize,
+
+    /// The closest peers to the target, ordered by increasing distance.
+    closest_peers: BTreeMap<Distance, ClosestPeer>,
+
+    /// The number of peers the iterator is currently waiting for.
+    num_waiting: usize,
+
+    /// The internal state of the iterator.
+    state: State,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Iterating,
+    Finished,
+}
+
+#[derive(Debug)]
+struct ClosestPeer {
+    key: Key<PeerId>,
+    state: PeerState,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum PeerState {
+    /// The peer has not yet been contacted.
+    NotContacted,
+
+    /// The iterator is waiting for a result to be reported back for the peer.
+    Waiting,
+
+    /// The iterator has been informed that the attempt to contact the peer
+    /// failed.
+    Failed,
+
+    /// The iterator has been informed of a successful result from the peer.
+    Succeeded,
+}
+
+impl ClosestPeersIter {
+    /// Creates a new iterator with a default number of `K_VALUE` results.
+    pub fn new<I>(target: Key<IdBytes>, peers: I, parallelism: NonZeroUsize) -> Self
+    where
+        I: IntoIterator<Item = Key<PeerId>>,
+    {
+        Self::with_num_results(target, peers, parallelism, K_VALUE)
+    }
+
+    /// Creates a new iterator that finishes once the `num_results` closest
+    /// peers have been contacted successfully.
+    pub fn with_num_results<I>(
+        target: Key<IdBytes>,
+        peers: I,
+        parallelism: NonZeroUsize,
+        num_results: NonZeroUsize,
+    ) -> Self
+    where
+        I: IntoIterator<Item = Key<PeerId>>,
+    {
+        let mut iter = Self {
+            target,
+            parallelism,
+            num_results,
+            closest_peers: Default::default(),
+            num_waiting: 0,
+            state: State::Iterating,
+        };
+        for peer in peers {
+            iter.add_peer(peer);
+        }
+        iter
+    }
+
+    /// Adds a peer that has not yet been contacted.
+    ///
+    /// Returns `false` if the peer is already known or the iterator is
+    /// finished.
+    pub fn add_peer(&mut self, peer: Key<PeerId>) -> bool {
+        self.insert(peer, PeerState::NotContacted)
+    }
+
+    /// Adds a peer that already delivered a successful result, e.g. during
+    /// the bootstrap phase of a query.
+    ///
+    /// Returns `false` if the peer is already known or the iterator is
+    /// finished.
+    pub fn add_succeeded(&mut self, peer: Key<PeerId>) -> bool {
+        self.insert(peer, PeerState::Succeeded)
+    }
+
+    fn insert(&mut self, key: Key<PeerId>, state: PeerState) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        match self.closest_peers.entry(self.target.distance(&key)) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(e) => {
+                e.insert(ClosestPeer { key, state });
+                true
+            }
+        }
+    }
+
+    /// Callback for delivering the result of a successful request to a peer.
+    ///
+    /// If the iterator is currently waiting for a result from `peer`,
+    /// the iterator state is updated and `true` is returned. In that
+    /// case, after calling this function, `next` should eventually be
+    /// called again to obtain the new state of the iterator.
+    ///
+    /// The closer peers the remote reported should be added with
+    /// [`ClosestPeersIter::add_peer`].
+    pub fn on_success(&mut self, peer: &Peer) -> bool {
+        self.on_result(peer, PeerState::Succeeded)
+    }
+
+    /// Callback for informing the iterator about a failed request to a peer.
+    ///
+    /// If the iterator is currently waiting for a result from `peer`,
+    /// the iterator state is updated and `true` is returned.
+    pub fn on_failure(&mut self, peer: &Peer) -> bool {
+        self.on_result(peer, PeerState::Failed)
+    }
+
+    fn on_result(&mut self, peer: &Peer, result: PeerState) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        if let Some(p) = self
+            .closest_peers
+            .values_mut()
+            .find(|p| p.state == PeerState::Waiting && p.key.preimage().addr == peer.addr)
+        {
+            p.state = result;
+            self.num_waiting -= 1;
+            return true;
+        }
+        false
+    }
+
+    pub fn finish(&mut self) {
+        self.state = State::Finished
+    }
+
+    /// Checks whether the iterator has finished.
+    pub fn is_finished(&self) -> bool {
+        self.state == State::Finished
+    }
+
+    pub fn next(&mut self) -> PeersIterState {
+        self.next_by_rtt(|_| None)
+    }
+
+    /// Like [`ClosestPeersIter::next`], but among peers that fall into the
+    /// same bucket relative to the target the one with the lowest round trip
+    /// time is contacted first.
+    ///
+    /// Peers without a known round trip time come last.
+    pub fn next_by_rtt<F>(&mut self, rtt: F) -> PeersIterState
+    where
+        F: Fn(&PeerId) -> Option<Duration>,
+    {
+        if self.is_finished() {
+            return PeersIterState::Finished;
+        }
+        if self.num_waiting >= self.parallelism.get() {
+            return PeersIterState::WaitingAtCapacity;
+        }
+
+        // only the `num_results` closest peers that did not fail are of
+        // interest, anything further away is not contacted.
+        let mut considered = 0;
+        let mut succeeded = 0;
+        let mut next: Option<(Distance, Duration)> = None;
+        for (distance, peer) in self.closest_peers.iter() {
+            if peer.state == PeerState::Failed {
+                continue;
+            }
+            if let Some((closest, _)) = next {
+                if closest.ilog2() != distance.ilog2() {
+                    break;
+                }
+            }
+            match peer.state {
+                PeerState::NotContacted => {
+                    let rtt = rtt(peer.key.preimage()).unwrap_or(Duration::MAX);
+                    if next.is_none_or(|(_, fastest)| rtt < fastest) {
+                        next = Some((*distance, rtt));
+                    }
+                }
+                PeerState::Succeeded => succeeded += 1,
+                _ => {}
+            }
+            considered += 1;
+            if considered >= self.num_results.get() {
+                break;
+            }
+        }
+
+        if let Some((distance, _)) = next {
+            let peer = self.closest_peers.get_mut(&distance).expect("s.a.");
+            peer.state = PeerState::Waiting;
+            self.num_waiting += 1;
+            return PeersIterState::Waiting(Some(Peer::from(peer.key.preimage().addr)));
+        }
+
+        if self.num_waiting == 0 || succeeded >= self.num_results.get() {
+            // all of the closest peers delivered a result and none of them
+            // reported a peer that is closer
+            self.state = State::Finished;
+            PeersIterState::Finished
+        } else {
+            PeersIterState::Waiting(None)
+        }
+    }
+
+    /// Consumes the iterator, returning the closest peers that delivered a
+    /// successful result, ordered by increasing distance to the target.
+    pub fn into_result(self) -> impl Iterator<Item = Key<PeerId>> {
+        let num_results = self.num_results.get();
+        self.closest_peers
+            .into_values()
+            .filter(|p| p.state == PeerState::Succeeded)
+            .map(|p| p.key)
+            .take(num_results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(target: &Key<IdBytes>, num: u16) -> Vec<Key<PeerId>> {
+        let mut peers = (0..num)
+            .map(|port| {
+                Key::new(PeerId::new(
+                    ([127, 0, 0, 1], port).into(),
+                    IdBytes::random(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|p| target.distance(p));
+        peers
+    }
+
+    fn expect_peer(iter: &mut ClosestPeersIter) -> Peer {
+        match iter.next() {
+            PeersIterState::Waiting(Some(peer)) => peer,
+            state => panic!("Unexpected iterator state {:?}", state),
+        }
+    }
+
+    #[test]
+    fn contacts_closest_first() {
+        let target = Key::new(IdBytes::random());
+        let peers = peers(&target, 5);
+        let mut iter = ClosestPeersIter::new(
+            target,
+            peers.iter().rev().cloned(),
+            NonZeroUsize::new(2).unwrap(),
+        );
+
+        let first = expect_peer(&mut iter);
+        let second = expect_peer(&mut iter);
+        assert_eq!(first.addr, peers[0].preimage().addr);
+        assert_eq!(second.addr, peers[1].preimage().addr);
+        assert_eq!(iter.next(), PeersIterState::WaitingAtCapacity);
+
+        assert!(iter.on_success(&first));
+        assert!(!iter.on_success(&first));
+        assert_eq!(expect_peer(&mut iter).addr, peers[2].preimage().addr);
+    }
+
+    #[test]
+    fn terminates_after_closest_succeeded() {
+        let target = Key::new(IdBytes::random());
+        let peers = peers(&target, 6);
+        let mut iter = ClosestPeersIter::with_num_results(
+            target,
+            peers[2..].iter().cloned(),
+            NonZeroUsize::new(3).unwrap(),
+            NonZeroUsize::new(2).unwrap(),
+        );
+
+        let a = expect_peer(&mut iter);
+        let b = expect_peer(&mut iter);
+        assert_eq!(a.addr, peers[2].preimage().addr);
+        assert_eq!(b.addr, peers[3].preimage().addr);
+        // nothing beyond the two closest is contacted while they are pending
+        assert_eq!(iter.next(), PeersIterState::Waiting(None));
+
+        // `a` reports two closer peers, which are contacted next
+        assert!(iter.on_success(&a));
+        assert!(iter.add_peer(peers[1].clone()));
+        assert!(iter.add_peer(peers[0].clone()));
+        assert!(!iter.add_peer(peers[0].clone()));
+        assert_eq!(expect_peer(&mut iter).addr, peers[0].preimage().addr);
+        assert_eq!(expect_peer(&mut iter).addr, peers[1].preimage().addr);
+        assert_eq!(iter.next(), PeersIterState::WaitingAtCapacity);
+
+        assert!(iter.on_failure(&b));
+        assert!(iter.on_success(&Peer::from(peers[0].preimage().addr)));
+        assert_eq!(iter.next(), PeersIterState::Waiting(None));
+        assert!(iter.on_success(&Peer::from(peers[1].preimage().addr)));
+
+        assert_eq!(iter.next(), PeersIterState::Finished);
+        assert!(iter.is_finished());
+        let result = iter.into_result().collect::<Vec<_>>();
+        assert_eq!(result, vec![peers[0].clone(), peers[1].clone()]);
+    }
+
+    #[test]
+    fn prefers_fast_peers_in_same_bucket() {
+        let target = Key::new(IdBytes::random());
+        let peers = peers(&target, 16);
+        // find two peers that fall into the same bucket relative to the target
+        let (near, far) = peers
+            .windows(2)
+            .find(|w| target.distance(&w[0]).ilog2() == target.distance(&w[1]).ilog2())
+            .map(|w| (w[0].clone(), w[1].clone()))
+            .expect("16 random peers share a bucket");
+        let mut iter = ClosestPeersIter::new(
+            target,
+            vec![near.clone(), far.clone()],
+            NonZeroUsize::new(1).unwrap(),
+        );
+
+        let rtt = |peer: &PeerId| {
+            if *peer == *far.preimage() {
+                Some(Duration::from_millis(10))
+            } else {
+                Some(Duration::from_millis(100))
+            }
+        };
+        match iter.next_by_rtt(rtt) {
+            PeersIterState::Waiting(Some(peer)) => assert_eq!(peer.addr, far.preimage().addr),
+            state => panic!("Unexpected iterator state {:?}", state),
+        }
+        assert!(iter.on_success(&Peer::from(far.preimage().addr)));
+        match iter.next_by_rtt(rtt) {
+            PeersIterState::Waiting(Some(peer)) => assert_eq!(peer.addr, near.preimage().addr),
+            state => panic!("Unexpected iterator state {:?}", state),
+        }
+    }
+
+    #[test]
+    fn terminates_when_exhausted() {
+        let target = Key::new(IdBytes::random());
+        let peers = peers(&target, 2);
+        let mut iter = ClosestPeersIter::new(target, peers.clone(), NonZeroUsize::new(3).unwrap());
+        let a = expect_peer(&mut iter);
+        let b = expect_peer(&mut iter);
+        assert_eq!(iter.next(), PeersIterState::Waiting(None));
+        iter.on_failure(&a);
+        iter.on_failure(&b);
+        assert_eq!(iter.next(), PeersIterState::Finished);
+        assert_eq!(iter.into_result().count(), 0);
+    }
+}
@@ -18,7 +18,7 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use std::{collections::hash_map::Entry, num::NonZeroUsize, vec};
+use std::{collections::hash_map::Entry, net::SocketAddr, num::NonZeroUsize, vec};
 
 use fnv::FnvHashMap;
 
@@ -31,8 +31,8 @@ pub struct FixedPeersIter {
     /// The permitted parallelism, i.e. number of pending results.
     parallelism: NonZeroUsize,
 
-    /// The state of peers emitted by the iterator.
-    peers: FnvHashMap<Peer, PeerState>,
+    /// The state of peers emitted by the iterator, by their address.
+    peers: FnvHashMap<SocketAddr, (Peer, PeerState)>,
 
     /// The backlog of peers that can still be emitted.
     iter: vec::IntoIter<Peer>,
@@ -47,6 +47,16 @@ enum State {
     Finished,
 }
 
+/// How a [`FixedPeersIter`] ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FixedPeersOutcome {
+    /// The addresses of the peers that responded, empty if there were no
+    /// peers to contact.
+    Finished(Vec<SocketAddr>),
+    /// None of the peers responded.
+    Failed,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum PeerState {
     /// The iterator is waiting for a result to be reported back for the peer.
@@ -87,7 +97,7 @@ impl FixedPeersIter {
     /// calling this function has no effect and `false` is returned.
     pub fn on_success(&mut self, peer: &Peer) -> bool {
         if let State::Waiting { num_waiting } = &mut self.state {
-            if let Some(state @ PeerState::Waiting) = self.peers.get_mut(peer) {
+            if let Some((_, state @ PeerState::Waiting)) = self.peers.get_mut(&peer.addr) {
                 *state = PeerState::Succeeded;
                 *num_waiting -= 1;
                 return true;
@@ -108,7 +118,7 @@ impl FixedPeersIter {
     /// calling this function has no effect and `false` is returned.
     pub fn on_failure(&mut self, peer: &Peer) -> bool {
         if let State::Waiting { num_waiting } = &mut self.state {
-            if let Some(state @ PeerState::Waiting) = self.peers.get_mut(peer) {
+            if let Some((_, state @ PeerState::Waiting)) = self.peers.get_mut(&peer.addr) {
                 *state = PeerState::Failed;
                 *num_waiting -= 1;
                 return true;
@@ -145,11 +155,11 @@ impl FixedPeersIter {
                                 return PeersIterState::Waiting(None);
                             }
                         }
-                        Some(p) => match self.peers.entry(p.clone()) {
+                        Some(p) => match self.peers.entry(p.addr) {
                             Entry::Occupied(_) => {} // skip duplicates
                             Entry::Vacant(e) => {
                                 *num_waiting += 1;
-                                e.insert(PeerState::Waiting);
+                                e.insert((p.clone(), PeerState::Waiting));
                                 return PeersIterState::Waiting(Some(p));
                             }
                         },
@@ -159,8 +169,26 @@ impl FixedPeersIter {
         }
     }
 
+    /// The outcome of the iterator, `None` until it finished.
+    pub fn outcome(&self) -> Option<FixedPeersOutcome> {
+        if !self.is_finished() {
+            return None;
+        }
+        let succeeded = self
+            .peers
+            .iter()
+            .filter(|(_, (_, state))| *state == PeerState::Succeeded)
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        if succeeded.is_empty() && !self.peers.is_empty() {
+            Some(FixedPeersOutcome::Failed)
+        } else {
+            Some(FixedPeersOutcome::Finished(succeeded))
+        }
+    }
+
     pub fn into_result(self) -> impl Iterator<Item = Peer> {
-        self.peers.into_iter().filter_map(|(p, s)| {
+        self.peers.into_values().filter_map(|(p, s)| {
             if let PeerState::Succeeded = s {
                 Some(p)
             } else {
@@ -169,3 +197,101 @@ impl FixedPeersIter {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(num: u16) -> Vec<Peer> {
+        (1..=num)
+            .map(|port| Peer::from(([127, 0, 0, 1], port)))
+            .collect()
+    }
+
+    fn expect_peer(iter: &mut FixedPeersIter) -> Peer {
+        match iter.next() {
+            PeersIterState::Waiting(Some(peer)) => peer,
+            state => panic!("Unexpected iterator state {:?}", state),
+        }
+    }
+
+    #[test]
+    fn concurrency_window() {
+        let mut iter = FixedPeersIter::new(peers(5), NonZeroUsize::new(3).unwrap());
+        let mut waiting = (0..3).map(|_| expect_peer(&mut iter)).collect::<Vec<_>>();
+        assert_eq!(iter.next(), PeersIterState::WaitingAtCapacity);
+
+        // every result releases the next peer
+        assert!(iter.on_success(&waiting.remove(0)));
+        waiting.push(expect_peer(&mut iter));
+        assert_eq!(iter.next(), PeersIterState::WaitingAtCapacity);
+        assert!(iter.on_failure(&waiting.remove(0)));
+        waiting.push(expect_peer(&mut iter));
+        assert_eq!(iter.next(), PeersIterState::WaitingAtCapacity);
+
+        // all peers were handed out
+        assert!(iter.on_success(&waiting.remove(0)));
+        assert_eq!(iter.next(), PeersIterState::Waiting(None));
+        for peer in waiting {
+            assert!(iter.on_success(&peer));
+            assert!(!iter.on_success(&peer));
+        }
+        assert_eq!(iter.next(), PeersIterState::Finished);
+        assert_eq!(iter.into_result().count(), 4);
+    }
+
+    #[test]
+    fn skip_duplicate_addrs() {
+        let mut bootstrap = peers(2);
+        bootstrap.push(Peer::new(bootstrap[0].addr, Some(bootstrap[1].addr)));
+        bootstrap.push(bootstrap[1].clone());
+        let mut iter = FixedPeersIter::new(bootstrap, NonZeroUsize::new(3).unwrap());
+
+        let a = expect_peer(&mut iter);
+        let b = expect_peer(&mut iter);
+        assert_eq!(iter.next(), PeersIterState::Waiting(None));
+        // results are matched by address
+        assert!(iter.on_success(&Peer::from(a.addr)));
+        assert!(iter.on_failure(&Peer::from(b.addr)));
+        assert_eq!(iter.next(), PeersIterState::Finished);
+    }
+
+    #[test]
+    fn finish_without_peers() {
+        let mut iter = FixedPeersIter::new(vec![], NonZeroUsize::new(3).unwrap());
+        assert_eq!(iter.next(), PeersIterState::Finished);
+        assert!(iter.is_finished());
+        assert_eq!(iter.outcome(), Some(FixedPeersOutcome::Finished(vec![])));
+    }
+
+    #[test]
+    fn fail_without_responses() {
+        let mut iter = FixedPeersIter::new(peers(2), NonZeroUsize::new(3).unwrap());
+        let a = expect_peer(&mut iter);
+        let b = expect_peer(&mut iter);
+        assert!(iter.on_failure(&a));
+        assert_eq!(iter.outcome(), None);
+        assert!(iter.on_failure(&b));
+        assert_eq!(iter.next(), PeersIterState::Finished);
+        assert_eq!(iter.outcome(), Some(FixedPeersOutcome::Failed));
+    }
+
+    #[test]
+    fn report_responsive_peers() {
+        let mut bootstrap = peers(3);
+        bootstrap.push(bootstrap[0].clone());
+        let mut iter = FixedPeersIter::new(bootstrap, NonZeroUsize::new(1).unwrap());
+        let a = expect_peer(&mut iter);
+        assert!(iter.on_failure(&a));
+        let b = expect_peer(&mut iter);
+        assert!(iter.on_success(&b));
+        let c = expect_peer(&mut iter);
+        assert!(iter.on_failure(&c));
+        // the duplicate of the failed peer is not contacted again
+        assert_eq!(iter.next(), PeersIterState::Finished);
+        assert_eq!(
+            iter.outcome(),
+            Some(FixedPeersOutcome::Finished(vec![b.addr]))
+        );
+    }
+}
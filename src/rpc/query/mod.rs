@@ -1,17 +1,28 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
 use std::num::NonZeroUsize;
+use std::ops::Bound;
+use std::pin::Pin;
 use std::time::Duration;
 
-use fnv::FnvHashMap;
-use futures::task::Poll;
+use bytes::Bytes;
+use futures::{
+    channel::mpsc,
+    task::{Context, Poll},
+    Stream,
+};
 use wasm_timer::Instant;
 
-use crate::peers::PeersEncoding;
+use crate::peers::{is_routable, PeersEncoding};
+use crate::rpc::rtt::RttTable;
 use crate::rpc::IdBytes;
+use crate::time;
 use crate::{
     kbucket::{Key, ALPHA_VALUE, K_VALUE},
     rpc::{
         io::VERSION,
         message::{Command, Message, Type},
+        query::closest::ClosestPeersIter,
         query::fixed::FixedPeersIter,
         query::peers::PeersIterState,
         query::table::{PeerState, QueryTable},
@@ -19,24 +30,43 @@ use crate::{
     },
 };
 
+mod closest;
 mod fixed;
 mod peers;
 pub mod table;
 
+pub use fixed::FixedPeersOutcome;
+
+/// Number of request deadlines a single phase of a query may take.
+const PHASE_ROUNDS: u32 = 8;
+
 /// A `QueryPool` provides an aggregate state machine for driving `Query`s to
 /// completion.
 #[derive(Debug)]
 pub struct QueryPool {
     local_id: Key<IdBytes>,
-    queries: FnvHashMap<QueryId, QueryStream>,
+    /// The running queries, by id so that they take turns in order.
+    queries: BTreeMap<QueryId, QueryStream>,
+    /// Queries waiting for a free slot, oldest first.
+    pending: VecDeque<QueryStream>,
     config: QueryConfig,
     next_id: usize,
+    /// The accumulated stats of all queries that left the pool.
+    finished: QueryStats,
+    /// Number of queries that finished or timed out.
+    num_finished: u64,
+    /// Round trip times of the nodes that responded.
+    rtt: RttTable,
+    /// How long a typical request takes to fail, including its retries.
+    request_deadline: Option<Duration>,
+    /// The query that issued the last request, polling resumes after it.
+    last_polled: Option<QueryId>,
 }
 
 /// The configuration for queries in a `QueryPool`.
 #[derive(Debug, Clone)]
 pub struct QueryConfig {
-    /// Timeout of a single query.
+    /// Upper bound of the timeout of a single query.
     pub timeout: Duration,
 
     /// The replication factor to use.
@@ -44,6 +74,14 @@ pub struct QueryConfig {
 
     /// Allowed level of parallelism for iterative queries.
     pub parallelism: NonZeroUsize,
+
+    /// Maximum number of queries that are running at the same time, further
+    /// queries are queued until a slot frees up.
+    pub max_active_queries: usize,
+
+    /// Whether peers at the same distance are contacted in the order of their
+    /// round trip times, fastest first.
+    pub rtt_selection: bool,
 }
 
 impl Default for QueryConfig {
@@ -52,10 +90,42 @@ impl Default for QueryConfig {
             timeout: Duration::from_secs(60),
             replication_factor: NonZeroUsize::new(K_VALUE.get()).expect("K_VALUE > 0"),
             parallelism: ALPHA_VALUE,
+            max_active_queries: 16,
+            rtt_selection: true,
         }
     }
 }
 
+/// The round trip times to order peers by, if the `config` selects by them.
+fn selection_rtt<'a>(config: &QueryConfig, rtt: &'a RttTable) -> Option<&'a RttTable> {
+    Some(rtt).filter(|_| config.rtt_selection)
+}
+
+/// Limits of a single query that override the [`QueryConfig`] of the pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryLimits {
+    /// Maximum number of requests of the query that are in flight at once,
+    /// [`QueryConfig::parallelism`] by default.
+    pub parallelism: Option<NonZeroUsize>,
+    /// Number of closest peers that need to respond before the query
+    /// finishes, [`QueryConfig::replication_factor`] by default.
+    pub num_results: Option<NonZeroUsize>,
+}
+
+impl QueryLimits {
+    /// Set the maximum number of requests in flight.
+    pub fn parallelism(mut self, parallelism: NonZeroUsize) -> Self {
+        self.parallelism = Some(parallelism);
+        self
+    }
+
+    /// Set the number of closest peers that need to respond.
+    pub fn num_results(mut self, num_results: NonZeroUsize) -> Self {
+        self.num_results = Some(num_results);
+        self
+    }
+}
+
 impl QueryPool {
     /// Creates a new `QueryPool` with the given configuration.
     pub fn new(local_id: Key<IdBytes>, config: QueryConfig) -> Self {
@@ -64,12 +134,18 @@ impl QueryPool {
             next_id: 0,
             config,
             queries: Default::default(),
+            pending: Default::default(),
+            finished: QueryStats::empty(),
+            num_finished: 0,
+            rtt: Default::default(),
+            request_deadline: None,
+            last_polled: None,
         }
     }
 
     /// Returns an iterator over the queries in the pool.
     pub fn iter(&self) -> impl Iterator<Item = &QueryStream> {
-        self.queries.values()
+        self.queries.values().chain(self.pending.iter())
     }
 
     /// Gets the current size of the pool, i.e. the number of running queries.
@@ -77,8 +153,100 @@ impl QueryPool {
         self.queries.len()
     }
 
+    /// Gets the number of queries that wait for a free slot.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.queries.is_empty()
+        self.queries.is_empty() && self.pending.is_empty()
+    }
+
+    /// Returns the merged stats of all queries that finished or timed out.
+    pub fn finished_stats(&self) -> &QueryStats {
+        &self.finished
+    }
+
+    /// Returns the number of queries that finished or timed out.
+    pub fn num_finished(&self) -> u64 {
+        self.num_finished
+    }
+
+    /// The round trip times of the nodes that responded to requests.
+    pub fn rtt(&self) -> &RttTable {
+        &self.rtt
+    }
+
+    /// The round trip times to order peers by, unless
+    /// [`QueryConfig::rtt_selection`] is disabled.
+    pub fn selection_rtt(&self) -> Option<&RttTable> {
+        selection_rtt(&self.config, &self.rtt)
+    }
+
+    /// Records the round trip time of a request to the node `id`.
+    pub fn observe_rtt(&mut self, id: IdBytes, rtt: Duration) {
+        self.rtt.observe(id, rtt);
+    }
+
+    fn on_finished(&mut self, query: &QueryStream) {
+        let finished = std::mem::replace(&mut self.finished, QueryStats::empty());
+        self.finished = finished.merge(query.stats.clone());
+        self.num_finished += 1;
+    }
+
+    /// Returns the replication factor, i.e. the number of closest peers a
+    /// query is done with.
+    pub fn replication_factor(&self) -> NonZeroUsize {
+        self.config.replication_factor
+    }
+
+    /// Returns the upper bound of the timeout of a single query.
+    pub fn timeout(&self) -> Duration {
+        self.config.timeout
+    }
+
+    /// Sets the upper bound of the timeout of a single query.
+    ///
+    /// Queries that are still running once this duration, or the expected
+    /// duration of their phases if shorter, has elapsed since they yielded
+    /// their first peer to contact are returned as
+    /// [`QueryPoolState::Timeout`], with the results they collected so far.
+    /// Queries that wait for a free slot don't time out.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.config.timeout = timeout;
+    }
+
+    /// Sets how long a typical request takes to fail, including its retries.
+    ///
+    /// Every phase of a query may take [`PHASE_ROUNDS`] of these deadlines.
+    pub fn set_request_deadline(&mut self, deadline: Duration) {
+        self.request_deadline = Some(deadline);
+    }
+
+    fn query_timeout(&self, query: &QueryStream) -> Duration {
+        query.timeout(self.config.timeout, self.request_deadline)
+    }
+
+    /// Returns the maximum number of concurrently running queries.
+    pub fn max_active_queries(&self) -> usize {
+        self.config.max_active_queries
+    }
+
+    /// Sets the maximum number of concurrently running queries.
+    ///
+    /// Lowering the limit does not affect queries that are already running.
+    pub fn set_max_active_queries(&mut self, max: usize) {
+        self.config.max_active_queries = max;
+    }
+
+    /// Returns the instant at which the next of the running queries times out.
+    ///
+    /// Queries that did not contact a peer yet are not considered.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.queries
+            .values()
+            .filter_map(|q| q.stats.start.map(|start| start + self.query_timeout(q)))
+            .min()
     }
 
     pub(crate) fn next_query_id(&mut self) -> QueryId {
@@ -87,7 +255,27 @@ impl QueryPool {
         id
     }
 
+    /// Adds a query of type [`QueryType::Query`] to the pool.
+    ///
+    /// See [`QueryPool::add_with_type`].
+    pub fn add<T, I, S>(
+        &mut self,
+        cmd: T,
+        peers: I,
+        target: Key<IdBytes>,
+        value: Option<Bytes>,
+        bootstrap: S,
+    ) -> QueryId
+    where
+        T: Into<Command>,
+        I: IntoIterator<Item = Key<PeerId>>,
+        S: IntoIterator<Item = Peer>,
+    {
+        self.add_with_type(cmd, peers, QueryType::Query, target, value, bootstrap)
+    }
+
     /// Adds a query to the pool.
+    #[deprecated(note = "use `QueryPool::add_with_type`")]
     pub fn add_stream<T, I, S>(
         &mut self,
         cmd: T,
@@ -97,6 +285,61 @@ impl QueryPool {
         value: Option<Vec<u8>>,
         bootstrap: S,
     ) -> QueryId
+    where
+        T: Into<Command>,
+        I: IntoIterator<Item = Key<PeerId>>,
+        S: IntoIterator<Item = Peer>,
+    {
+        let value = value.map(Bytes::from);
+        self.add_with_type(cmd, peers, query_type, target, value, bootstrap)
+    }
+
+    /// Adds a query to the pool, with the limits of the [`QueryConfig`].
+    ///
+    /// See [`QueryPool::add_with_limits`].
+    pub fn add_with_type<T, I, S>(
+        &mut self,
+        cmd: T,
+        peers: I,
+        query_type: QueryType,
+        target: Key<IdBytes>,
+        value: Option<Bytes>,
+        bootstrap: S,
+    ) -> QueryId
+    where
+        T: Into<Command>,
+        I: IntoIterator<Item = Key<PeerId>>,
+        S: IntoIterator<Item = Peer>,
+    {
+        self.add_with_limits(
+            cmd,
+            peers,
+            query_type,
+            target,
+            value,
+            bootstrap,
+            QueryLimits::default(),
+        )
+    }
+
+    /// Adds a query to the pool.
+    ///
+    /// The query starts in the bootstrap phase, contacting the `bootstrap`
+    /// peers first, with its `QueryTable` seeded by the known closest `peers`.
+    /// If the pool is already running [`QueryConfig::max_active_queries`]
+    /// queries, the query is queued and started once another query left the
+    /// pool.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_with_limits<T, I, S>(
+        &mut self,
+        cmd: T,
+        peers: I,
+        query_type: QueryType,
+        target: Key<IdBytes>,
+        value: Option<Bytes>,
+        bootstrap: S,
+        limits: QueryLimits,
+    ) -> QueryId
     where
         T: Into<Command>,
         I: IntoIterator<Item = Key<PeerId>>,
@@ -106,7 +349,8 @@ impl QueryPool {
         let query = QueryStream::bootstrap(
             id,
             cmd,
-            self.config.parallelism,
+            limits.parallelism.unwrap_or(self.config.parallelism),
+            limits.num_results.unwrap_or(self.config.replication_factor),
             query_type,
             self.local_id.clone(),
             target,
@@ -114,31 +358,154 @@ impl QueryPool {
             peers,
             bootstrap,
         );
+        if self.num_active() < self.config.max_active_queries {
+            self.queries.insert(id, query);
+        } else {
+            self.pending.push_back(query);
+        }
+        id
+    }
+
+    /// Adds a `find_node` query that maintains the routing table, like
+    /// [`QueryPool::add`].
+    ///
+    /// The query starts right away and doesn't take one of the
+    /// [`QueryConfig::max_active_queries`] slots.
+    pub fn add_maintenance<I, S>(&mut self, peers: I, target: Key<IdBytes>, bootstrap: S) -> QueryId
+    where
+        I: IntoIterator<Item = Key<PeerId>>,
+        S: IntoIterator<Item = Peer>,
+    {
+        let id = self.next_query_id();
+        let mut query = QueryStream::bootstrap(
+            id,
+            Command::FindNode,
+            self.config.parallelism,
+            self.config.replication_factor,
+            QueryType::Query,
+            self.local_id.clone(),
+            target,
+            None,
+            peers,
+            bootstrap,
+        );
+        query.maintenance = true;
         self.queries.insert(id, query);
         id
     }
 
+    /// Number of running queries that take a slot, i.e. that don't maintain
+    /// the routing table.
+    fn num_active(&self) -> usize {
+        self.queries.values().filter(|q| !q.maintenance).count()
+    }
+
+    /// Removes the query with the given ID from the pool.
+    ///
+    /// A query that was still queued is dropped without ever contacting a
+    /// peer.
+    pub fn cancel(&mut self, id: &QueryId) -> Option<QueryStream> {
+        if let Some(mut query) = self.queries.remove(id) {
+            query.drop_held();
+            return Some(query);
+        }
+        let idx = self.pending.iter().position(|q| q.id == *id)?;
+        self.pending.remove(idx)
+    }
+
+    /// Moves queued queries into the free slots.
+    fn start_pending(&mut self) {
+        while self.num_active() < self.config.max_active_queries {
+            if let Some(query) = self.pending.pop_front() {
+                self.queries.insert(query.id, query);
+            } else {
+                break;
+            }
+        }
+    }
+
     /// Returns a reference to a query with the given ID, if it is in the pool.
     pub fn get(&self, id: &QueryId) -> Option<&QueryStream> {
-        self.queries.get(id)
+        self.queries
+            .get(id)
+            .or_else(|| self.pending.iter().find(|q| q.id == *id))
     }
 
     /// Returns a mutable reference to a query with the given ID, if it is in
     /// the pool.
     pub fn get_mut(&mut self, id: &QueryId) -> Option<&mut QueryStream> {
-        self.queries.get_mut(id)
+        if self.queries.contains_key(id) {
+            self.queries.get_mut(id)
+        } else {
+            self.pending.iter_mut().find(|q| q.id == *id)
+        }
+    }
+
+    /// Returns a stream of all further responses of the query with the given
+    /// ID, if it is in the pool.
+    pub fn responses(&mut self, id: &QueryId) -> Option<QueryResponses> {
+        self.get_mut(id).map(QueryStream::responses)
     }
 
     /// Polls the pool to advance the queries.
     pub fn poll(&mut self, now: Instant) -> QueryPoolState<'_> {
+        self.poll_queries(now, true)
+    }
+
+    /// Like [`QueryPool::poll`], but holds back the next requests of the
+    /// queries, e.g. while the socket can't take more.
+    ///
+    /// Finished and timed out queries are still reported, the held back
+    /// requests are returned by the next [`QueryPool::poll`].
+    pub fn poll_finished(&mut self, now: Instant) -> QueryPoolState<'_> {
+        self.poll_queries(now, false)
+    }
+
+    fn poll_queries(&mut self, now: Instant, dispatch: bool) -> QueryPoolState<'_> {
+        self.start_pending();
+
         let mut finished = None;
         let mut timeout = None;
         let mut waiting = None;
 
-        for (&query_id, query) in self.queries.iter_mut() {
-            query.stats.start = query.stats.start.or(Some(now));
-            match query.poll(now) {
+        let (max_timeout, deadline) = (self.config.timeout, self.request_deadline);
+        let rtt = selection_rtt(&self.config, &self.rtt);
+        let timed_out = |query: &QueryStream| {
+            query
+                .stats
+                .start
+                .is_some_and(|start| now - start >= query.timeout(max_timeout, deadline))
+        };
+        // the queries take turns, so that one with many peers to contact
+        // doesn't starve the others while requests in flight are limited
+        let mut cursor = self.last_polled;
+        for _ in 0..self.queries.len() {
+            let query_id = *cursor
+                .and_then(|last| {
+                    self.queries
+                        .range((Bound::Excluded(last), Bound::Unbounded))
+                        .next()
+                })
+                .or_else(|| self.queries.iter().next())
+                .expect("s.a.")
+                .0;
+            cursor = Some(query_id);
+            let query = self.queries.get_mut(&query_id).expect("s.a.");
+            let poll = match query.held.take() {
+                Some(ev) => Poll::Ready(Some(ev)),
+                None => query.poll(now, rtt),
+            };
+            match poll {
+                Poll::Ready(Some(ev)) if !dispatch => {
+                    query.held = Some(ev);
+                    if timed_out(query) {
+                        timeout = Some(query_id);
+                        break;
+                    }
+                }
                 Poll::Ready(Some(ev)) => {
+                    // the timeout counts from the first peer to contact
+                    query.stats.start = query.stats.start.or(Some(now));
                     waiting = Some((ev, query_id));
                     break;
                 }
@@ -148,8 +515,7 @@ impl QueryPool {
                     break;
                 }
                 Poll::Pending => {
-                    let elapsed = now - query.stats.start.unwrap_or(now);
-                    if elapsed >= self.config.timeout {
+                    if timed_out(query) {
                         timeout = Some(query_id);
                         break;
                     }
@@ -158,6 +524,7 @@ impl QueryPool {
         }
 
         if let Some((event, query_id)) = waiting {
+            self.last_polled = Some(query_id);
             let query = self.queries.get_mut(&query_id).expect("s.a.");
             return QueryPoolState::Waiting(Some((query, event)));
         }
@@ -165,16 +532,19 @@ impl QueryPool {
         if let Some(query_id) = finished {
             let mut query = self.queries.remove(&query_id).expect("s.a.");
             query.stats.end = Some(now);
+            self.on_finished(&query);
             return QueryPoolState::Finished(query);
         }
 
         if let Some(query_id) = timeout {
             let mut query = self.queries.remove(&query_id).expect("s.a.");
+            query.drop_held();
             query.stats.end = Some(now);
+            self.on_finished(&query);
             return QueryPoolState::Timeout(query);
         }
 
-        if self.queries.is_empty() {
+        if self.is_empty() {
             QueryPoolState::Idle
         } else {
             QueryPoolState::Waiting(None)
@@ -182,6 +552,22 @@ impl QueryPool {
     }
 }
 
+/// Stream of the responses to a query, in the order they were received.
+///
+/// The stream ends once the query finished or timed out.
+#[derive(Debug)]
+pub struct QueryResponses {
+    rx: mpsc::UnboundedReceiver<Response>,
+}
+
+impl Stream for QueryResponses {
+    type Item = Response;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Stream::poll_next(Pin::new(&mut self.rx), cx)
+    }
+}
+
 /// The observable states emitted by [`QueryPool::poll`].
 pub enum QueryPoolState<'a> {
     /// The pool is idle, i.e. there are no queries to process.
@@ -201,6 +587,8 @@ pub struct QueryStream {
     id: QueryId,
     /// The permitted parallelism, i.e. number of pending results.
     parallelism: NonZeroUsize,
+    /// The number of closest peers that need to respond.
+    num_results: NonZeroUsize,
     /// The peer iterator that drives the query state.
     peer_iter: QueryPeerIter,
     /// The rpc command of this stream
@@ -211,9 +599,27 @@ pub struct QueryStream {
     /// or [`Type::Update`]
     ty: QueryType,
     /// The value to include in each message
-    value: Option<Vec<u8>>,
+    value: Option<Bytes>,
+    /// The value of the update messages, the query value unless set
+    /// separately.
+    update_value: Option<Bytes>,
+    /// Receivers of the responses of this query
+    subscribers: Vec<mpsc::UnboundedSender<Response>>,
+    /// Whether the query maintains the routing table.
+    maintenance: bool,
+    /// The values of the responses, in the order they arrived.
+    values: Vec<(SocketAddr, Bytes)>,
+    /// The errors remote peers responded with, in the order they arrived.
+    errors: Vec<(SocketAddr, String)>,
+    /// How contacting the bootstrap nodes ended, `None` while in progress.
+    bootstrap: Option<FixedPeersOutcome>,
+    /// The next request, held back by [`QueryPool::poll_finished`].
+    held: Option<QueryEvent>,
     /// The inner query state.
-    pub inner: QueryTable,
+    inner: QueryTable,
+    /// The span of the events of this query.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
 impl QueryStream {
@@ -222,10 +628,11 @@ impl QueryStream {
         id: QueryId,
         cmd: T,
         parallelism: NonZeroUsize,
+        num_results: NonZeroUsize,
         ty: QueryType,
         local_id: Key<IdBytes>,
         target: Key<IdBytes>,
-        value: Option<Vec<u8>>,
+        value: Option<Bytes>,
         peers: I,
         bootstrap: S,
     ) -> Self
@@ -234,18 +641,41 @@ impl QueryStream {
         I: IntoIterator<Item = Key<PeerId>>,
         S: IntoIterator<Item = Peer>,
     {
+        let cmd = cmd.into();
         Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!(
+                "query",
+                id = id.0,
+                command = %cmd,
+                target = %crate::trace::Prefix(target.preimage().as_ref()),
+            ),
             id,
             parallelism,
+            num_results,
             peer_iter: QueryPeerIter::Bootstrap(FixedPeersIter::new(bootstrap, parallelism)),
-            cmd: cmd.into(),
+            cmd,
             stats: QueryStats::empty(),
+            update_value: value.clone(),
             value,
             ty,
-            inner: QueryTable::new(local_id, target, peers),
+            subscribers: Vec::new(),
+            maintenance: false,
+            values: Vec::new(),
+            errors: Vec::new(),
+            bootstrap: None,
+            held: None,
+            inner: QueryTable::new(local_id, target, num_results, peers),
         }
     }
 
+    /// Returns a stream of all further responses to this query.
+    pub fn responses(&mut self) -> QueryResponses {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.push(tx);
+        QueryResponses { rx }
+    }
+
     pub fn command(&self) -> &Command {
         &self.cmd
     }
@@ -253,38 +683,145 @@ impl QueryStream {
     pub fn target(&self) -> &Key<IdBytes> {
         self.inner.target()
     }
-    pub fn value(&self) -> Option<&Vec<u8>> {
+    pub fn value(&self) -> Option<&Bytes> {
         self.value.as_ref()
     }
 
+    /// Sends the updates with `value` instead of the value of the query
+    /// phase.
+    pub fn set_update_value(&mut self, value: Option<Bytes>) {
+        self.update_value = value;
+    }
+
     pub fn id(&self) -> QueryId {
         self.id
     }
 
+    /// Whether the query maintains the routing table, see
+    /// [`QueryPool::add_maintenance`].
+    pub fn is_maintenance(&self) -> bool {
+        self.maintenance
+    }
+
+    /// Whether the query already sends its updates to the closest peers.
+    pub fn is_updating(&self) -> bool {
+        matches!(self.peer_iter, QueryPeerIter::Updating(_))
+    }
+
+    /// The stats of the query so far.
+    pub fn stats(&self) -> &QueryStats {
+        &self.stats
+    }
+
+    /// The span of the events of this query.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// The timeout of the query, [`PHASE_ROUNDS`] request deadlines per phase
+    /// but at most `max`.
+    fn timeout(&self, max: Duration, request_deadline: Option<Duration>) -> Duration {
+        let phases = if self.ty.is_update() { 3 } else { 2 };
+        request_deadline.map_or(max, |deadline| max.min(deadline * PHASE_ROUNDS * phases))
+    }
+
+    /// Discards the held back request, it was counted but never sent.
+    fn drop_held(&mut self) {
+        if let Some(QueryEvent::Query { .. } | QueryEvent::Update { .. }) = self.held.take() {
+            self.stats.requests -= 1;
+        }
+    }
+
     pub(crate) fn on_timeout(&mut self, peer: Peer) {
         self.stats.failure += 1;
-        for (p, state) in self.inner.peers_mut() {
-            if p.preimage().addr == peer.addr {
-                *state = PeerState::Failed;
-                break;
-            }
-        }
+        self.stats.timeouts += 1;
+        self.inner.on_failure(&peer.addr);
+        self.peer_iter.on_failure(&peer);
+    }
+
+    /// The request to the peer failed without a timeout, e.g. it couldn't be
+    /// sent or its response was dropped because it came from ourselves.
+    pub(crate) fn on_rejected(&mut self, peer: Peer) {
+        self.stats.failure += 1;
+        self.inner.on_failure(&peer.addr);
+        self.peer_iter.on_failure(&peer);
+    }
+
+    /// The request to the peer was dropped before it could time out, e.g.
+    /// because the socket was bound again.
+    ///
+    /// Unlike after a timeout the peer is not considered failed, so it can be
+    /// contacted again in the next phase and stays in the routing table.
+    pub(crate) fn on_aborted(&mut self, peer: Peer) {
+        self.stats.failure += 1;
         self.peer_iter.on_failure(&peer);
     }
 
     /// Received a response to a requested driven by this query.
-    pub(crate) fn inject_response(&mut self, mut resp: Message, peer: Peer) -> Option<Response> {
-        // check for errors
+    #[cfg(test)]
+    pub(crate) fn inject_response(&mut self, resp: Message, peer: Peer) -> Option<Response> {
+        self.inject_response_filtered(resp, peer, &[], |_| true)
+    }
 
+    /// Drops the closer nodes of a response that are not worth contacting:
+    /// our own id or one of `own_addrs`, the responding peer itself,
+    /// unroutable addresses and duplicates. At most [`K_VALUE`] nodes of a
+    /// response are accepted.
+    fn sanitize_closer_nodes(
+        &mut self,
+        nodes: Vec<PeerId>,
+        peer: &Peer,
+        own_addrs: &[SocketAddr],
+    ) -> Vec<PeerId> {
+        let mut accepted: Vec<PeerId> = Vec::with_capacity(nodes.len().min(K_VALUE.get()));
+        for node in nodes {
+            let junk = accepted.len() >= K_VALUE.get()
+                || &node.id == self.inner.local_id().preimage()
+                || own_addrs.contains(&node.addr)
+                || node.addr == peer.addr
+                || !is_routable(&node.addr)
+                || accepted
+                    .iter()
+                    .any(|other| other.id == node.id || other.addr == node.addr);
+            if junk {
+                self.stats.dropped_nodes += 1;
+            } else {
+                accepted.push(node);
+            }
+        }
+        accepted
+    }
+
+    /// Like [`QueryStream::inject_response`], but the closer nodes of the
+    /// response at one of the addresses of this node, `own_addrs`, or that
+    /// `allow` rejects are not added to the query.
+    pub(crate) fn inject_response_filtered<F>(
+        &mut self,
+        mut resp: Message,
+        peer: Peer,
+        own_addrs: &[SocketAddr],
+        allow: F,
+    ) -> Option<Response>
+    where
+        F: Fn(&PeerId) -> bool,
+    {
+        // the request of a peer that failed already, e.g. because it timed
+        // out, was counted as failed
+        if self.inner.state(&peer.addr).is_some_and(|s| s.is_failed()) {
+            return None;
+        }
         let remote = resp.key(&peer);
 
-        if resp.is_error() {
+        // an included id that is not a valid 32 byte id is treated like an error
+        if resp.is_error() || (resp.id.is_some() && remote.is_none()) {
+            if let Some(error) = resp.error.take() {
+                self.errors.push((peer.addr, error));
+            }
             self.stats.failure += 1;
             self.peer_iter.on_failure(&peer);
             if let Some(ref remote) = remote {
-                if let Some(state) = self.inner.peers_mut().get_mut(remote) {
-                    *state = PeerState::Failed;
-                }
+                self.inner.set_state(remote, PeerState::Failed);
             }
             return None;
         }
@@ -292,10 +829,27 @@ impl QueryStream {
         self.stats.success += 1;
         self.peer_iter.on_success(&peer);
 
-        if let QueryPeerIter::Bootstrap(_) = self.peer_iter {
-            for node in resp.decode_closer_nodes() {
-                self.inner.add_unverified(node);
+        let closer_nodes = match self.peer_iter {
+            QueryPeerIter::Updating(_) => Vec::new(),
+            _ => self.sanitize_closer_nodes(resp.decode_closer_nodes(), &peer, own_addrs),
+        };
+        match &mut self.peer_iter {
+            QueryPeerIter::Bootstrap(_) => {
+                for node in closer_nodes.into_iter().filter(&allow) {
+                    self.inner.add_unverified(node, peer.addr);
+                }
             }
+            QueryPeerIter::MovingCloser(iter) => {
+                for node in closer_nodes.into_iter().filter(&allow) {
+                    if self.inner.add_unverified(node.clone(), peer.addr) {
+                        iter.add_peer(Key::new(node));
+                    }
+                }
+            }
+            QueryPeerIter::Updating(_) => {}
+        }
+
+        if let QueryPeerIter::Bootstrap(_) = self.peer_iter {
             if !self.ty.is_query() {
                 let to = resp.decode_to_peer();
                 if let Some(token) = resp.roundtrip_token {
@@ -314,18 +868,30 @@ impl QueryStream {
             }
         }
 
-        Some(Response {
+        if let Some(value) = &resp.value {
+            self.values.push((peer.addr, value.clone()));
+        }
+        let resp = Response {
             query: self.id,
             ty: self.ty,
             cmd: self.cmd.clone(),
             to: resp.decode_to_peer(),
             peer: peer.addr,
             peer_id: resp.valid_id_bytes(),
+            referrers: self.inner.referrers(&peer.addr).to_vec(),
             value: resp.value,
-        })
+        };
+        // drop the subscribers that went away
+        self.subscribers
+            .retain(|tx| tx.unbounded_send(resp.clone()).is_ok());
+        Some(resp)
     }
 
-    fn next_bootstrap(&mut self, state: PeersIterState) -> Poll<Option<QueryEvent>> {
+    fn next_bootstrap(
+        &mut self,
+        state: PeersIterState,
+        rtt: Option<&RttTable>,
+    ) -> Poll<Option<QueryEvent>> {
         match state {
             PeersIterState::Waiting(peer) => {
                 if let Some(peer) = peer {
@@ -336,14 +902,29 @@ impl QueryStream {
             }
             PeersIterState::WaitingAtCapacity => Poll::Pending,
             PeersIterState::Finished => {
-                self.peer_iter =
-                    QueryPeerIter::MovingCloser(self.inner.unverified_peers_iter(self.parallelism));
-                self.poll_iter()
+                if let QueryPeerIter::Bootstrap(iter) = &self.peer_iter {
+                    self.bootstrap = iter.outcome();
+                }
+                // without any responses there is no one to move closer to
+                if self.bootstrap == Some(FixedPeersOutcome::Failed)
+                    && self.inner.peers().all(|(_, state)| state.is_failed())
+                {
+                    return Poll::Ready(None);
+                }
+                self.peer_iter = QueryPeerIter::MovingCloser(
+                    self.inner
+                        .closer_peers_iter(self.parallelism, self.num_results),
+                );
+                self.poll_iter(rtt)
             }
         }
     }
 
-    fn next_move_closer(&mut self, state: PeersIterState) -> Poll<Option<QueryEvent>> {
+    fn next_move_closer(
+        &mut self,
+        state: PeersIterState,
+        rtt: Option<&RttTable>,
+    ) -> Poll<Option<QueryEvent>> {
         match state {
             PeersIterState::Waiting(peer) => {
                 if let Some(peer) = peer {
@@ -355,9 +936,11 @@ impl QueryStream {
             PeersIterState::WaitingAtCapacity => Poll::Pending,
             PeersIterState::Finished => {
                 if self.ty.is_update() {
-                    self.peer_iter =
-                        QueryPeerIter::Updating(self.inner.closest_peers_iter(self.parallelism));
-                    self.poll_iter()
+                    self.peer_iter = QueryPeerIter::Updating(
+                        self.inner
+                            .closest_peers_iter(self.parallelism, self.num_results),
+                    );
+                    self.poll_iter(rtt)
                 } else {
                     Poll::Ready(None)
                 }
@@ -379,15 +962,20 @@ impl QueryStream {
         }
     }
 
-    fn send(&mut self, peer: Peer, update: bool) -> QueryEvent {
+    fn send(&mut self, mut peer: Peer, update: bool) -> QueryEvent {
+        if peer.referrer.is_none() {
+            peer.referrer = self.inner.referrers(&peer.addr).first().copied();
+        }
+        self.inner.on_sent(&peer.addr);
         if update {
             if let Some(token) = self.inner.get_token(&peer) {
+                self.stats.requests += 1;
                 QueryEvent::Update {
                     command: self.cmd.clone(),
                     token: Some(token.clone()),
                     target: self.target().preimage().clone(),
                     peer,
-                    value: self.value.clone(),
+                    value: self.update_value.clone(),
                 }
             } else {
                 // don't wait for a response
@@ -395,6 +983,7 @@ impl QueryStream {
                 QueryEvent::MissingRoundtripToken { peer }
             }
         } else if self.ty.is_query() {
+            self.stats.requests += 1;
             QueryEvent::Query {
                 command: self.cmd.clone(),
                 target: self.target().preimage().clone(),
@@ -402,6 +991,7 @@ impl QueryStream {
                 peer,
             }
         } else {
+            self.stats.requests += 1;
             QueryEvent::Query {
                 command: Command::FindNode,
                 target: self.target().preimage().clone(),
@@ -411,15 +1001,16 @@ impl QueryStream {
         }
     }
 
-    fn poll_iter(&mut self) -> Poll<Option<QueryEvent>> {
+    /// With `rtt` the fastest of the closest peers are contacted first.
+    fn poll_iter(&mut self, rtt: Option<&RttTable>) -> Poll<Option<QueryEvent>> {
         match &mut self.peer_iter {
             QueryPeerIter::Bootstrap(iter) => {
                 let state = iter.next();
-                self.next_bootstrap(state)
+                self.next_bootstrap(state, rtt)
             }
             QueryPeerIter::MovingCloser(iter) => {
-                let state = iter.next();
-                self.next_move_closer(state)
+                let state = iter.next_by_rtt(|peer| rtt.and_then(|rtt| rtt.get(&peer.id)));
+                self.next_move_closer(state, rtt)
             }
             QueryPeerIter::Updating(iter) => {
                 let state = iter.next();
@@ -428,14 +1019,29 @@ impl QueryStream {
         }
     }
 
-    fn poll(&mut self, _now: Instant) -> Poll<Option<QueryEvent>> {
-        self.poll_iter()
+    fn poll(&mut self, _now: Instant, rtt: Option<&RttTable>) -> Poll<Option<QueryEvent>> {
+        self.poll_iter(rtt)
     }
 
     /// Consumes the query, producing the final `QueryResult`.
     pub fn into_result(self) -> QueryResult<QueryId, impl Iterator<Item = (PeerId, PeerState)>> {
+        let closest = self
+            .inner
+            .closest()
+            .filter_map(|(p, s)| match s {
+                PeerState::Succeeded {
+                    roundtrip_token, ..
+                } => Some((p.preimage().clone(), roundtrip_token.clone())),
+                _ => None,
+            })
+            .collect();
         QueryResult {
+            target: self.target().preimage().clone(),
+            closest,
             peers: self.inner.into_result(),
+            values: self.values,
+            errors: self.errors,
+            bootstrap: self.bootstrap,
             inner: self.id,
             stats: self.stats,
             cmd: self.cmd,
@@ -447,7 +1053,7 @@ impl QueryStream {
 #[derive(Debug)]
 enum QueryPeerIter {
     Bootstrap(FixedPeersIter),
-    MovingCloser(FixedPeersIter),
+    MovingCloser(ClosestPeersIter),
     Updating(FixedPeersIter),
 }
 
@@ -492,7 +1098,7 @@ pub enum QueryEvent {
         peer: Peer,
         command: Command,
         target: IdBytes,
-        value: Option<Vec<u8>>,
+        value: Option<Bytes>,
     },
     RemoveNode {
         id: IdBytes,
@@ -504,8 +1110,8 @@ pub enum QueryEvent {
         peer: Peer,
         command: Command,
         target: IdBytes,
-        value: Option<Vec<u8>>,
-        token: Option<Vec<u8>>,
+        value: Option<Bytes>,
+        token: Option<Bytes>,
     },
 }
 
@@ -523,10 +1129,28 @@ pub struct CommandQuery {
     /// the query/update target (32 byte target)
     pub target: IdBytes,
     /// the query/update payload decoded with the inputEncoding
-    pub value: Option<Vec<u8>>,
+    pub value: Option<Bytes>,
 }
 
 impl CommandQuery {
+    /// Decodes an instance of the message from the query's value.
+    pub fn decode_value<T: prost::Message + Default>(&self) -> Option<T> {
+        self.value.as_ref().and_then(|val| T::decode(&val[..]).ok())
+    }
+
+    /// Creates the response with the encoded `value`.
+    pub fn into_response_with_value<T: prost::Message>(
+        mut self,
+        value: &T,
+    ) -> CommandQueryResponse {
+        let mut buf = Vec::with_capacity(value.encoded_len());
+        value
+            .encode(&mut buf)
+            .expect("the buffer has the length of the message");
+        self.value = Some(buf.into());
+        self.into()
+    }
+
     pub fn into_response_with_error(self, err: impl Into<String>) -> CommandQueryResponse {
         let mut resp = CommandQueryResponse::from(self);
         resp.msg.error = Some(err.into());
@@ -538,6 +1162,9 @@ impl CommandQuery {
 pub struct CommandQueryResponse {
     pub msg: Message,
     pub peer: Peer,
+    /// The command of the query, the roundtrip token of the response only
+    /// authorizes updates for it and the `target`.
+    pub command: String,
     pub target: IdBytes,
 }
 
@@ -555,22 +1182,99 @@ impl From<CommandQuery> for CommandQueryResponse {
             command: None,
             error: None,
             value: q.value,
+            closer_nodes6: None,
+            unknown_fields: Vec::new(),
         };
 
         Self {
             msg,
             peer: q.peer,
+            command: q.command,
             target: q.target,
         }
     }
 }
 
+/// Answers a [`CommandQuery`] later, e.g. from another task.
+///
+/// The response is sent the next time the dht is polled. Dropping the sender
+/// without answering lets the dht reply with
+/// [`ERR_NO_REPLY`](crate::rpc::ERR_NO_REPLY) after half of its request
+/// timeout, unless the query was answered with
+/// [`RpcDht::reply_command`](crate::rpc::RpcDht::reply_command) in the
+/// meantime.
+#[derive(Debug)]
+pub struct ResponseSender {
+    query: Option<CommandQuery>,
+    tx: mpsc::UnboundedSender<Reply>,
+}
+
+/// Sent by a [`ResponseSender`] to the dht.
+#[derive(Debug)]
+pub(crate) enum Reply {
+    Response(Box<CommandQueryResponse>),
+    /// The sender was dropped without answering the query.
+    Dropped(CommandQuery),
+}
+
+impl ResponseSender {
+    pub(crate) fn new(query: CommandQuery, tx: mpsc::UnboundedSender<Reply>) -> Self {
+        Self {
+            query: Some(query),
+            tx,
+        }
+    }
+
+    /// The query to answer.
+    pub fn query(&self) -> &CommandQuery {
+        self.query.as_ref().expect("only taken when answering")
+    }
+
+    /// Answers the query with the `value`.
+    pub fn send(mut self, value: Option<Vec<u8>>) {
+        let mut query = self.query.take().expect("only taken when answering");
+        query.value = value.map(Bytes::from);
+        let _ = self
+            .tx
+            .unbounded_send(Reply::Response(Box::new(query.into())));
+    }
+
+    /// Answers the query with the error `msg`.
+    pub fn error(mut self, msg: &str) {
+        let query = self.query.take().expect("only taken when answering");
+        let resp = query.into_response_with_error(msg);
+        let _ = self.tx.unbounded_send(Reply::Response(Box::new(resp)));
+    }
+}
+
+impl Drop for ResponseSender {
+    fn drop(&mut self) {
+        if let Some(query) = self.query.take() {
+            let _ = self.tx.unbounded_send(Reply::Dropped(query));
+        }
+    }
+}
+
 /// The result of a `Query`.
 pub struct QueryResult<TInner, TPeers> {
     /// The opaque inner query state.
     pub inner: TInner,
-    /// The successfully contacted peers.
+    /// The target of the query.
+    pub target: IdBytes,
+    /// The closest peers to the target that responded with their roundtrip
+    /// tokens, closest first.
+    pub closest: Vec<(PeerId, Bytes)>,
+    /// The contacted peers and their final state.
     pub peers: TPeers,
+    /// The values of all responses with the peer that sent them, in the
+    /// order they arrived.
+    pub values: Vec<(SocketAddr, Bytes)>,
+    /// The errors of all responses with the peer that sent them, in the order
+    /// they arrived.
+    pub errors: Vec<(SocketAddr, String)>,
+    /// How contacting the bootstrap nodes ended, `None` if the query
+    /// finished or timed out before.
+    pub bootstrap: Option<FixedPeersOutcome>,
     /// The collected query statistics.
     pub stats: QueryStats,
     /// The Command of the query.
@@ -578,16 +1282,21 @@ pub struct QueryResult<TInner, TPeers> {
 }
 
 /// Unique identifier for an active query.
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
-pub struct QueryId(usize);
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct QueryId(pub(crate) usize);
 
 /// Execution statistics of a query.
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct QueryStats {
     requests: u32,
     success: u32,
     failure: u32,
+    timeouts: u32,
+    dropped_nodes: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
     start: Option<Instant>,
+    #[cfg_attr(feature = "serde", serde(skip))]
     end: Option<Instant>,
 }
 
@@ -597,6 +1306,8 @@ impl QueryStats {
             requests: 0,
             success: 0,
             failure: 0,
+            timeouts: 0,
+            dropped_nodes: 0,
             start: None,
             end: None,
         }
@@ -617,6 +1328,23 @@ impl QueryStats {
         self.failure
     }
 
+    /// Gets the number of failed requests that timed out.
+    pub fn num_timeouts(&self) -> u32 {
+        self.timeouts
+    }
+
+    /// Gets the number of failed requests that were answered with an error.
+    pub fn num_errors(&self) -> u32 {
+        self.failure - self.timeouts
+    }
+
+    /// Gets the number of closer nodes of responses that were ignored, because
+    /// they were unroutable, duplicates, our own id or the responding peer,
+    /// or beyond the [`K_VALUE`] nodes accepted per response.
+    pub fn num_dropped_nodes(&self) -> u32 {
+        self.dropped_nodes
+    }
+
     /// Gets the number of pending requests.
     ///
     /// > **Note**: A query can finish while still having pending
@@ -637,7 +1365,7 @@ impl QueryStats {
             if let Some(e) = self.end {
                 Some(e - s)
             } else {
-                Some(Instant::now() - s)
+                Some(time::now() - s)
             }
         } else {
             None
@@ -655,6 +1383,8 @@ impl QueryStats {
             requests: self.requests + other.requests,
             success: self.success + other.success,
             failure: self.failure + other.failure,
+            timeouts: self.timeouts + other.timeouts,
+            dropped_nodes: self.dropped_nodes + other.dropped_nodes,
             start: match (self.start, other.start) {
                 (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
                 (a, b) => a.or(b),
@@ -663,3 +1393,1387 @@ impl QueryStats {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(id: Option<Vec<u8>>, closer_nodes: &[PeerId]) -> Message {
+        let mut buf = Vec::new();
+        for node in closer_nodes {
+            buf.extend_from_slice(node.id.as_ref());
+            buf.extend_from_slice(&node.addr.encode());
+        }
+        Message {
+            version: Some(VERSION),
+            r#type: Type::Response.id(),
+            rid: 0,
+            to: None,
+            id,
+            target: None,
+            closer_nodes: Some(buf.into()),
+            roundtrip_token: Some(vec![1; 32].into()),
+            command: None,
+            error: None,
+            value: Some(Bytes::from_static(b"value")),
+            closer_nodes6: None,
+            unknown_fields: Vec::new(),
+        }
+    }
+
+    fn peer_key(port: u16) -> Key<PeerId> {
+        let addr = ([127, 0, 0, 1], port).into();
+        Key::new(PeerId::new(addr, IdBytes::random()))
+    }
+
+    #[test]
+    fn add_queries() {
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), QueryConfig::default());
+        assert!(pool.is_empty());
+
+        let query = pool.add(
+            Command::FindNode,
+            vec![peer_key(1), peer_key(2)],
+            Key::new(IdBytes::random()),
+            None,
+            vec![],
+        );
+        let update = pool.add_with_type(
+            "values",
+            vec![peer_key(3)],
+            QueryType::Update,
+            Key::new(IdBytes::random()),
+            Some(Bytes::from_static(b"hello")),
+            vec![],
+        );
+        assert_ne!(query, update);
+        assert_eq!(pool.len(), 2);
+
+        let mut ids = pool.iter().map(QueryStream::id).collect::<Vec<_>>();
+        ids.sort_by_key(|id| id.0);
+        assert_eq!(ids, vec![query, update]);
+
+        let q = pool.get(&query).unwrap();
+        assert!(q.command().is_find_node());
+        assert_eq!(q.inner.peers().len(), 2);
+        assert!(pool.get(&update).unwrap().command().is_custom("values"));
+    }
+
+    #[test]
+    fn contact_fast_peers_first() {
+        let target = Key::new(IdBytes([0; 32]));
+        let mut peers = (1..=16)
+            .map(|i| {
+                Key::new(PeerId::new(
+                    ([127, 0, 0, 1], i).into(),
+                    IdBytes([i as u8; 32]),
+                ))
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| target.distance(peer));
+        // two peers that fall into the same bucket relative to the target
+        let (near, far) = peers
+            .windows(2)
+            .find(|w| target.distance(&w[0]).ilog2() == target.distance(&w[1]).ilog2())
+            .map(|w| (w[0].clone(), w[1].clone()))
+            .expect("two of the fixed peers share a bucket");
+
+        let first_contacted = |rtt_selection| {
+            let config = QueryConfig {
+                parallelism: NonZeroUsize::new(1).unwrap(),
+                rtt_selection,
+                ..Default::default()
+            };
+            let mut pool = QueryPool::new(Key::new(IdBytes::random()), config);
+            pool.observe_rtt(near.preimage().id.clone(), Duration::from_millis(100));
+            pool.observe_rtt(far.preimage().id.clone(), Duration::from_millis(10));
+            pool.add(
+                Command::FindNode,
+                vec![near.clone(), far.clone()],
+                target.clone(),
+                None,
+                vec![],
+            );
+            match pool.poll(time::now()) {
+                QueryPoolState::Waiting(Some((_, QueryEvent::Query { peer, .. }))) => peer.addr,
+                _ => panic!("expected a request"),
+            }
+        };
+        assert_eq!(first_contacted(true), far.preimage().addr);
+        assert_eq!(first_contacted(false), near.preimage().addr);
+    }
+
+    #[test]
+    fn take_turns_in_sending() {
+        let config = QueryConfig {
+            parallelism: NonZeroUsize::new(8).unwrap(),
+            ..Default::default()
+        };
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), config);
+        let queries = (0..3u16)
+            .map(|i| {
+                let peers = (1..=4)
+                    .map(|port| peer_key(i * 10 + port))
+                    .collect::<Vec<_>>();
+                pool.add(
+                    Command::FindNode,
+                    peers,
+                    Key::new(IdBytes::random()),
+                    None,
+                    vec![],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let polled = (0..9)
+            .map(|_| match pool.poll(time::now()) {
+                QueryPoolState::Waiting(Some((query, QueryEvent::Query { .. }))) => query.id(),
+                _ => panic!("expected a request"),
+            })
+            .collect::<Vec<_>>();
+        let expected = queries.iter().cycle().take(9).copied().collect::<Vec<_>>();
+        assert_eq!(polled, expected);
+    }
+
+    #[test]
+    fn poll_finished_and_timeout() {
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), QueryConfig::default());
+        pool.set_timeout(Duration::from_secs(10));
+
+        // nothing to contact, finishes right away
+        let finished = pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            vec![],
+        );
+        // waits for a bootstrap node that never responds
+        let bootstrap = Peer::from(([127, 0, 0, 1], 1234));
+        let stalled = pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            vec![bootstrap.clone()],
+        );
+
+        let now = time::now();
+        let mut done = Vec::new();
+        let mut requested = Vec::new();
+        loop {
+            match pool.poll(now) {
+                QueryPoolState::Waiting(Some((query, event))) => match event {
+                    QueryEvent::Query { peer, .. } => requested.push((query.id(), peer)),
+                    ev => panic!("unexpected event {:?}", ev),
+                },
+                QueryPoolState::Finished(query) => done.push(query.id()),
+                QueryPoolState::Timeout(_) => panic!("timed out too early"),
+                QueryPoolState::Waiting(None) => break,
+                QueryPoolState::Idle => panic!("pool should not be idle"),
+            }
+        }
+        assert_eq!(done, vec![finished]);
+        assert_eq!(requested, vec![(stalled, bootstrap)]);
+
+        match pool.poll(now + Duration::from_secs(5)) {
+            QueryPoolState::Waiting(None) => {}
+            _ => panic!("expected to wait"),
+        }
+        match pool.poll(now + Duration::from_secs(10)) {
+            QueryPoolState::Timeout(query) => {
+                assert_eq!(query.id(), stalled);
+                assert_eq!(query.stats.duration(), Some(Duration::from_secs(10)));
+            }
+            _ => panic!("expected a timeout"),
+        }
+        assert!(matches!(pool.poll(now), QueryPoolState::Idle));
+    }
+
+    #[test]
+    fn report_finished_while_holding_back() {
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), QueryConfig::default());
+        pool.set_timeout(Duration::from_secs(10));
+        let bootstrap = Peer::from(([127, 0, 0, 1], 1234));
+        let held = pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            vec![bootstrap.clone()],
+        );
+        let finished = pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            vec![],
+        );
+
+        let now = time::now();
+        match pool.poll_finished(now) {
+            QueryPoolState::Finished(query) => assert_eq!(query.id(), finished),
+            _ => panic!("expected the empty query to finish"),
+        }
+        assert!(matches!(
+            pool.poll_finished(now),
+            QueryPoolState::Waiting(None)
+        ));
+        match pool.poll(now) {
+            QueryPoolState::Waiting(Some((query, QueryEvent::Query { peer, .. }))) => {
+                assert_eq!((query.id(), peer), (held, bootstrap));
+            }
+            _ => panic!("expected the held request"),
+        }
+
+        pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            vec![Peer::from(([127, 0, 0, 1], 1235))],
+        );
+        assert!(matches!(
+            pool.poll_finished(now),
+            QueryPoolState::Waiting(None)
+        ));
+        match pool.poll_finished(now + Duration::from_secs(10)) {
+            QueryPoolState::Timeout(query) => {
+                assert_eq!(query.id(), held);
+                // the request held back at the end was never sent
+                assert_eq!(query.stats().num_requests(), 1);
+            }
+            _ => panic!("expected a timeout"),
+        }
+    }
+
+    #[test]
+    fn concurrent_queries_finish_and_stall() {
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), QueryConfig::default());
+        pool.set_timeout(Duration::from_secs(10));
+        let peers = (1..=3)
+            .map(|port| Peer::from(([127, 0, 0, 1], port)))
+            .collect::<Vec<_>>();
+        let ids = peers
+            .iter()
+            .map(|peer| {
+                pool.add(
+                    Command::FindNode,
+                    vec![],
+                    Key::new(IdBytes::random()),
+                    None,
+                    vec![peer.clone()],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let now = time::now();
+        let mut requested = Vec::new();
+        while let QueryPoolState::Waiting(Some((query, event))) = pool.poll(now) {
+            match event {
+                QueryEvent::Query { peer, .. } => requested.push((query.id(), peer)),
+                ev => panic!("unexpected event {:?}", ev),
+            }
+        }
+        requested.sort_by_key(|(_, peer)| peer.addr);
+        assert_eq!(
+            requested,
+            ids.iter().copied().zip(peers.clone()).collect::<Vec<_>>()
+        );
+
+        // the first two queries get their responses, the last one stalls
+        for (id, peer) in ids.iter().zip(&peers).take(2) {
+            let query = pool.get_mut(id).unwrap();
+            let resp = response(Some(IdBytes::random().to_vec()), &[]);
+            assert!(query.inject_response(resp, peer.clone()).is_some());
+        }
+        let mut finished = Vec::new();
+        while let QueryPoolState::Finished(query) = pool.poll(now) {
+            assert_eq!(query.stats().num_successes(), 1);
+            let result = query.into_result();
+            assert_eq!(result.closest.len(), 1);
+            finished.push(result.inner);
+        }
+        finished.sort_by_key(|id| id.0);
+        assert_eq!(finished, ids[..2]);
+        assert!(pool.get(&ids[0]).is_none());
+        assert!(pool.get(&ids[2]).is_some());
+
+        match pool.poll(now + Duration::from_secs(10)) {
+            QueryPoolState::Timeout(query) => {
+                assert_eq!(query.id(), ids[2]);
+                assert_eq!(query.stats().num_pending(), 1);
+            }
+            _ => panic!("expected a timeout"),
+        }
+        assert!(matches!(pool.poll(now), QueryPoolState::Idle));
+    }
+
+    #[test]
+    fn timeout_counts_from_first_request() {
+        let config = QueryConfig {
+            max_active_queries: 1,
+            ..Default::default()
+        };
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), config);
+        pool.set_timeout(Duration::from_secs(10));
+        let add = |pool: &mut QueryPool, port| {
+            pool.add(
+                Command::FindNode,
+                vec![peer_key(port + 10)],
+                Key::new(IdBytes::random()),
+                None,
+                vec![Peer::from(([127, 0, 0, 1], port))],
+            )
+        };
+        let first = add(&mut pool, 1);
+        let queued = add(&mut pool, 2);
+
+        let now = time::now();
+        while let QueryPoolState::Waiting(Some(_)) = pool.poll(now) {}
+        assert_eq!(pool.get(&queued).unwrap().stats().duration(), None);
+
+        // the queued query starts once the first one timed out
+        let later = now + Duration::from_secs(10);
+        match pool.poll(later) {
+            QueryPoolState::Timeout(query) => {
+                assert_eq!(query.id(), first);
+                // the results so far are kept
+                assert_eq!(query.inner.peers().len(), 1);
+                assert_eq!(query.stats().num_pending(), 1);
+            }
+            _ => panic!("expected a timeout"),
+        }
+        while let QueryPoolState::Waiting(Some(_)) = pool.poll(later) {}
+        assert_eq!(pool.next_timeout(), Some(later + Duration::from_secs(10)));
+        assert!(matches!(
+            pool.poll(now + Duration::from_secs(15)),
+            QueryPoolState::Waiting(None)
+        ));
+        match pool.poll(later + Duration::from_secs(10)) {
+            QueryPoolState::Timeout(query) => {
+                assert_eq!(query.id(), queued);
+                assert_eq!(query.stats().duration(), Some(Duration::from_secs(10)));
+            }
+            _ => panic!("expected a timeout"),
+        }
+    }
+
+    #[test]
+    fn timeout_follows_request_deadline() {
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), QueryConfig::default());
+        pool.set_timeout(Duration::from_secs(10));
+        pool.set_request_deadline(Duration::from_millis(10));
+
+        let bootstrap = Peer::from(([127, 0, 0, 1], 1234));
+        let stalled = pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            vec![bootstrap],
+        );
+        let now = time::now();
+        while let QueryPoolState::Waiting(Some(_)) = pool.poll(now) {}
+        // two phases of eight deadlines each
+        let expected = Duration::from_millis(160);
+        assert_eq!(pool.next_timeout(), Some(now + expected));
+        assert!(matches!(
+            pool.poll(now + expected / 2),
+            QueryPoolState::Waiting(None)
+        ));
+        match pool.poll(now + expected) {
+            QueryPoolState::Timeout(query) => assert_eq!(query.id(), stalled),
+            _ => panic!("expected a timeout"),
+        }
+
+        // the configured timeout stays the upper bound
+        pool.set_request_deadline(Duration::from_secs(10));
+        pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            vec![Peer::from(([127, 0, 0, 1], 1234))],
+        );
+        while let QueryPoolState::Waiting(Some(_)) = pool.poll(now) {}
+        assert_eq!(pool.next_timeout(), Some(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn timeout_of_update_phase() {
+        for ty in [QueryType::Update, QueryType::QueryUpdate] {
+            let mut pool = QueryPool::new(Key::new(IdBytes::random()), QueryConfig::default());
+            pool.set_timeout(Duration::from_secs(10));
+            let deadline = Duration::from_millis(10);
+            pool.set_request_deadline(deadline);
+            pool.add_with_type(
+                "values",
+                vec![],
+                ty,
+                Key::new(IdBytes::random()),
+                Some(Bytes::from_static(b"hello")),
+                vec![Peer::from(([127, 0, 0, 1], 1234))],
+            );
+            let now = time::now();
+            while let QueryPoolState::Waiting(Some(_)) = pool.poll(now) {}
+            // bootstrap, moving closer and updating the closest nodes
+            let expected = deadline * PHASE_ROUNDS * 3;
+            assert_eq!(pool.next_timeout(), Some(now + expected), "{:?}", ty);
+        }
+    }
+
+    #[test]
+    fn limit_requests_in_flight() {
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), QueryConfig::default());
+        let peers = (1..=10).map(peer_key).collect::<Vec<_>>();
+        let limits = QueryLimits::default()
+            .parallelism(NonZeroUsize::new(2).unwrap())
+            .num_results(NonZeroUsize::new(3).unwrap());
+        let id = pool.add_with_limits(
+            Command::FindNode,
+            peers.clone(),
+            QueryType::Query,
+            Key::new(IdBytes::random()),
+            None,
+            vec![],
+            limits,
+        );
+
+        let now = time::now();
+        let mut in_flight = Vec::new();
+        let mut max_in_flight = 0;
+        let mut responded = 0;
+        loop {
+            // none of the requests is answered until the query stops sending
+            match pool.poll(now) {
+                QueryPoolState::Waiting(Some((_, QueryEvent::Query { peer, .. }))) => {
+                    in_flight.push(peer);
+                    max_in_flight = max_in_flight.max(in_flight.len());
+                }
+                QueryPoolState::Waiting(_) => {
+                    let peer = in_flight.remove(0);
+                    let key = peers.iter().find(|k| k.preimage().addr == peer.addr);
+                    let msg = response(Some(key.unwrap().preimage().id.to_vec()), &[]);
+                    pool.get_mut(&id).unwrap().inject_response(msg, peer);
+                    responded += 1;
+                }
+                QueryPoolState::Finished(query) => {
+                    assert_eq!(query.id(), id);
+                    break;
+                }
+                _ => panic!("unexpected state"),
+            }
+        }
+        assert_eq!(max_in_flight, 2);
+        assert_eq!(responded, 3);
+    }
+
+    #[test]
+    fn limit_active_queries() {
+        let config = QueryConfig {
+            max_active_queries: 2,
+            ..Default::default()
+        };
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), config);
+        let ids = (1..=10)
+            .map(|port| {
+                pool.add(
+                    Command::FindNode,
+                    vec![],
+                    Key::new(IdBytes::random()),
+                    None,
+                    vec![Peer::from(([127, 0, 0, 1], port))],
+                )
+            })
+            .collect::<Vec<_>>();
+        let cancelled = pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            vec![Peer::from(([127, 0, 0, 1], 11))],
+        );
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.pending(), 9);
+        assert!(pool.get(&cancelled).is_some());
+        assert_eq!(pool.cancel(&cancelled).map(|q| q.id()), Some(cancelled));
+        assert_eq!(pool.pending(), 8);
+
+        let now = time::now();
+        let mut finished = Vec::new();
+        let mut in_flight = Vec::new();
+        loop {
+            match pool.poll(now) {
+                QueryPoolState::Waiting(Some((query, event))) => match event {
+                    QueryEvent::Query { peer, .. } => {
+                        assert_ne!(query.id(), cancelled);
+                        in_flight.push((query.id(), peer));
+                        assert!(in_flight.len() <= 2);
+                    }
+                    ev => panic!("unexpected event {:?}", ev),
+                },
+                QueryPoolState::Finished(query) => finished.push(query.id()),
+                QueryPoolState::Timeout(_) => panic!("unexpected timeout"),
+                QueryPoolState::Waiting(None) => {
+                    assert!(!in_flight.is_empty());
+                    for (id, peer) in in_flight.drain(..) {
+                        pool.get_mut(&id)
+                            .unwrap()
+                            .inject_response(response(None, &[]), peer);
+                    }
+                }
+                QueryPoolState::Idle => break,
+            }
+        }
+        finished.sort_by_key(|id| id.0);
+        assert_eq!(finished, ids);
+        assert_eq!(pool.finished_stats().num_requests(), 10);
+        assert_eq!(pool.num_finished(), 10);
+    }
+
+    #[test]
+    fn maintenance_queries_take_no_slot() {
+        let config = QueryConfig {
+            max_active_queries: 1,
+            ..Default::default()
+        };
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), config);
+        let bootstrap = || vec![Peer::from(([127, 0, 0, 1], 1))];
+        let maintenance = pool.add_maintenance(vec![], Key::new(IdBytes::random()), bootstrap());
+        let query = pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            bootstrap(),
+        );
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.pending(), 0);
+        assert!(pool.get(&maintenance).unwrap().is_maintenance());
+        assert!(!pool.get(&query).unwrap().is_maintenance());
+
+        // but user queries still do
+        pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            bootstrap(),
+        );
+        assert_eq!(pool.pending(), 1);
+        pool.add_maintenance(vec![], Key::new(IdBytes::random()), bootstrap());
+        assert_eq!(pool.len(), 3);
+    }
+
+    #[test]
+    fn stream_responses() {
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), QueryConfig::default());
+        let bootstrap = (1..=2)
+            .map(|port| Peer::from(([127, 0, 0, 1], port)))
+            .collect::<Vec<_>>();
+        let id = pool.add(
+            Command::FindNode,
+            vec![],
+            Key::new(IdBytes::random()),
+            None,
+            bootstrap.clone(),
+        );
+        let responses = pool.responses(&id).unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                pool.poll(time::now()),
+                QueryPoolState::Waiting(Some(_))
+            ));
+        }
+
+        let query = pool.get_mut(&id).unwrap();
+        for peer in bootstrap.iter().rev() {
+            let mut resp = response(None, &[]);
+            resp.value = Some(peer.addr.port().to_be_bytes().to_vec().into());
+            assert!(query.inject_response(resp, peer.clone()).is_some());
+        }
+        assert!(matches!(
+            pool.poll(time::now()),
+            QueryPoolState::Finished(_)
+        ));
+
+        let received = futures::executor::block_on_stream(responses)
+            .map(|resp| {
+                assert_eq!(resp.query, id);
+                resp.peer
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(received, vec![bootstrap[1].addr, bootstrap[0].addr]);
+        assert!(pool.responses(&id).is_none());
+    }
+
+    #[test]
+    fn finish_after_closest_responded() {
+        let config = QueryConfig {
+            replication_factor: NonZeroUsize::new(2).unwrap(),
+            ..Default::default()
+        };
+        let mut pool = QueryPool::new(Key::new(IdBytes::random()), config);
+        let peers = (1..=5).map(peer_key).collect::<Vec<_>>();
+        let id = pool.add(
+            Command::FindNode,
+            peers,
+            Key::new(IdBytes::random()),
+            None,
+            vec![],
+        );
+
+        // only the 2 closest peers are contacted
+        let mut contacted = Vec::new();
+        while let QueryPoolState::Waiting(Some((_, event))) = pool.poll(time::now()) {
+            match event {
+                QueryEvent::Query { peer, .. } => contacted.push(peer),
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+        }
+        assert_eq!(contacted.len(), 2);
+
+        let query = pool.get_mut(&id).unwrap();
+        for peer in contacted {
+            query.inject_response(response(Some(IdBytes::random().to_vec()), &[]), peer);
+        }
+        assert!(matches!(
+            pool.poll(time::now()),
+            QueryPoolState::Finished(_)
+        ));
+    }
+
+    #[test]
+    fn inject_response() {
+        let bootstrap = Peer::from(([127, 0, 0, 1], 1));
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            Command::FindNode,
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::Query,
+            Key::new(IdBytes::random()),
+            Key::new(IdBytes::random()),
+            None,
+            vec![],
+            vec![bootstrap.clone()],
+        );
+        assert!(matches!(
+            query.poll(time::now(), None),
+            Poll::Ready(Some(QueryEvent::Query { .. }))
+        ));
+        assert_eq!(query.stats.num_requests(), 1);
+
+        let closer = vec![peer_key(2).into_preimage(), peer_key(3).into_preimage()];
+        let resp = query
+            .inject_response(
+                response(Some(IdBytes::random().to_vec()), &closer),
+                bootstrap,
+            )
+            .unwrap();
+        assert_eq!(resp.value, Some(Bytes::from_static(b"value")));
+        assert_eq!(query.stats.num_successes(), 1);
+        assert_eq!(query.stats.num_pending(), 0);
+        // the responding node and all its closer nodes are known now
+        assert_eq!(query.inner.peers().len(), 3);
+
+        // next up are the nodes we just learned about
+        for _ in 0..2 {
+            assert!(matches!(
+                query.poll(time::now(), None),
+                Poll::Ready(Some(QueryEvent::Query { .. }))
+            ));
+        }
+
+        // an invalid id counts as failure
+        let resp =
+            query.inject_response(response(Some(vec![0; 8]), &[]), Peer::from(closer[0].addr));
+        assert!(resp.is_none());
+        assert_eq!(query.stats.num_failures(), 1);
+
+        let mut error = response(None, &[]);
+        error.error = Some("Unsupported command".to_string());
+        assert!(query
+            .inject_response(error, Peer::from(closer[1].addr))
+            .is_none());
+        assert_eq!(query.stats.num_failures(), 2);
+        assert_eq!(query.stats.num_pending(), 0);
+        assert!(matches!(query.poll(time::now(), None), Poll::Ready(None)));
+    }
+
+    #[test]
+    fn skip_filtered_closer_nodes() {
+        let bootstrap = Peer::from(([127, 0, 0, 1], 1));
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            Command::FindNode,
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::Query,
+            Key::new(IdBytes::random()),
+            Key::new(IdBytes::random()),
+            None,
+            vec![],
+            vec![bootstrap.clone()],
+        );
+        assert!(matches!(
+            query.poll(time::now(), None),
+            Poll::Ready(Some(QueryEvent::Query { .. }))
+        ));
+
+        let blocked = peer_key(2).into_preimage();
+        let allowed = peer_key(3).into_preimage();
+        query.inject_response_filtered(
+            response(None, &[blocked.clone(), allowed.clone()]),
+            bootstrap,
+            &[],
+            |node| node.addr != blocked.addr,
+        );
+        let known = query
+            .inner
+            .peers()
+            .map(|(key, _)| key.preimage().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(known, vec![allowed]);
+    }
+
+    #[test]
+    fn remember_referrers() {
+        let bootstrap = (1..=4)
+            .map(|port| Peer::from(([127, 0, 0, 1], port)))
+            .collect::<Vec<_>>();
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            Command::FindNode,
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::Query,
+            Key::new(IdBytes::random()),
+            Key::new(IdBytes::random()),
+            None,
+            vec![],
+            bootstrap.clone(),
+        );
+        let node = peer_key(10).into_preimage();
+        let other = peer_key(11).into_preimage();
+        let mut contacted = Vec::new();
+        let mut responded = 0;
+        while responded < bootstrap.len() {
+            while let Poll::Ready(Some(QueryEvent::Query { peer, .. })) =
+                query.poll(time::now(), None)
+            {
+                contacted.push(peer);
+            }
+            // every bootstrap node returns the same node, the first one
+            // another node as well
+            let peer = bootstrap[responded].clone();
+            let closer = if responded == 0 {
+                vec![node.clone(), other.clone()]
+            } else {
+                vec![node.clone()]
+            };
+            query.inject_response(response(None, &closer), peer);
+            responded += 1;
+        }
+        let addrs = |peers: &[Peer]| peers.iter().map(|p| p.addr).collect::<Vec<_>>();
+        assert_eq!(query.inner.referrers(&node.addr), &addrs(&bootstrap)[..3]);
+        assert_eq!(query.inner.referrers(&other.addr), &[bootstrap[0].addr]);
+
+        // the nodes are contacted with their first referrer
+        while let Poll::Ready(Some(QueryEvent::Query { peer, .. })) = query.poll(time::now(), None)
+        {
+            contacted.push(peer);
+        }
+        for peer in contacted.iter().filter(|p| !bootstrap.contains(p)) {
+            assert_eq!(peer.referrer, Some(bootstrap[0].addr));
+        }
+        assert_eq!(contacted.len(), bootstrap.len() + 2);
+
+        let resp = query
+            .inject_response(response(None, &[]), Peer::from(node.addr))
+            .unwrap();
+        assert_eq!(resp.referrers, addrs(&bootstrap)[..3]);
+    }
+
+    #[test]
+    fn query_update_phases() {
+        let bootstrap = Peer::from(([127, 0, 0, 1], 1));
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            "test",
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::QueryUpdate,
+            Key::new(IdBytes::random()),
+            Key::new(IdBytes::random()),
+            Some(Bytes::from_static(b"value")),
+            vec![],
+            vec![bootstrap.clone()],
+        );
+
+        let peer = match query.poll(time::now(), None) {
+            Poll::Ready(Some(QueryEvent::Query { peer, command, .. })) => {
+                assert_eq!(command, Command::Unknown("test".to_string()));
+                peer
+            }
+            ev => panic!("Unexpected event {:?}", ev),
+        };
+        assert!(matches!(query.peer_iter, QueryPeerIter::Bootstrap(_)));
+        let closer = peer_key(2).into_preimage();
+        let mut resp = response(
+            Some(IdBytes::random().to_vec()),
+            std::slice::from_ref(&closer),
+        );
+        resp.roundtrip_token = Some(vec![1; 32].into());
+        query.inject_response(resp, peer).unwrap();
+
+        // the bootstrap node is done, continue with the discovered node
+        let peer = match query.poll(time::now(), None) {
+            Poll::Ready(Some(QueryEvent::Query { peer, .. })) => peer,
+            ev => panic!("Unexpected event {:?}", ev),
+        };
+        assert!(matches!(query.peer_iter, QueryPeerIter::MovingCloser(_)));
+        assert_eq!(peer.addr, closer.addr);
+        let mut resp = response(Some(closer.id.to_vec()), &[]);
+        resp.roundtrip_token = Some(vec![2; 32].into());
+        query.inject_response(resp, peer).unwrap();
+
+        // no closer nodes, update the closest nodes with their tokens
+        let mut updated = Vec::new();
+        for _ in 0..2 {
+            match query.poll(time::now(), None) {
+                Poll::Ready(Some(QueryEvent::Update {
+                    peer, token, value, ..
+                })) => {
+                    assert_eq!(value, Some(Bytes::from_static(b"value")));
+                    updated.push((peer, token.unwrap()));
+                }
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+        }
+        assert!(matches!(query.peer_iter, QueryPeerIter::Updating(_)));
+        updated.sort();
+        assert_eq!(
+            updated,
+            vec![
+                (bootstrap.clone(), vec![1; 32].into()),
+                (
+                    Peer::new(closer.addr, Some(bootstrap.addr)),
+                    vec![2; 32].into()
+                )
+            ]
+        );
+        assert!(matches!(query.poll(time::now(), None), Poll::Pending));
+
+        for (peer, _) in updated {
+            query.inject_response(response(None, &[]), peer).unwrap();
+        }
+        assert!(matches!(query.poll(time::now(), None), Poll::Ready(None)));
+        assert_eq!(query.stats.num_requests(), 4);
+        assert_eq!(query.stats.num_successes(), 4);
+    }
+
+    #[test]
+    fn update_token_holders_with_own_value() {
+        let nodes = (2..=3)
+            .map(|port| peer_key(port).into_preimage())
+            .collect::<Vec<_>>();
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            "test",
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::QueryUpdate,
+            Key::new(IdBytes::random()),
+            Key::new(IdBytes::random()),
+            Some(Bytes::from_static(b"query")),
+            vec![],
+            nodes.iter().map(|n| Peer::from(n.addr)),
+        );
+        query.set_update_value(Some(Bytes::from_static(b"update")));
+        for _ in &nodes {
+            match poll_query(&mut query) {
+                Poll::Ready(Some(QueryEvent::Query { value, .. })) => {
+                    assert_eq!(value, Some(Bytes::from_static(b"query")))
+                }
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+        }
+        let with_token = response(Some(nodes[0].id.to_vec()), &[]);
+        query.inject_response(with_token, Peer::from(nodes[0].addr));
+        let mut without_token = response(Some(nodes[1].id.to_vec()), &[]);
+        without_token.roundtrip_token = None;
+        query.inject_response(without_token, Peer::from(nodes[1].addr));
+
+        let mut updated = Vec::new();
+        loop {
+            match poll_query(&mut query) {
+                Poll::Ready(Some(QueryEvent::Update { peer, value, .. })) => {
+                    assert_eq!(value, Some(Bytes::from_static(b"update")));
+                    updated.push(peer.addr);
+                }
+                Poll::Ready(Some(QueryEvent::MissingRoundtripToken { .. })) => {}
+                Poll::Pending => break,
+                ev => panic!("Unexpected event {:?}", ev),
+            }
+        }
+        assert_eq!(updated, [nodes[0].addr]);
+
+        // only finished once the update was answered
+        query.inject_response(response(None, &[]), Peer::from(nodes[0].addr));
+        assert!(matches!(poll_query(&mut query), Poll::Ready(None)));
+        assert_eq!(query.stats().num_requests(), 3);
+    }
+
+    #[test]
+    fn converge_on_target() {
+        let target = Key::new(IdBytes::random());
+        let mut nodes = (2..=5)
+            .map(|port| peer_key(port).into_preimage())
+            .collect::<Vec<_>>();
+        let bootstrap = Peer::from(nodes[0].addr);
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            Command::FindNode,
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::Query,
+            Key::new(IdBytes::random()),
+            target.clone(),
+            None,
+            vec![],
+            vec![bootstrap.clone()],
+        );
+        let expect_query = |query: &mut QueryStream| match poll_query(query) {
+            Poll::Ready(Some(QueryEvent::Query { peer, .. })) => peer.addr,
+            ev => panic!("Unexpected event {:?}", ev),
+        };
+        let respond = |query: &mut QueryStream, node: &PeerId, closer: &[PeerId]| {
+            let mut resp = response(Some(node.id.to_vec()), closer);
+            resp.roundtrip_token = Some(node.addr.port().to_be_bytes().to_vec().into());
+            query.inject_response(resp, Peer::from(node.addr))
+        };
+
+        // every node knows one that is closer, the last one knows two
+        assert_eq!(expect_query(&mut query), nodes[0].addr);
+        assert!(respond(&mut query, &nodes[0], &nodes[1..2]).is_some());
+        assert_eq!(expect_query(&mut query), nodes[1].addr);
+        assert!(respond(&mut query, &nodes[1], &nodes[2..]).is_some());
+        let mut contacted = vec![expect_query(&mut query), expect_query(&mut query)];
+        contacted.sort();
+        assert_eq!(contacted, [nodes[2].addr, nodes[3].addr]);
+        assert!(matches!(poll_query(&mut query), Poll::Pending));
+
+        // the late response of a node that timed out is not counted
+        query.on_timeout(Peer::from(nodes[3].addr));
+        assert!(respond(&mut query, &nodes[3], &[]).is_none());
+        assert_eq!(query.stats.num_successes(), 2);
+        assert!(respond(&mut query, &nodes[2], &[]).is_some());
+        assert!(matches!(poll_query(&mut query), Poll::Ready(None)));
+        assert_eq!(query.stats.num_requests(), 4);
+        assert_eq!(query.stats.num_successes(), 3);
+
+        // the responding nodes with their tokens, closest first
+        for node in &nodes[..3] {
+            let token = query.inner.get_token(&Peer::from(node.addr));
+            assert_eq!(
+                token.map(|t| t.to_vec()),
+                Some(node.addr.port().to_be_bytes().to_vec())
+            );
+        }
+        let failed = nodes.pop().unwrap();
+        assert!(query.inner.get_token(&Peer::from(failed.addr)).is_none());
+        nodes.sort_by_key(|n| target.distance(&Key::new(n.clone())));
+        let closest = query.into_result().closest;
+        let closest = closest.into_iter().map(|(n, _)| n).collect::<Vec<_>>();
+        assert_eq!(closest, nodes);
+    }
+
+    #[test]
+    fn sanitize_closer_nodes() {
+        let local_id = IdBytes::random();
+        let target = IdBytes::random();
+        let remote = peer_key(2).into_preimage();
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            "values",
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::Query,
+            Key::new(local_id.clone()),
+            Key::new(target.clone()),
+            None,
+            vec![],
+            vec![Peer::from(remote.addr)],
+        );
+        assert!(matches!(
+            poll_query(&mut query),
+            Poll::Ready(Some(QueryEvent::Query { .. }))
+        ));
+
+        let node = |addr: SocketAddr| PeerId::new(addr, IdBytes::random());
+        let own_addr = ([10, 0, 0, 9], 1000).into();
+        // closest to the target, so that the table keeps it among the extra nodes
+        let valid = PeerId::new(([10, 0, 0, 1], 1000).into(), target);
+        let mut closer = vec![
+            valid.clone(),
+            PeerId::new(([10, 0, 0, 2], 1000).into(), local_id),
+            node(own_addr),
+            node(remote.addr),
+            node(([0, 0, 0, 0], 1000).into()),
+            node(([224, 0, 0, 1], 1000).into()),
+            node(([255, 255, 255, 255], 1000).into()),
+            node(([10, 0, 0, 3], 0).into()),
+            // same address or id as an earlier node
+            node(valid.addr),
+            PeerId::new(([10, 0, 0, 4], 1000).into(), valid.id.clone()),
+        ];
+        let junk = closer.len() - 1;
+        // more than the nodes accepted per response
+        let extra = 5;
+        closer.extend(
+            (0..(K_VALUE.get() + extra) as u16).map(|port| node(([10, 0, 1, 1], port + 1).into())),
+        );
+
+        let resp = response(Some(remote.id.to_vec()), &closer);
+        assert!(query
+            .inject_response_filtered(resp, Peer::from(remote.addr), &[own_addr], |_| true)
+            .is_some());
+
+        let known = query
+            .inner
+            .peers()
+            .map(|(key, _)| key.preimage().addr)
+            .collect::<Vec<_>>();
+        assert!(known.contains(&valid.addr));
+        for dropped in &closer[1..=junk] {
+            if dropped.addr != remote.addr && dropped.addr != valid.addr {
+                assert!(!known.contains(&dropped.addr));
+            }
+        }
+        // all but `K_VALUE - 1` of the extra nodes are dropped as well
+        assert_eq!(query.stats().num_dropped_nodes() as usize, junk + extra + 1);
+    }
+
+    #[test]
+    fn collect_errors() {
+        let nodes = (2..=3)
+            .map(|port| peer_key(port).into_preimage())
+            .collect::<Vec<_>>();
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            "values",
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::Query,
+            Key::new(IdBytes::random()),
+            Key::new(IdBytes::random()),
+            None,
+            vec![],
+            nodes.iter().map(|n| Peer::from(n.addr)),
+        );
+        for _ in &nodes {
+            assert!(matches!(
+                poll_query(&mut query),
+                Poll::Ready(Some(QueryEvent::Query { .. }))
+            ));
+        }
+        let closer = [peer_key(4).into_preimage()];
+        let mut error = response(Some(nodes[0].id.to_vec()), &closer);
+        error.error = Some("Unsupported command".to_string());
+        assert!(query
+            .inject_response(error, Peer::from(nodes[0].addr))
+            .is_none());
+        let ok = response(Some(nodes[1].id.to_vec()), &[]);
+        assert!(query
+            .inject_response(ok, Peer::from(nodes[1].addr))
+            .is_some());
+
+        // the nodes of an error response are not contacted
+        assert!(query.inner.state(&closer[0].addr).is_none());
+        let result = query.into_result();
+        assert_eq!(
+            result.errors,
+            vec![(nodes[0].addr, "Unsupported command".to_string())]
+        );
+        assert_eq!(result.stats.failure, 1);
+    }
+
+    #[test]
+    fn collect_values() {
+        let nodes = (2..=4)
+            .map(|port| peer_key(port).into_preimage())
+            .collect::<Vec<_>>();
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            "values",
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::Query,
+            Key::new(IdBytes::random()),
+            Key::new(IdBytes::random()),
+            None,
+            vec![],
+            nodes.iter().map(|n| Peer::from(n.addr)),
+        );
+        for _ in &nodes {
+            assert!(matches!(
+                poll_query(&mut query),
+                Poll::Ready(Some(QueryEvent::Query { .. }))
+            ));
+        }
+        for (i, node) in nodes.iter().enumerate().rev() {
+            let mut resp = response(Some(node.id.to_vec()), &[]);
+            // not every node has a value
+            resp.value = (i > 0).then(|| vec![i as u8].into());
+            resp.roundtrip_token = Some(vec![i as u8].into());
+            query.inject_response(resp, Peer::from(node.addr));
+        }
+
+        let result = query.into_result();
+        assert_eq!(
+            result.values,
+            vec![
+                (nodes[2].addr, Bytes::from(vec![2])),
+                (nodes[1].addr, Bytes::from(vec![1])),
+            ]
+        );
+        let mut closest = result.closest;
+        closest.sort_by_key(|(node, _)| node.addr.port());
+        let tokens = closest
+            .into_iter()
+            .map(|(node, token)| (node, token.to_vec()));
+        assert!(tokens.eq(nodes.into_iter().zip(vec![vec![0], vec![1], vec![2]])));
+    }
+
+    fn poll_query(query: &mut QueryStream) -> Poll<Option<QueryEvent>> {
+        query.poll(time::now(), None)
+    }
+
+    fn bootstrap_query(peers: Vec<Key<PeerId>>, bootstrap: Vec<Peer>) -> QueryStream {
+        QueryStream::bootstrap(
+            QueryId(0),
+            Command::FindNode,
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::Query,
+            Key::new(IdBytes::random()),
+            Key::new(IdBytes::random()),
+            None,
+            peers,
+            bootstrap,
+        )
+    }
+
+    fn expect_request(query: &mut QueryStream) -> Peer {
+        match poll_query(query) {
+            Poll::Ready(Some(QueryEvent::Query { peer, .. })) => peer,
+            ev => panic!("Unexpected event {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn finish_when_bootstrap_failed() {
+        let bootstrap = (1..=2)
+            .map(|port| Peer::from(([127, 0, 0, 1], port)))
+            .collect::<Vec<_>>();
+        let mut query = bootstrap_query(vec![], bootstrap);
+        for _ in 0..2 {
+            let peer = expect_request(&mut query);
+            query.on_timeout(peer);
+        }
+        assert!(matches!(poll_query(&mut query), Poll::Ready(None)));
+        let result = query.into_result();
+        assert_eq!(result.bootstrap, Some(FixedPeersOutcome::Failed));
+        assert_eq!(result.stats.num_timeouts(), 2);
+
+        // known peers are still asked if no bootstrap node responded
+        let known = peer_key(3);
+        let mut query = bootstrap_query(vec![known.clone()], vec![Peer::from(([127, 0, 0, 1], 1))]);
+        let peer = expect_request(&mut query);
+        query.on_timeout(peer);
+        assert_eq!(expect_request(&mut query).addr, known.preimage().addr);
+        assert_eq!(query.bootstrap, Some(FixedPeersOutcome::Failed));
+    }
+
+    #[test]
+    fn continue_with_responsive_bootstrap_nodes() {
+        let bootstrap = (1..=3)
+            .map(|port| Peer::from(([127, 0, 0, 1], port)))
+            .collect::<Vec<_>>();
+        let mut query = bootstrap_query(vec![], bootstrap);
+        let mut requested = (0..3)
+            .map(|_| expect_request(&mut query))
+            .collect::<Vec<_>>();
+        requested.sort();
+        let closer = peer_key(4).into_preimage();
+        let resp = response(
+            Some(IdBytes::random().to_vec()),
+            std::slice::from_ref(&closer),
+        );
+        query.inject_response(resp, requested[0].clone()).unwrap();
+        query.on_timeout(requested[1].clone());
+        query.on_timeout(requested[2].clone());
+
+        assert_eq!(expect_request(&mut query).addr, closer.addr);
+        assert!(matches!(query.peer_iter, QueryPeerIter::MovingCloser(_)));
+        assert_eq!(
+            query.bootstrap,
+            Some(FixedPeersOutcome::Finished(vec![requested[0].addr]))
+        );
+    }
+
+    #[test]
+    fn query_phases() {
+        let bootstrap = Peer::from(([127, 0, 0, 1], 1));
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            "test",
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::Query,
+            Key::new(IdBytes::random()),
+            Key::new(IdBytes::random()),
+            Some(Bytes::from_static(b"value")),
+            vec![],
+            vec![bootstrap.clone()],
+        );
+        let target = query.target().preimage().clone();
+
+        // the bootstrap nodes are queried with the command right away
+        let peer = match poll_query(&mut query) {
+            Poll::Ready(Some(QueryEvent::Query {
+                peer,
+                command,
+                target: t,
+                value,
+            })) => {
+                assert_eq!(command, Command::Unknown("test".to_string()));
+                assert_eq!(t, target);
+                assert_eq!(value, Some(Bytes::from_static(b"value")));
+                peer
+            }
+            ev => panic!("Unexpected event {:?}", ev),
+        };
+        assert_eq!(peer, bootstrap);
+        // waiting for the response
+        assert!(matches!(poll_query(&mut query), Poll::Pending));
+        assert!(matches!(query.peer_iter, QueryPeerIter::Bootstrap(_)));
+
+        let closer = peer_key(2).into_preimage();
+        let resp = response(
+            Some(IdBytes::random().to_vec()),
+            std::slice::from_ref(&closer),
+        );
+        assert!(query.inject_response(resp, peer).is_some());
+
+        let peer = match poll_query(&mut query) {
+            Poll::Ready(Some(QueryEvent::Query { peer, command, .. })) => {
+                assert_eq!(command, Command::Unknown("test".to_string()));
+                peer
+            }
+            ev => panic!("Unexpected event {:?}", ev),
+        };
+        assert!(matches!(query.peer_iter, QueryPeerIter::MovingCloser(_)));
+        assert_eq!(peer.addr, closer.addr);
+        assert!(matches!(poll_query(&mut query), Poll::Pending));
+
+        // the closest node responded without closer nodes, a query finishes
+        // without updating
+        let resp = response(Some(closer.id.to_vec()), &[]);
+        assert!(query.inject_response(resp, peer).is_some());
+        assert!(matches!(poll_query(&mut query), Poll::Ready(None)));
+        assert!(!query.is_updating());
+        assert_eq!(query.stats.num_requests(), 2);
+        assert_eq!(query.stats.num_pending(), 0);
+    }
+
+    #[test]
+    fn update_phases() {
+        let bootstrap = Peer::from(([127, 0, 0, 1], 1));
+        let mut query = QueryStream::bootstrap(
+            QueryId(0),
+            "test",
+            ALPHA_VALUE,
+            K_VALUE,
+            QueryType::Update,
+            Key::new(IdBytes::random()),
+            Key::new(IdBytes::random()),
+            Some(Bytes::from_static(b"value")),
+            vec![],
+            vec![bootstrap.clone()],
+        );
+
+        // only the update carries the command and the value, the phases
+        // before look up the closest nodes
+        let peer = match poll_query(&mut query) {
+            Poll::Ready(Some(QueryEvent::Query {
+                peer,
+                command,
+                value,
+                ..
+            })) => {
+                assert_eq!(command, Command::FindNode);
+                assert_eq!(value, None);
+                peer
+            }
+            ev => panic!("Unexpected event {:?}", ev),
+        };
+        let remote = IdBytes::random();
+        let mut resp = response(Some(remote.to_vec()), &[]);
+        resp.roundtrip_token = Some(vec![1; 32].into());
+        // responses of the bootstrap phase of an update are not reported
+        assert!(query.inject_response(resp, peer).is_none());
+
+        match poll_query(&mut query) {
+            Poll::Ready(Some(QueryEvent::Update {
+                peer,
+                command,
+                token,
+                value,
+                ..
+            })) => {
+                assert_eq!(peer, bootstrap);
+                assert_eq!(command, Command::Unknown("test".to_string()));
+                assert_eq!(token, Some(vec![1; 32].into()));
+                assert_eq!(value, Some(Bytes::from_static(b"value")));
+            }
+            ev => panic!("Unexpected event {:?}", ev),
+        }
+        assert!(query.is_updating());
+        assert!(matches!(poll_query(&mut query), Poll::Pending));
+
+        assert!(query
+            .inject_response(response(None, &[]), bootstrap)
+            .is_some());
+        assert!(matches!(poll_query(&mut query), Poll::Ready(None)));
+        assert_eq!(query.stats.num_requests(), 2);
+        assert_eq!(query.stats.num_successes(), 2);
+    }
+
+    #[test]
+    fn typed_command_values() {
+        use crate::rpc::message::Holepunch;
+
+        let value = Holepunch::with_from(vec![1, 2, 3]);
+        let mut buf = Vec::new();
+        prost::Message::encode(&value, &mut buf).unwrap();
+        let query = CommandQuery {
+            rid: RequestId(7),
+            ty: Type::Query,
+            command: "values".to_string(),
+            peer: Peer::from(([127, 0, 0, 1], 1)),
+            target: IdBytes::random(),
+            value: Some(buf.into()),
+        };
+        assert_eq!(query.decode_value::<Holepunch>(), Some(value));
+
+        let reply = Holepunch::new(vec![4], vec![5]);
+        let resp = query.into_response_with_value(&reply);
+        assert_eq!(resp.msg.rid, 7);
+        assert_eq!(resp.command, "values");
+        let value = resp.msg.value.unwrap();
+        assert_eq!(prost::Message::decode(&value[..]).ok(), Some(reply));
+    }
+}
@@ -1,40 +1,78 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::num::NonZeroUsize;
 
+use bytes::Bytes;
 use fnv::FnvHashMap;
 
-use crate::kbucket::{Key, K_VALUE};
+use crate::kbucket::{Distance, Key};
+use crate::rpc::query::closest::ClosestPeersIter;
 use crate::rpc::query::fixed::FixedPeersIter;
 use crate::rpc::{self, IdBytes, PeerId};
 
+/// Maximum number of referrers that are kept for a peer.
+pub const MAX_REFERRERS: usize = 3;
+
 #[derive(Debug)]
 pub struct QueryTable {
     id: Key<IdBytes>,
     target: Key<IdBytes>,
-    /// The closest peers to the target.
-    peers: FnvHashMap<Key<PeerId>, PeerState>,
+    /// How many peers that did not fail are kept.
+    k: NonZeroUsize,
+    /// The closest peers to the target, and the peers that failed, by their
+    /// distance to the target.
+    peers: BTreeMap<Distance, (Key<PeerId>, PeerState)>,
+    /// The nodes that returned a peer as one of their closer nodes, by the
+    /// address of the peer.
+    referrers: FnvHashMap<SocketAddr, Vec<SocketAddr>>,
 }
 
 impl QueryTable {
-    pub fn new<T>(id: Key<IdBytes>, target: Key<IdBytes>, known_closest_peers: T) -> Self
+    /// Creates a table that keeps the `k` closest peers to the `target`.
+    pub fn new<T>(
+        id: Key<IdBytes>,
+        target: Key<IdBytes>,
+        k: NonZeroUsize,
+        known_closest_peers: T,
+    ) -> Self
     where
         T: IntoIterator<Item = Key<PeerId>>,
     {
+        let mut table = Self {
+            id,
+            target,
+            k,
+            peers: Default::default(),
+            referrers: Default::default(),
+        };
         // Initialise the closest peers to start the iterator with.
-        let peers: FnvHashMap<Key<PeerId>, PeerState> = known_closest_peers
-            .into_iter()
-            .map(|key| (key, PeerState::NotContacted))
-            .collect();
+        for key in known_closest_peers {
+            table.insert(key, PeerState::NotContacted);
+        }
+        table
+    }
+
+    /// The id of the local node.
+    pub fn local_id(&self) -> &Key<IdBytes> {
+        &self.id
+    }
 
-        Self { id, target, peers }
+    /// All peers with their state, closest to the target first.
+    pub fn peers(&self) -> impl ExactSizeIterator<Item = (&Key<PeerId>, &PeerState)> {
+        self.peers.values().map(|(p, s)| (p, s))
     }
 
-    pub fn peers(&self) -> &FnvHashMap<Key<PeerId>, PeerState> {
-        &self.peers
+    /// The peers that did not fail, closest to the target first.
+    pub fn closest(&self) -> impl Iterator<Item = (&Key<PeerId>, &PeerState)> {
+        self.peers().filter(|(_, s)| !s.is_failed())
     }
 
-    pub(crate) fn peers_mut(&mut self) -> &mut FnvHashMap<Key<PeerId>, PeerState> {
-        &mut self.peers
+    /// The state of the peer at `addr`.
+    pub fn state(&self, addr: &SocketAddr) -> Option<&PeerState> {
+        self.peers()
+            .find(|(p, _)| &p.preimage().addr == addr)
+            .map(|(_, s)| s)
     }
 
     pub fn target(&self) -> &Key<IdBytes> {
@@ -42,100 +80,173 @@ impl QueryTable {
     }
 
     pub fn get_peer(&self, peer: &rpc::Peer) -> Option<rpc::Peer> {
-        self.peers
-            .keys()
-            .filter(|p| p.preimage().addr == peer.addr)
-            .map(|p| rpc::Peer::from(p.preimage().addr))
-            .next()
+        self.peers()
+            .find(|(p, _)| p.preimage().addr == peer.addr)
+            .map(|(p, _)| rpc::Peer::from(p.preimage().addr))
     }
 
-    pub fn get_token(&self, peer: &rpc::Peer) -> Option<&Vec<u8>> {
-        self.peers
-            .iter()
+    /// The nodes that returned the peer at `addr`, first come first.
+    pub fn referrers(&self, addr: &SocketAddr) -> &[SocketAddr] {
+        self.referrers.get(addr).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn get_token(&self, peer: &rpc::Peer) -> Option<&Bytes> {
+        self.peers()
             .filter(|(p, _)| p.preimage().addr == peer.addr)
             .map(|(_, s)| s.get_token())
             .next()
             .flatten()
     }
 
-    pub fn unverified_peers_iter(&self, parallelism: NonZeroUsize) -> FixedPeersIter {
-        FixedPeersIter::new(
-            self.peers
-                .iter()
-                .filter(|(_, s)| s.is_not_contacted())
-                .map(|(p, _)| rpc::Peer::from(p.preimage().addr)),
-            parallelism,
-        )
+    /// Creates an iterator over the closest peers to the target, seeded with
+    /// all peers that didn't fail so far, that finishes once the `num_results`
+    /// closest peers responded.
+    pub fn closer_peers_iter(
+        &self,
+        parallelism: NonZeroUsize,
+        num_results: NonZeroUsize,
+    ) -> ClosestPeersIter {
+        let mut iter =
+            ClosestPeersIter::with_num_results(self.target.clone(), None, parallelism, num_results);
+        for (peer, state) in self.peers() {
+            match state {
+                // a request that is still running is not awaited by the iterator
+                PeerState::NotContacted | PeerState::Waiting => iter.add_peer(peer.clone()),
+                PeerState::Succeeded { .. } => iter.add_succeeded(peer.clone()),
+                PeerState::Failed => false,
+            };
+        }
+        iter
     }
 
-    pub fn closest_peers_iter(&self, parallelism: NonZeroUsize) -> FixedPeersIter {
-        let mut peers = self
-            .peers
-            .iter()
-            .filter(|(_, s)| s.is_verified())
-            .map(|(p, _)| p)
-            .collect::<Vec<_>>();
-
-        peers.sort_by_key(|&a| self.target.distance(a));
-
+    /// Creates an iterator over the `num_results` closest verified peers.
+    pub fn closest_peers_iter(
+        &self,
+        parallelism: NonZeroUsize,
+        num_results: NonZeroUsize,
+    ) -> FixedPeersIter {
         FixedPeersIter::new(
-            peers
-                .into_iter()
-                .take(usize::from(K_VALUE))
-                .map(|p| rpc::Peer::from(p.preimage().addr)),
+            self.peers()
+                .filter(|(_, s)| s.is_verified())
+                .take(num_results.get())
+                .map(|(p, _)| rpc::Peer::from(p.preimage().addr)),
             parallelism,
         )
     }
 
-    pub(crate) fn add_unverified(&mut self, peer: PeerId) {
+    /// Adds a peer that has not been contacted yet, returned by the node at
+    /// `referrer`.
+    ///
+    /// The referrer is remembered even if the peer is already known, up to
+    /// [`MAX_REFERRERS`] per peer.
+    ///
+    /// Returns `false` if the peer is our own or already known.
+    pub(crate) fn add_unverified(&mut self, peer: PeerId, referrer: SocketAddr) -> bool {
         if &peer.id == self.id.preimage() {
-            return;
+            return false;
+        }
+        if peer.addr != referrer {
+            let referrers = self.referrers.entry(peer.addr).or_default();
+            if referrers.len() < MAX_REFERRERS && !referrers.contains(&referrer) {
+                referrers.push(referrer);
+            }
         }
-        self.peers.insert(Key::new(peer), PeerState::NotContacted);
+        let key = Key::new(peer);
+        let distance = self.target.distance(&key);
+        if self.peers.contains_key(&distance) {
+            return false;
+        }
+        self.insert(key, PeerState::NotContacted);
+        // the peer is not kept if it is not one of the closest
+        self.peers.contains_key(&distance)
     }
 
     pub(crate) fn add_verified(
         &mut self,
         key: Key<PeerId>,
-        roundtrip_token: Vec<u8>,
+        roundtrip_token: Bytes,
         to: Option<SocketAddr>,
     ) {
         if key == self.id {
             return;
         }
-        if let Some(prev) = self.peers.get_mut(&key) {
-            *prev = PeerState::Succeeded {
-                roundtrip_token,
-                to,
-            };
+        let state = PeerState::Succeeded {
+            roundtrip_token,
+            to,
+        };
+        if self.peers.contains_key(&self.target.distance(&key)) {
+            self.set_state(&key, state);
         } else {
-            self.peers.insert(
-                key,
-                PeerState::Succeeded {
-                    roundtrip_token,
-                    to,
-                },
-            );
+            self.insert(key, state);
         }
     }
 
-    /// Set the state of every `Peer` to `PeerState::NotContacted`
-    pub fn set_all_not_contacted(&mut self) {
-        for state in self.peers.values_mut() {
-            *state = PeerState::NotContacted;
+    /// A request was sent to the peer at `addr`.
+    pub(crate) fn on_sent(&mut self, addr: &SocketAddr) {
+        if let Some(key) = self.key(addr) {
+            self.set_state(&key, PeerState::Waiting);
         }
     }
 
-    pub(crate) fn into_result(self) -> impl Iterator<Item = (PeerId, PeerState)> {
-        self.peers.into_iter().map(|(k, v)| (k.into_preimage(), v))
+    /// The request to the peer at `addr` failed.
+    pub(crate) fn on_failure(&mut self, addr: &SocketAddr) {
+        if let Some(key) = self.key(addr) {
+            self.set_state(&key, PeerState::Failed);
+        }
     }
-}
 
-/// Representation of a peer in the context of a iterator.
-#[derive(Debug, Clone)]
-pub(crate) struct Peer {
-    key: Key<PeerId>,
-    state: PeerState,
+    /// Moves the peer to the `next` state.
+    ///
+    /// Returns `false` if the peer is unknown or the transition is not
+    /// allowed, see [`PeerState::can_become`].
+    pub(crate) fn set_state(&mut self, key: &Key<PeerId>, next: PeerState) -> bool {
+        match self.peers.get_mut(&self.target.distance(key)) {
+            Some((_, state)) if state.can_become(&next) => {
+                *state = next;
+                self.truncate();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn key(&self, addr: &SocketAddr) -> Option<Key<PeerId>> {
+        self.peers()
+            .find(|(p, _)| &p.preimage().addr == addr)
+            .map(|(p, _)| p.clone())
+    }
+
+    fn insert(&mut self, key: Key<PeerId>, state: PeerState) {
+        if let Entry::Vacant(e) = self.peers.entry(self.target.distance(&key)) {
+            e.insert((key, state));
+            self.truncate();
+        }
+    }
+
+    /// Drops the farthest peers until at most `k` peers that were not
+    /// contacted or responded are left.
+    ///
+    /// Failed peers are kept so they can be removed from the routing table
+    /// once the query finished, peers that were sent a request are kept until
+    /// they responded.
+    fn truncate(&mut self) {
+        let farthest = self
+            .peers
+            .iter()
+            .filter(|(_, (_, s))| s.is_not_contacted() || s.is_verified())
+            .skip(self.k.get())
+            .map(|(distance, _)| *distance)
+            .collect::<Vec<_>>();
+        for distance in farthest {
+            self.peers.remove(&distance);
+        }
+    }
+
+    pub(crate) fn into_result(self) -> impl Iterator<Item = (PeerId, PeerState)> {
+        self.peers
+            .into_iter()
+            .map(|(_, (k, v))| (k.into_preimage(), v))
+    }
 }
 
 /// The state of a single `Peer`.
@@ -146,6 +257,9 @@ pub enum PeerState {
     /// This is the starting state for every peer.
     NotContacted,
 
+    /// A request was sent to the peer and its response is awaited.
+    Waiting,
+
     /// Obtaining a result from the peer has failed.
     ///
     /// This is a final state, reached as a result of a call to `on_failure`.
@@ -153,9 +267,9 @@ pub enum PeerState {
 
     /// A successful result from the peer has been delivered.
     ///
-    /// This is a final state, reached as a result of a call to `on_success`.
+    /// The peer can still fail, if a later update request to it fails.
     Succeeded {
-        roundtrip_token: Vec<u8>,
+        roundtrip_token: Bytes,
         to: Option<SocketAddr>,
     },
 }
@@ -169,7 +283,25 @@ impl PeerState {
         matches!(self, PeerState::NotContacted)
     }
 
-    pub fn get_token(&self) -> Option<&Vec<u8>> {
+    pub fn is_failed(&self) -> bool {
+        matches!(self, PeerState::Failed)
+    }
+
+    /// Whether a peer in this state may move to the `next` state.
+    ///
+    /// A failed peer stays failed and a peer that responded is not waited
+    /// for again, its roundtrip token is kept for the update requests.
+    pub fn can_become(&self, next: &PeerState) -> bool {
+        match (self, next) {
+            (PeerState::Failed, _) => false,
+            (PeerState::Succeeded { .. }, PeerState::Failed | PeerState::Succeeded { .. }) => true,
+            (PeerState::Succeeded { .. }, _) => false,
+            (PeerState::Waiting, PeerState::NotContacted) => false,
+            _ => true,
+        }
+    }
+
+    pub fn get_token(&self) -> Option<&Bytes> {
         match self {
             PeerState::Succeeded {
                 roundtrip_token, ..
@@ -178,3 +310,157 @@ impl PeerState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(target: &Key<IdBytes>, num: u16) -> Vec<Key<PeerId>> {
+        let mut peers = (0..num)
+            .map(|port| {
+                Key::new(PeerId::new(
+                    ([127, 0, 0, 1], port).into(),
+                    IdBytes::random(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|p| target.distance(p));
+        peers
+    }
+
+    fn table(target: &Key<IdBytes>, k: usize) -> QueryTable {
+        QueryTable::new(
+            Key::new(IdBytes::random()),
+            target.clone(),
+            NonZeroUsize::new(k).unwrap(),
+            None,
+        )
+    }
+
+    #[test]
+    fn keep_k_closest() {
+        let target = Key::new(IdBytes::random());
+        let peers = peers(&target, 8);
+        let mut table = table(&target, 3);
+        let referrer = ([127, 0, 0, 2], 1).into();
+
+        // the farthest first, every closer peer replaces one of them
+        for peer in peers[3..].iter().rev() {
+            table.add_unverified(peer.preimage().clone(), referrer);
+        }
+        assert!(!table.add_unverified(peers[7].preimage().clone(), referrer));
+        for peer in peers[..3].iter().rev() {
+            assert!(table.add_unverified(peer.preimage().clone(), referrer));
+        }
+        assert!(!table.add_unverified(peers[4].preimage().clone(), referrer));
+        let closest = table.closest().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+        assert_eq!(closest, peers[..3]);
+
+        // failed peers are kept but do not take a slot
+        table.on_failure(&peers[0].preimage().addr);
+        assert!(table.add_unverified(peers[3].preimage().clone(), referrer));
+        let closest = table.closest().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+        assert_eq!(closest, peers[1..4]);
+        assert!(table.state(&peers[0].preimage().addr).unwrap().is_failed());
+    }
+
+    #[test]
+    fn order_by_full_distance() {
+        let target = Key::with_bytes(IdBytes::random(), [0; 32]);
+        let key = |bytes: &[(usize, u8)]| {
+            let mut key = [0; 32];
+            for (i, b) in bytes {
+                key[*i] = *b;
+            }
+            let addr = ([127, 0, 0, 1], key[0] as u16).into();
+            Key::with_bytes(PeerId::new(addr, IdBytes::random()), key)
+        };
+        // the lowest 64 bits of the distances order them the other way
+        // around, the highest 64 bits can't tell the first two apart
+        let peers = [
+            key(&[(0, 1), (31, 0xff)]),
+            key(&[(0, 1), (8, 1)]),
+            key(&[(0, 2)]),
+        ];
+        let mut table = table(&target, 2);
+        for peer in peers.iter().rev() {
+            table.insert(peer.clone(), PeerState::NotContacted);
+        }
+        let closest = table.closest().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+        assert_eq!(closest, peers[..2]);
+    }
+
+    #[test]
+    fn keep_waiting_peers() {
+        let target = Key::new(IdBytes::random());
+        let peers = peers(&target, 3);
+        let mut table = table(&target, 1);
+        table.add_unverified(peers[2].preimage().clone(), peers[0].preimage().addr);
+        table.on_sent(&peers[2].preimage().addr);
+
+        // a peer that was sent a request does not take a slot
+        assert!(table.add_unverified(peers[1].preimage().clone(), peers[0].preimage().addr));
+        assert_eq!(table.peers().len(), 2);
+        // but it is dropped once it responded and is not one of the closest
+        table.add_verified(peers[2].clone(), Bytes::from_static(b"token"), None);
+        assert_eq!(table.peers().len(), 1);
+        table.add_unverified(peers[0].preimage().clone(), peers[1].preimage().addr);
+        let closest = table.closest().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+        assert_eq!(closest, [peers[0].clone()]);
+    }
+
+    #[test]
+    fn state_transitions() {
+        let target = Key::new(IdBytes::random());
+        let peers = peers(&target, 2);
+        let mut table = table(&target, 20);
+        let (failed, succeeded) = (&peers[0], &peers[1]);
+        let referrer = ([127, 0, 0, 2], 1).into();
+        table.add_unverified(failed.preimage().clone(), referrer);
+        table.add_unverified(succeeded.preimage().clone(), referrer);
+
+        let state = |table: &QueryTable, peer: &Key<PeerId>| {
+            table.state(&peer.preimage().addr).unwrap().clone()
+        };
+        assert!(state(&table, failed).is_not_contacted());
+        table.on_sent(&failed.preimage().addr);
+        assert!(matches!(state(&table, failed), PeerState::Waiting));
+        assert!(!table.set_state(failed, PeerState::NotContacted));
+        table.on_failure(&failed.preimage().addr);
+        assert!(state(&table, failed).is_failed());
+        // a failed peer stays failed
+        assert!(!table.set_state(failed, PeerState::Waiting));
+        table.add_verified(failed.clone(), Bytes::from_static(b"token"), None);
+        assert!(state(&table, failed).is_failed());
+
+        table.on_sent(&succeeded.preimage().addr);
+        table.add_verified(succeeded.clone(), Bytes::from_static(b"token"), None);
+        // the token is kept while the update request is awaited
+        table.on_sent(&succeeded.preimage().addr);
+        assert_eq!(
+            table.get_token(&rpc::Peer::from(succeeded.preimage().addr)),
+            Some(&Bytes::from_static(b"token"))
+        );
+        table.on_failure(&succeeded.preimage().addr);
+        assert!(state(&table, succeeded).is_failed());
+    }
+
+    #[test]
+    fn dedupe_by_id() {
+        let target = Key::new(IdBytes::random());
+        let mut table = table(&target, 3);
+        let referrer = ([127, 0, 0, 2], 1).into();
+        let peer = peers(&target, 1).remove(0).into_preimage();
+        assert!(table.add_unverified(peer.clone(), referrer));
+        assert!(!table.add_unverified(peer.clone(), referrer));
+
+        // the same node at another address is not added again
+        let moved = PeerId::new(([127, 0, 0, 3], 1).into(), peer.id.clone());
+        assert!(!table.add_unverified(moved, referrer));
+        assert_eq!(table.peers().len(), 1);
+        assert_eq!(
+            table.state(&peer.addr).map(|s| s.is_not_contacted()),
+            Some(true)
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use std::net::SocketAddr;
+
+use lru::LruCache;
+use wasm_timer::Instant;
+
+/// Maximum number of addresses the limiter keeps track of.
+pub const MAX_ADDRS: usize = 64 * 1024;
+
+/// The number of requests a single address may send.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Sustained number of requests per second.
+    pub rate: f64,
+    /// Number of requests that may arrive at once.
+    pub burst: f64,
+}
+
+impl RateLimit {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self { rate, burst }
+    }
+
+    /// Scales both rate and burst by `factor`.
+    pub fn scale(self, factor: f64) -> Self {
+        Self {
+            rate: self.rate * factor,
+            burst: self.burst * factor,
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new(20.0, 50.0)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per source address.
+///
+/// Only the most recently seen [`MAX_ADDRS`] addresses are tracked, an
+/// address that was evicted starts with a full bucket again.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: LruCache<SocketAddr, Bucket>,
+    /// Number of requests that exceeded the limit.
+    dropped: u64,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buckets: LruCache::new(capacity),
+            dropped: 0,
+        }
+    }
+
+    /// Takes a token from the bucket of `addr`.
+    ///
+    /// Returns `false` if the bucket is empty, i.e. the request should be
+    /// dropped.
+    pub fn check(&mut self, addr: SocketAddr, limit: RateLimit, now: Instant) -> bool {
+        let allowed = if let Some(bucket) = self.buckets.get_mut(&addr) {
+            let elapsed = now
+                .checked_duration_since(bucket.updated)
+                .unwrap_or_default();
+            bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * limit.rate).min(limit.burst);
+            bucket.updated = now;
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                true
+            } else {
+                false
+            }
+        } else {
+            self.buckets.put(
+                addr,
+                Bucket {
+                    tokens: limit.burst - 1.0,
+                    updated: now,
+                },
+            );
+            limit.burst >= 1.0
+        };
+        if !allowed {
+            self.dropped += 1;
+        }
+        allowed
+    }
+
+    /// Number of requests that exceeded the limit.
+    pub fn num_dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        ([10, 0, 0, 1], port).into()
+    }
+
+    #[test]
+    fn refill_after_burst() {
+        let limit = RateLimit::new(2.0, 3.0);
+        let mut limiter = RateLimiter::new(MAX_ADDRS);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check(addr(1), limit, now));
+        }
+        assert!(!limiter.check(addr(1), limit, now));
+        // other addresses have their own bucket
+        assert!(limiter.check(addr(2), limit, now));
+        assert_eq!(limiter.num_dropped(), 1);
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check(addr(1), limit, later));
+        assert!(!limiter.check(addr(1), limit, later));
+
+        // never refilled beyond the burst
+        let much_later = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.check(addr(1), limit, much_later));
+        }
+        assert!(!limiter.check(addr(1), limit, much_later));
+        assert_eq!(limiter.num_dropped(), 3);
+    }
+
+    #[test]
+    fn bounded_addrs() {
+        let mut limiter = RateLimiter::new(10);
+        let now = Instant::now();
+        for port in 0..100 {
+            limiter.check(addr(port), RateLimit::default(), now);
+        }
+        assert_eq!(limiter.buckets.len(), 10);
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use fnv::FnvHashMap;
+
+use crate::kbucket::Key;
+use crate::rpc::jobs::PeriodicJob;
+use crate::rpc::query::QueryId;
+use crate::rpc::IdBytes;
+
+/// Default time a bucket may go without activity before it is refreshed.
+pub const BUCKET_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 15);
+
+/// Refreshes buckets that had no activity for a while, by looking up a random
+/// id that falls into them.
+#[derive(Debug)]
+pub struct BucketRefresh {
+    /// How long a bucket may go without activity.
+    pub interval: Duration,
+    /// Checks for idle buckets four times per interval.
+    pub job: PeriodicJob,
+    /// The running refreshes.
+    running: FnvHashMap<QueryId, Refresh>,
+}
+
+/// A running refresh of a bucket.
+#[derive(Debug)]
+pub struct Refresh {
+    /// The index of the refreshed bucket.
+    pub index: usize,
+    /// The nodes that were in the bucket when the refresh started.
+    pub known: Vec<Key<IdBytes>>,
+}
+
+impl BucketRefresh {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            job: PeriodicJob::new(interval / 4),
+            running: Default::default(),
+        }
+    }
+
+    /// Whether the bucket at `index` is being refreshed.
+    pub fn is_running(&self, index: usize) -> bool {
+        self.running.values().any(|r| r.index == index)
+    }
+
+    /// Records the query that refreshes a bucket.
+    pub fn started(&mut self, id: QueryId, refresh: Refresh) {
+        self.running.insert(id, refresh);
+    }
+
+    /// Returns the refresh of the finished query, if it refreshed a bucket.
+    pub fn finished(&mut self, id: &QueryId) -> Option<Refresh> {
+        self.running.remove(id)
+    }
+}
+
+/// Generates a random id that falls into the bucket at `index` of the
+/// routing table of `local`.
+///
+/// The id shares the bits above `index` with `local`, differs in the bit at
+/// `index` and is random below it.
+pub fn random_target(local: &IdBytes, index: usize) -> IdBytes {
+    let mut target = IdBytes::random();
+    for (i, (t, l)) in target.0.iter_mut().zip(local.0.iter()).enumerate() {
+        // the lowest bit of this byte, counted from the end of the id
+        let low = (31 - i) * 8;
+        let (random, flip) = match index.checked_sub(low) {
+            Some(bit) if bit < 8 => ((1u8 << bit) - 1, 1u8 << bit),
+            Some(_) => (0xff, 0),
+            None => (0, 0),
+        };
+        *t = ((*l ^ flip) & !random) | (*t & random);
+    }
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_in_bucket() {
+        let local = IdBytes::random();
+        for index in [255, 250, 248, 247, 100, 8, 7, 1, 0] {
+            let target = Key::new(random_target(&local, index));
+            assert_eq!(
+                Key::new(local.clone()).distance(&target).ilog2(),
+                Some(index as u32)
+            );
+        }
+    }
+}
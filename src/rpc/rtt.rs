@@ -0,0 +1,151 @@
+use std::hash::Hash;
+use std::time::Duration;
+
+use lru::LruCache;
+
+use crate::rpc::IdBytes;
+
+/// Maximum number of nodes the table keeps a round trip time for.
+pub const MAX_NODES: usize = 4096;
+
+/// The smoothed round trip time and its variation, like TCP computes them.
+#[derive(Debug, Clone, Copy)]
+struct Estimate {
+    srtt: Duration,
+    rttvar: Duration,
+}
+
+impl Estimate {
+    fn rto(&self) -> Duration {
+        self.srtt * 3 + self.rttvar * 4
+    }
+}
+
+/// Smoothed round trip times of the nodes that responded to our requests.
+///
+/// Every new sample is weighted `1/8` and the variation `1/4`, like the
+/// smoothed RTT of TCP.
+#[derive(Debug)]
+pub struct RttTable<K: Hash + Eq = IdBytes> {
+    nodes: LruCache<K, Estimate>,
+}
+
+impl<K: Hash + Eq> RttTable<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            nodes: LruCache::new(capacity),
+        }
+    }
+
+    /// Records a measured round trip time of the node `key`.
+    ///
+    /// Returns the new smoothed round trip time.
+    pub fn observe(&mut self, key: K, sample: Duration) -> Duration {
+        let estimate = match self.nodes.get(&key) {
+            Some(e) => Estimate {
+                srtt: e.srtt * 7 / 8 + sample / 8,
+                rttvar: e.rttvar * 3 / 4 + e.srtt.abs_diff(sample) / 4,
+            },
+            None => Estimate {
+                srtt: sample,
+                rttvar: sample / 2,
+            },
+        };
+        self.nodes.put(key, estimate);
+        estimate.srtt
+    }
+
+    /// Whether the node `key` has a round trip time.
+    pub fn contains(&self, key: &K) -> bool {
+        self.nodes.contains(key)
+    }
+
+    /// The smoothed round trip time of the node `key`, if it responded before.
+    pub fn get(&self, key: &K) -> Option<Duration> {
+        self.nodes.peek(key).map(|e| e.srtt)
+    }
+
+    /// How long to wait for a response of the node `key`:
+    /// `3 * srtt + 4 * rttvar`.
+    pub fn rto(&self, key: &K) -> Option<Duration> {
+        self.nodes.peek(key).map(Estimate::rto)
+    }
+
+    /// The median of the round trip times of all tracked nodes.
+    pub fn median(&self) -> Option<Duration> {
+        median(self.nodes.iter().map(|(_, e)| e.srtt))
+    }
+
+    /// The median of the timeouts of all tracked nodes.
+    pub fn median_rto(&self) -> Option<Duration> {
+        median(self.nodes.iter().map(|(_, e)| e.rto()))
+    }
+}
+
+impl<K: Hash + Eq> Default for RttTable<K> {
+    fn default() -> Self {
+        Self::new(MAX_NODES)
+    }
+}
+
+fn median(values: impl Iterator<Item = Duration>) -> Option<Duration> {
+    let mut values = values.collect::<Vec<_>>();
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    Some(values[values.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smoothed_rtt() {
+        let mut table = RttTable::default();
+        let id = IdBytes::random();
+        assert_eq!(table.get(&id), None);
+        assert_eq!(table.median(), None);
+
+        let first = table.observe(id.clone(), Duration::from_millis(80));
+        assert_eq!(first, Duration::from_millis(80));
+        // a single outlier only moves the estimate by an eighth
+        let second = table.observe(id.clone(), Duration::from_millis(160));
+        assert_eq!(second, Duration::from_millis(90));
+        assert_eq!(table.get(&id), Some(second));
+
+        table.observe(IdBytes::random(), Duration::from_millis(10));
+        table.observe(IdBytes::random(), Duration::from_millis(200));
+        assert_eq!(table.median(), Some(Duration::from_millis(90)));
+    }
+
+    #[test]
+    fn rto_follows_variation() {
+        let mut table = RttTable::<u16>::default();
+        table.observe(1, Duration::from_millis(100));
+        // the first sample counts half as variation
+        assert_eq!(table.rto(&1), Some(Duration::from_millis(500)));
+        for _ in 0..20 {
+            table.observe(1, Duration::from_millis(100));
+        }
+        // a stable round trip time leaves little variation
+        let rto = table.rto(&1).unwrap();
+        assert!(rto >= Duration::from_millis(300));
+        assert!(rto < Duration::from_millis(310));
+
+        table.observe(2, Duration::from_millis(10));
+        table.observe(3, Duration::from_millis(1000));
+        assert_eq!(table.median_rto(), Some(rto));
+        assert_eq!(table.rto(&4), None);
+    }
+
+    #[test]
+    fn bounded_nodes() {
+        let mut table = RttTable::new(10);
+        for _ in 0..100 {
+            table.observe(IdBytes::random(), Duration::from_millis(1));
+        }
+        assert_eq!(table.nodes.len(), 10);
+    }
+}
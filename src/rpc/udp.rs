@@ -6,19 +6,23 @@ use std::task::{Context, Poll};
 
 use async_std::{
     net::UdpSocket,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
     stream::Stream,
 };
 use bytes::BytesMut;
 use futures::{ready, Future, Sink};
 use futures_codec::{Decoder, Encoder};
 
+use crate::rpc::{message::Message, protocol::DhtRpcCodec};
+
 pub type RecvFuture =
     Pin<Box<dyn Future<Output = (Vec<u8>, io::Result<(usize, SocketAddr)>)> + Send + Sync>>;
 pub type SendFuture = Pin<Box<dyn Future<Output = (BytesMut, io::Result<usize>)> + Send + Sync>>;
 
 const INITIAL_RD_CAPACITY: usize = 64 * 1024;
 const INITIAL_WR_CAPACITY: usize = 8 * 1024;
+/// Size of the blocks received packets are copied into.
+const FRAMES_CAPACITY: usize = 16 * 1024;
 
 async fn recv_next(
     socket: Arc<UdpSocket>,
@@ -28,6 +32,30 @@ async fn recv_next(
     (buf, res)
 }
 
+/// Maps IPv4 addresses to IPv6, so that a dual-stack IPv6 socket can send to
+/// them.
+fn to_socket_family(addr: SocketAddr, ipv6: bool) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if ipv6 => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        addr => addr,
+    }
+}
+
+/// Turns the IPv4-mapped addresses a dual-stack socket receives from back
+/// into IPv4 addresses, so every node has the same address on both socket
+/// families.
+fn from_socket_family(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
+
 async fn send_next(
     socket: Arc<UdpSocket>,
     addr: SocketAddr,
@@ -57,10 +85,18 @@ async fn send_next(
 // #[cfg_attr(docsrs, doc(all(feature = "codec", feature = "udp")))]
 pub struct UdpFramed<C> {
     socket: Arc<UdpSocket>,
+    /// Whether the socket is an IPv6 socket, which is dual-stack unless
+    /// `IPV6_V6ONLY` is set, the default on some platforms.
+    ipv6: bool,
     codec: C,
     out_addr: SocketAddr,
     flushed: bool,
     recv_buf: Option<Vec<u8>>,
+    /// Received packets are copied from `recv_buf` into the free space of
+    /// this block and decoded messages share its memory. Once the messages of
+    /// all packets in the block are dropped it is reused, so that there is no
+    /// allocation per packet.
+    frames: BytesMut,
     send_buf: Option<BytesMut>,
     recv_fut: Option<RecvFuture>,
     send_fut: Option<SendFuture>,
@@ -93,10 +129,13 @@ impl<C: Decoder + Unpin> Stream for UdpFramed<C> {
         let res = match recv_res {
             Err(e) => Some(Err(e.into())),
             Ok((n, addr)) => {
-                let frame = self.codec.decode(&mut buf[..n].into());
+                self.frames.reserve(n);
+                self.frames.extend_from_slice(&buf[..n]);
+                let mut frame = self.frames.split();
+                let frame = self.codec.decode(&mut frame);
                 match frame {
                     Err(e) => Some(Err(e)),
-                    Ok(Some(frame)) => Some(Ok((frame, addr))),
+                    Ok(Some(frame)) => Some(Ok((frame, from_socket_family(addr)))),
                     Ok(None) => Some(Err(io_error("received empty package").into())),
                 }
             }
@@ -139,7 +178,8 @@ impl<C: Encoder + Unpin> Sink<(C::Item, SocketAddr)> for UdpFramed<C> {
         if self.send_fut.is_none() {
             let socket = self.socket.clone();
             let buf = self.send_buf.take().unwrap();
-            let fut = send_next(socket, self.out_addr, buf);
+            let addr = to_socket_family(self.out_addr, self.ipv6);
+            let fut = send_next(socket, addr, buf);
             self.send_fut = Some(Box::pin(fut));
         };
 
@@ -170,11 +210,14 @@ impl<C> UdpFramed<C> {
     ///
     /// See struct level documentation for more details.
     pub fn new(socket: UdpSocket, codec: C) -> UdpFramed<C> {
+        let ipv6 = socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
         UdpFramed {
             socket: Arc::new(socket),
+            ipv6,
             codec,
             flushed: true,
             recv_buf: Some(vec![0u8; INITIAL_RD_CAPACITY]),
+            frames: BytesMut::with_capacity(FRAMES_CAPACITY),
             recv_fut: None,
             send_buf: Some(BytesMut::with_capacity(INITIAL_WR_CAPACITY)),
             send_fut: None,
@@ -190,7 +233,7 @@ impl<C> UdpFramed<C> {
     /// coming in as it may corrupt the stream of frames otherwise being worked
     /// with.
     pub fn get_ref(&self) -> &UdpSocket {
-        &*self.socket
+        &self.socket
     }
 
     /// Consumes the `Framed`, returning its underlying I/O stream.
@@ -200,8 +243,97 @@ impl<C> UdpFramed<C> {
     }
 }
 
+/// The datagram socket the [`IoHandler`](crate::rpc::io::IoHandler) sends
+/// and receives messages over.
+///
+/// Implemented by [`UdpFramed`] for real UDP sockets and by the in-memory
+/// sockets of the `testing` module.
+pub trait Transport:
+    Stream<Item = io::Result<(Message, SocketAddr)>>
+    + Sink<(Vec<u8>, SocketAddr), Error = io::Error>
+    + fmt::Debug
+    + Send
+    + Unpin
+{
+    /// Returns the local address the transport is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Sets the maximum size of a received message, larger ones should fail
+    /// with [`io::ErrorKind::InvalidData`] without being decoded.
+    ///
+    /// Called with the limit of the node once it takes over the transport.
+    fn set_max_message_size(&mut self, _max_message_size: usize) {}
+
+    /// Binds a second transport on the same host, which the NAT probe pings
+    /// our external address from.
+    ///
+    /// Fails with [`io::ErrorKind::Unsupported`] by default, the node is then
+    /// not probed.
+    fn bind_probe(&self) -> io::Result<Box<dyn Transport>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the transport can't bind a probe",
+        ))
+    }
+
+    /// Binds a new transport on the same host, which replaces this one after
+    /// it failed.
+    ///
+    /// Fails with [`io::ErrorKind::Unsupported`] by default, the node then
+    /// gives up on the transport.
+    fn rebind(&self) -> io::Result<Box<dyn Transport>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the transport can't be bound again",
+        ))
+    }
+}
+
+impl Transport for Box<dyn Transport> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: usize) {
+        (**self).set_max_message_size(max_message_size)
+    }
+
+    fn bind_probe(&self) -> io::Result<Box<dyn Transport>> {
+        (**self).bind_probe()
+    }
+
+    fn rebind(&self) -> io::Result<Box<dyn Transport>> {
+        (**self).rebind()
+    }
+}
+
+impl Transport for UdpFramed<DhtRpcCodec> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().local_addr()
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.codec = DhtRpcCodec::new(max_message_size);
+    }
+
+    fn bind_probe(&self) -> io::Result<Box<dyn Transport>> {
+        let socket = std::net::UdpSocket::bind((self.local_addr()?.ip(), 0))?;
+        socket.set_nonblocking(true)?;
+        Ok(Box::new(UdpFramed::new(
+            UdpSocket::from(socket),
+            DhtRpcCodec::default(),
+        )))
+    }
+
+    /// Binds a socket at the same ip, on a random port since the port of this
+    /// one is still taken.
+    fn rebind(&self) -> io::Result<Box<dyn Transport>> {
+        self.bind_probe()
+    }
+}
+
 pub fn io_error(message: &str) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, message)
+    io::Error::other(message)
 }
 
 #[cfg(test)]
@@ -239,4 +371,70 @@ mod tests {
         handle.await?;
         Ok(())
     }
+
+    // other platforms may set `IPV6_V6ONLY` by default
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[async_std::test]
+    async fn dual_stack() -> Result<(), Box<dyn std::error::Error>> {
+        let socket = UdpSocket::bind("[::]:0").await?;
+        let port = socket.local_addr()?.port();
+        let mut framed = UdpFramed::new(socket, BytesCodec);
+
+        let v4 = UdpSocket::bind("127.0.0.1:0").await?;
+        v4.send_to(QUICK_BROWN_FOX.as_bytes(), ("127.0.0.1", port))
+            .await?;
+        let (msg, addr) = framed.next().await.unwrap()?;
+        assert_eq!(addr, v4.local_addr()?);
+        framed.send((msg, addr)).await?;
+        let mut buf = vec![0; QUICK_BROWN_FOX.len()];
+        let (len, _) = v4.recv_from(&mut buf).await?;
+        assert_eq!(QUICK_BROWN_FOX.as_bytes(), &buf[..len]);
+
+        let v6 = UdpSocket::bind("[::1]:0").await?;
+        v6.send_to(QUICK_BROWN_FOX.as_bytes(), ("::1", port))
+            .await?;
+        let (_, addr) = framed.next().await.unwrap()?;
+        assert_eq!(addr, v6.local_addr()?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn reuse_receive_block() -> Result<(), Box<dyn std::error::Error>> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = socket.local_addr()?;
+        let mut framed = UdpFramed::new(socket, DhtRpcCodec::default());
+        let sender = UdpSocket::bind("127.0.0.1:0").await?;
+        let msg = Message {
+            value: Some(vec![1; 1000].into()),
+            ..Default::default()
+        };
+        let buf = msg.encode_to_vec(false);
+
+        // far more than fits into one block, but every message is dropped
+        // before the next one arrives
+        let mut values = Vec::new();
+        for _ in 0..4 * FRAMES_CAPACITY / buf.len() {
+            sender.send_to(&buf, addr).await?;
+            let (msg, _) = framed.next().await.unwrap()?;
+            assert_eq!(msg.value.as_deref(), Some(&[1; 1000][..]));
+            values.push(msg.value.unwrap().as_ptr() as usize);
+        }
+        let start = values.iter().min().unwrap();
+        let end = values.iter().max().unwrap();
+        assert!(end - start < FRAMES_CAPACITY);
+
+        // a message that is kept moves the next packets into a new block
+        sender.send_to(&buf, addr).await?;
+        let (kept, _) = framed.next().await.unwrap()?;
+        let mut moved = false;
+        for _ in 0..2 * FRAMES_CAPACITY / buf.len() {
+            sender.send_to(&buf, addr).await?;
+            let (msg, _) = framed.next().await.unwrap()?;
+            let value = msg.value.unwrap().as_ptr() as usize;
+            moved |= value < *start || value > *end;
+        }
+        assert!(moved);
+        drop(kept);
+        Ok(())
+    }
 }
@@ -1,14 +1,18 @@
 use std::hash::Hash;
+use std::time::Duration;
 
+use bytes::Bytes;
 use ed25519_dalek::PublicKey;
 use lru::LruCache;
 use prost::Message;
+use wasm_timer::Instant;
 
 use crate::crypto::VALUE_MAX_SIZE;
 use crate::dht_proto::Mutable;
 use crate::rpc::message::Type;
 use crate::rpc::query::{CommandQuery, CommandQueryResponse};
 use crate::rpc::IdBytes;
+use crate::time;
 use crate::{crypto, ERR_INVALID_INPUT};
 use crate::{IMMUTABLE_STORE_CMD, MUTABLE_STORE_CMD};
 
@@ -62,18 +66,72 @@ pub enum StorageKey {
     Immutable(IdBytes),
 }
 
+#[derive(Debug)]
+struct Stored {
+    entry: StorageEntry,
+    expires: Instant,
+}
+
+/// Stores the values of the `mutable-store` and `immutable-store` commands.
+///
+/// Like announcements, values expire after `max_age` unless they are put
+/// again, and the least recently used values are evicted once the store is
+/// full. Every command has a store of its own, so that puts of one kind of
+/// values can't evict the other.
 #[derive(Debug)]
 pub struct Store {
-    /// Value cache
-    inner: LruCache<StorageKey, StorageEntry>,
+    /// Values of the `immutable-store` command
+    immutable: LruCache<StorageKey, Stored>,
+    /// Values of the `mutable-store` command
+    mutable: LruCache<StorageKey, Stored>,
+    /// How long a value is kept
+    max_age: Duration,
 }
 
 impl Store {
-    pub fn new(cap: usize) -> Self {
+    /// Keeps up to `cap` values per command.
+    pub fn new(cap: usize, max_age: Duration) -> Self {
         Self {
-            inner: LruCache::new(cap),
-            // streams: Default::default(),
+            immutable: LruCache::new(cap),
+            mutable: LruCache::new(cap),
+            max_age,
+        }
+    }
+
+    /// Number of stored values, including expired ones that were not
+    /// accessed since.
+    pub fn len(&self) -> usize {
+        self.immutable.len() + self.mutable.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.immutable.is_empty() && self.mutable.is_empty()
+    }
+
+    /// The values of the command the key belongs to.
+    fn values(&mut self, key: &StorageKey) -> &mut LruCache<StorageKey, Stored> {
+        match key {
+            StorageKey::Immutable(_) => &mut self.immutable,
+            StorageKey::Mutable(_) => &mut self.mutable,
+        }
+    }
+
+    fn insert(&mut self, key: StorageKey, entry: StorageEntry) -> Option<StorageEntry> {
+        let expires = time::now() + self.max_age;
+        self.values(&key)
+            .put(key, Stored { entry, expires })
+            .map(|stored| stored.entry)
+    }
+
+    /// Returns the value for the key, expired values are removed.
+    fn lookup(&mut self, key: &StorageKey) -> Option<&StorageEntry> {
+        let values = self.values(key);
+        let expired = values.peek(key)?.expires <= time::now();
+        if expired {
+            values.pop(key);
+            return None;
         }
+        values.get(key).map(|stored| &stored.entry)
     }
 
     /// Callback for immutable command.
@@ -89,11 +147,7 @@ impl Store {
     /// Callback for mutable command
     pub fn on_command_mut(&mut self, mut query: CommandQuery) -> CommandQueryResponse {
         assert_eq!(query.command.as_str(), MUTABLE_STORE_CMD);
-        if let Some(mutable) = query
-            .value
-            .take()
-            .and_then(|buf| Mutable::decode(buf.as_slice()).ok())
-        {
+        if let Some(mutable) = query.value.take().and_then(|buf| Mutable::decode(buf).ok()) {
             return if query.ty == Type::Update {
                 self.update_mut(query, mutable)
             } else {
@@ -104,18 +158,16 @@ impl Store {
     }
 
     pub fn get(&mut self, key: &StorageKey) -> Option<&StorageEntry> {
-        self.inner.get(key)
+        self.lookup(key)
     }
 
     pub fn put_immutable(&mut self, key: IdBytes, value: Vec<u8>) -> Option<Vec<u8>> {
-        self.inner
-            .put(StorageKey::Immutable(key), StorageEntry::Immutable(value))
+        self.insert(StorageKey::Immutable(key), StorageEntry::Immutable(value))
             .and_then(StorageEntry::into_immutable)
     }
 
     pub fn put_mutable(&mut self, key: Vec<u8>, value: Mutable) -> Option<Mutable> {
-        self.inner
-            .put(StorageKey::Mutable(key), StorageEntry::Mutable(value))
+        self.insert(StorageKey::Mutable(key), StorageEntry::Mutable(value))
             .and_then(StorageEntry::into_mutable)
     }
 
@@ -127,13 +179,11 @@ impl Store {
         }
     }
 
-    pub fn query_mut(&mut self, mut query: CommandQuery, mutable: Mutable) -> CommandQueryResponse {
+    pub fn query_mut(&mut self, query: CommandQuery, mutable: Mutable) -> CommandQueryResponse {
         let key = StorageKey::Mutable(Self::get_mut_key(&mutable, &query.target));
-        if let Some(val) = self.inner.get(&key).and_then(StorageEntry::as_mutable) {
+        if let Some(val) = self.lookup(&key).and_then(StorageEntry::as_mutable) {
             if val.seq.unwrap_or_default() >= mutable.seq.unwrap_or_default() {
-                let mut buf = Vec::with_capacity(val.encoded_len());
-                val.encode(&mut buf).unwrap();
-                query.value = Some(buf);
+                return query.into_response_with_value(val);
             }
         }
         query.into()
@@ -143,33 +193,35 @@ impl Store {
         if mutable.value.is_none() || mutable.signature.is_none() {
             return query.into();
         }
+        if mutable.value.as_ref().map(Vec::len).unwrap_or_default() > PUT_VALUE_MAX_SIZE {
+            return query.into_response_with_error(ERR_INVALID_INPUT);
+        }
 
         let key = StorageKey::Mutable(Self::get_mut_key(&mutable, &query.target));
         if let Err(err) = verify(&query.target, &mutable) {
             return query.into_response_with_error(err);
         }
 
-        if let Some(local) = self.inner.get(&key).and_then(StorageEntry::as_mutable) {
+        if let Some(local) = self.lookup(&key).and_then(StorageEntry::as_mutable) {
             if let Err(err) = maybe_seq_error(&mutable, local) {
                 let mut resp = query.into_response_with_error(err);
                 let mut buf = Vec::with_capacity(local.encoded_len());
                 local.encode(&mut buf).unwrap();
-                resp.msg.value = Some(buf);
+                resp.msg.value = Some(buf.into());
                 return resp;
             }
         }
 
-        self.inner.put(key, StorageEntry::Mutable(mutable));
+        self.insert(key, StorageEntry::Mutable(mutable));
         query.into()
     }
 
     /// Callback for a [`IMMUTABLE_STORE_CMD`] request of type [`Type::Query`].
     pub fn query(&mut self, mut query: CommandQuery) -> CommandQueryResponse {
         let val = self
-            .inner
-            .get(&StorageKey::Immutable(query.target.clone()))
+            .lookup(&StorageKey::Immutable(query.target.clone()))
             .and_then(StorageEntry::as_immutable)
-            .cloned();
+            .map(|val| Bytes::copy_from_slice(val));
         query.value = val;
         query.into()
     }
@@ -177,12 +229,14 @@ impl Store {
     /// Callback for a [`IMMUTABLE_STORE_CMD`] request of type [`Type::Update`].
     pub fn update(&mut self, mut query: CommandQuery) -> CommandQueryResponse {
         if let Some(value) = query.value.take() {
-            let key = crypto::hash_id(value.as_slice());
-            if key != query.target {
+            let key = crypto::hash_id(&value);
+            if key != query.target || value.len() > PUT_VALUE_MAX_SIZE {
                 return query.into_response_with_error(ERR_INVALID_INPUT);
             }
-            self.inner
-                .put(StorageKey::Immutable(key), StorageEntry::Immutable(value));
+            self.insert(
+                StorageKey::Immutable(key),
+                StorageEntry::Immutable(value.to_vec()),
+            );
         }
         query.into()
     }
@@ -192,8 +246,8 @@ impl Store {
 pub fn verify(pk: &IdBytes, mutable: &Mutable) -> Result<(), String> {
     let public_key =
         PublicKey::from_bytes(pk.as_ref()).map_err(|_| ERR_INVALID_INPUT.to_string())?;
-    let sig = crypto::signature(&mutable).ok_or_else(|| ERR_INVALID_INPUT.to_string())?;
-    let msg = crypto::signable_mutable(&mutable).map_err(|_| ERR_INVALID_INPUT.to_string())?;
+    let sig = crypto::signature(mutable).ok_or_else(|| ERR_INVALID_INPUT.to_string())?;
+    let msg = crypto::signable_mutable(mutable).map_err(|_| ERR_INVALID_INPUT.to_string())?;
     crypto::verify(&public_key, &msg, &sig).map_err(|_| ERR_INVALID_INPUT.to_string())
 }
 
@@ -213,8 +267,44 @@ pub fn maybe_seq_error(a: &Mutable, b: &Mutable) -> Result<(), String> {
 
 #[cfg(test)]
 mod tests {
+    use ed25519_dalek::{ed25519::signature::Signature, Keypair};
+
+    use crate::rpc::{Peer, RequestId};
+
     use super::*;
 
+    fn signed(keypair: &Keypair, value: &[u8], seq: u64) -> Mutable {
+        let signature = crypto::sign(&keypair.public, &keypair.secret, value, None, seq);
+        Mutable {
+            value: Some(value.to_vec()),
+            signature: Some(signature.as_bytes().to_vec()),
+            seq: Some(seq),
+            salt: None,
+        }
+    }
+
+    fn command(ty: Type, cmd: &str, target: IdBytes, value: Option<Vec<u8>>) -> CommandQuery {
+        CommandQuery {
+            rid: RequestId(0),
+            ty,
+            command: cmd.to_string(),
+            peer: Peer::from(([127, 0, 0, 1], 1)),
+            target,
+            value: value.map(Bytes::from),
+        }
+    }
+
+    fn put_mut(store: &mut Store, id: &IdBytes, mutable: &Mutable) -> CommandQueryResponse {
+        let mut buf = Vec::new();
+        mutable.encode(&mut buf).unwrap();
+        store.on_command_mut(command(
+            Type::Update,
+            MUTABLE_STORE_CMD,
+            id.clone(),
+            Some(buf),
+        ))
+    }
+
     #[test]
     fn verify_test() {
         use ed25519_dalek::ed25519::signature::Signature;
@@ -233,4 +323,124 @@ mod tests {
         let id = keypair.public.to_bytes().into();
         assert!(verify(&id, &m).is_ok())
     }
+
+    #[test]
+    fn reject_invalid_signature() {
+        let keypair = crypto::keypair();
+        let id: IdBytes = keypair.public.to_bytes().into();
+        let mut store = Store::new(10, Duration::from_secs(60));
+
+        let mut mutable = signed(&keypair, b"hello", 1);
+        mutable.value = Some(b"tampered".to_vec());
+        let resp = put_mut(&mut store, &id, &mutable);
+        assert_eq!(resp.msg.error.as_deref(), Some(ERR_INVALID_INPUT));
+
+        // signed by somebody else
+        let other = signed(&crypto::keypair(), b"hello", 1);
+        let resp = put_mut(&mut store, &id, &other);
+        assert_eq!(resp.msg.error.as_deref(), Some(ERR_INVALID_INPUT));
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn higher_seq_wins() {
+        let keypair = crypto::keypair();
+        let id: IdBytes = keypair.public.to_bytes().into();
+        let mut store = Store::new(10, Duration::from_secs(60));
+        let key = StorageKey::Mutable(id.to_vec());
+
+        assert!(put_mut(&mut store, &id, &signed(&keypair, b"one", 1))
+            .msg
+            .error
+            .is_none());
+        assert!(put_mut(&mut store, &id, &signed(&keypair, b"two", 2))
+            .msg
+            .error
+            .is_none());
+
+        // an older value is rejected and answered with the current one
+        let resp = put_mut(&mut store, &id, &signed(&keypair, b"old", 1));
+        assert_eq!(
+            resp.msg.error.as_deref(),
+            Some("ERR_SEQ_MUST_EXCEED_CURRENT")
+        );
+        let current = Mutable::decode(resp.msg.value.unwrap()).unwrap();
+        assert_eq!(current.value, Some(b"two".to_vec()));
+
+        // so is a different value with the same seq
+        let resp = put_mut(&mut store, &id, &signed(&keypair, b"other", 2));
+        assert_eq!(resp.msg.error.as_deref(), Some("ERR_INVALID_SEQ"));
+
+        let stored = store.get(&key).and_then(StorageEntry::as_mutable).unwrap();
+        assert_eq!(stored.value, Some(b"two".to_vec()));
+        assert_eq!(stored.seq, Some(2));
+    }
+
+    #[test]
+    fn immutable_values() {
+        let mut store = Store::new(10, Duration::from_secs(60));
+        let value = b"immutable".to_vec();
+        let key = crypto::hash_id(&value);
+
+        // the target must be the hash of the value
+        let resp = store.on_command(command(
+            Type::Update,
+            IMMUTABLE_STORE_CMD,
+            IdBytes::random(),
+            Some(value.clone()),
+        ));
+        assert_eq!(resp.msg.error.as_deref(), Some(ERR_INVALID_INPUT));
+
+        let too_big = vec![0; PUT_VALUE_MAX_SIZE + 1];
+        let resp = store.on_command(command(
+            Type::Update,
+            IMMUTABLE_STORE_CMD,
+            crypto::hash_id(&too_big),
+            Some(too_big),
+        ));
+        assert_eq!(resp.msg.error.as_deref(), Some(ERR_INVALID_INPUT));
+        assert!(store.is_empty());
+
+        store.on_command(command(
+            Type::Update,
+            IMMUTABLE_STORE_CMD,
+            key.clone(),
+            Some(value.clone()),
+        ));
+        let resp = store.on_command(command(Type::Query, IMMUTABLE_STORE_CMD, key, None));
+        assert_eq!(resp.msg.value, Some(value.into()));
+    }
+
+    #[test]
+    fn store_per_command() {
+        let mut store = Store::new(2, Duration::from_secs(60));
+        let keypair = crypto::keypair();
+        let id = IdBytes::from(keypair.public.to_bytes());
+        put_mut(&mut store, &id, &signed(&keypair, b"mutable", 1));
+
+        // immutable values only evict each other
+        let values = (0..3u8).map(|i| vec![i]).collect::<Vec<_>>();
+        for value in &values {
+            store.put_immutable(crypto::hash_id(value), value.clone());
+        }
+        assert_eq!(store.len(), 3);
+        let immutable = |store: &mut Store, value: &[u8]| {
+            store
+                .get(&StorageKey::Immutable(crypto::hash_id(value)))
+                .is_some()
+        };
+        assert!(!immutable(&mut store, &values[0]));
+        assert!(immutable(&mut store, &values[2]));
+        assert!(store.get(&StorageKey::Mutable(id.to_vec())).is_some());
+    }
+
+    #[test]
+    fn values_expire() {
+        let mut store = Store::new(10, Duration::from_millis(0));
+        let key = crypto::hash_id(b"value");
+        store.put_immutable(key.clone(), b"value".to_vec());
+        assert_eq!(store.len(), 1);
+        assert!(store.get(&StorageKey::Immutable(key)).is_none());
+        assert!(store.is_empty());
+    }
 }
@@ -0,0 +1,2030 @@
+//! An in-memory network to wire up many nodes in a single process.
+//!
+//! Every [`MemorySocket`] bound to a [`Network`] implements [`Transport`] and
+//! can be passed to [`DhtConfig::set_transport`](crate::DhtConfig::set_transport).
+//! Packet loss, latency and jitter, which reorders packets, are configurable
+//! per link. Whether a packet is lost and how long it takes is drawn from a
+//! random number generator with a fixed seed, so the same seed produces the
+//! same pattern of losses and delays for the same sequence of packets.
+//!
+//! Socket errors are injected by wrapping any transport in [`Faulty`].
+//!
+//! The nodes of a test are run by a [`Simulation`], on a virtual clock that
+//! jumps to the next deadline of a timer once all of them are idle. Request
+//! timeouts, periodic jobs, expiry times and link latencies all run on this
+//! clock, so minutes of simulated time pass in milliseconds, and how loaded
+//! the machine is doesn't change when a packet arrives or a request times
+//! out.
+//!
+//! # Note
+//!
+//! Outside of a [`Simulation`] the network runs on the system clock, like
+//! the rest of the crate.
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::executor::{LocalPool, LocalSpawner};
+use futures::task::{waker, ArcWake, LocalSpawnExt};
+use futures::{Sink, Stream};
+use futures_codec::Decoder;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use wasm_timer::Instant;
+
+use crate::rpc::{message::Message, protocol::DhtRpcCodec, udp::Transport, IdBytes};
+use crate::time::{self, Delay, VirtualClock};
+
+/// How much virtual time a [`Simulation`] may run for.
+pub const MAX_SIMULATED_TIME: Duration = Duration::from_secs(60 * 60);
+
+thread_local! {
+    static SPAWNER: RefCell<Option<LocalSpawner>> = const { RefCell::new(None) };
+}
+
+/// Runs the nodes of a test on the current thread, on a virtual clock.
+///
+/// The clock stands still while any task can make progress. Once all of
+/// them wait, it jumps to the earliest deadline of a timer.
+pub struct Simulation {
+    pool: LocalPool,
+    clock: VirtualClock,
+}
+
+impl fmt::Debug for Simulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Simulation")
+            .field("now", &self.clock.now())
+            .finish()
+    }
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wakes the main future of a [`Simulation`].
+#[derive(Default)]
+struct Woken(AtomicBool);
+
+impl ArcWake for Woken {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        Self {
+            pool: LocalPool::new(),
+            clock: VirtualClock::new(),
+        }
+    }
+
+    /// Runs `future` to completion, together with the tasks it [`spawn`]s.
+    ///
+    /// # Panics
+    ///
+    /// If all tasks wait without a timer that could wake them up, or once
+    /// more than [`MAX_SIMULATED_TIME`] passed.
+    pub fn run<F: Future>(&mut self, future: F) -> F::Output {
+        let Self { pool, clock } = self;
+        let end = clock.now() + MAX_SIMULATED_TIME;
+        let spawner = pool.spawner();
+        clock.enter(|| {
+            let _reset = SpawnerReset(SPAWNER.with(|s| s.replace(Some(spawner))));
+            let mut future = Box::pin(future);
+            let woken = Arc::new(Woken(AtomicBool::new(true)));
+            let waker = waker(woken.clone());
+            let mut cx = Context::from_waker(&waker);
+            loop {
+                if woken.0.swap(false, Ordering::SeqCst) {
+                    if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                        return output;
+                    }
+                }
+                pool.run_until_stalled();
+                if woken.0.load(Ordering::SeqCst) {
+                    continue;
+                }
+                match clock.next_deadline() {
+                    Some(deadline) if deadline <= end => clock.advance_to(deadline),
+                    Some(_) => panic!("the simulation ran for {:?}", MAX_SIMULATED_TIME),
+                    None => panic!("the simulation stalled"),
+                }
+            }
+        })
+    }
+}
+
+struct SpawnerReset(Option<LocalSpawner>);
+
+impl Drop for SpawnerReset {
+    fn drop(&mut self) {
+        SPAWNER.with(|s| *s.borrow_mut() = self.0.take());
+    }
+}
+
+/// Spawns a task on the [`Simulation`] running on this thread, or on
+/// `async-std` outside of one.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    match SPAWNER.with(|s| s.borrow().clone()) {
+        Some(spawner) => spawner
+            .spawn_local(future)
+            .expect("the simulation is running"),
+        None => {
+            async_std::task::spawn(future);
+        }
+    }
+}
+
+/// Completes after `duration`, on the virtual clock in a [`Simulation`].
+pub async fn sleep(duration: Duration) {
+    let _ = Delay::new(duration).await;
+}
+
+/// Fails with [`io::ErrorKind::TimedOut`] if `future` doesn't complete within
+/// `duration`, on the virtual clock in a [`Simulation`].
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> io::Result<F::Output> {
+    futures::pin_mut!(future);
+    match futures::future::select(future, Delay::new(duration)).await {
+        futures::future::Either::Left((output, _)) => Ok(output),
+        futures::future::Either::Right(_) => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+/// The conditions of the packets sent over a link.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Link {
+    /// Probability between `0.0` and `1.0` that a packet is dropped.
+    pub loss: f64,
+    /// The time it takes for a packet to arrive.
+    pub latency: Duration,
+    /// Additional random delay of up to `jitter`, packets can overtake each
+    /// other.
+    pub jitter: Duration,
+}
+
+impl Link {
+    /// A link that loses the given fraction of packets.
+    pub fn lossy(loss: f64) -> Self {
+        Self {
+            loss,
+            ..Default::default()
+        }
+    }
+
+    /// A link with a fixed latency.
+    pub fn with_latency(latency: Duration) -> Self {
+        Self {
+            latency,
+            ..Default::default()
+        }
+    }
+}
+
+/// A packet on its way.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct InFlight {
+    arrival: Instant,
+    /// Keeps packets with the same arrival in the order they were sent.
+    seq: u64,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct Inbox {
+    packets: BinaryHeap<Reverse<InFlight>>,
+    waker: Option<Waker>,
+}
+
+struct Inner {
+    inboxes: FnvHashMap<SocketAddr, Inbox>,
+    links: FnvHashMap<(SocketAddr, SocketAddr), Link>,
+    /// Addresses whose packets are all lost.
+    disconnected: FnvHashSet<SocketAddr>,
+    default_link: Link,
+    rng: StdRng,
+    next_port: u16,
+    next_seq: u64,
+    sent: u64,
+    dropped: u64,
+}
+
+/// A simulated network that delivers packets between [`MemorySocket`]s.
+#[derive(Clone)]
+pub struct Network {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl fmt::Debug for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.lock();
+        f.debug_struct("Network")
+            .field("sockets", &inner.inboxes.len())
+            .field("sent", &inner.sent)
+            .field("dropped", &inner.dropped)
+            .finish()
+    }
+}
+
+impl Network {
+    /// Creates an empty network, losses and delays are drawn from a random
+    /// number generator seeded with `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                inboxes: Default::default(),
+                links: Default::default(),
+                disconnected: Default::default(),
+                default_link: Link::default(),
+                rng: StdRng::seed_from_u64(seed),
+                next_port: 1,
+                next_seq: 0,
+                sent: 0,
+                dropped: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("network lock poisoned")
+    }
+
+    /// Creates a socket at a new address.
+    pub fn bind(&self) -> MemorySocket {
+        let mut inner = self.lock();
+        let addr = SocketAddr::from(([10, 0, 0, 1], inner.next_port));
+        inner.next_port += 1;
+        inner.inboxes.insert(addr, Inbox::default());
+        MemorySocket {
+            addr,
+            network: self.clone(),
+            timer: None,
+            send_interval: None,
+            send_timer: None,
+            codec: DhtRpcCodec::default(),
+        }
+    }
+
+    /// A random id drawn from the seeded generator, to give the nodes of a
+    /// simulation the same ids in every run.
+    pub fn random_id(&self) -> IdBytes {
+        IdBytes(self.lock().rng.gen())
+    }
+
+    /// Sets the conditions of all links without an explicit configuration.
+    pub fn set_default_link(&self, link: Link) {
+        self.lock().default_link = link;
+    }
+
+    /// Sets the conditions of the packets sent from `from` to `to`.
+    pub fn set_link(&self, from: SocketAddr, to: SocketAddr, link: Link) {
+        self.lock().links.insert((from, to), link);
+    }
+
+    /// Drops all packets sent from and to `addr` from now on, as if the
+    /// network of its socket went away.
+    pub fn disconnect(&self, addr: SocketAddr) {
+        self.lock().disconnected.insert(addr);
+    }
+
+    /// Number of packets that were sent.
+    pub fn num_sent(&self) -> u64 {
+        self.lock().sent
+    }
+
+    /// Number of packets that were lost, including those sent to an address
+    /// nobody is bound to.
+    pub fn num_dropped(&self) -> u64 {
+        self.lock().dropped
+    }
+
+    fn send(&self, from: SocketAddr, to: SocketAddr, data: Vec<u8>) {
+        let mut inner = self.lock();
+        inner.sent += 1;
+        let link = inner
+            .links
+            .get(&(from, to))
+            .copied()
+            .unwrap_or(inner.default_link);
+        let lost = (link.loss > 0.0 && inner.rng.gen::<f64>() < link.loss)
+            || inner.disconnected.contains(&from)
+            || inner.disconnected.contains(&to);
+        let jitter = if link.jitter > Duration::from_secs(0) {
+            link.jitter.mul_f64(inner.rng.gen::<f64>())
+        } else {
+            Duration::from_secs(0)
+        };
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        match inner.inboxes.get_mut(&to) {
+            Some(inbox) if !lost => {
+                inbox.packets.push(Reverse(InFlight {
+                    arrival: time::now() + link.latency + jitter,
+                    seq,
+                    from,
+                    data,
+                }));
+                if let Some(waker) = inbox.waker.take() {
+                    waker.wake();
+                }
+            }
+            _ => inner.dropped += 1,
+        }
+    }
+}
+
+/// A socket of a [`Network`].
+pub struct MemorySocket {
+    addr: SocketAddr,
+    network: Network,
+    /// Wakes up the socket once the next packet arrives.
+    timer: Option<Delay>,
+    /// How long the socket takes to send a datagram, if it is throttled.
+    send_interval: Option<Duration>,
+    /// Elapses once the socket accepts the next datagram.
+    send_timer: Option<Delay>,
+    codec: DhtRpcCodec,
+}
+
+impl fmt::Debug for MemorySocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemorySocket")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl MemorySocket {
+    /// The address of the socket in the network.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Makes the socket accept only one datagram per `interval`, like a
+    /// socket whose send buffer is full most of the time.
+    pub fn throttled(mut self, interval: Duration) -> Self {
+        self.send_interval = Some(interval);
+        self
+    }
+}
+
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        self.network.lock().inboxes.remove(&self.addr);
+    }
+}
+
+impl Stream for MemorySocket {
+    type Item = io::Result<(Message, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let now = time::now();
+        let next = {
+            let mut inner = self.network.lock();
+            let inbox = inner.inboxes.get_mut(&self.addr).expect("socket is bound");
+            match inbox.packets.peek() {
+                Some(Reverse(packet)) if packet.arrival <= now => {
+                    inbox.packets.pop().map(|Reverse(packet)| Ok(packet))
+                }
+                Some(Reverse(packet)) => {
+                    inbox.waker = Some(cx.waker().clone());
+                    Some(Err(packet.arrival))
+                }
+                None => {
+                    inbox.waker = Some(cx.waker().clone());
+                    None
+                }
+            }
+        };
+
+        match next {
+            Some(Ok(packet)) => {
+                self.timer = None;
+                let msg = self
+                    .codec
+                    .decode(&mut BytesMut::from(&packet.data[..]))
+                    .and_then(|msg| msg.ok_or_else(|| io::Error::other("received empty package")));
+                Poll::Ready(Some(msg.map(|msg| (msg, packet.from))))
+            }
+            Some(Err(arrival)) => {
+                let mut timer = Delay::new_at(arrival);
+                if Future::poll(Pin::new(&mut timer), cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                } else {
+                    self.timer = Some(timer);
+                }
+                Poll::Pending
+            }
+            None => {
+                self.timer = None;
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Sink<(Vec<u8>, SocketAddr)> for MemorySocket {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(timer) = self.send_timer.as_mut() {
+            if Future::poll(Pin::new(timer), cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.send_timer = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        item: (Vec<u8>, SocketAddr),
+    ) -> Result<(), Self::Error> {
+        let (data, to) = item;
+        self.network.send(self.addr, to, data);
+        self.send_timer = self.send_interval.map(Delay::new);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Transport for MemorySocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.codec = DhtRpcCodec::new(max_message_size);
+    }
+
+    fn bind_probe(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.network.bind()))
+    }
+}
+
+/// Controls the errors of a [`Faulty`] transport.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    inner: Arc<Mutex<FaultsInner>>,
+}
+
+#[derive(Debug, Default)]
+struct FaultsInner {
+    /// Errors returned by the next receives, before any message.
+    recv: VecDeque<io::ErrorKind>,
+    /// Errors of all sends to an address.
+    send: FnvHashMap<SocketAddr, io::ErrorKind>,
+}
+
+impl Faults {
+    fn lock(&self) -> MutexGuard<'_, FaultsInner> {
+        self.inner.lock().expect("faults lock poisoned")
+    }
+
+    /// Makes the next receive fail with `kind`, calls queue up.
+    pub fn fail_recv(&self, kind: io::ErrorKind) {
+        self.lock().recv.push_back(kind);
+    }
+
+    /// Makes every send to `addr` fail with `kind`, or succeed again with
+    /// `None`.
+    pub fn fail_send_to(&self, addr: SocketAddr, kind: Option<io::ErrorKind>) {
+        let mut inner = self.lock();
+        match kind {
+            Some(kind) => inner.send.insert(addr, kind),
+            None => inner.send.remove(&addr),
+        };
+    }
+}
+
+/// A transport whose sends and receives fail as configured by its [`Faults`].
+///
+/// Like a UDP socket, a failed send is reported once the transport is polled
+/// for the next send.
+#[derive(Debug)]
+pub struct Faulty<T> {
+    inner: T,
+    faults: Faults,
+    /// The error of the last send.
+    failed: Option<io::ErrorKind>,
+}
+
+impl<T: Transport> Faulty<T> {
+    /// Wraps `inner`, the returned handle injects the errors.
+    pub fn new(inner: T) -> (Self, Faults) {
+        let faults = Faults::default();
+        let faulty = Self {
+            inner,
+            faults: faults.clone(),
+            failed: None,
+        };
+        (faulty, faults)
+    }
+}
+
+impl<T: Transport> Stream for Faulty<T> {
+    type Item = io::Result<(Message, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(kind) = self.faults.lock().recv.pop_front() {
+            return Poll::Ready(Some(Err(kind.into())));
+        }
+        Stream::poll_next(Pin::new(&mut self.inner), cx)
+    }
+}
+
+impl<T: Transport> Sink<(Vec<u8>, SocketAddr)> for Faulty<T> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(kind) = self.failed.take() {
+            return Poll::Ready(Err(kind.into()));
+        }
+        Sink::poll_ready(Pin::new(&mut self.inner), cx)
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        item: (Vec<u8>, SocketAddr),
+    ) -> Result<(), Self::Error> {
+        let failed = self.faults.lock().send.get(&item.1).copied();
+        if failed.is_some() {
+            self.failed = failed;
+            return Ok(());
+        }
+        Sink::start_send(Pin::new(&mut self.inner), item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::poll_flush(Pin::new(&mut self.inner), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::poll_close(Pin::new(&mut self.inner), cx)
+    }
+}
+
+impl<T: Transport> Transport for Faulty<T> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.inner.set_max_message_size(max_message_size)
+    }
+
+    fn bind_probe(&self) -> io::Result<Box<dyn Transport>> {
+        self.inner.bind_probe()
+    }
+
+    /// Binds the inner transport again, the new one fails as injected too.
+    fn rebind(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Faulty {
+            inner: self.inner.rebind()?,
+            faults: self.faults.clone(),
+            failed: None,
+        }))
+    }
+}
+
+/// The helpers the tests of the crate share to run nodes and hand-made
+/// messages over a [`Network`].
+///
+/// Tests wait for the events or the state they expect instead of driving a
+/// node for a fixed time, so they don't depend on how long anything takes.
+#[cfg(test)]
+pub(crate) mod harness {
+    use std::error::Error;
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::task::Poll;
+    use std::time::Duration;
+
+    use futures::{FutureExt, SinkExt, Stream, StreamExt};
+
+    use super::{spawn, MemorySocket, Network};
+    use crate::rpc::{
+        io::VERSION,
+        message::{Command, Message, Type},
+        query::{QueryId, QueryStats},
+        DhtConfig, IdBytes, PeerId, RpcDht, RpcDhtEvent,
+    };
+
+    /// The config of a node on the `network`, with a short request timeout.
+    pub fn config(network: &Network) -> DhtConfig {
+        DhtConfig::default()
+            .set_transport(network.bind())
+            .set_local_id(network.random_id())
+            .set_request_timeout(Duration::from_millis(50))
+    }
+
+    /// A node on the `network` that knows no other nodes.
+    pub async fn node(network: &Network) -> io::Result<RpcDht> {
+        RpcDht::with_config(config(network).empty_bootstrap_nodes()).await
+    }
+
+    /// Spawns a node with the `config` that is driven in the background and
+    /// returns its address and id.
+    pub async fn spawn_node(config: DhtConfig) -> io::Result<PeerId> {
+        let mut node = RpcDht::with_config(config).await?;
+        let peer = PeerId {
+            addr: node.local_addr()?,
+            id: node.local_id().clone(),
+        };
+        spawn(async move { while node.next().await.is_some() {} });
+        Ok(peer)
+    }
+
+    /// Spawns a bootstrapped node without bootstrap nodes of its own.
+    pub async fn spawn_bootstrap(network: &Network) -> Result<SocketAddr, Box<dyn Error>> {
+        let mut bs = node(network).await?;
+        let addr = bs.local_addr()?;
+        assert!(matches!(
+            bs.next().await,
+            Some(RpcDhtEvent::Bootstrapped { .. })
+        ));
+        spawn(async move { while bs.next().await.is_some() {} });
+        Ok(addr)
+    }
+
+    /// Drives the `node` until `f` picks one of its events.
+    pub async fn next_event<S, T>(node: &mut S, mut f: impl FnMut(S::Item) -> Option<T>) -> T
+    where
+        S: Stream + Unpin,
+    {
+        loop {
+            match node.next().await {
+                Some(event) => {
+                    if let Some(picked) = f(event) {
+                        return picked;
+                    }
+                }
+                None => panic!("the node stopped"),
+            }
+        }
+    }
+
+    /// Drives the `node` until it bootstrapped.
+    pub async fn bootstrapped(node: &mut RpcDht) -> QueryStats {
+        next_event(node, |event| match event {
+            RpcDhtEvent::Bootstrapped { stats } => Some(stats),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Drives the `node` until the query finished.
+    pub async fn finish_query(node: &mut RpcDht, id: QueryId) -> QueryStats {
+        next_event(node, |event| match event {
+            RpcDhtEvent::QueryResult {
+                id: query, stats, ..
+            } if query == id => Some(stats),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Drives the `node` until `done` holds.
+    pub async fn until<S>(node: &mut S, mut done: impl FnMut(&S) -> bool)
+    where
+        S: Stream + Unpin,
+    {
+        futures::future::poll_fn(|cx| loop {
+            if done(node) {
+                return Poll::Ready(());
+            }
+            match node.poll_next_unpin(cx) {
+                Poll::Ready(Some(_)) => {}
+                Poll::Ready(None) => panic!("the node stopped"),
+                Poll::Pending => {
+                    return if done(node) {
+                        Poll::Ready(())
+                    } else {
+                        Poll::Pending
+                    }
+                }
+            }
+        })
+        .await
+    }
+
+    /// Drives the `node` while waiting for the `future`.
+    pub async fn drive<S, F>(node: &mut S, future: F) -> F::Output
+    where
+        S: Stream + Unpin,
+        F: Future,
+    {
+        futures::pin_mut!(future);
+        futures::future::poll_fn(|cx| {
+            if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                return Poll::Ready(output);
+            }
+            while let Poll::Ready(event) = node.poll_next_unpin(cx) {
+                assert!(event.is_some(), "the node stopped");
+                if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                    return Poll::Ready(output);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// A ping from a node that doesn't tell its id.
+    pub fn ping() -> Message {
+        query(Command::Ping, None)
+    }
+
+    /// A request of the `command` for the `target`.
+    pub fn query(command: Command, target: Option<IdBytes>) -> Message {
+        Message {
+            version: Some(VERSION),
+            r#type: Type::Query.id(),
+            command: Some(command.to_string()),
+            target: target.map(|target| target.to_vec().into()),
+            ..Default::default()
+        }
+    }
+
+    /// A response of the node with the `id`.
+    pub fn pong(id: &IdBytes) -> Message {
+        Message {
+            version: Some(VERSION),
+            r#type: Type::Response.id(),
+            id: Some(id.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    /// A peer that sends and receives hand-made messages instead of running
+    /// a node.
+    #[derive(Debug)]
+    pub struct Remote {
+        socket: MemorySocket,
+        id: IdBytes,
+    }
+
+    impl Remote {
+        pub fn bind(network: &Network) -> Self {
+            Self {
+                socket: network.bind(),
+                id: network.random_id(),
+            }
+        }
+
+        pub fn addr(&self) -> SocketAddr {
+            self.socket.addr()
+        }
+
+        /// The id the remote answers with.
+        pub fn id(&self) -> &IdBytes {
+            &self.id
+        }
+
+        pub fn peer_id(&self) -> PeerId {
+            PeerId {
+                addr: self.addr(),
+                id: self.id.clone(),
+            }
+        }
+
+        pub async fn send(&mut self, msg: &Message, to: SocketAddr) -> io::Result<()> {
+            self.socket.send((msg.encode_to_vec(false), to)).await
+        }
+
+        /// Waits for the next message.
+        pub async fn recv(&mut self) -> io::Result<(Message, SocketAddr)> {
+            self.socket.next().await.expect("sockets never end")
+        }
+
+        /// The messages that arrived so far.
+        pub fn received(&mut self) -> Vec<Message> {
+            let mut received = Vec::new();
+            while let Some(Some(Ok((msg, _)))) = self.socket.next().now_or_never() {
+                received.push(msg);
+            }
+            received
+        }
+
+        /// Answers the request `req` from `to` with `resp`, sent with the id
+        /// of the remote.
+        pub async fn reply(
+            &mut self,
+            req: &Message,
+            to: SocketAddr,
+            resp: Message,
+        ) -> io::Result<()> {
+            let resp = Message {
+                rid: req.rid,
+                id: Some(self.id.to_vec()),
+                ..resp
+            };
+            self.send(&resp, to).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use fnv::FnvHashSet;
+    use futures::{SinkExt, StreamExt};
+
+    use crate::kbucket::{Key, K_VALUE};
+    use crate::rpc::{
+        message::Command, message::Type, DhtConfig, PeerId, RequestOk, ResponseOk, RpcDht,
+        RpcDhtEvent, ADAPTIVE_UPTIME, BUCKET_REFRESH_INTERVAL,
+    };
+    use crate::{HyperDht, HyperDhtEvent, JoinOpts, QueryOpts};
+
+    use super::harness::{self, config, drive, finish_query, spawn_bootstrap};
+    use super::*;
+
+    /// The requests of a node that were sent but not answered yet.
+    #[derive(Debug, Clone, Default)]
+    struct Outstanding {
+        inner: Arc<Mutex<(FnvHashSet<u64>, usize)>>,
+    }
+
+    impl Outstanding {
+        /// The most requests that were outstanding at once.
+        fn max(&self) -> usize {
+            self.inner.lock().unwrap().1
+        }
+    }
+
+    /// A transport that tracks the outstanding requests other than pings.
+    #[derive(Debug)]
+    struct Tracking<T> {
+        inner: T,
+        outstanding: Outstanding,
+    }
+
+    impl<T: Transport> Stream for Tracking<T> {
+        type Item = io::Result<(Message, SocketAddr)>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let poll = Stream::poll_next(Pin::new(&mut self.inner), cx);
+            if let Poll::Ready(Some(Ok((msg, _)))) = &poll {
+                if msg.is_response() {
+                    self.outstanding.inner.lock().unwrap().0.remove(&msg.rid);
+                }
+            }
+            poll
+        }
+    }
+
+    impl<T: Transport> Sink<(Vec<u8>, SocketAddr)> for Tracking<T> {
+        type Error = io::Error;
+
+        fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Sink::poll_ready(Pin::new(&mut self.inner), cx)
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: (Vec<u8>, SocketAddr)) -> io::Result<()> {
+            let msg = Message::decode_bytes(item.0.clone().into())?;
+            if (msg.is_query() || msg.is_update()) && !msg.is_ping() {
+                let mut outstanding = self.outstanding.inner.lock().unwrap();
+                outstanding.0.insert(msg.rid);
+                outstanding.1 = outstanding.1.max(outstanding.0.len());
+            }
+            Sink::start_send(Pin::new(&mut self.inner), item)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Sink::poll_flush(Pin::new(&mut self.inner), cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Sink::poll_close(Pin::new(&mut self.inner), cx)
+        }
+    }
+
+    impl<T: Transport> Transport for Tracking<T> {
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+
+        fn set_max_message_size(&mut self, max_message_size: usize) {
+            self.inner.set_max_message_size(max_message_size)
+        }
+
+        fn bind_probe(&self) -> io::Result<Box<dyn Transport>> {
+            self.inner.bind_probe()
+        }
+    }
+
+    fn ping(rid: u64) -> Vec<u8> {
+        Message {
+            rid,
+            ..harness::ping()
+        }
+        .encode_to_vec(false)
+    }
+
+    #[test]
+    fn deliver_with_latency_and_loss() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(0);
+            let mut a = network.bind();
+            let mut b = network.bind();
+            network.set_link(
+                a.addr(),
+                b.addr(),
+                Link::with_latency(Duration::from_millis(50)),
+            );
+
+            let start = time::now();
+            a.send((ping(1), b.addr())).await?;
+            let (msg, from) = b.next().await.unwrap()?;
+            assert_eq!(time::now() - start, Duration::from_millis(50));
+            assert_eq!((msg.rid, from), (1, a.addr()));
+
+            // a lossy link drops everything, and so does sending to nobody
+            network.set_link(b.addr(), a.addr(), Link::lossy(1.0));
+            b.send((ping(2), a.addr())).await?;
+            a.send((ping(3), ([10, 0, 0, 2], 1).into())).await?;
+            assert_eq!(network.num_sent(), 3);
+            assert_eq!(network.num_dropped(), 2);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn reorder_with_jitter() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(7);
+            network.set_default_link(Link {
+                jitter: Duration::from_millis(20),
+                ..Default::default()
+            });
+            let mut a = network.bind();
+            let mut b = network.bind();
+            for rid in 0..20 {
+                a.send((ping(rid), b.addr())).await?;
+            }
+            let mut received = Vec::new();
+            for _ in 0..20 {
+                received.push(b.next().await.unwrap()?.0.rid);
+            }
+            let mut sorted = received.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+            assert_ne!(received, sorted);
+            Ok(())
+        })
+    }
+
+    /// Spawns `num` nodes that are driven in the background and waits until
+    /// all of them are bootstrapped.
+    async fn spawn_nodes(
+        network: &Network,
+        num: usize,
+        bootstrap: SocketAddr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        spawn_nodes_with(network, num, bootstrap, |config| config).await
+    }
+
+    /// Like [`spawn_nodes`], with `configure` applied to the config of every
+    /// node.
+    async fn spawn_nodes_with(
+        network: &Network,
+        num: usize,
+        bootstrap: SocketAddr,
+        configure: fn(DhtConfig) -> DhtConfig,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        for _ in 0..num {
+            let config = configure(config(network).set_bootstrap_nodes(&[bootstrap]));
+            let mut node = HyperDht::with_config(config).await?;
+            let tx = tx.clone();
+            spawn(async move {
+                while let Some(event) = node.next().await {
+                    if let HyperDhtEvent::Bootstrapped { .. } = event {
+                        let _ = tx.unbounded_send(());
+                    }
+                }
+            });
+        }
+        assert_eq!(rx.take(num).count().await, num);
+        Ok(())
+    }
+
+    #[test]
+    fn stats_track_queries() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(26);
+            let bs = spawn_bootstrap(&network).await?;
+            let mut node = RpcDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            // the stats are read through a shared reference
+            let stats = |node: &RpcDht| node.stats();
+            let before = stats(&node);
+            assert_eq!(
+                (before.nodes, before.messages_in, before.messages_out),
+                (0, 0, 0)
+            );
+            harness::bootstrapped(&mut node).await;
+            let after_bootstrap = stats(&node);
+            assert_eq!(after_bootstrap.nodes, 1);
+            assert_eq!(after_bootstrap.buckets.iter().sum::<usize>(), 1);
+            assert_eq!(after_bootstrap.active_queries, 0);
+
+            let id = node.query(Command::FindNode, Key::new(IdBytes::random()), None);
+            assert_eq!(stats(&node).active_queries, 1);
+            finish_query(&mut node, id).await;
+
+            let stats = stats(&node);
+            assert_eq!(stats.active_queries, 0);
+            assert!(stats.messages_in > after_bootstrap.messages_in);
+            assert!(stats.messages_out > after_bootstrap.messages_out);
+            assert!(stats.bytes_in > after_bootstrap.bytes_in);
+            assert!(stats.bytes_out > after_bootstrap.bytes_out);
+            assert_eq!(stats.malformed_messages, 0);
+            assert!(stats.queries.num_requests() > after_bootstrap.queries.num_requests());
+            assert!(stats.queries.num_successes() > after_bootstrap.queries.num_successes());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn throttled_socket_keeps_responses() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(13);
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 10, bs).await?;
+
+            let mut node = RpcDht::with_config(
+                DhtConfig::default()
+                    .set_transport(network.bind().throttled(Duration::from_millis(1)))
+                    .set_request_timeout(Duration::from_millis(20))
+                    .set_bootstrap_nodes(&[bs])
+                    .set_send_queue_capacity(2)
+                    .set_parallelism(NonZeroUsize::new(8).unwrap()),
+            )
+            .await?;
+            let addr = node.local_addr()?;
+            while !matches!(node.next().await, Some(RpcDhtEvent::Bootstrapped { .. })) {}
+
+            // the queries wait for the socket instead of overflowing the queue,
+            // only the response to the NAT probe took the place of requests
+            let dropped = node.stats().dropped_messages;
+            let id = node.query(Command::FindNode, Key::new(network.random_id()), None);
+            finish_query(&mut node, id).await;
+            assert_eq!(node.stats().dropped_messages, dropped);
+
+            // requests make room for the responses to a remote
+            let mut remote = network.bind();
+            let id = node.query(Command::FindNode, Key::new(network.random_id()), None);
+            for rid in 0..2 {
+                remote.send((ping(rid), addr)).await?;
+            }
+            finish_query(&mut node, id).await;
+            let mut pongs = Vec::new();
+            while pongs.len() < 2 {
+                let (msg, _) = drive(&mut node, remote.next()).await.unwrap()?;
+                pongs.push(msg.rid);
+            }
+            pongs.sort_unstable();
+            assert_eq!(pongs, [0, 1]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn drop_oversized_messages() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(17);
+            let mut node = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .set_max_message_size(64),
+            )
+            .await?;
+            let addr = node.local_addr()?;
+
+            let mut remote = network.bind();
+            let mut msg: Message = prost::Message::decode(&ping(1)[..])?;
+            msg.value = Some(vec![0; 64].into());
+            let mut oversized = Vec::new();
+            prost::Message::encode(&msg, &mut oversized)?;
+            remote.send((oversized, addr)).await?;
+            remote.send((ping(2), addr)).await?;
+
+            let (pong, _) = drive(&mut node, remote.next()).await.unwrap()?;
+            assert_eq!(pong.rid, 2);
+            assert_eq!(node.stats().malformed_messages, 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn limit_requests_in_flight() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(15);
+            // the responses take a while, so that the requests pile up
+            network.set_default_link(Link::with_latency(Duration::from_millis(20)));
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 10, bs).await?;
+
+            let outstanding = Outstanding::default();
+            let socket = Tracking {
+                inner: network.bind(),
+                outstanding: outstanding.clone(),
+            };
+            let mut node = RpcDht::with_config(
+                DhtConfig::default()
+                    .set_transport(socket)
+                    .set_local_id(network.random_id())
+                    .set_request_timeout(Duration::from_millis(500))
+                    .set_bootstrap_nodes(&[bs])
+                    .set_max_requests_in_flight(3)
+                    .set_parallelism(NonZeroUsize::new(8).unwrap()),
+            )
+            .await?;
+            while !matches!(node.next().await, Some(RpcDhtEvent::Bootstrapped { .. })) {}
+
+            let mut queries = (0..4)
+                .map(|_| node.query(Command::FindNode, Key::new(network.random_id()), None))
+                .collect::<FnvHashSet<_>>();
+            while !queries.is_empty() {
+                match node.next().await {
+                    Some(RpcDhtEvent::QueryResult { id, .. }) => {
+                        queries.remove(&id);
+                    }
+                    Some(_) => {}
+                    None => panic!("the node stopped"),
+                }
+                assert!(node.stats().requests_in_flight <= 3);
+            }
+            assert_eq!(outstanding.max(), 3);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn cancel_queued_query() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(30);
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 5, bs).await?;
+            let mut node = RpcDht::with_config(
+                config(&network)
+                    .set_bootstrap_nodes(&[bs])
+                    .set_max_active_queries(1),
+            )
+            .await?;
+            while !matches!(node.next().await, Some(RpcDhtEvent::Bootstrapped { .. })) {}
+
+            let running = node.query(Command::FindNode, Key::new(network.random_id()), None);
+            let queued = node.query(Command::FindNode, Key::new(network.random_id()), None);
+            assert_eq!(node.stats().pending_queries, 1);
+            assert!(node.cancel_query(&queued));
+            assert_eq!(node.stats().pending_queries, 0);
+            // the queued query never sends a request
+            let mut cancelled = false;
+            loop {
+                match node.next().await {
+                    Some(RpcDhtEvent::QueryCancelled { id, stats, .. }) => {
+                        assert_eq!(id, queued);
+                        assert_eq!(stats.num_requests(), 0);
+                        cancelled = true;
+                    }
+                    Some(RpcDhtEvent::QueryResult { id, .. }) => {
+                        assert_eq!(id, running);
+                        break;
+                    }
+                    Some(_) => {}
+                    None => panic!("the node stopped"),
+                }
+            }
+            assert!(cancelled);
+            assert!(!node.cancel_query(&running));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn give_up_on_failed_socket() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(16);
+            let (socket, faults) = Faulty::new(network.bind());
+            let mut node = RpcDht::with_config(
+                DhtConfig::default()
+                    .set_transport(socket)
+                    .empty_bootstrap_nodes(),
+            )
+            .await?;
+            // a socket of the network can't be bound again
+            faults.fail_recv(io::ErrorKind::Other);
+            loop {
+                match node.next().await {
+                    Some(RpcDhtEvent::SocketError { err }) => {
+                        assert_eq!(err.kind(), io::ErrorKind::Other);
+                        break;
+                    }
+                    Some(RpcDhtEvent::Rebound { .. }) => panic!("unexpected rebind"),
+                    Some(_) => {}
+                    None => panic!("the node stopped without an error"),
+                }
+            }
+            assert!(node.next().await.is_none());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn retry_failed_bootstrap() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(17);
+            let bs = spawn_bootstrap(&network).await?;
+            let socket = network.bind();
+            let addr = socket.addr();
+            network.set_link(addr, bs, Link::lossy(1.0));
+
+            let interval = Duration::from_secs(60);
+            let mut node = RpcDht::with_config(
+                DhtConfig::default()
+                    .set_transport(socket)
+                    .set_local_id(network.random_id())
+                    .set_request_timeout(Duration::from_millis(50))
+                    .set_bootstrap_nodes(&[bs])
+                    .bootstrap_interval(interval),
+            )
+            .await?;
+            loop {
+                match node.next().await {
+                    Some(RpcDhtEvent::BootstrapFailed { stats }) => {
+                        assert_eq!(stats.num_requests(), 1);
+                        break;
+                    }
+                    Some(RpcDhtEvent::Bootstrapped { .. }) => {
+                        panic!("bootstrapped without a reply")
+                    }
+                    Some(_) => {}
+                    None => panic!("the node stopped"),
+                }
+            }
+
+            // the next bootstrap reaches the node
+            network.set_link(addr, bs, Link::default());
+            let start = time::now();
+            timeout(2 * interval, async {
+                while !matches!(node.next().await, Some(RpcDhtEvent::Bootstrapped { .. })) {}
+            })
+            .await?;
+            assert!(time::now() - start <= interval);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn probe_nat_over_transport() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(18);
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 5, bs).await?;
+
+            let mut node = RpcDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            let status = loop {
+                match node.next().await {
+                    Some(RpcDhtEvent::NatStatus { status }) if status.firewalled.is_some() => {
+                        break status
+                    }
+                    Some(_) => {}
+                    None => panic!("the node stopped"),
+                }
+            };
+            // the probe is sent from a second socket of the network
+            assert_eq!(status.firewalled, Some(false));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn adaptive_node_turns_persistent() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(19);
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 5, bs).await?;
+
+            let start = time::now();
+            let mut node =
+                RpcDht::with_config(config(&network).set_bootstrap_nodes(&[bs]).adaptive()).await?;
+            assert!(node.is_ephemeral());
+            timeout(ADAPTIVE_UPTIME * 2, async {
+                while node.is_ephemeral() {
+                    node.next().await;
+                }
+            })
+            .await?;
+            assert!(time::now() - start >= ADAPTIVE_UPTIME);
+            assert_eq!(node.nat_status().firewalled, Some(false));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn every_node_finds_every_other() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            const NUM: usize = 10;
+            let network = Network::new(14);
+            let bs = spawn_bootstrap(&network).await?;
+
+            let (boot_tx, boot_rx) = futures::channel::mpsc::unbounded();
+            let (found_tx, found_rx) = futures::channel::mpsc::unbounded();
+            let mut ids = Vec::new();
+            let mut lookups = Vec::new();
+            for _ in 0..NUM {
+                let mut node =
+                    RpcDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+                ids.push(node.local_id().clone());
+                let (lookup_tx, mut lookup_rx) =
+                    futures::channel::oneshot::channel::<Vec<IdBytes>>();
+                lookups.push(lookup_tx);
+                let (boot_tx, found_tx) = (boot_tx.clone(), found_tx.clone());
+                spawn(async move {
+                    while !matches!(node.next().await, Some(RpcDhtEvent::Bootstrapped { .. })) {}
+                    let _ = boot_tx.unbounded_send(());
+                    // keep answering until all nodes are bootstrapped
+                    let others = loop {
+                        match futures::future::select(&mut lookup_rx, node.next()).await {
+                            futures::future::Either::Left((ids, _)) => break ids.unwrap(),
+                            futures::future::Either::Right(_) => {}
+                        }
+                    };
+                    let mut found = 0;
+                    for id in others {
+                        let query = node.query(Command::FindNode, Key::new(id.clone()), None);
+                        loop {
+                            match node.next().await {
+                                Some(RpcDhtEvent::QueryResult { id: q, closest, .. })
+                                    if q == query =>
+                                {
+                                    if closest.first().map(|(peer, _)| &peer.id) == Some(&id) {
+                                        found += 1;
+                                    }
+                                    break;
+                                }
+                                Some(_) => {}
+                                None => return,
+                            }
+                        }
+                    }
+                    let _ = found_tx.unbounded_send(found);
+                    while node.next().await.is_some() {}
+                });
+            }
+            assert_eq!(boot_rx.take(NUM).count().await, NUM);
+            for (i, lookup) in lookups.into_iter().enumerate() {
+                let mut others = ids.clone();
+                others.remove(i);
+                let _ = lookup.send(others);
+            }
+            let found = found_rx.take(NUM).collect::<Vec<_>>().await;
+            assert_eq!(found, vec![NUM - 1; NUM]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn track_rtt_per_node() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(3);
+            // long enough that the slow peer answers before the ping is resent
+            let mut node = RpcDht::with_config(
+                config(&network)
+                    .empty_bootstrap_nodes()
+                    .set_request_timeout(Duration::from_millis(500)),
+            )
+            .await?;
+            let mut peers = Vec::new();
+            for latency in [2, 30] {
+                let mut peer =
+                    RpcDht::with_config(config(&network).empty_bootstrap_nodes()).await?;
+                let addr = peer.local_addr()?;
+                network.set_link(
+                    node.local_addr()?,
+                    addr,
+                    Link::with_latency(Duration::from_millis(latency)),
+                );
+                peers.push(PeerId {
+                    addr,
+                    id: peer.local_id().clone(),
+                });
+                spawn(async move { while peer.next().await.is_some() {} });
+            }
+            assert_eq!(node.stats().median_rtt, None);
+
+            for peer in &peers {
+                node.ping(peer);
+            }
+            // the pongs are reported as invalid since neither peer is in the
+            // routing table, but their round trip time is measured anyway
+            let mut pongs = 0;
+            while pongs < peers.len() {
+                if let Some(RpcDhtEvent::ResponseResult(_)) = node.next().await {
+                    pongs += 1;
+                }
+            }
+            let fast = node.peer_rtt(&peers[0].id).unwrap();
+            let slow = node.peer_rtt(&peers[1].id).unwrap();
+            assert!(fast < slow);
+            assert!(slow >= Duration::from_millis(30));
+            assert!(node.stats().median_rtt.is_some());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn responses_name_their_referrers() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(5);
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 20, bs).await?;
+
+            let mut node = RpcDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            let mut responders = vec![bs];
+            let mut query = None;
+            loop {
+                match node.next().await {
+                    Some(RpcDhtEvent::Bootstrapped { .. }) => {
+                        query = Some(node.query(
+                            Command::FindNode,
+                            Key::new(network.random_id()),
+                            None,
+                        ));
+                    }
+                    Some(RpcDhtEvent::ResponseResult(Ok(ResponseOk::Response(resp))))
+                        if Some(resp.query) == query =>
+                    {
+                        if resp.peer == bs {
+                            assert!(resp.referrers.is_empty());
+                        } else {
+                            // only nodes that responded before can have returned it
+                            assert!(!resp.referrers.is_empty());
+                            assert!(resp.referrers.len() <= 3);
+                            assert!(resp.referrers.iter().all(|r| responders.contains(r)));
+                        }
+                        responders.push(resp.peer);
+                    }
+                    Some(RpcDhtEvent::QueryResult { id, .. }) if Some(id) == query => break,
+                    _ => {}
+                }
+            }
+            assert!(responders.len() > 2);
+            Ok(())
+        })
+    }
+
+    /// Pings `num_peers` nodes behind links of 200ms latency for a few rounds
+    /// and returns how many pings were sent again.
+    async fn retries_on_slow_links(adaptive: bool) -> Result<u64, Box<dyn std::error::Error>> {
+        let network = Network::new(4);
+        network.set_default_link(Link::with_latency(Duration::from_millis(200)));
+        // shorter than the round trip time of 400ms
+        let mut node = RpcDht::with_config(
+            config(&network)
+                .empty_bootstrap_nodes()
+                .set_request_timeout(Duration::from_millis(250))
+                .set_adaptive_timeouts(adaptive),
+        )
+        .await?;
+        let mut peers = Vec::new();
+        for _ in 0..3 {
+            let mut peer = RpcDht::with_config(config(&network).empty_bootstrap_nodes()).await?;
+            peers.push(PeerId {
+                addr: peer.local_addr()?,
+                id: peer.local_id().clone(),
+            });
+            spawn(async move { while peer.next().await.is_some() {} });
+        }
+        for _ in 0..3 {
+            for peer in &peers {
+                node.ping(peer);
+            }
+            let mut pongs = 0;
+            while pongs < peers.len() {
+                if let Some(RpcDhtEvent::ResponseResult(_)) = node.next().await {
+                    pongs += 1;
+                }
+            }
+        }
+        Ok(node.stats().retried_requests)
+    }
+
+    #[test]
+    fn adaptive_timeouts_on_slow_links() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            // every single ping is sent again with the fixed timeout
+            assert!(retries_on_slow_links(false).await? >= 9);
+            // only the first pings to the unknown peers are
+            assert!(retries_on_slow_links(true).await? <= 3);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn announce_and_lookup_with_loss() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(1);
+            network.set_default_link(Link {
+                loss: 0.1,
+                latency: Duration::from_millis(1),
+                jitter: Duration::from_millis(2),
+            });
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 30, bs).await?;
+
+            let start = time::now();
+            let opts = QueryOpts::new(network.random_id()).port(4242);
+            let mut announcer =
+                HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            let announcer_addr = announcer.local_addr()?;
+            loop {
+                match announcer.next().await {
+                    Some(HyperDhtEvent::Bootstrapped { .. }) => {
+                        announcer.announce(opts.clone());
+                    }
+                    Some(HyperDhtEvent::AnnounceResult { .. }) => break,
+                    _ => {}
+                }
+            }
+            spawn(async move { while announcer.next().await.is_some() {} });
+
+            let mut node =
+                HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            let lookup = loop {
+                match node.next().await {
+                    Some(HyperDhtEvent::Bootstrapped { .. }) => {
+                        node.lookup(opts.topic.clone());
+                    }
+                    Some(HyperDhtEvent::LookupResult { lookup, .. }) => break lookup,
+                    _ => {}
+                }
+            };
+            let found = lookup
+                .all_peers()
+                .any(|peer| *peer == SocketAddr::new(announcer_addr.ip(), 4242));
+            assert!(found);
+            assert!(network.num_dropped() > 0);
+            assert!(time::now() - start < Duration::from_secs(10));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn immutable_put_get_with_reordering() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(2);
+            network.set_default_link(Link {
+                loss: 0.05,
+                latency: Duration::from_millis(1),
+                jitter: Duration::from_millis(5),
+            });
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 20, bs).await?;
+
+            let mut a = HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            let value = b"simulated".to_vec();
+            loop {
+                match a.next().await {
+                    Some(HyperDhtEvent::Bootstrapped { .. }) => {
+                        a.put_immutable(&value);
+                    }
+                    Some(HyperDhtEvent::PutImmutableResult { .. }) => break,
+                    _ => {}
+                }
+            }
+            spawn(async move { while a.next().await.is_some() {} });
+
+            let mut b = HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            let key = crate::crypto::hash_id(&value);
+            let result = loop {
+                match b.next().await {
+                    Some(HyperDhtEvent::Bootstrapped { .. }) => {
+                        assert!(b.get_immutable(key.clone()).is_left());
+                    }
+                    Some(HyperDhtEvent::GetImmutableResult(result)) => break result,
+                    _ => {}
+                }
+            };
+            assert!(result.values().any(|v| *v == value));
+            Ok(())
+        })
+    }
+
+    async fn bootstrapped(node: &mut HyperDht) {
+        while !matches!(node.next().await, Some(HyperDhtEvent::Bootstrapped { .. })) {}
+    }
+
+    /// Drives the node for `duration` and returns its events.
+    async fn drive_for(node: &mut HyperDht, duration: Duration) -> Vec<HyperDhtEvent> {
+        let mut events = Vec::new();
+        let _ = timeout(duration, async {
+            while let Some(event) = node.next().await {
+                events.push(event);
+            }
+        })
+        .await;
+        events
+    }
+
+    /// Looks up the topic and returns whether `peer` was found.
+    async fn lookup_finds(node: &mut HyperDht, topic: &IdBytes, peer: SocketAddr) -> bool {
+        let id = node.lookup(topic.clone());
+        loop {
+            if let Some(HyperDhtEvent::LookupResult { lookup, query_id }) = node.next().await {
+                if query_id == id {
+                    return lookup.all_peers().any(|p| *p == peer);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reannounce_joined_topic_before_expiry() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            const TTL: Duration = Duration::from_secs(10 * 60);
+            let network = Network::new(4);
+            network.set_default_link(Link::with_latency(Duration::from_millis(1)));
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes_with(&network, 10, bs, |config| config.set_peers_max_age(TTL)).await?;
+            let config = || {
+                config(&network)
+                    .set_bootstrap_nodes(&[bs])
+                    .set_peers_max_age(TTL)
+            };
+
+            let mut announcer = HyperDht::with_config(config()).await?;
+            let peer = SocketAddr::new(announcer.local_addr()?.ip(), 4242);
+            bootstrapped(&mut announcer).await;
+            let joined = QueryOpts::new(network.random_id()).port(4242);
+            let handle = announcer.join(joined.clone(), JoinOpts::new(true, false));
+            // announced once, expires on the remotes after the ttl
+            let once = QueryOpts::new(network.random_id()).port(4242);
+            announcer.announce(once.clone());
+            spawn(async move {
+                let _handle = handle;
+                while announcer.next().await.is_some() {}
+            });
+
+            sleep(3 * TTL).await;
+            let mut node = HyperDht::with_config(config()).await?;
+            bootstrapped(&mut node).await;
+            assert!(lookup_finds(&mut node, &joined.topic, peer).await);
+            assert!(!lookup_finds(&mut node, &once.topic, peer).await);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn leave_topic_stops_traffic() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(5);
+            network.set_default_link(Link::with_latency(Duration::from_millis(1)));
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 10, bs).await?;
+
+            let topic = network.random_id();
+            let mut other =
+                HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            let other_peer = SocketAddr::new(other.local_addr()?.ip(), 4242);
+            bootstrapped(&mut other).await;
+            other.announce(QueryOpts::new(topic.clone()).port(4242));
+            while !matches!(
+                other.next().await,
+                Some(HyperDhtEvent::AnnounceResult { .. })
+            ) {}
+            spawn(async move { while other.next().await.is_some() {} });
+
+            let mut node =
+                HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            let peer = SocketAddr::new(node.local_addr()?.ip(), 4343);
+            bootstrapped(&mut node).await;
+            let join = JoinOpts::default().lookup_interval(Duration::from_millis(50));
+            let handle = node.join(QueryOpts::new(topic.clone()).port(4343), join);
+
+            // many lookups, but the peer is reported once
+            let mut discovered = Vec::new();
+            for event in drive_for(&mut node, Duration::from_millis(500)).await {
+                match event {
+                    HyperDhtEvent::PeerDiscovered {
+                        topic: t,
+                        peer,
+                        local,
+                        ..
+                    } => {
+                        assert_eq!(t, topic);
+                        assert!(!local);
+                        discovered.push(peer);
+                    }
+                    HyperDhtEvent::Peers { .. }
+                    | HyperDhtEvent::AnnounceResult { .. }
+                    | HyperDhtEvent::LookupResult { .. } => panic!("Unexpected event {:?}", event),
+                    _ => {}
+                }
+            }
+            // lookups don't return the announcement of the node itself
+            assert_eq!(discovered, [other_peer]);
+
+            handle.leave();
+            // the unannouncement is the last traffic for the topic
+            let events = drive_for(&mut node, Duration::from_millis(300)).await;
+            assert!(events.is_empty(), "{:?}", events);
+            let sent = node.stats().messages_out;
+            drive_for(&mut node, Duration::from_millis(300)).await;
+            assert_eq!(node.stats().messages_out, sent);
+
+            assert!(lookup_finds(&mut node, &topic, other_peer).await);
+            assert!(!lookup_finds(&mut node, &topic, peer).await);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn rebind_after_network_change() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(8);
+            network.set_default_link(Link::with_latency(Duration::from_millis(1)));
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 10, bs).await?;
+
+            let topic = network.random_id();
+            let mut other =
+                HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            let other_peer = SocketAddr::new(other.local_addr()?.ip(), 4242);
+            bootstrapped(&mut other).await;
+            other.announce(QueryOpts::new(topic.clone()).port(4242));
+            while !matches!(
+                other.next().await,
+                Some(HyperDhtEvent::AnnounceResult { .. })
+            ) {}
+            spawn(async move { while other.next().await.is_some() {} });
+
+            let mut node = HyperDht::with_config(
+                config(&network)
+                    .set_bootstrap_nodes(&[bs])
+                    .set_network_suspect_window(Duration::from_millis(100)),
+            )
+            .await?;
+            let peer = SocketAddr::new(node.local_addr()?.ip(), 4343);
+            bootstrapped(&mut node).await;
+            assert!(lookup_finds(&mut node, &topic, other_peer).await);
+
+            // the socket silently stops working
+            network.disconnect(node.local_addr()?);
+            let joined = QueryOpts::new(network.random_id()).port(4343);
+            let _handle = node.join(joined.clone(), JoinOpts::new(true, false));
+            node.lookup(topic.clone());
+            let suspect = timeout(Duration::from_secs(10), async {
+                while !matches!(
+                    node.next().await,
+                    Some(HyperDhtEvent::NetworkSuspect { .. })
+                ) {}
+            });
+            assert!(suspect.await.is_ok());
+
+            node.rebind_transport(network.bind());
+            assert!(lookup_finds(&mut node, &topic, other_peer).await);
+            spawn(async move { while node.next().await.is_some() {} });
+
+            // the joined topic was announced over the new socket
+            let mut lookup =
+                HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            bootstrapped(&mut lookup).await;
+            assert!(lookup_finds(&mut lookup, &joined.topic, peer).await);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn update_reaches_closest_nodes() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(10);
+            let bs = spawn_bootstrap(&network).await?;
+
+            // every node reports the updates it receives
+            let (bootstrapped_tx, bootstrapped) = futures::channel::mpsc::unbounded();
+            let (updated_tx, mut updated) = futures::channel::mpsc::unbounded();
+            let mut ids = Vec::new();
+            for _ in 0..30 {
+                let config = config(&network)
+                    .set_bootstrap_nodes(&[bs])
+                    .register_commands(["store"]);
+                let mut node = RpcDht::with_config(config).await?;
+                let id = node.local_id().clone();
+                ids.push(id.clone());
+                let (bootstrapped_tx, updated_tx) = (bootstrapped_tx.clone(), updated_tx.clone());
+                spawn(async move {
+                    while let Some(event) = node.next().await {
+                        match event {
+                            RpcDhtEvent::Bootstrapped { .. } => {
+                                let _ = bootstrapped_tx.unbounded_send(());
+                            }
+                            RpcDhtEvent::RequestResult(Ok(RequestOk::CustomCommandRequest {
+                                query,
+                                ..
+                            })) => {
+                                if query.ty == Type::Update {
+                                    let _ = updated_tx.unbounded_send(id.clone());
+                                }
+                                node.reply_command(query);
+                            }
+                            _ => {}
+                        }
+                    }
+                });
+            }
+            assert_eq!(bootstrapped.take(ids.len()).count().await, ids.len());
+
+            let mut node = RpcDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            let target = Key::new(network.random_id());
+            let query = loop {
+                if let Some(RpcDhtEvent::Bootstrapped { .. }) = node.next().await {
+                    break node.query_and_update("store", target.clone(), Some(b"value".to_vec()));
+                }
+            };
+            loop {
+                if let Some(RpcDhtEvent::QueryResult { id, .. }) = node.next().await {
+                    if id == query {
+                        break;
+                    }
+                }
+            }
+
+            let mut received = Vec::new();
+            while let Ok(id) = updated.try_recv() {
+                received.push(id);
+            }
+            // once each
+            assert_eq!(received.len(), K_VALUE.get());
+            ids.sort_by_key(|id| target.distance(&Key::new(id.clone())));
+            let closest = ids[..K_VALUE.get()].iter().collect::<FnvHashSet<_>>();
+            assert_eq!(received.iter().collect::<FnvHashSet<_>>(), closest);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn refresh_lost_bucket() -> Result<(), Box<dyn std::error::Error>> {
+        Simulation::new().run(async {
+            let network = Network::new(9);
+            network.set_default_link(Link::with_latency(Duration::from_millis(1)));
+            let bs = spawn_bootstrap(&network).await?;
+            spawn_nodes(&network, 20, bs).await?;
+
+            let mut node =
+                HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+            bootstrapped(&mut node).await;
+            drive_for(&mut node, Duration::from_millis(50)).await;
+
+            // lose every node of the farthest bucket
+            let local = Key::new(node.inner.local_id().clone());
+            let lost = node
+                .inner
+                .nodes()
+                .map(|n| Key::new(n.id))
+                .filter(|key| local.distance(key).ilog2() == Some(255))
+                .collect::<Vec<_>>();
+            assert!(!lost.is_empty());
+            for key in &lost {
+                node.inner.remove_peer(key);
+            }
+            assert_eq!(node.bucket_info()[255].nodes, 0);
+
+            // the refreshes find the nodes again once the bucket was idle for the
+            // default interval, whichever bucket they were for
+            let start = time::now();
+            let refreshes = timeout(BUCKET_REFRESH_INTERVAL * 2, async {
+                let mut refreshes = 0;
+                while node.bucket_info()[255].nodes == 0 {
+                    if let Some(HyperDhtEvent::RefreshCompleted { .. }) = node.next().await {
+                        refreshes += 1;
+                    }
+                }
+                refreshes
+            })
+            .await?;
+            assert!(refreshes > 0);
+            assert!(time::now() - start >= BUCKET_REFRESH_INTERVAL / 2);
+            Ok(())
+        })
+    }
+
+    #[async_std::test]
+    async fn concurrent_lookups_through_handles() -> Result<(), Box<dyn std::error::Error>> {
+        let network = Network::new(6);
+        network.set_default_link(Link::with_latency(Duration::from_millis(1)));
+        let bs = spawn_bootstrap(&network).await?;
+        spawn_nodes(&network, 10, bs).await?;
+
+        let mut announcer =
+            HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+        let peer = SocketAddr::new(announcer.local_addr()?.ip(), 4242);
+        bootstrapped(&mut announcer).await;
+        let (announcer, _) = announcer.spawn();
+        let topics = [network.random_id(), network.random_id()];
+        for topic in &topics {
+            let opts = QueryOpts::new(topic.clone()).port(4242);
+            announcer.announce(opts).await?;
+        }
+
+        let mut node = HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+        bootstrapped(&mut node).await;
+        let (handle, task) = node.spawn();
+        let lookups = topics.iter().cloned().map(|topic| {
+            let handle = handle.clone();
+            async_std::task::spawn(async move { handle.lookup(topic).await })
+        });
+        for lookup in futures::future::join_all(lookups).await {
+            assert!(lookup?.all_peers().any(|p| *p == peer));
+        }
+        assert!(handle.stats().await?.queries.num_requests() > 0);
+
+        // the task stops once all handles are dropped
+        drop(handle);
+        task.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn find_nodes_through_handles() -> Result<(), Box<dyn std::error::Error>> {
+        use futures::FutureExt;
+
+        let network = Network::new(8);
+        network.set_default_link(Link::with_latency(Duration::from_millis(1)));
+        let bs = spawn_bootstrap(&network).await?;
+        spawn_nodes(&network, 5, bs).await?;
+
+        let mut a = HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+        let (a_id, a_addr) = (a.local_id().clone(), a.local_addr()?);
+        bootstrapped(&mut a).await;
+        let (_a, _) = a.spawn();
+
+        let mut b = HyperDht::with_config(config(&network).set_bootstrap_nodes(&[bs])).await?;
+        bootstrapped(&mut b).await;
+        let (handle, _) = b.spawn();
+        let (closest, result) = futures::join!(
+            handle.find_node(a_id.clone()),
+            handle.query(Command::FindNode, network.random_id(), None)
+        );
+        let closest = closest?;
+        assert_eq!((closest[0].addr, &closest[0].id), (a_addr, &a_id));
+        let result = result?;
+        assert_eq!(result.cmd, Command::FindNode);
+        assert!(!result.closest.is_empty());
+
+        // a dropped future cancels its query
+        network.set_default_link(Link::with_latency(Duration::from_millis(100)));
+        assert!(handle
+            .find_node(network.random_id())
+            .now_or_never()
+            .is_none());
+        assert_eq!(handle.stats().await?.active_queries, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn ping_through_handles() -> Result<(), Box<dyn std::error::Error>> {
+        let network = Network::new(9);
+        network.set_default_link(Link::with_latency(Duration::from_millis(1)));
+        let bs = spawn_bootstrap(&network).await?;
+
+        let node = HyperDht::with_config(config(&network).empty_bootstrap_nodes()).await?;
+        let (handle, _) = node.spawn();
+        // the node doesn't need to be in the routing table
+        handle.ping(bs).await?;
+
+        let gone = network.bind().addr();
+        let (pinged, again) = futures::join!(handle.ping(gone), handle.ping(gone));
+        for result in [pinged, again] {
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! The clock that timeouts, periodic jobs and expiry times are measured with.
+//!
+//! Outside of tests this is the clock of `wasm_timer`. A
+//! [`Simulation`](crate::testing::Simulation) instead runs its nodes against
+//! a virtual clock that only advances once all of them are idle, so the
+//! simulated time doesn't depend on how fast the machine running it is.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use wasm_timer::Instant;
+
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use self::virtual_clock::VirtualClock;
+
+/// The current time, of the virtual clock of a simulation running on this
+/// thread if there is one.
+pub(crate) fn now() -> Instant {
+    #[cfg(any(test, feature = "testing"))]
+    if let Some(clock) = VirtualClock::current() {
+        return clock.now();
+    }
+    Instant::now()
+}
+
+/// A future that completes once its deadline passed, like
+/// [`wasm_timer::Delay`] on the clock of [`now`].
+pub(crate) struct Delay {
+    deadline: Instant,
+    inner: DelayInner,
+}
+
+enum DelayInner {
+    System(wasm_timer::Delay),
+    #[cfg(any(test, feature = "testing"))]
+    Virtual(virtual_clock::Timer),
+}
+
+impl Delay {
+    /// Completes `duration` from now.
+    pub fn new(duration: std::time::Duration) -> Self {
+        Self::new_at(now() + duration)
+    }
+
+    /// Completes at `deadline`.
+    pub fn new_at(deadline: Instant) -> Self {
+        #[cfg(any(test, feature = "testing"))]
+        if let Some(clock) = VirtualClock::current() {
+            return Self {
+                deadline,
+                inner: DelayInner::Virtual(clock.timer(deadline)),
+            };
+        }
+        Self {
+            deadline,
+            inner: DelayInner::System(wasm_timer::Delay::new_at(deadline)),
+        }
+    }
+}
+
+impl fmt::Debug for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delay")
+            .field("deadline", &self.deadline)
+            .finish()
+    }
+}
+
+impl Future for Delay {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match &mut self.inner {
+            DelayInner::System(delay) => Pin::new(delay).poll(cx),
+            #[cfg(any(test, feature = "testing"))]
+            DelayInner::Virtual(timer) => timer.poll(cx).map(Ok),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+mod virtual_clock {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::task::{Context, Poll, Waker};
+    use std::time::Duration;
+
+    use wasm_timer::Instant;
+
+    thread_local! {
+        static CURRENT: RefCell<Option<VirtualClock>> = const { RefCell::new(None) };
+    }
+
+    /// A clock that stands still until it is advanced.
+    #[derive(Debug, Clone)]
+    pub(crate) struct VirtualClock {
+        inner: Arc<Mutex<Inner>>,
+    }
+
+    #[derive(Debug)]
+    struct Inner {
+        now: Instant,
+        /// The wakers of the pending timers, by deadline.
+        timers: BTreeMap<(Instant, u64), Option<Waker>>,
+        next_timer: u64,
+    }
+
+    impl VirtualClock {
+        /// A clock that starts at the current system time.
+        pub fn new() -> Self {
+            Self {
+                inner: Arc::new(Mutex::new(Inner {
+                    now: Instant::now(),
+                    timers: Default::default(),
+                    next_timer: 0,
+                })),
+            }
+        }
+
+        fn lock(&self) -> MutexGuard<'_, Inner> {
+            self.inner.lock().expect("clock lock poisoned")
+        }
+
+        /// The clock of the simulation running on this thread.
+        pub fn current() -> Option<Self> {
+            CURRENT.with(|current| current.borrow().clone())
+        }
+
+        /// Runs `f` with this clock as the clock of the current thread.
+        pub fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+            struct Reset(Option<VirtualClock>);
+            impl Drop for Reset {
+                fn drop(&mut self) {
+                    CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+                }
+            }
+            let _reset = Reset(CURRENT.with(|current| current.replace(Some(self.clone()))));
+            f()
+        }
+
+        pub fn now(&self) -> Instant {
+            self.lock().now
+        }
+
+        /// The earliest deadline of a pending timer.
+        pub fn next_deadline(&self) -> Option<Instant> {
+            self.lock()
+                .timers
+                .keys()
+                .next()
+                .map(|(deadline, _)| *deadline)
+        }
+
+        /// Moves the clock forward to `deadline` and wakes the timers that
+        /// elapsed.
+        pub fn advance_to(&self, deadline: Instant) {
+            let wakers = {
+                let mut inner = self.lock();
+                inner.now = inner.now.max(deadline);
+                let later = (inner.now + Duration::from_nanos(1), 0);
+                let pending = inner.timers.split_off(&later);
+                std::mem::replace(&mut inner.timers, pending)
+            };
+            for waker in wakers.into_values().flatten() {
+                waker.wake();
+            }
+        }
+
+        pub(super) fn timer(&self, deadline: Instant) -> Timer {
+            let mut inner = self.lock();
+            let id = inner.next_timer;
+            inner.next_timer += 1;
+            inner.timers.insert((deadline, id), None);
+            Timer {
+                clock: self.clone(),
+                key: (deadline, id),
+            }
+        }
+    }
+
+    /// A timer of a [`VirtualClock`], removed from the clock when dropped.
+    pub(crate) struct Timer {
+        clock: VirtualClock,
+        key: (Instant, u64),
+    }
+
+    impl Timer {
+        pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            let mut inner = self.clock.lock();
+            if inner.now >= self.key.0 {
+                inner.timers.remove(&self.key);
+                return Poll::Ready(());
+            }
+            inner.timers.insert(self.key, Some(cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+
+    impl Drop for Timer {
+        fn drop(&mut self) {
+            self.clock.lock().timers.remove(&self.key);
+        }
+    }
+}
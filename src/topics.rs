@@ -0,0 +1,382 @@
+//! Topics that stay joined, see [`HyperDht::join`](crate::HyperDht::join).
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use fnv::FnvHashMap;
+use futures::channel::oneshot;
+use futures::task::{Context, Poll};
+use futures::Future;
+use wasm_timer::Instant;
+
+use crate::rpc::jobs::PeriodicJob;
+use crate::rpc::query::QueryId;
+use crate::{IdBytes, Peers, QueryOpts};
+
+/// How often a joined topic is looked up by default.
+pub const DEFAULT_LOOKUP_INTERVAL: Duration = Duration::from_secs(60 * 10);
+
+/// How long a discovered peer is not reported again by default.
+pub const DEFAULT_REDISCOVER_AFTER: Duration = Duration::from_secs(60 * 10);
+
+/// How a topic is kept joined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JoinOpts {
+    /// Announce the topic and announce it again before the remotes forget
+    /// about it.
+    pub announce: bool,
+    /// Look up the peers of the topic periodically.
+    pub lookup: bool,
+    /// How often the topic is looked up.
+    pub lookup_interval: Duration,
+    /// How long a discovered peer is not reported again.
+    pub rediscover_after: Duration,
+}
+
+impl JoinOpts {
+    pub fn new(announce: bool, lookup: bool) -> Self {
+        Self {
+            announce,
+            lookup,
+            lookup_interval: DEFAULT_LOOKUP_INTERVAL,
+            rediscover_after: DEFAULT_REDISCOVER_AFTER,
+        }
+    }
+
+    /// Set how often the topic is looked up.
+    pub fn lookup_interval(mut self, interval: Duration) -> Self {
+        self.lookup_interval = interval;
+        self
+    }
+
+    /// Set how long a discovered peer is not reported again.
+    pub fn rediscover_after(mut self, after: Duration) -> Self {
+        self.rediscover_after = after;
+        self
+    }
+}
+
+impl Default for JoinOpts {
+    fn default() -> Self {
+        Self::new(true, true)
+    }
+}
+
+/// Keeps a topic joined, see [`HyperDht::join`](crate::HyperDht::join).
+///
+/// The topic is left once the handle is dropped or [`TopicHandle::leave`] is
+/// called.
+#[derive(Debug)]
+pub struct TopicHandle {
+    topic: IdBytes,
+    _joined: oneshot::Sender<()>,
+}
+
+impl TopicHandle {
+    /// The joined topic.
+    pub fn topic(&self) -> &IdBytes {
+        &self.topic
+    }
+
+    /// Leaves the topic, it is unannounced if it was announced and no longer
+    /// looked up.
+    pub fn leave(self) {}
+}
+
+/// What needs to be done for a topic.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TopicAction {
+    Announce(QueryOpts),
+    Lookup(QueryOpts),
+    /// The topic was left, its running query needs to be stopped.
+    Leave {
+        opts: QueryOpts,
+        query: Option<QueryId>,
+        unannounce: bool,
+    },
+}
+
+#[derive(Debug)]
+struct Topic {
+    opts: QueryOpts,
+    join: JoinOpts,
+    left: oneshot::Receiver<()>,
+    announce_job: Option<PeriodicJob>,
+    lookup_job: Option<PeriodicJob>,
+    announce_due: bool,
+    lookup_due: bool,
+    /// The query running for the topic, there is only one at a time.
+    query: Option<QueryId>,
+    /// When a peer was last reported.
+    reported: FnvHashMap<SocketAddr, Instant>,
+}
+
+/// The joined topics of a node.
+#[derive(Debug)]
+pub(crate) struct Topics {
+    /// Half the time remotes keep an announcement.
+    announce_interval: Duration,
+    topics: FnvHashMap<IdBytes, Topic>,
+    /// The queries started for joined topics, including the unannouncements
+    /// of left ones.
+    queries: FnvHashMap<QueryId, IdBytes>,
+}
+
+impl Topics {
+    /// `ttl` is how long remotes keep an announcement.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            announce_interval: ttl / 2,
+            topics: Default::default(),
+            queries: Default::default(),
+        }
+    }
+
+    /// Joins the topic, the first announce or lookup is due right away.
+    ///
+    /// Joining a joined topic again replaces its options and the previous
+    /// handle no longer controls the topic.
+    pub fn join(&mut self, opts: QueryOpts, join: JoinOpts) -> TopicHandle {
+        let (tx, rx) = oneshot::channel();
+        let previous = self.topics.remove(&opts.topic);
+        let topic = Topic {
+            join,
+            left: rx,
+            announce_job: join
+                .announce
+                .then(|| PeriodicJob::new(self.announce_interval)),
+            lookup_job: join.lookup.then(|| PeriodicJob::new(join.lookup_interval)),
+            announce_due: join.announce,
+            lookup_due: join.lookup,
+            query: previous.as_ref().and_then(|t| t.query),
+            reported: previous.map(|t| t.reported).unwrap_or_default(),
+            opts,
+        };
+        let handle = TopicHandle {
+            topic: topic.opts.topic.clone(),
+            _joined: tx,
+        };
+        self.topics.insert(topic.opts.topic.clone(), topic);
+        handle
+    }
+
+    /// Makes the announce and lookup of every joined topic due right away.
+    pub fn refresh(&mut self) {
+        for topic in self.topics.values_mut() {
+            topic.announce_due = topic.join.announce;
+            topic.lookup_due = topic.join.lookup;
+        }
+    }
+
+    /// Whether the query was started for a joined topic.
+    pub fn contains_query(&self, id: &QueryId) -> bool {
+        self.queries.contains_key(id)
+    }
+
+    /// Records the query that was started for the `topic` of an action.
+    pub fn started(&mut self, topic: &IdBytes, id: QueryId) {
+        if let Some(topic) = self.topics.get_mut(topic) {
+            topic.query = Some(id);
+        }
+        self.queries.insert(id, topic.clone());
+    }
+
+    /// A query of a joined topic finished or was cancelled.
+    ///
+    /// Returns `false` if the query was not started for a joined topic.
+    pub fn finished(&mut self, id: &QueryId) -> bool {
+        if let Some(topic) = self.queries.remove(id) {
+            if let Some(topic) = self.topics.get_mut(&topic) {
+                if topic.query == Some(*id) {
+                    topic.query = None;
+                }
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the peers of the response to a query of a joined topic that
+    /// were not reported recently, and whether they are local.
+    pub fn discovered(
+        &mut self,
+        id: &QueryId,
+        peers: &Peers,
+        now: Instant,
+    ) -> Vec<(SocketAddr, bool)> {
+        let topics = &mut self.topics;
+        let topic = match self.queries.get(id).and_then(|t| topics.get_mut(t)) {
+            Some(topic) => topic,
+            None => return Vec::new(),
+        };
+        let rediscover_after = topic.join.rediscover_after;
+        let remotes = peers.peers.iter().map(|p| (*p, false));
+        let locals = peers.local_peers.iter().map(|p| (*p, true));
+        remotes
+            .chain(locals)
+            .filter(|(peer, _)| {
+                let recent = topic
+                    .reported
+                    .get(peer)
+                    .is_some_and(|at| now.duration_since(*at) < rediscover_after);
+                if !recent {
+                    topic.reported.insert(*peer, now);
+                }
+                !recent
+            })
+            .collect()
+    }
+
+    /// Returns the next announce or lookup that is due, or that the topic was
+    /// left.
+    pub fn poll(&mut self, cx: &mut Context<'_>, now: Instant) -> Poll<TopicAction> {
+        let mut left = None;
+        for (id, topic) in self.topics.iter_mut() {
+            if Pin::new(&mut topic.left).poll(cx).is_ready() {
+                left = Some(id.clone());
+                break;
+            }
+            if let Some(job) = topic.announce_job.as_mut() {
+                if job.poll(cx, now).is_ready() {
+                    topic.announce_due = true;
+                }
+            }
+            if let Some(job) = topic.lookup_job.as_mut() {
+                if job.poll(cx, now).is_ready() {
+                    topic.lookup_due = true;
+                }
+            }
+            if topic.query.is_some() {
+                continue;
+            }
+            // an announce looks up the peers as well
+            if topic.announce_due || topic.lookup_due {
+                let announce = std::mem::take(&mut topic.announce_due);
+                topic.lookup_due = false;
+                let rediscover_after = topic.join.rediscover_after;
+                topic
+                    .reported
+                    .retain(|_, at| now.duration_since(*at) < rediscover_after);
+                return Poll::Ready(if announce {
+                    TopicAction::Announce(topic.opts.clone())
+                } else {
+                    TopicAction::Lookup(topic.opts.clone())
+                });
+            }
+        }
+        if let Some(topic) = left.and_then(|id| self.topics.remove(&id)) {
+            return Poll::Ready(TopicAction::Leave {
+                unannounce: topic.join.announce,
+                query: topic.query,
+                opts: topic.opts,
+            });
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker_ref;
+
+    use super::*;
+
+    fn poll(topics: &mut Topics, now: Instant) -> Poll<TopicAction> {
+        topics.poll(&mut Context::from_waker(noop_waker_ref()), now)
+    }
+
+    fn peers(peers: &[u16]) -> Peers {
+        Peers {
+            node: ([10, 0, 0, 1], 1).into(),
+            peer_id: None,
+            peers: peers.iter().map(|p| ([10, 0, 0, 2], *p).into()).collect(),
+            local_peers: Vec::new(),
+            referrers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn one_query_per_topic() {
+        let mut topics = Topics::new(Duration::from_secs(60));
+        let opts = QueryOpts::new(IdBytes::random());
+        let handle = topics.join(opts.clone(), JoinOpts::default());
+        let now = Instant::now();
+
+        assert_eq!(
+            poll(&mut topics, now),
+            Poll::Ready(TopicAction::Announce(opts.clone()))
+        );
+        topics.started(handle.topic(), QueryId(1));
+        // the announce covers the first lookup
+        assert_eq!(poll(&mut topics, now), Poll::Pending);
+
+        assert!(topics.finished(&QueryId(1)));
+        assert!(!topics.finished(&QueryId(1)));
+        assert_eq!(poll(&mut topics, now), Poll::Pending);
+        topics.started(handle.topic(), QueryId(2));
+
+        handle.leave();
+        assert_eq!(
+            poll(&mut topics, now),
+            Poll::Ready(TopicAction::Leave {
+                opts: opts.clone(),
+                query: Some(QueryId(2)),
+                unannounce: true,
+            })
+        );
+        assert!(!topics.topics.contains_key(&opts.topic));
+        // the stopped query still belongs to the topic
+        assert!(topics.contains_query(&QueryId(2)));
+        assert_eq!(poll(&mut topics, now), Poll::Pending);
+    }
+
+    #[test]
+    fn leave_on_drop() {
+        let mut topics = Topics::new(Duration::from_secs(60));
+        let opts = QueryOpts::new(IdBytes::random());
+        drop(topics.join(opts.clone(), JoinOpts::new(false, true)));
+        assert_eq!(
+            poll(&mut topics, Instant::now()),
+            Poll::Ready(TopicAction::Leave {
+                opts,
+                query: None,
+                unannounce: false,
+            })
+        );
+    }
+
+    #[test]
+    fn deduplicate_discovered_peers() {
+        let mut topics = Topics::new(Duration::from_secs(60));
+        let join = JoinOpts::new(false, true).rediscover_after(Duration::from_secs(10));
+        let handle = topics.join(QueryOpts::new(IdBytes::random()), join);
+        let now = Instant::now();
+        topics.started(handle.topic(), QueryId(1));
+
+        let port = |found: Vec<(SocketAddr, bool)>| {
+            found.into_iter().map(|(p, _)| p.port()).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            port(topics.discovered(&QueryId(1), &peers(&[1, 2]), now)),
+            [1, 2]
+        );
+        assert_eq!(
+            port(topics.discovered(&QueryId(1), &peers(&[2, 3]), now)),
+            [3]
+        );
+        // responses to other queries are ignored
+        assert!(topics.discovered(&QueryId(2), &peers(&[4]), now).is_empty());
+
+        let later = now + Duration::from_secs(5);
+        assert!(topics
+            .discovered(&QueryId(1), &peers(&[1]), later)
+            .is_empty());
+        let much_later = now + Duration::from_secs(10);
+        assert_eq!(
+            port(topics.discovered(&QueryId(1), &peers(&[1, 3]), much_later)),
+            [1, 3]
+        );
+    }
+}
@@ -0,0 +1,35 @@
+//! Instrumentation with [`tracing`](https://docs.rs/tracing), only compiled
+//! in with the `tracing` feature.
+
+/// Emits a debug event, nothing without the `tracing` feature.
+///
+/// The fields are only evaluated if a subscriber is interested in the event.
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+/// Displays bytes as lowercase hex.
+pub(crate) struct Hex<'a>(pub &'a [u8]);
+
+impl std::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Displays the first bytes of an id as hex.
+#[cfg(feature = "tracing")]
+pub(crate) struct Prefix<'a>(pub &'a [u8]);
+
+#[cfg(feature = "tracing")]
+impl std::fmt::Display for Prefix<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Hex(&self.0[..self.0.len().min(4)]).fmt(f)
+    }
+}